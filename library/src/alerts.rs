@@ -0,0 +1,297 @@
+//! `AlertEngine` evaluates threshold alert rules against readings as they're
+//! relayed. A rule fires once its metric has stayed above `trigger_above` for
+//! `trigger_secs` and clears once it has stayed below `clear_below` for
+//! `clear_secs`, the gap between the thresholds stops alerts flapping
+use crate::db::model::{AlertEvent, AlertRule};
+use crate::iaq::Iaq;
+use crate::relay_server::Reading;
+use serde::Serialize;
+use std::collections::HashMap;
+use utoipa::ToSchema;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertState {
+    Firing,
+    Cleared,
+}
+
+impl AlertState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AlertState::Firing => "firing",
+            AlertState::Cleared => "cleared",
+        }
+    }
+}
+
+/// alert transition, sent to subscribers as `/alert {json}`
+#[derive(Clone, Debug, Serialize)]
+pub struct Alert {
+    pub rule_id: i32,
+    pub name: String,
+    pub pub_id: u64,
+    pub metric: String,
+    pub state: AlertState,
+    /// metric value of the reading that caused the transition
+    pub value: f64,
+    /// read_time of the reading that caused the transition
+    pub time: u64,
+}
+
+#[derive(Default, Debug)]
+struct RuleState {
+    /// the alert that fired, none while the rule is clear
+    firing: Option<Alert>,
+    /// read_time the metric first crossed the threshold towards the other state
+    pending_since: Option<u64>,
+}
+
+/// metrics every reading can be alerted on, others are the extra metrics a
+/// sensor reports
+pub const METRICS: [&str; 5] = ["eco2", "evtoc", "temperature", "humidity", "iaq"];
+
+/// value of a reading's metric by name, falling back to its extra metrics
+pub fn metric_value(reading: &Reading, metric: &str) -> Option<f64> {
+    match metric {
+        "eco2" => Some(reading.eco2 as f64),
+        "evtoc" => Some(reading.evtoc as f64),
+        "temperature" => reading.temperature,
+        "humidity" => reading.humidity,
+        "iaq" => Some(Iaq::of_reading(reading).score as f64),
+        other => reading.metrics.get(other).cloned(),
+    }
+}
+
+#[derive(Default)]
+pub struct AlertEngine {
+    rules: Vec<AlertRule>,
+    /// indexed by (rule id, publisher id)
+    states: HashMap<(i32, u64), RuleState>,
+}
+
+impl AlertEngine {
+    pub fn new() -> AlertEngine {
+        AlertEngine::default()
+    }
+
+    /// replace the enabled rules, state of rules that no longer exist is dropped
+    pub fn set_rules(&mut self, rules: Vec<AlertRule>) {
+        self.rules = rules.into_iter().filter(|r| r.enabled).collect();
+        let rules = &self.rules;
+        self.states
+            .retain(|(rule_id, _), _| rules.iter().any(|r| r.id == *rule_id));
+    }
+
+    /// mark alerts whose latest persisted event is `firing` as active
+    pub fn restore(&mut self, latest_events: Vec<AlertEvent>) {
+        for event in latest_events {
+            if event.state != AlertState::Firing.as_str() {
+                continue;
+            }
+            if let Some(rule) = self.rules.iter().find(|r| r.id == event.rule_id) {
+                let pub_id = event.publisher_id as u64;
+                self.states.entry((rule.id, pub_id)).or_default().firing = Some(Alert {
+                    rule_id: rule.id,
+                    name: rule.name.clone(),
+                    pub_id,
+                    metric: rule.metric.clone(),
+                    state: AlertState::Firing,
+                    value: event.value,
+                    time: event.event_time as u64,
+                });
+            }
+        }
+    }
+
+    /// evaluate every rule for a publisher's reading, returns any transitions
+    pub fn evaluate(&mut self, pub_id: u64, reading: &Reading) -> Vec<Alert> {
+        let mut transitions = vec![];
+        let now = reading.read_time;
+        for rule in &self.rules {
+            if rule.publisher_id.is_some_and(|id| id as u64 != pub_id) {
+                continue;
+            }
+            let value = match metric_value(reading, &rule.metric) {
+                Some(value) => value,
+                None => continue,
+            };
+            let state = self.states.entry((rule.id, pub_id)).or_default();
+            let (crossed, hold) = match state.firing {
+                None => (value > rule.trigger_above, rule.trigger_secs),
+                Some(_) => (value < rule.clear_below, rule.clear_secs),
+            };
+            if !crossed {
+                state.pending_since = None;
+                continue;
+            }
+            let since = *state.pending_since.get_or_insert(now);
+            if now.saturating_sub(since) < hold.max(0) as u64 {
+                continue;
+            }
+            state.pending_since = None;
+            let alert = Alert {
+                rule_id: rule.id,
+                name: rule.name.clone(),
+                pub_id,
+                metric: rule.metric.clone(),
+                state: match state.firing {
+                    None => AlertState::Firing,
+                    Some(_) => AlertState::Cleared,
+                },
+                value,
+                time: now,
+            };
+            state.firing = match alert.state {
+                AlertState::Firing => Some(alert.clone()),
+                AlertState::Cleared => None,
+            };
+            transitions.push(alert);
+        }
+        transitions
+    }
+
+    /// currently firing alerts, optionally only for one publisher
+    pub fn active(&self, pub_id: Option<u64>) -> Vec<Alert> {
+        self.states
+            .iter()
+            .filter(|((_, id), _)| pub_id.is_none_or(|p| p == *id))
+            .filter_map(|(_, state)| state.firing.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(id: i32, publisher_id: Option<i64>) -> AlertRule {
+        AlertRule {
+            id,
+            name: format!("rule {}", id),
+            publisher_id,
+            metric: "eco2".to_owned(),
+            trigger_above: 1000.0,
+            trigger_secs: 60,
+            clear_below: 800.0,
+            clear_secs: 30,
+            enabled: true,
+        }
+    }
+
+    fn reading(eco2: u16, read_time: u64) -> Reading {
+        serde_json::from_value(serde_json::json!({
+            "eco2": eco2,
+            "evtoc": 0,
+            "read_time": read_time,
+            "start_time": 0,
+            "increment": "ConstantPower1s",
+        }))
+        .unwrap()
+    }
+
+    fn states(engine: &mut AlertEngine, pub_id: u64, readings: &[(u16, u64)]) -> Vec<AlertState> {
+        readings
+            .iter()
+            .flat_map(|&(eco2, time)| engine.evaluate(pub_id, &reading(eco2, time)))
+            .map(|alert| alert.state)
+            .collect()
+    }
+
+    #[test]
+    fn reads_every_known_metric() {
+        let mut reading = reading(640, 0);
+        reading.temperature = Some(21.5);
+        reading.metrics.insert("pm25".to_owned(), 12.5);
+        let values = METRICS
+            .iter()
+            .map(|metric| metric_value(&reading, metric))
+            .collect::<Vec<_>>();
+        let iaq = Iaq::of_reading(&reading).score as f64;
+        assert_eq!(
+            values,
+            [Some(640.0), Some(0.0), Some(21.5), None, Some(iaq)]
+        );
+        assert_eq!(metric_value(&reading, "pm25"), Some(12.5));
+        assert_eq!(metric_value(&reading, "co2"), None);
+    }
+
+    #[test]
+    fn fires_once_held_above_trigger() {
+        let mut engine = AlertEngine::new();
+        engine.set_rules(vec![rule(1, None)]);
+        assert!(states(&mut engine, 811, &[(1200, 0), (1200, 30)]).is_empty());
+        // dipping below the trigger restarts the hold
+        assert!(states(&mut engine, 811, &[(900, 40), (1200, 50), (1200, 100)]).is_empty());
+        let fired = engine.evaluate(811, &reading(1300, 110));
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].state, AlertState::Firing);
+        assert_eq!((fired[0].value, fired[0].time), (1300.0, 110));
+        // already firing, staying above doesn't fire again
+        assert!(states(&mut engine, 811, &[(1300, 200)]).is_empty());
+        assert_eq!(engine.active(Some(811)).len(), 1);
+    }
+
+    #[test]
+    fn clears_only_below_clear_threshold() {
+        let mut engine = AlertEngine::new();
+        engine.set_rules(vec![rule(1, None)]);
+        assert_eq!(
+            states(&mut engine, 811, &[(1200, 0), (1200, 60)]),
+            vec![AlertState::Firing]
+        );
+        // between the thresholds the alert keeps firing however long it stays
+        assert!(states(&mut engine, 811, &[(900, 100), (900, 1000)]).is_empty());
+        assert_eq!(
+            states(&mut engine, 811, &[(700, 1010), (700, 1040)]),
+            vec![AlertState::Cleared]
+        );
+        assert!(engine.active(None).is_empty());
+    }
+
+    #[test]
+    fn rules_apply_to_their_publisher() {
+        let mut engine = AlertEngine::new();
+        engine.set_rules(vec![rule(1, Some(811)), rule(2, None)]);
+        let fired = |engine: &mut AlertEngine, pub_id| {
+            engine.evaluate(pub_id, &reading(1200, 0));
+            engine
+                .evaluate(pub_id, &reading(1200, 60))
+                .iter()
+                .map(|alert| alert.rule_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(fired(&mut engine, 811), vec![1, 2]);
+        assert_eq!(fired(&mut engine, 812), vec![2]);
+        assert_eq!(engine.active(Some(812)).len(), 1);
+        assert_eq!(engine.active(None).len(), 3);
+    }
+
+    #[test]
+    fn restores_firing_alerts_of_enabled_rules() {
+        let mut engine = AlertEngine::new();
+        let mut disabled = rule(2, None);
+        disabled.enabled = false;
+        engine.set_rules(vec![rule(1, None), disabled]);
+        let event = |rule_id, state: AlertState| AlertEvent {
+            id: rule_id,
+            rule_id,
+            publisher_id: 811,
+            state: state.as_str().to_owned(),
+            value: 1200.0,
+            event_time: 50,
+        };
+        engine.restore(vec![
+            event(1, AlertState::Firing),
+            event(2, AlertState::Firing),
+        ]);
+        let active = engine.active(None);
+        assert_eq!(active.len(), 1);
+        assert_eq!((active[0].rule_id, active[0].time), (1, 50));
+        // the restored alert clears rather than firing again
+        assert_eq!(
+            states(&mut engine, 811, &[(700, 100), (700, 130)]),
+            vec![AlertState::Cleared]
+        );
+    }
+}
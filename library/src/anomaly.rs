@@ -0,0 +1,371 @@
+//! `AnomalyDetector` checks relayed readings for signs of a faulty sensor:
+//! values outside the sensor's range, spikes faster than air can change, and
+//! flatlines where a stuck CCS811 keeps reporting identical values (typically
+//! 400 ppm eCO2 / 0 ppb TVOC). Affected readings get `quality_flags` bits and
+//! device health events are raised as anomalies start and end.
+use crate::db::model::{QUALITY_FLATLINE, QUALITY_OUT_OF_RANGE, QUALITY_SPIKE};
+use crate::relay_server::Reading;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+/// CCS811 output ranges, eco2 ppm and etvoc ppb
+const CCS811_ECO2: RangeInclusive<u16> = 400..=8192;
+const CCS811_EVTOC: RangeInclusive<u16> = 0..=1187;
+/// upper limit of common NDIR CO2 sensors
+const NDIR_ECO2: RangeInclusive<u16> = 0..=40000;
+/// fastest plausible change per second, beyond `SPIKE_ALLOWANCE_*`
+const MAX_ECO2_RATE: f64 = 50.0;
+const MAX_EVTOC_RATE: f64 = 50.0;
+/// change tolerated between readings regardless of interval, sensor noise
+const SPIKE_ALLOWANCE_ECO2: f64 = 200.0;
+const SPIKE_ALLOWANCE_EVTOC: f64 = 100.0;
+/// readings further apart than this aren't compared for spikes, in seconds
+const MAX_SPIKE_GAP: u64 = 5 * 60;
+/// consecutive spikes at a new level taken as a real change
+const SPIKE_CONFIRM: u32 = 3;
+/// least time between sensor resets of a publisher, in seconds
+const RESET_COOLDOWN: u64 = 60 * 60;
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnomalyConfig {
+    /// identical readings for this many seconds are a flatline, 0 disables
+    pub flatline_secs: u64,
+    /// ask publishers to reset a flatlined sensor
+    pub reset_sensor: bool,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> AnomalyConfig {
+        AnomalyConfig {
+            flatline_secs: 10 * 60,
+            reset_sensor: false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Anomaly {
+    Flatline,
+    Spike,
+    OutOfRange,
+}
+
+impl Anomaly {
+    pub const ALL: [Anomaly; 3] = [Anomaly::Flatline, Anomaly::Spike, Anomaly::OutOfRange];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Anomaly::Flatline => "flatline",
+            Anomaly::Spike => "spike",
+            Anomaly::OutOfRange => "out_of_range",
+        }
+    }
+
+    pub fn flag(self) -> i32 {
+        match self {
+            Anomaly::Flatline => QUALITY_FLATLINE,
+            Anomaly::Spike => QUALITY_SPIKE,
+            Anomaly::OutOfRange => QUALITY_OUT_OF_RANGE,
+        }
+    }
+}
+
+/// device health transition, sent to subscribers as `/health {json}`
+#[derive(Clone, Debug, Serialize)]
+pub struct HealthEvent {
+    pub pub_id: u64,
+    pub anomaly: Anomaly,
+    /// false once readings are normal again, spikes are only reported active
+    pub active: bool,
+    pub eco2: u16,
+    pub evtoc: u16,
+    /// read_time of the reading that caused the transition
+    pub time: u64,
+    /// read_time the anomaly started, for flatlines detected after the fact
+    pub since: u64,
+}
+
+/// result of checking a reading
+#[derive(Debug, Default)]
+pub struct Check {
+    /// `quality_flags` bits to set on the reading
+    pub flags: i32,
+    pub events: Vec<HealthEvent>,
+    /// earlier readings from this read_time are part of a flatline
+    pub flatline_since: Option<u64>,
+    /// the publisher should reset its sensor
+    pub reset_sensor: bool,
+}
+
+#[derive(Default, Debug)]
+struct PublisherState {
+    /// last reading received, (read_time, eco2, evtoc)
+    previous: Option<(u64, u16, u16)>,
+    /// last reading spikes are measured from
+    reference: Option<(u64, u16, u16)>,
+    spikes: u32,
+    /// read_time values last changed
+    unchanged_since: u64,
+    flatline: bool,
+    out_of_range: bool,
+    last_reset: Option<u64>,
+}
+
+pub struct AnomalyDetector {
+    config: AnomalyConfig,
+    publishers: HashMap<u64, PublisherState>,
+}
+
+/// within the output range of the reading's sensor, a CCS811 if it's unnamed
+pub fn in_range(reading: &Reading) -> bool {
+    match reading.sensor_model.as_deref() {
+        None | Some("CCS811") => {
+            CCS811_ECO2.contains(&reading.eco2) && CCS811_EVTOC.contains(&reading.evtoc)
+        }
+        Some(_) => NDIR_ECO2.contains(&reading.eco2),
+    }
+}
+
+/// change from `reference` faster than air plausibly changes
+fn is_spike(reference: (u64, u16, u16), reading: &Reading) -> bool {
+    let (time, eco2, evtoc) = reference;
+    let dt = reading.read_time.saturating_sub(time);
+    if dt > MAX_SPIKE_GAP {
+        return false;
+    }
+    let dt = dt.max(1) as f64;
+    let d_eco2 = (reading.eco2 as f64 - eco2 as f64).abs();
+    let d_evtoc = (reading.evtoc as f64 - evtoc as f64).abs();
+    d_eco2 > SPIKE_ALLOWANCE_ECO2 + MAX_ECO2_RATE * dt
+        || d_evtoc > SPIKE_ALLOWANCE_EVTOC + MAX_EVTOC_RATE * dt
+}
+
+impl AnomalyDetector {
+    pub fn new(config: AnomalyConfig) -> AnomalyDetector {
+        AnomalyDetector {
+            config,
+            publishers: HashMap::new(),
+        }
+    }
+
+    pub fn check(&mut self, pub_id: u64, reading: &Reading) -> Check {
+        let config = &self.config;
+        let state = self.publishers.entry(pub_id).or_default();
+        let mut check = Check::default();
+        let current = (reading.read_time, reading.eco2, reading.evtoc);
+        let event = |anomaly, active, since| HealthEvent {
+            pub_id,
+            anomaly,
+            active,
+            eco2: reading.eco2,
+            evtoc: reading.evtoc,
+            time: reading.read_time,
+            since,
+        };
+
+        // out of range
+        let out_of_range = !in_range(reading);
+        if out_of_range {
+            check.flags |= Anomaly::OutOfRange.flag();
+        }
+        if out_of_range != state.out_of_range {
+            state.out_of_range = out_of_range;
+            check
+                .events
+                .push(event(Anomaly::OutOfRange, out_of_range, reading.read_time));
+        }
+
+        // spikes, a sustained change is a new level rather than a spike
+        if !out_of_range {
+            match state.reference {
+                Some(reference) if is_spike(reference, reading) => {
+                    state.spikes += 1;
+                    if state.spikes >= SPIKE_CONFIRM {
+                        state.reference = Some(current);
+                        state.spikes = 0;
+                    } else {
+                        check.flags |= Anomaly::Spike.flag();
+                        check
+                            .events
+                            .push(event(Anomaly::Spike, true, reading.read_time));
+                    }
+                }
+                _ => {
+                    state.reference = Some(current);
+                    state.spikes = 0;
+                }
+            }
+        }
+
+        // flatlines
+        let unchanged = state
+            .previous
+            .is_some_and(|(_, eco2, evtoc)| eco2 == reading.eco2 && evtoc == reading.evtoc);
+        if !unchanged {
+            state.unchanged_since = reading.read_time;
+            if state.flatline {
+                state.flatline = false;
+                check
+                    .events
+                    .push(event(Anomaly::Flatline, false, reading.read_time));
+            }
+        }
+        state.previous = Some(current);
+        let flat_for = reading.read_time.saturating_sub(state.unchanged_since);
+        if config.flatline_secs > 0 && unchanged && flat_for >= config.flatline_secs {
+            check.flags |= Anomaly::Flatline.flag();
+            if !state.flatline {
+                state.flatline = true;
+                check.flatline_since = Some(state.unchanged_since);
+                check
+                    .events
+                    .push(event(Anomaly::Flatline, true, state.unchanged_since));
+                let cooled_down = state
+                    .last_reset
+                    .is_none_or(|last| reading.read_time.saturating_sub(last) >= RESET_COOLDOWN);
+                if config.reset_sensor && cooled_down {
+                    state.last_reset = Some(reading.read_time);
+                    check.reset_sensor = true;
+                }
+            }
+        }
+        check
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(eco2: u16, evtoc: u16, read_time: u64) -> Reading {
+        serde_json::from_value(serde_json::json!({
+            "eco2": eco2,
+            "evtoc": evtoc,
+            "read_time": read_time,
+            "start_time": 0,
+            "increment": "ConstantPower1s",
+        }))
+        .unwrap()
+    }
+
+    fn detector(reset_sensor: bool) -> AnomalyDetector {
+        AnomalyDetector::new(AnomalyConfig {
+            flatline_secs: 600,
+            reset_sensor,
+        })
+    }
+
+    fn events(check: &Check) -> Vec<(Anomaly, bool, u64)> {
+        check
+            .events
+            .iter()
+            .map(|e| (e.anomaly, e.active, e.since))
+            .collect()
+    }
+
+    #[test]
+    fn ranges_depend_on_the_sensor() {
+        assert!(in_range(&reading(400, 0, 0)));
+        assert!(!in_range(&reading(399, 0, 0)));
+        assert!(!in_range(&reading(500, 1188, 0)));
+        let mut ndir = reading(9000, 5000, 0);
+        ndir.sensor_model = Some("SCD30".to_owned());
+        assert!(in_range(&ndir));
+        ndir.eco2 = 40001;
+        assert!(!in_range(&ndir));
+    }
+
+    #[test]
+    fn out_of_range_starts_and_ends() {
+        let mut detector = detector(false);
+        let check = detector.check(811, &reading(9000, 0, 10));
+        assert_eq!(check.flags, QUALITY_OUT_OF_RANGE);
+        assert_eq!(events(&check), vec![(Anomaly::OutOfRange, true, 10)]);
+        assert!(detector.check(811, &reading(9000, 0, 11)).events.is_empty());
+        let check = detector.check(811, &reading(600, 0, 12));
+        assert_eq!(check.flags, 0);
+        assert_eq!(events(&check), vec![(Anomaly::OutOfRange, false, 12)]);
+    }
+
+    #[test]
+    fn spikes_are_flagged_until_the_level_holds() {
+        let mut detector = detector(false);
+        detector.check(811, &reading(600, 10, 0));
+        // within the allowance plus 50 ppm/s
+        assert_eq!(detector.check(811, &reading(840, 10, 1)).flags, 0);
+        let spike = detector.check(811, &reading(2000, 10, 2));
+        assert_eq!(spike.flags, QUALITY_SPIKE);
+        assert_eq!(events(&spike), vec![(Anomaly::Spike, true, 2)]);
+        assert_eq!(
+            detector.check(811, &reading(2000, 10, 3)).flags,
+            QUALITY_SPIKE
+        );
+        // the third reading at the new level is taken as a real change
+        assert_eq!(detector.check(811, &reading(2000, 10, 4)).flags, 0);
+        assert_eq!(detector.check(811, &reading(2010, 10, 5)).flags, 0);
+        // readings far apart aren't compared
+        assert_eq!(
+            detector
+                .check(811, &reading(600, 10, 5 + MAX_SPIKE_GAP + 1))
+                .flags,
+            0
+        );
+    }
+
+    #[test]
+    fn flatlines_are_detected_after_the_fact() {
+        let mut detector = detector(false);
+        detector.check(811, &reading(600, 10, 0));
+        for t in (100..700).step_by(100) {
+            assert_eq!(detector.check(811, &reading(400, 0, t)).flags, 0);
+        }
+        let check = detector.check(811, &reading(400, 0, 700));
+        assert_eq!(check.flags, QUALITY_FLATLINE);
+        assert_eq!(check.flatline_since, Some(100));
+        assert_eq!(events(&check), vec![(Anomaly::Flatline, true, 100)]);
+        assert!(!check.reset_sensor);
+        let check = detector.check(811, &reading(400, 0, 800));
+        assert_eq!(
+            (check.flags, check.flatline_since),
+            (QUALITY_FLATLINE, None)
+        );
+        assert!(check.events.is_empty());
+        let check = detector.check(811, &reading(410, 0, 900));
+        assert_eq!(check.flags, 0);
+        assert_eq!(events(&check), vec![(Anomaly::Flatline, false, 900)]);
+    }
+
+    #[test]
+    fn flatlined_sensors_are_reset_at_most_hourly() {
+        let mut detector = detector(true);
+        let flatline_from = |detector: &mut AnomalyDetector, start: u64| {
+            detector.check(811, &reading(600, 10, start));
+            detector.check(811, &reading(400, 0, start + 1));
+            detector
+                .check(811, &reading(400, 0, start + 601))
+                .reset_sensor
+        };
+        assert!(flatline_from(&mut detector, 0));
+        assert!(!flatline_from(&mut detector, 1000));
+        assert!(flatline_from(&mut detector, RESET_COOLDOWN + 1000));
+        // each publisher has its own cooldown
+        assert!(detector.check(812, &reading(400, 0, 0)).events.is_empty());
+        assert!(detector.check(812, &reading(400, 0, 600)).reset_sensor);
+    }
+
+    #[test]
+    fn zero_flatline_secs_disables_flatlines() {
+        let mut detector = AnomalyDetector::new(AnomalyConfig {
+            flatline_secs: 0,
+            reset_sensor: true,
+        });
+        for t in 0..10 {
+            let check = detector.check(811, &reading(400, 0, t * 3600));
+            assert_eq!(check.flags, 0);
+            assert!(!check.reset_sensor);
+        }
+    }
+}
@@ -1,4 +1,10 @@
-use crate::db::model::DbReading;
+use crate::alerts::Alert;
+use crate::db::model::{
+    AlertEvent, AlertRule, DbReading, NewAlertRule, NewWebhook, NewWebhookDelivery, ReadingBucket,
+    Webhook, WebhookDelivery,
+};
+use crate::health::DbHealth;
+use crate::import::{ImportReport, ImportedReading};
 use actix::prelude::Message;
 use serde::Deserialize;
 
@@ -12,3 +18,218 @@ pub struct GetReadings {
 impl Message for GetReadings {
     type Result = Vec<DbReading>;
 }
+
+/// a publisher's latest `limit` readings, newest first, optionally only those
+/// read before `before`
+#[derive(Clone, Debug)]
+pub struct GetPublisherReadings {
+    pub pub_id: u64,
+    pub before: Option<u64>,
+    pub limit: u16,
+}
+
+impl Message for GetPublisherReadings {
+    type Result = Vec<DbReading>;
+}
+
+/// page of readings ordered by read_time then id, used to stream exports
+/// `after` is the (read_time, id) of the last reading of the previous page
+#[derive(Clone, Debug)]
+pub struct ExportReadings {
+    pub pub_id: Option<u64>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub after: Option<(i64, i32)>,
+    pub limit: u16,
+}
+
+impl Message for ExportReadings {
+    type Result = Vec<DbReading>;
+}
+
+/// a publisher's latest `limit` readings stored after row `after`, oldest
+/// first. Row ids follow the order readings were stored in, so they're the
+/// event ids of reading streams
+#[derive(Clone, Debug)]
+pub struct ReadingsAfter {
+    pub pub_id: u64,
+    pub after: Option<i32>,
+    pub limit: u16,
+}
+
+impl Message for ReadingsAfter {
+    type Result = Vec<DbReading>;
+}
+
+/// a publisher's readings aggregated into `bucket` second buckets, optionally
+/// within a `from` (inclusive) `to` (exclusive) read_time range
+#[derive(Clone, Debug)]
+pub struct AggregateReadings {
+    pub pub_id: u64,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub bucket: u32,
+    pub limit: u16,
+}
+
+impl Message for AggregateReadings {
+    type Result = Result<Vec<ReadingBucket>, String>;
+}
+
+/// publishers with readings from `from` (inclusive) to `to` (exclusive) read_time
+#[derive(Debug)]
+pub struct GetPublishers {
+    pub from: u64,
+    pub to: u64,
+}
+
+impl Message for GetPublishers {
+    type Result = Result<Vec<u64>, String>;
+}
+
+/// set `flags` on a publisher's stored readings from `from` (inclusive) to
+/// `to` (exclusive) read_time
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct MarkReadings {
+    pub pub_id: u64,
+    pub from: u64,
+    pub to: u64,
+    pub flags: i32,
+}
+
+/// store imported readings for a publisher, skipping any with a read_time
+/// already stored for that publisher. nothing is written on a dry run
+#[derive(Debug)]
+pub struct ImportReadings {
+    pub pub_id: u64,
+    pub readings: Vec<ImportedReading>,
+    pub dry_run: bool,
+}
+
+impl Message for ImportReadings {
+    type Result = Result<ImportReport, String>;
+}
+
+/// List alert rules, optionally only enabled ones
+pub struct GetAlertRules {
+    pub enabled_only: bool,
+}
+
+impl Message for GetAlertRules {
+    type Result = Vec<AlertRule>;
+}
+
+/// Names of the extra metrics sensors have reported
+pub struct GetMetricNames;
+
+impl Message for GetMetricNames {
+    type Result = Result<Vec<String>, String>;
+}
+
+/// Store a new alert rule
+pub struct AddAlertRule(pub NewAlertRule);
+
+impl Message for AddAlertRule {
+    type Result = Result<AlertRule, String>;
+}
+
+/// Disable an alert rule, its history is kept
+pub struct DisableAlertRule {
+    pub id: i32,
+}
+
+impl Message for DisableAlertRule {
+    type Result = Result<(), String>;
+}
+
+/// Persist an alert state transition
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SaveAlertEvent(pub Alert);
+
+/// Latest alert event for each rule and publisher, used to restore active alerts
+pub struct GetLatestAlertEvents;
+
+impl Message for GetLatestAlertEvents {
+    type Result = Vec<AlertEvent>;
+}
+
+/// Alert events, newest first
+#[derive(Clone, Debug, Deserialize)]
+pub struct GetAlertHistory {
+    pub pub_id: Option<u64>,
+    pub before: Option<u64>,
+    pub limit: u16,
+}
+
+impl Message for GetAlertHistory {
+    type Result = Vec<AlertEvent>;
+}
+
+/// List webhooks, optionally only enabled ones
+pub struct GetWebhooks {
+    pub enabled_only: bool,
+}
+
+impl Message for GetWebhooks {
+    type Result = Vec<Webhook>;
+}
+
+/// Store a new webhook
+pub struct AddWebhook(pub NewWebhook);
+
+impl Message for AddWebhook {
+    type Result = Result<Webhook, String>;
+}
+
+/// Disable a webhook, its delivery log is kept
+pub struct DisableWebhook {
+    pub id: i32,
+}
+
+impl Message for DisableWebhook {
+    type Result = Result<(), String>;
+}
+
+/// Record an attempt to deliver an event to a webhook
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct LogWebhookDelivery(pub NewWebhookDelivery);
+
+/// Webhook delivery attempts, newest first
+#[derive(Clone, Debug, Deserialize)]
+pub struct GetWebhookDeliveries {
+    pub webhook_id: Option<i32>,
+    pub before: Option<i32>,
+    pub limit: u16,
+}
+
+impl Message for GetWebhookDeliveries {
+    type Result = Vec<WebhookDelivery>;
+}
+
+/// Does nothing, but the mailbox is processed in order so once it's answered
+/// every reading queued before it is stored. Each insert commits on its own,
+/// there's nothing buffered to write out
+pub struct Flush;
+
+impl Message for Flush {
+    type Result = ();
+}
+
+/// Answered as soon as the actor gets to it, a reply shows it's processing
+/// its mailbox
+pub struct Ping;
+
+impl Message for Ping {
+    type Result = ();
+}
+
+/// Pool state and the latest applied migration, checking a connection can be
+/// taken and queried
+pub struct DbStatus;
+
+impl Message for DbStatus {
+    type Result = Result<DbHealth, String>;
+}
@@ -0,0 +1,758 @@
+//! Server configuration, layered from lowest to highest precedence:
+//!
+//! 1. built in defaults
+//! 2. a TOML file, `--config <path>` or `AIR_METER_CONFIG`, else `air_meter.toml`
+//!    in the working directory if it exists
+//! 3. env vars, `AIR_METER_*` plus `DATABASE_URL`, `RUST_LOG`, `ADMIN_TOKEN`,
+//!    `INGEST_TOKENS` and the keys of `[anomaly]`, `[backup]`, `[iaq]`,
+//!    `[influx]`, `[mqtt]` and `[report]` prefixed with the section's name
+//! 4. command line flags
+//!
+//! `ServerConfig::load` reads and validates every layer, then `install` makes
+//! the timeouts, limits, reading checks and IAQ bands available through
+//! `timeouts()`, `limits()`, `readings()` and `iaq()`.
+use crate::anomaly::AnomalyConfig;
+use crate::db::backup::BackupConfig;
+use crate::iaq::{Bands, IaqConfig};
+use crate::influx::InfluxConfig;
+use crate::logging::{self, LogFormat};
+use crate::mqtt::MqttConfig;
+use crate::rate_limit::Limits;
+use crate::relay_server::validate::ReadingsConfig;
+use crate::report::ReportConfig;
+use crate::rest_api::handlers::ingest::IngestTokens;
+use crate::tls::{self, TlsConfig};
+use crate::{CLIENT_TIMEOUT, HEARTBEAT_INTERVAL};
+use serde::{Deserialize, Serialize, Serializer};
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// read when no config file is given and it exists
+const DEFAULT_CONFIG_FILE: &str = "air_meter.toml";
+
+pub const USAGE: &str = "usage:
+    server [--config FILE] [--bind ADDR] [--cors-origin ORIGIN]... [--database-url PATH]
+        [--static-dir DIR] [--heartbeat-secs SECS] [--client-timeout-secs SECS]
+        [--local-sensor | --no-local-sensor] [--sensor-baseline FILE] [--log FILTER]
+        [--log-format text|json] [--shutdown-timeout-secs SECS] [--lock-file FILE] [--tls-cert FILE --tls-key FILE]
+    server restore <backup file>
+    server openapi [typescript]";
+
+static TIMEOUTS: OnceLock<Timeouts> = OnceLock::new();
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+static READINGS: OnceLock<ReadingsConfig> = OnceLock::new();
+static IAQ: OnceLock<IaqConfig> = OnceLock::new();
+
+#[derive(Copy, Clone, Debug)]
+pub struct Timeouts {
+    /// how often heartbeat pings are sent
+    pub heartbeat: Duration,
+    /// how long before lack of client response causes a timeout
+    pub client: Duration,
+}
+
+/// installed timeouts, the crate defaults if none were
+pub fn timeouts() -> Timeouts {
+    TIMEOUTS.get().copied().unwrap_or(Timeouts {
+        heartbeat: HEARTBEAT_INTERVAL,
+        client: CLIENT_TIMEOUT,
+    })
+}
+
+/// installed limits, the defaults if none were
+pub fn limits() -> Limits {
+    LIMITS.get().copied().unwrap_or_default()
+}
+
+/// installed reading checks, the defaults if none were
+pub fn readings() -> ReadingsConfig {
+    READINGS.get().copied().unwrap_or_default()
+}
+
+/// installed IAQ bands, the defaults if none were
+pub fn iaq() -> IaqConfig {
+    IAQ.get().copied().unwrap_or_default()
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// address the http server listens on
+    pub bind: String,
+    /// origins allowed cross origin requests, `*` allows any
+    pub cors_origins: Vec<String>,
+    /// sqlite database path
+    pub database_url: String,
+    /// served under `/static`
+    pub static_dir: PathBuf,
+    pub heartbeat_secs: u64,
+    pub client_timeout_secs: u64,
+    /// run the on board sensor client, publishing as 811
+    pub local_sensor: bool,
+    /// the local sensor's baseline is saved here on shutdown and restored
+    /// once it has warmed up
+    pub sensor_baseline: PathBuf,
+    /// longest a graceful shutdown may take before connections are dropped
+    pub shutdown_timeout_secs: u64,
+    /// locked while the server runs so only one runs at a time, empty disables
+    pub lock_file: PathBuf,
+    /// per module log levels, e.g. `info,library::relay_server=debug`
+    pub log: String,
+    /// `text` or one JSON object per line
+    pub log_format: LogFormat,
+    /// bearer token for the admin routes, they're disabled without it
+    #[serde(serialize_with = "redact", skip_serializing_if = "Option::is_none")]
+    pub admin_token: Option<String>,
+    /// rate and size limits on websocket sessions and REST requests
+    pub limits: Limits,
+    /// checks publishers' readings must pass to be relayed
+    pub readings: ReadingsConfig,
+    /// each http publisher's token, http ingest is disabled without any
+    #[serde(skip_serializing_if = "IngestTokens::is_empty")]
+    pub ingest_tokens: IngestTokens,
+    /// scheduled database backups
+    pub backup: BackupConfig,
+    /// faulty sensor checks on relayed readings
+    pub anomaly: AnomalyConfig,
+    /// air quality index bands
+    pub iaq: IaqConfig,
+    /// scheduled reports and the thresholds they're written with
+    pub report: ReportConfig,
+    /// serve https and wss when set
+    pub tls: Option<TlsConfig>,
+    /// bridge readings to an MQTT broker when set
+    pub mqtt: Option<MqttConfig>,
+    /// write readings to InfluxDB when set
+    pub influx: Option<InfluxConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            bind: "0.0.0.0:8080".to_owned(),
+            cors_origins: vec!["http://localhost:3000".to_owned()],
+            database_url: String::new(),
+            static_dir: "./static".into(),
+            heartbeat_secs: HEARTBEAT_INTERVAL.as_secs(),
+            client_timeout_secs: CLIENT_TIMEOUT.as_secs(),
+            local_sensor: true,
+            sensor_baseline: "./ccs811_baseline".into(),
+            shutdown_timeout_secs: 30,
+            lock_file: "./air_meter.lock".into(),
+            log: "info".to_owned(),
+            log_format: LogFormat::Text,
+            admin_token: None,
+            limits: Limits::default(),
+            readings: ReadingsConfig::default(),
+            ingest_tokens: IngestTokens::default(),
+            backup: BackupConfig::default(),
+            anomaly: AnomalyConfig::default(),
+            iaq: IaqConfig::default(),
+            report: ReportConfig::default(),
+            tls: None,
+            mqtt: None,
+            influx: None,
+        }
+    }
+}
+
+/// printed in place of secrets
+pub const REDACTED: &str = "********";
+
+/// serialize a secret as `REDACTED`, so the printed config doesn't leak it
+pub fn redact<S: Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match secret {
+        Some(_) => serializer.serialize_str(REDACTED),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("{} {:?}: {}", key, value, e))
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "1" | "true" | "yes" => Ok(true),
+        "0" | "false" | "no" => Ok(false),
+        _ => Err(format!("{} {:?}: expected true or false", key, value)),
+    }
+}
+
+impl ServerConfig {
+    /// layer the config file, env vars and `args` (without the program name)
+    /// over the defaults and validate the result
+    pub fn load(args: &[String]) -> Result<ServerConfig, String> {
+        ServerConfig::load_with(args, |key| std::env::var(key).ok())
+    }
+
+    /// `load` with env vars looked up by `env`
+    fn load_with(
+        args: &[String],
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<ServerConfig, String> {
+        let var = |key: &str| env(key).filter(|v| !v.is_empty());
+        let flag_file = args
+            .iter()
+            .position(|a| a == "--config")
+            .map(|i| args.get(i + 1).cloned().ok_or("--config needs a value"))
+            .transpose()?;
+        let mut config = match flag_file.or_else(|| var("AIR_METER_CONFIG")) {
+            Some(path) => ServerConfig::from_file(Path::new(&path))?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                ServerConfig::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => ServerConfig::default(),
+        };
+        config.apply_env(var)?;
+        config.apply_args(args)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<ServerConfig, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{:?}: {}", path, e))?;
+        toml::from_str(&text).map_err(|e| format!("{:?}: {}", path, e))
+    }
+
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        if let Some(v) = var("AIR_METER_BIND") {
+            self.bind = v;
+        }
+        if let Some(v) = var("AIR_METER_CORS_ORIGINS") {
+            self.cors_origins = v.split(',').map(|o| o.trim().to_owned()).collect();
+        }
+        if let Some(v) = var("DATABASE_URL") {
+            self.database_url = v;
+        }
+        if let Some(v) = var("AIR_METER_STATIC_DIR") {
+            self.static_dir = v.into();
+        }
+        if let Some(v) = var("AIR_METER_HEARTBEAT_SECS") {
+            self.heartbeat_secs = parse("AIR_METER_HEARTBEAT_SECS", &v)?;
+        }
+        if let Some(v) = var("AIR_METER_CLIENT_TIMEOUT_SECS") {
+            self.client_timeout_secs = parse("AIR_METER_CLIENT_TIMEOUT_SECS", &v)?;
+        }
+        if let Some(v) = var("AIR_METER_LOCAL_SENSOR") {
+            self.local_sensor = parse_bool("AIR_METER_LOCAL_SENSOR", &v)?;
+        }
+        if let Some(v) = var("AIR_METER_SENSOR_BASELINE") {
+            self.sensor_baseline = v.into();
+        }
+        if let Some(v) = var("AIR_METER_SHUTDOWN_TIMEOUT_SECS") {
+            self.shutdown_timeout_secs = parse("AIR_METER_SHUTDOWN_TIMEOUT_SECS", &v)?;
+        }
+        if let Some(v) = var("AIR_METER_LOCK_FILE") {
+            self.lock_file = v.into();
+        }
+        if let Some(v) = var("RUST_LOG") {
+            self.log = v;
+        }
+        if let Some(v) = var("AIR_METER_LOG_FORMAT") {
+            self.log_format =
+                LogFormat::parse(&v).map_err(|e| format!("AIR_METER_LOG_FORMAT {}", e))?;
+        }
+        if let Some(v) = var("ADMIN_TOKEN") {
+            self.admin_token = Some(v);
+        }
+        macro_rules! limit {
+            ($key:literal, $field:ident) => {
+                if let Some(v) = var($key) {
+                    self.limits.$field = parse($key, &v)?;
+                }
+            };
+        }
+        limit!("AIR_METER_WS_MAX_FRAME_BYTES", ws_max_frame_bytes);
+        limit!("AIR_METER_WS_MESSAGES_PER_SEC", ws_messages_per_sec);
+        limit!("AIR_METER_WS_MESSAGE_BURST", ws_message_burst);
+        limit!("AIR_METER_WS_IP_MESSAGES_PER_SEC", ws_ip_messages_per_sec);
+        limit!("AIR_METER_WS_IP_MESSAGE_BURST", ws_ip_message_burst);
+        limit!("AIR_METER_WS_MAX_SUBSCRIPTIONS", ws_max_subscriptions);
+        limit!("AIR_METER_WS_MAX_VIOLATIONS", ws_max_violations);
+        limit!("AIR_METER_REST_REQUESTS_PER_SEC", rest_requests_per_sec);
+        limit!("AIR_METER_REST_REQUEST_BURST", rest_request_burst);
+        if let Some(v) = var("AIR_METER_REJECT_OUT_OF_RANGE") {
+            self.readings.reject_out_of_range = parse_bool("AIR_METER_REJECT_OUT_OF_RANGE", &v)?;
+        }
+        if let Some(v) = var("AIR_METER_MAX_CLOCK_SKEW_SECS") {
+            self.readings.max_clock_skew_secs = parse("AIR_METER_MAX_CLOCK_SKEW_SECS", &v)?;
+        }
+        if let Some(v) = var("AIR_METER_CORRECT_CLOCK_SKEW") {
+            self.readings.correct_clock_skew = parse_bool("AIR_METER_CORRECT_CLOCK_SKEW", &v)?;
+        }
+        if let Some(v) = var("INGEST_TOKENS") {
+            self.ingest_tokens =
+                IngestTokens::parse(&v).map_err(|e| format!("INGEST_TOKENS {}", e))?;
+        }
+        if let Some(v) = var("BACKUP_DIR") {
+            self.backup.dir = v.into();
+        }
+        if let Some(v) = var("BACKUP_INTERVAL_HOURS") {
+            self.backup.interval_hours = parse("BACKUP_INTERVAL_HOURS", &v)?;
+        }
+        if let Some(v) = var("BACKUP_KEEP") {
+            self.backup.keep = parse("BACKUP_KEEP", &v)?;
+        }
+        if let Some(v) = var("ANOMALY_FLATLINE_SECS") {
+            self.anomaly.flatline_secs = parse("ANOMALY_FLATLINE_SECS", &v)?;
+        }
+        if let Some(v) = var("ANOMALY_RESET_SENSOR") {
+            self.anomaly.reset_sensor = parse_bool("ANOMALY_RESET_SENSOR", &v)?;
+        }
+        if let Some(v) = var("IAQ_CO2_BANDS") {
+            self.iaq.co2_bands = Bands::parse(&v).map_err(|e| format!("IAQ_CO2_BANDS {}", e))?;
+        }
+        if let Some(v) = var("IAQ_TVOC_BANDS") {
+            self.iaq.tvoc_bands = Bands::parse(&v).map_err(|e| format!("IAQ_TVOC_BANDS {}", e))?;
+        }
+        if let Some(v) = var("REPORT_DIR") {
+            self.report.dir = Some(v.into());
+        }
+        if let Some(v) = var("REPORT_ECO2_THRESHOLD") {
+            self.report.eco2_threshold = parse("REPORT_ECO2_THRESHOLD", &v)?;
+        }
+        if let Some(v) = var("REPORT_EVTOC_THRESHOLD") {
+            self.report.evtoc_threshold = parse("REPORT_EVTOC_THRESHOLD", &v)?;
+        }
+        if let Some(v) = var("AIR_METER_TLS_CERT") {
+            self.tls.get_or_insert_with(TlsConfig::default).cert = v.into();
+        }
+        if let Some(v) = var("AIR_METER_TLS_KEY") {
+            self.tls.get_or_insert_with(TlsConfig::default).key = v.into();
+        }
+        if let Some(v) = var("MQTT_HOST") {
+            self.mqtt.get_or_insert_with(MqttConfig::default).host = v;
+        }
+        if let Some(v) = var("MQTT_PORT") {
+            self.mqtt.get_or_insert_with(MqttConfig::default).port = parse("MQTT_PORT", &v)?;
+        }
+        if let Some(v) = var("MQTT_CLIENT_ID") {
+            self.mqtt.get_or_insert_with(MqttConfig::default).client_id = v;
+        }
+        if let Some(v) = var("MQTT_USERNAME") {
+            self.mqtt.get_or_insert_with(MqttConfig::default).username = Some(v);
+        }
+        if let Some(v) = var("MQTT_PASSWORD") {
+            self.mqtt.get_or_insert_with(MqttConfig::default).password = Some(v);
+        }
+        if let Some(v) = var("MQTT_DISCOVERY_PREFIX") {
+            self.mqtt
+                .get_or_insert_with(MqttConfig::default)
+                .discovery_prefix = v;
+        }
+        if let Some(v) = var("INFLUX_URL") {
+            self.influx.get_or_insert_with(InfluxConfig::default).url = v;
+        }
+        if let Some(v) = var("INFLUX_ORG") {
+            self.influx.get_or_insert_with(InfluxConfig::default).org = v;
+        }
+        if let Some(v) = var("INFLUX_BUCKET") {
+            self.influx.get_or_insert_with(InfluxConfig::default).bucket = v;
+        }
+        if let Some(v) = var("INFLUX_TOKEN") {
+            self.influx.get_or_insert_with(InfluxConfig::default).token = Some(v);
+        }
+        if let Some(v) = var("INFLUX_MEASUREMENT") {
+            self.influx
+                .get_or_insert_with(InfluxConfig::default)
+                .measurement = v;
+        }
+        if let Some(v) = var("INFLUX_BATCH_SIZE") {
+            self.influx
+                .get_or_insert_with(InfluxConfig::default)
+                .batch_size = parse("INFLUX_BATCH_SIZE", &v)?;
+        }
+        if let Some(v) = var("INFLUX_FLUSH_SECS") {
+            self.influx
+                .get_or_insert_with(InfluxConfig::default)
+                .flush_secs = parse("INFLUX_FLUSH_SECS", &v)?;
+        }
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &[String]) -> Result<(), String> {
+        // flags given cors origins replace the configured ones
+        let mut cors_origins = vec![];
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                "--local-sensor" => self.local_sensor = true,
+                "--no-local-sensor" => self.local_sensor = false,
+                "--config"
+                | "--bind"
+                | "--cors-origin"
+                | "--database-url"
+                | "--static-dir"
+                | "--heartbeat-secs"
+                | "--client-timeout-secs"
+                | "--sensor-baseline"
+                | "--shutdown-timeout-secs"
+                | "--lock-file"
+                | "--log"
+                | "--log-format"
+                | "--tls-cert"
+                | "--tls-key" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("{} needs a value", flag))?;
+                    match flag.as_str() {
+                        "--bind" => self.bind = value.clone(),
+                        "--cors-origin" => cors_origins.push(value.clone()),
+                        "--database-url" => self.database_url = value.clone(),
+                        "--static-dir" => self.static_dir = value.into(),
+                        "--heartbeat-secs" => self.heartbeat_secs = parse(flag, value)?,
+                        "--client-timeout-secs" => self.client_timeout_secs = parse(flag, value)?,
+                        "--sensor-baseline" => self.sensor_baseline = value.into(),
+                        "--shutdown-timeout-secs" => {
+                            self.shutdown_timeout_secs = parse(flag, value)?
+                        }
+                        "--lock-file" => self.lock_file = value.into(),
+                        "--log" => self.log = value.clone(),
+                        "--log-format" => {
+                            self.log_format =
+                                LogFormat::parse(value).map_err(|e| format!("{} {}", flag, e))?
+                        }
+                        "--tls-cert" => {
+                            self.tls.get_or_insert_with(TlsConfig::default).cert = value.into()
+                        }
+                        "--tls-key" => {
+                            self.tls.get_or_insert_with(TlsConfig::default).key = value.into()
+                        }
+                        _ => {}
+                    }
+                }
+                _ => return Err(format!("unknown argument {:?}", flag)),
+            }
+        }
+        if !cors_origins.is_empty() {
+            self.cors_origins = cors_origins;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = vec![];
+        match self.bind.to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(_)) => {}
+            Ok(None) => errors.push(format!("bind {:?}: resolves to no address", self.bind)),
+            Err(e) => errors.push(format!("bind {:?}: {}", self.bind, e)),
+        }
+        for origin in &self.cors_origins {
+            let scheme_ok = origin.starts_with("http://") || origin.starts_with("https://");
+            if origin != "*" && (!scheme_ok || origin.ends_with('/')) {
+                errors.push(format!(
+                    "cors origin {:?}: expected scheme://host[:port] or *",
+                    origin
+                ));
+            }
+        }
+        if self.database_url.is_empty() {
+            errors.push("database_url: required, or set DATABASE_URL".to_owned());
+        }
+        if self.heartbeat_secs == 0 {
+            errors.push("heartbeat_secs: must be above 0".to_owned());
+        }
+        if self.client_timeout_secs <= self.heartbeat_secs {
+            errors.push(format!(
+                "client_timeout_secs: must be above heartbeat_secs ({})",
+                self.heartbeat_secs
+            ));
+        }
+        if self.shutdown_timeout_secs == 0 {
+            errors.push("shutdown_timeout_secs: must be above 0".to_owned());
+        }
+        if self.log.trim().is_empty() {
+            errors.push("log: filter can't be empty".to_owned());
+        } else if let Err(err) = logging::parse_filter(&self.log) {
+            errors.push(format!("log: {}", err));
+        }
+        self.limits.validate(&mut errors);
+        self.backup.validate(&mut errors);
+        self.iaq.validate(&mut errors);
+        self.report.validate(&mut errors);
+        if let Some(tls) = &self.tls {
+            if let Err(err) = tls::load_cert(&tls.cert, &tls.key) {
+                errors.push(format!("tls: {}", err));
+            }
+        }
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.validate(&mut errors);
+        }
+        if let Some(influx) = &self.influx {
+            influx.validate(&mut errors);
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("invalid config:\n  {}", errors.join("\n  ")))
+        }
+    }
+
+    /// websocket url the local sensor client connects to, and the CA it pins
+    /// when TLS is enabled
+    pub fn local_url(&self) -> (String, Option<PathBuf>) {
+        let port = self.bind.rsplit(':').next().unwrap_or("8080");
+        match &self.tls {
+            Some(tls) => (
+                format!("wss://{}:{}/ws/", tls.local_name, port),
+                Some(tls.cert.clone()),
+            ),
+            None => (format!("ws://127.0.0.1:{}/ws/", port), None),
+        }
+    }
+
+    /// make the timeouts, limits, reading checks and IAQ bands available
+    /// through `timeouts()`, `limits()`, `readings()` and `iaq()`, only the
+    /// first call has an effect
+    pub fn install(&self) {
+        let _ = TIMEOUTS.set(Timeouts {
+            heartbeat: Duration::from_secs(self.heartbeat_secs),
+            client: Duration::from_secs(self.client_timeout_secs),
+        });
+        let _ = LIMITS.set(self.limits);
+        let _ = READINGS.set(self.readings);
+        let _ = IAQ.set(self.iaq);
+    }
+
+    /// the effective config as TOML
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).unwrap_or_else(|e| format!("{:?}: {}", self, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    /// a config file in the temp dir holding `text`
+    fn config_file(name: &str, text: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "air_meter-config-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn load(flags: &[&str], env: &[(&str, &str)]) -> Result<ServerConfig, String> {
+        let env = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>();
+        ServerConfig::load_with(&args(flags), |key| env.get(key).cloned())
+    }
+
+    #[test]
+    fn flags_override_env_over_the_file_over_defaults() {
+        let file = config_file(
+            "layers",
+            r#"
+            bind = "127.0.0.1:9000"
+            database_url = "file.db"
+            static_dir = "file_static"
+            heartbeat_secs = 7
+            client_timeout_secs = 40
+            local_sensor = false
+
+            [backup]
+            keep = 3
+            "#,
+        );
+        let config = load(
+            &[
+                "--config",
+                &file,
+                "--heartbeat-secs",
+                "9",
+                "--no-local-sensor",
+            ],
+            &[
+                ("DATABASE_URL", "env.db"),
+                ("AIR_METER_HEARTBEAT_SECS", "8"),
+                ("AIR_METER_LOCAL_SENSOR", "true"),
+                ("BACKUP_KEEP", "4"),
+            ],
+        )
+        .unwrap();
+        // file over defaults
+        assert_eq!(config.bind, "127.0.0.1:9000");
+        assert_eq!(config.static_dir, PathBuf::from("file_static"));
+        assert_eq!(config.client_timeout_secs, 40);
+        // env over the file
+        assert_eq!(config.database_url, "env.db");
+        assert_eq!(config.backup.keep, 4);
+        // flags over env
+        assert_eq!(config.heartbeat_secs, 9);
+        assert!(!config.local_sensor);
+        // defaults where nothing's set
+        assert_eq!(config.shutdown_timeout_secs, 30);
+        assert_eq!(config.backup.interval_hours, 24);
+    }
+
+    #[test]
+    fn finds_the_file_from_the_flag_or_env() {
+        let flagged = config_file("flagged", "database_url = \"flagged.db\"");
+        let env = config_file("env", "database_url = \"env.db\"");
+        let config = load(&[], &[("AIR_METER_CONFIG", &env)]).unwrap();
+        assert_eq!(config.database_url, "env.db");
+        let config = load(&["--config", &flagged], &[("AIR_METER_CONFIG", &env)]).unwrap();
+        assert_eq!(config.database_url, "flagged.db");
+    }
+
+    #[test]
+    fn ignores_empty_env_vars() {
+        let file = config_file("empty_env", "database_url = \"file.db\"");
+        let config = load(&["--config", &file], &[("DATABASE_URL", "")]).unwrap();
+        assert_eq!(config.database_url, "file.db");
+    }
+
+    #[test]
+    fn cors_origin_flags_replace_configured_origins() {
+        let env = [
+            ("DATABASE_URL", "test.db"),
+            (
+                "AIR_METER_CORS_ORIGINS",
+                "https://a.example, https://b.example",
+            ),
+        ];
+        let config = load(&[], &env).unwrap();
+        assert_eq!(
+            config.cors_origins,
+            ["https://a.example", "https://b.example"]
+        );
+        let flags = [
+            "--cors-origin",
+            "*",
+            "--cors-origin",
+            "http://c.example:3000",
+        ];
+        let config = load(&flags, &env).unwrap();
+        assert_eq!(config.cors_origins, ["*", "http://c.example:3000"]);
+    }
+
+    #[test]
+    fn rejects_unknown_keys_bad_values_and_flags() {
+        let file = config_file("unknown", "bnid = \"0.0.0.0:80\"");
+        let err = load(&["--config", &file], &[]).unwrap_err();
+        assert!(err.contains("unknown field `bnid`"), "{}", err);
+        let file = config_file("unknown_section_key", "[backup]\nkeeep = 2");
+        let err = load(&["--config", &file], &[]).unwrap_err();
+        assert!(err.contains("unknown field `keeep`"), "{}", err);
+
+        let err = load(&[], &[("AIR_METER_HEARTBEAT_SECS", "soon")]).unwrap_err();
+        assert!(
+            err.starts_with("AIR_METER_HEARTBEAT_SECS \"soon\": "),
+            "{}",
+            err
+        );
+        let err = load(&[], &[("AIR_METER_LOCAL_SENSOR", "maybe")]).unwrap_err();
+        assert_eq!(
+            err,
+            "AIR_METER_LOCAL_SENSOR \"maybe\": expected true or false"
+        );
+        assert_eq!(load(&["--bind"], &[]).unwrap_err(), "--bind needs a value");
+        assert_eq!(
+            load(&["--verbose"], &[]).unwrap_err(),
+            "unknown argument \"--verbose\""
+        );
+    }
+
+    #[test]
+    fn defaults_with_a_database_are_valid() {
+        let config = ServerConfig {
+            database_url: "test.db".to_owned(),
+            ..ServerConfig::default()
+        };
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn lists_every_invalid_setting() {
+        let mut config = ServerConfig {
+            bind: "localhost".to_owned(),
+            cors_origins: vec!["example.com".to_owned(), "https://b.example/".to_owned()],
+            heartbeat_secs: 0,
+            client_timeout_secs: 0,
+            shutdown_timeout_secs: 0,
+            log: "info,=nope=".to_owned(),
+            tls: Some(TlsConfig::default()),
+            mqtt: Some(MqttConfig::default()),
+            influx: Some(InfluxConfig::default()),
+            ..ServerConfig::default()
+        };
+        config.limits.ws_max_frame_bytes = 0;
+        config.backup.keep = 0;
+        config.iaq.co2_bands = Bands([400.0, 300.0, 800.0, 1000.0, 1500.0]);
+        config.report.eco2_threshold = 0.0;
+        let err = config.validate().unwrap_err();
+        for expected in [
+            "bind \"localhost\": ",
+            "cors origin \"example.com\": expected scheme://host[:port] or *",
+            "cors origin \"https://b.example/\": expected scheme://host[:port] or *",
+            "database_url: required, or set DATABASE_URL",
+            "heartbeat_secs: must be above 0",
+            "client_timeout_secs: must be above heartbeat_secs (0)",
+            "shutdown_timeout_secs: must be above 0",
+            "log: \"info,=nope=\": ",
+            "limits.ws_max_frame_bytes: must be at least 1024",
+            "backup.keep: must be above 0",
+            "iaq.co2_bands: bands must be increasing",
+            "report.eco2_threshold: must be above 0",
+            "tls: ",
+            "mqtt.host: required, or set MQTT_HOST",
+            "influx.url \"\": expected http(s)://host[:port], or set INFLUX_URL",
+        ] {
+            assert!(err.contains(expected), "{:?} not in {}", expected, err);
+        }
+
+        config.log = " ".to_owned();
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("log: filter can't be empty"), "{}", err);
+    }
+
+    #[test]
+    fn redacts_secrets_in_the_printed_config() {
+        let config = load(
+            &[],
+            &[
+                ("DATABASE_URL", "test.db"),
+                ("ADMIN_TOKEN", "admin-secret"),
+                ("INGEST_TOKENS", "812=ingest-secret"),
+                ("MQTT_HOST", "broker"),
+                ("MQTT_USERNAME", "meter"),
+                ("MQTT_PASSWORD", "mqtt-secret"),
+                ("INFLUX_URL", "http://influx:8086"),
+                ("INFLUX_TOKEN", "influx-secret"),
+            ],
+        )
+        .unwrap();
+        let dump = config.to_toml();
+        assert!(!dump.contains("secret"), "{}", dump);
+        for redacted in [
+            "admin_token = '********'",
+            "812 = '********'",
+            "password = '********'",
+            "token = '********'",
+        ] {
+            assert!(dump.contains(redacted), "{:?} not in {}", redacted, dump);
+        }
+        assert!(dump.contains("username = 'meter'"), "{}", dump);
+        // the dump loads back, secrets aside
+        let reloaded = toml::from_str::<ServerConfig>(&dump).unwrap();
+        assert_eq!(reloaded.bind, config.bind);
+    }
+}
@@ -1,16 +1,48 @@
 use actix::prelude::*;
+use diesel::connection::SimpleConnection;
 use diesel::prelude::*;
 use diesel::r2d2::ConnectionManager;
 use diesel::result::Error;
+use std::collections::HashSet;
 
 use crate::{
-    common::GetReadings,
-    db::model::{DbReading, NewReading},
+    common::{
+        AddAlertRule, AddWebhook, AggregateReadings, DbStatus, DisableAlertRule, DisableWebhook,
+        ExportReadings, Flush, GetAlertHistory, GetAlertRules, GetLatestAlertEvents,
+        GetMetricNames, GetPublisherReadings, GetPublishers, GetReadings, GetWebhookDeliveries,
+        GetWebhooks, ImportReadings, LogWebhookDelivery, MarkReadings, Ping, ReadingsAfter,
+        SaveAlertEvent,
+    },
+    db::model::{
+        AlertEvent, AlertRule, DbReading, Mode, NewAlertEvent, NewReading, ReadingBucket,
+        ReadingMetric, Webhook, WebhookDelivery, QUALITY_NO_EVTOC, QUALITY_SUSPECT,
+    },
+    health::DbHealth,
+    import::ImportReport,
+    metrics,
     relay_server::{PublisherMessage as PubMsg, Reading},
 };
 
+use super::embedded_migrations;
+
 type DbPool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
 
+/// longest a health check waits for a pooled connection
+const STATUS_CONN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// per connection settings: wait for locks, e.g. held by a backup, instead of
+/// failing writes immediately, and enforce foreign keys so deleting a reading
+/// cascades to its metrics
+#[derive(Debug)]
+struct Pragmas;
+
+impl r2d2::CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for Pragmas {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.batch_execute("PRAGMA busy_timeout = 5000; PRAGMA foreign_keys = ON;")
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
 pub struct Actions {
     pool: DbPool,
 }
@@ -22,33 +54,34 @@ impl Actor for Actions {
 impl Handler<PubMsg<Reading>> for Actions {
     type Result = ();
     fn handle(&mut self, msg: PubMsg<Reading>, _: &mut Context<Self>) {
+        metrics::dequeued(metrics::ACTIONS);
         let conn = self.conn();
-        let rd = msg.msg;
+        let mut rd = msg.msg;
+        let corr_id = rd.corr_id().to_owned();
         let new_reading = NewReading {
             publisher_id: msg.pub_id as i64,
             eco2: rd.eco2 as i32,
             evtoc: rd.evtoc as i32,
             read_time: rd.read_time as i64,
             start_time: rd.start_time as i64,
-            increment: rd.increment,
+            mode: Mode::from_name(&rd.increment),
+            sensor_model: rd.sensor_model,
+            temperature: rd.temperature,
+            humidity: rd.humidity,
+            quality_flags: rd.quality_flags,
+            raw_current: rd.raw_current.map(i32::from),
+            raw_voltage: rd.raw_voltage.map(i32::from),
         };
+        let extra_metrics = rd.metrics;
+        let timer = metrics::get().db_insert_seconds.start_timer();
         let reading = conn
-            .transaction::<_, Error, _>(|| {
-                {
-                    use crate::schema::readings;
-                    diesel::insert_into(readings::table)
-                        .values(&new_reading)
-                        .execute(&conn)
-                        .unwrap();
-                }
-                use crate::schema::readings::dsl::*;
-                Ok(readings.order(id.desc()).first::<DbReading>(&conn).unwrap())
-            })
+            .transaction::<_, Error, _>(|| insert_reading(&conn, &new_reading, extra_metrics))
             .map_err(|e| {
-                println!("{:?}", e);
+                tracing::error!(%corr_id, error = ?e, "reading insert failed");
             });
-        if let Err(reading) = reading {
-            println!("FAILED TO INSERT NEW READING IN DB: {:?}", reading);
+        timer.observe_duration();
+        if reading.is_ok() {
+            tracing::debug!(%corr_id, pub_id = msg.pub_id, "reading stored");
         }
     }
 }
@@ -73,12 +106,445 @@ impl Handler<GetReadings> for Actions {
     }
 }
 
-embed_migrations!("../migrations");
+/// gets a publisher's latest readings, newest first
+impl Handler<GetPublisherReadings> for Actions {
+    type Result = MessageResult<GetPublisherReadings>;
+
+    fn handle(&mut self, msg: GetPublisherReadings, _: &mut Context<Self>) -> Self::Result {
+        use crate::schema::readings::dsl::*;
+        let mut query = readings
+            .filter(publisher_id.eq(msg.pub_id as i64))
+            .order(read_time.desc())
+            .limit(msg.limit as i64)
+            .into_boxed();
+        if let Some(before) = msg.before {
+            query = query.filter(read_time.lt(before as i64));
+        }
+        MessageResult(query.load::<DbReading>(&self.conn()).unwrap())
+    }
+}
+
+/// gets a page of readings in ascending order by read_time, optionally filtered
+/// by publisher and a `from` (inclusive) `to` (exclusive) read_time range
+impl Handler<ExportReadings> for Actions {
+    type Result = MessageResult<ExportReadings>;
+
+    fn handle(&mut self, msg: ExportReadings, _: &mut Context<Self>) -> Self::Result {
+        use crate::schema::readings::dsl::*;
+        let mut query = readings
+            .order((read_time.asc(), id.asc()))
+            .limit(msg.limit as i64)
+            .into_boxed();
+        if let Some(pub_id) = msg.pub_id {
+            query = query.filter(publisher_id.eq(pub_id as i64));
+        }
+        if let Some(from) = msg.from {
+            query = query.filter(read_time.ge(from as i64));
+        }
+        if let Some(to) = msg.to {
+            query = query.filter(read_time.lt(to as i64));
+        }
+        if let Some((last_time, last_id)) = msg.after {
+            query = query.filter(
+                read_time
+                    .gt(last_time)
+                    .or(read_time.eq(last_time).and(id.gt(last_id))),
+            );
+        }
+        MessageResult(query.load::<DbReading>(&self.conn()).unwrap())
+    }
+}
+
+impl Handler<ReadingsAfter> for Actions {
+    type Result = MessageResult<ReadingsAfter>;
+
+    fn handle(&mut self, msg: ReadingsAfter, _: &mut Context<Self>) -> Self::Result {
+        use crate::schema::readings::dsl::*;
+        let mut query = readings
+            .filter(publisher_id.eq(msg.pub_id as i64))
+            .order(id.desc())
+            .limit(msg.limit as i64)
+            .into_boxed();
+        if let Some(after) = msg.after {
+            query = query.filter(id.gt(after));
+        }
+        let mut result = query.load::<DbReading>(&self.conn()).unwrap();
+        result.reverse();
+        MessageResult(result)
+    }
+}
+
+/// aggregates a publisher's readings into time buckets
+/// buckets in ascending order, readings without a TVOC value are left out of
+/// the evtoc aggregates
+impl Handler<AggregateReadings> for Actions {
+    type Result = Result<Vec<ReadingBucket>, String>;
+
+    fn handle(&mut self, msg: AggregateReadings, _: &mut Context<Self>) -> Self::Result {
+        use diesel::sql_types::{BigInt, Integer};
+        diesel::sql_query(format!(
+            "SELECT read_time / ?1 * ?1 AS start, COUNT(*) AS readings, \
+             COUNT(CASE WHEN quality_flags & {1} != 0 THEN 1 END) AS suspect, \
+             AVG(eco2) AS eco2_mean, MIN(eco2) AS eco2_min, MAX(eco2) AS eco2_max, \
+             AVG(CASE WHEN quality_flags & {0} = 0 THEN evtoc END) AS evtoc_mean, \
+             MIN(CASE WHEN quality_flags & {0} = 0 THEN evtoc END) AS evtoc_min, \
+             MAX(CASE WHEN quality_flags & {0} = 0 THEN evtoc END) AS evtoc_max \
+             FROM readings WHERE publisher_id = ?2 AND read_time >= ?3 AND read_time < ?4 \
+             GROUP BY start ORDER BY start LIMIT ?5",
+            QUALITY_NO_EVTOC, QUALITY_SUSPECT
+        ))
+        .bind::<BigInt, _>(msg.bucket as i64)
+        .bind::<BigInt, _>(msg.pub_id as i64)
+        .bind::<BigInt, _>(msg.from.unwrap_or(0) as i64)
+        .bind::<BigInt, _>(msg.to.map_or(i64::MAX, |to| to as i64))
+        .bind::<Integer, _>(msg.limit as i32)
+        .load::<ReadingBucket>(&self.conn())
+        .map_err(|e| format!("{}", e))
+    }
+}
+
+impl Handler<GetPublishers> for Actions {
+    type Result = Result<Vec<u64>, String>;
+
+    fn handle(&mut self, msg: GetPublishers, _: &mut Context<Self>) -> Self::Result {
+        use crate::schema::readings::dsl::*;
+        readings
+            .select(publisher_id)
+            .distinct()
+            .filter(read_time.ge(msg.from as i64))
+            .filter(read_time.lt(msg.to as i64))
+            .order(publisher_id.asc())
+            .load::<i64>(&self.conn())
+            .map(|ids| ids.into_iter().map(|pub_id| pub_id as u64).collect())
+            .map_err(|e| format!("{}", e))
+    }
+}
+
+impl Handler<MarkReadings> for Actions {
+    type Result = ();
+
+    fn handle(&mut self, msg: MarkReadings, _: &mut Context<Self>) {
+        use diesel::sql_types::{BigInt, Integer};
+        let marked = diesel::sql_query(
+            "UPDATE readings SET quality_flags = quality_flags | ?1 \
+             WHERE publisher_id = ?2 AND read_time >= ?3 AND read_time < ?4",
+        )
+        .bind::<Integer, _>(msg.flags)
+        .bind::<BigInt, _>(msg.pub_id as i64)
+        .bind::<BigInt, _>(msg.from as i64)
+        .bind::<BigInt, _>(msg.to as i64)
+        .execute(&self.conn());
+        if let Err(err) = marked {
+            tracing::error!(error = ?err, "couldn't mark readings");
+        }
+    }
+}
+
+impl Handler<Flush> for Actions {
+    type Result = ();
+
+    fn handle(&mut self, _: Flush, _: &mut Context<Self>) {}
+}
+
+impl Handler<Ping> for Actions {
+    type Result = ();
+
+    fn handle(&mut self, _: Ping, _: &mut Context<Self>) {}
+}
+
+impl Handler<DbStatus> for Actions {
+    type Result = Result<DbHealth, String>;
+
+    fn handle(&mut self, _: DbStatus, _: &mut Context<Self>) -> Self::Result {
+        let state = self.pool.state();
+        let conn = self
+            .pool
+            .get_timeout(STATUS_CONN_TIMEOUT)
+            .map_err(|e| format!("{}", e))?;
+        let migration = super::backup::migration_versions(&conn)?.into_iter().max();
+        Ok(DbHealth {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            max_connections: self.pool.max_size(),
+            migration,
+        })
+    }
+}
+
+/// imports readings in a single transaction, de-duplicated by (publisher_id, read_time)
+impl Handler<ImportReadings> for Actions {
+    type Result = Result<ImportReport, String>;
+
+    fn handle(&mut self, msg: ImportReadings, _: &mut Context<Self>) -> Self::Result {
+        let conn = self.conn();
+        let mut report = ImportReport {
+            dry_run: msg.dry_run,
+            ..ImportReport::default()
+        };
+        let times = msg.readings.iter().map(|r| r.reading.read_time);
+        let (min, max) = match (times.clone().min(), times.max()) {
+            (Some(min), Some(max)) => (min, max),
+            _ => return Ok(report),
+        };
+        let mut seen = {
+            use crate::schema::readings::dsl::*;
+            readings
+                .select(read_time)
+                .filter(publisher_id.eq(msg.pub_id as i64))
+                .filter(read_time.between(min, max))
+                .load::<i64>(&conn)
+                .map_err(|e| format!("{:?}", e))?
+                .into_iter()
+                .collect::<HashSet<i64>>()
+        };
+        let dry_run = msg.dry_run;
+        conn.transaction::<_, Error, _>(|| {
+            for imported in msg.readings {
+                if !seen.insert(imported.reading.read_time) {
+                    report.skipped += 1;
+                    continue;
+                }
+                if !dry_run {
+                    insert_reading(&conn, &imported.reading, imported.metrics)?;
+                }
+                report.inserted += 1;
+            }
+            Ok(())
+        })
+        .map_err(|e| format!("{:?}", e))?;
+        Ok(report)
+    }
+}
+
+impl Handler<GetAlertRules> for Actions {
+    type Result = MessageResult<GetAlertRules>;
+
+    fn handle(&mut self, msg: GetAlertRules, _: &mut Context<Self>) -> Self::Result {
+        use crate::schema::alert_rules::dsl::*;
+        let mut query = alert_rules.order(id.asc()).into_boxed();
+        if msg.enabled_only {
+            query = query.filter(enabled.eq(true));
+        }
+        MessageResult(query.load::<AlertRule>(&self.conn()).unwrap())
+    }
+}
+
+impl Handler<GetMetricNames> for Actions {
+    type Result = Result<Vec<String>, String>;
+
+    fn handle(&mut self, _: GetMetricNames, _: &mut Context<Self>) -> Self::Result {
+        use crate::schema::reading_metrics::dsl::*;
+        reading_metrics
+            .select(metric_name)
+            .distinct()
+            .order(metric_name.asc())
+            .load::<String>(&self.conn())
+            .map_err(|e| format!("{:?}", e))
+    }
+}
+
+impl Handler<AddAlertRule> for Actions {
+    type Result = Result<AlertRule, String>;
+
+    fn handle(&mut self, msg: AddAlertRule, _: &mut Context<Self>) -> Self::Result {
+        let conn = self.conn();
+        conn.transaction::<_, Error, _>(|| {
+            use crate::schema::alert_rules::dsl::*;
+            diesel::insert_into(alert_rules)
+                .values(&msg.0)
+                .execute(&conn)?;
+            alert_rules.order(id.desc()).first::<AlertRule>(&conn)
+        })
+        .map_err(|e| format!("{:?}", e))
+    }
+}
+
+impl Handler<DisableAlertRule> for Actions {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: DisableAlertRule, _: &mut Context<Self>) -> Self::Result {
+        use crate::schema::alert_rules::dsl::*;
+        let updated = diesel::update(alert_rules.find(msg.id))
+            .set(enabled.eq(false))
+            .execute(&self.conn())
+            .map_err(|e| format!("{:?}", e))?;
+        match updated {
+            0 => Err(format!("no alert rule {}", msg.id)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Handler<SaveAlertEvent> for Actions {
+    type Result = ();
+
+    fn handle(&mut self, msg: SaveAlertEvent, _: &mut Context<Self>) {
+        use crate::schema::alert_events;
+        let alert = msg.0;
+        let event = NewAlertEvent {
+            rule_id: alert.rule_id,
+            publisher_id: alert.pub_id as i64,
+            state: alert.state.as_str().to_owned(),
+            value: alert.value,
+            event_time: alert.time as i64,
+        };
+        if let Err(err) = diesel::insert_into(alert_events::table)
+            .values(&event)
+            .execute(&self.conn())
+        {
+            tracing::error!(error = ?err, "alert event insert failed");
+        }
+    }
+}
+
+impl Handler<GetLatestAlertEvents> for Actions {
+    type Result = MessageResult<GetLatestAlertEvents>;
+
+    fn handle(&mut self, _: GetLatestAlertEvents, _: &mut Context<Self>) -> Self::Result {
+        let events = diesel::sql_query(
+            "SELECT * FROM alert_events WHERE id IN \
+             (SELECT MAX(id) FROM alert_events GROUP BY rule_id, publisher_id)",
+        )
+        .load::<AlertEvent>(&self.conn())
+        .unwrap();
+        MessageResult(events)
+    }
+}
+
+impl Handler<GetAlertHistory> for Actions {
+    type Result = MessageResult<GetAlertHistory>;
+
+    fn handle(&mut self, msg: GetAlertHistory, _: &mut Context<Self>) -> Self::Result {
+        use crate::schema::alert_events::dsl::*;
+        let mut query = alert_events
+            .order((event_time.desc(), id.desc()))
+            .limit(msg.limit as i64)
+            .into_boxed();
+        if let Some(pub_id) = msg.pub_id {
+            query = query.filter(publisher_id.eq(pub_id as i64));
+        }
+        if let Some(before) = msg.before {
+            query = query.filter(event_time.lt(before as i64));
+        }
+        MessageResult(query.load::<AlertEvent>(&self.conn()).unwrap())
+    }
+}
+
+impl Handler<GetWebhooks> for Actions {
+    type Result = MessageResult<GetWebhooks>;
+
+    fn handle(&mut self, msg: GetWebhooks, _: &mut Context<Self>) -> Self::Result {
+        use crate::schema::webhooks::dsl::*;
+        let mut query = webhooks.order(id.asc()).into_boxed();
+        if msg.enabled_only {
+            query = query.filter(enabled.eq(true));
+        }
+        MessageResult(query.load::<Webhook>(&self.conn()).unwrap())
+    }
+}
+
+impl Handler<AddWebhook> for Actions {
+    type Result = Result<Webhook, String>;
+
+    fn handle(&mut self, msg: AddWebhook, _: &mut Context<Self>) -> Self::Result {
+        let conn = self.conn();
+        conn.transaction::<_, Error, _>(|| {
+            use crate::schema::webhooks::dsl::*;
+            diesel::insert_into(webhooks)
+                .values(&msg.0)
+                .execute(&conn)?;
+            webhooks.order(id.desc()).first::<Webhook>(&conn)
+        })
+        .map_err(|e| format!("{:?}", e))
+    }
+}
+
+impl Handler<DisableWebhook> for Actions {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: DisableWebhook, _: &mut Context<Self>) -> Self::Result {
+        use crate::schema::webhooks::dsl::*;
+        let updated = diesel::update(webhooks.find(msg.id))
+            .set(enabled.eq(false))
+            .execute(&self.conn())
+            .map_err(|e| format!("{:?}", e))?;
+        match updated {
+            0 => Err(format!("no webhook {}", msg.id)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Handler<LogWebhookDelivery> for Actions {
+    type Result = ();
+
+    fn handle(&mut self, msg: LogWebhookDelivery, _: &mut Context<Self>) {
+        use crate::schema::webhook_deliveries;
+        if let Err(err) = diesel::insert_into(webhook_deliveries::table)
+            .values(&msg.0)
+            .execute(&self.conn())
+        {
+            tracing::error!(error = ?err, "webhook delivery insert failed");
+        }
+    }
+}
+
+impl Handler<GetWebhookDeliveries> for Actions {
+    type Result = MessageResult<GetWebhookDeliveries>;
+
+    fn handle(&mut self, msg: GetWebhookDeliveries, _: &mut Context<Self>) -> Self::Result {
+        use crate::schema::webhook_deliveries::dsl::*;
+        let mut query = webhook_deliveries
+            .order(id.desc())
+            .limit(msg.limit as i64)
+            .into_boxed();
+        if let Some(hook) = msg.webhook_id {
+            query = query.filter(webhook_id.eq(hook));
+        }
+        if let Some(before) = msg.before {
+            query = query.filter(id.lt(before));
+        }
+        MessageResult(query.load::<WebhookDelivery>(&self.conn()).unwrap())
+    }
+}
+
+/// insert a reading and its extra metrics, should be called within a transaction
+fn insert_reading(
+    conn: &SqliteConnection,
+    new_reading: &NewReading,
+    metrics: impl IntoIterator<Item = (String, f64)>,
+) -> Result<DbReading, Error> {
+    {
+        use crate::schema::readings;
+        diesel::insert_into(readings::table)
+            .values(new_reading)
+            .execute(conn)?;
+    }
+    let reading = {
+        use crate::schema::readings::dsl::*;
+        readings.order(id.desc()).first::<DbReading>(conn)?
+    };
+    {
+        use crate::schema::reading_metrics;
+        // sqlite doesn't support batch inserts in diesel
+        for (metric_name, value) in metrics {
+            diesel::insert_into(reading_metrics::table)
+                .values(&ReadingMetric {
+                    reading_id: reading.id,
+                    metric_name,
+                    value,
+                })
+                .execute(conn)?;
+        }
+    }
+    Ok(reading)
+}
 
 impl Actions {
     pub fn new(connspec: &str) -> Actions {
         let manager = ConnectionManager::<SqliteConnection>::new(connspec);
         let pool = r2d2::Pool::builder()
+            .connection_customizer(Box::new(Pragmas))
             .build(manager)
             .expect("Failed to create pool.");
 
@@ -93,3 +559,78 @@ impl Actions {
         self.pool.get().unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actions(name: &str) -> Addr<Actions> {
+        let path = std::env::temp_dir().join(format!(
+            "air_meter-actions-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        Actions::new(&path.to_string_lossy()).start()
+    }
+
+    async fn store(actions: &Addr<Actions>, pub_id: u64, read_time: u64) {
+        let msg: Reading = serde_json::from_value(serde_json::json!({
+            "eco2": 400 + read_time,
+            "evtoc": 0,
+            "read_time": read_time,
+            "start_time": 0,
+            "increment": "ConstantPower1s",
+        }))
+        .unwrap();
+        actions
+            .send(PubMsg {
+                msg,
+                pub_id,
+                json: String::new(),
+            })
+            .await
+            .unwrap();
+    }
+
+    fn times(readings: &[DbReading]) -> Vec<(i64, i64)> {
+        readings
+            .iter()
+            .map(|r| (r.publisher_id, r.read_time))
+            .collect()
+    }
+
+    #[actix_rt::test]
+    async fn legacy_readings_span_publishers() {
+        let actions = actions("legacy");
+        for (pub_id, time) in [(811, 10), (900, 20), (811, 30), (900, 40)] {
+            store(&actions, pub_id, time).await;
+        }
+        let readings = actions
+            .send(GetReadings {
+                pub_id: 811,
+                before: Some(40),
+                limit: 2,
+            })
+            .await
+            .unwrap();
+        assert_eq!(times(&readings), vec![(811, 30), (900, 20)]);
+    }
+
+    #[actix_rt::test]
+    async fn publisher_readings_are_only_that_publishers() {
+        let actions = actions("publisher");
+        for (pub_id, time) in [(811, 10), (900, 20), (811, 30), (900, 40)] {
+            store(&actions, pub_id, time).await;
+        }
+        let get = |before| GetPublisherReadings {
+            pub_id: 811,
+            before,
+            limit: 10,
+        };
+        let readings = actions.send(get(None)).await.unwrap();
+        assert_eq!(times(&readings), vec![(811, 30), (811, 10)]);
+        let readings = actions.send(get(Some(30))).await.unwrap();
+        assert_eq!(times(&readings), vec![(811, 10)]);
+    }
+}
@@ -0,0 +1,489 @@
+//! Online snapshots of the live sqlite database using the sqlite backup api,
+//! scheduled backups with rotation, and restoring a snapshot in place of the db
+use actix::prelude::*;
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use libsqlite3_sys as ffi;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::db::embedded_migrations;
+use utoipa::ToSchema;
+
+/// how long a backup waits on a locked database before giving up
+const BUSY_TIMEOUT_MS: c_int = 5000;
+/// in backup and replaced database names, with microseconds so backups taken
+/// in the same second don't collide, sorts chronologically
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.6fZ";
+
+/// copy a consistent snapshot of the database at `src` to `dest`,
+/// safe to run while the server is writing to `src`
+pub fn backup(src: &Path, dest: &Path) -> Result<(), String> {
+    let src_db = Handle::open(src, ffi::SQLITE_OPEN_READONLY)?;
+    let dest_db = Handle::open(dest, ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE)?;
+    let main = CString::new("main").unwrap();
+    unsafe {
+        ffi::sqlite3_busy_timeout(src_db.0, BUSY_TIMEOUT_MS);
+        let bk = ffi::sqlite3_backup_init(dest_db.0, main.as_ptr(), src_db.0, main.as_ptr());
+        if bk.is_null() {
+            return Err(dest_db.errmsg());
+        }
+        // copy every page in one step so the snapshot can't be restarted by a writer
+        let mut rc = ffi::sqlite3_backup_step(bk, -1);
+        let mut retries = 0;
+        while (rc == ffi::SQLITE_BUSY || rc == ffi::SQLITE_LOCKED) && retries < 50 {
+            std::thread::sleep(Duration::from_millis(100));
+            rc = ffi::sqlite3_backup_step(bk, -1);
+            retries += 1;
+        }
+        ffi::sqlite3_backup_finish(bk);
+        if rc != ffi::SQLITE_DONE {
+            return Err(format!("backup failed: {}", dest_db.errmsg()));
+        }
+    }
+    Ok(())
+}
+
+/// raw sqlite connection closed on drop
+struct Handle(*mut ffi::sqlite3);
+
+impl Handle {
+    fn open(path: &Path, flags: c_int) -> Result<Handle, String> {
+        let c_path = CString::new(path.to_string_lossy().as_bytes())
+            .map_err(|_| format!("invalid path {:?}", path))?;
+        let mut db = ptr::null_mut();
+        let rc = unsafe { ffi::sqlite3_open_v2(c_path.as_ptr(), &mut db, flags, ptr::null()) };
+        let handle = Handle(db);
+        if rc != ffi::SQLITE_OK {
+            return Err(format!("couldn't open {:?}: {}", path, handle.errmsg()));
+        }
+        Ok(handle)
+    }
+
+    fn errmsg(&self) -> String {
+        if self.0.is_null() {
+            return "out of memory".to_owned();
+        }
+        unsafe { CStr::from_ptr(ffi::sqlite3_errmsg(self.0)) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::sqlite3_close(self.0);
+        }
+    }
+}
+
+#[derive(QueryableByName)]
+struct MigrationVersion {
+    #[sql_type = "Text"]
+    version: String,
+}
+
+#[derive(QueryableByName)]
+struct IntegrityCheck {
+    #[sql_type = "Text"]
+    integrity_check: String,
+}
+
+pub(crate) fn migration_versions(conn: &SqliteConnection) -> Result<HashSet<String>, String> {
+    diesel::sql_query("SELECT version FROM __diesel_schema_migrations")
+        .load::<MigrationVersion>(conn)
+        .map(|rows| rows.into_iter().map(|r| r.version).collect())
+        .map_err(|e| format!("not an air meter database: {}", e))
+}
+
+/// migration versions embedded in this server binary
+fn known_migrations() -> Result<HashSet<String>, String> {
+    let conn = SqliteConnection::establish(":memory:").map_err(|e| format!("{}", e))?;
+    embedded_migrations::run(&conn).map_err(|e| format!("{}", e))?;
+    migration_versions(&conn)
+}
+
+/// replace the database at `db_path` with the snapshot at `backup_path`.
+/// the snapshot must pass an integrity check and only contain migrations this
+/// server knows about, it's then migrated to the current schema before being
+/// swapped in. the replaced database is kept next to it.
+/// the server must not be running while restoring
+pub fn restore(db_path: &Path, backup_path: &Path) -> Result<String, String> {
+    if !backup_path.is_file() {
+        return Err(format!("{:?} doesn't exist", backup_path));
+    }
+    let staging = db_path.with_extension("restore");
+    let _ = fs::remove_file(&staging);
+    backup(backup_path, &staging)?;
+    let check = || -> Result<usize, String> {
+        let conn = SqliteConnection::establish(&staging.to_string_lossy())
+            .map_err(|e| format!("{}", e))?;
+        let integrity = diesel::sql_query("PRAGMA integrity_check")
+            .load::<IntegrityCheck>(&conn)
+            .map_err(|e| format!("{}", e))?;
+        if integrity.iter().any(|row| row.integrity_check != "ok") {
+            return Err("backup failed integrity check".to_owned());
+        }
+        let applied = migration_versions(&conn)?;
+        let known = known_migrations()?;
+        let mut unknown = applied.difference(&known).cloned().collect::<Vec<String>>();
+        if !unknown.is_empty() {
+            unknown.sort();
+            return Err(format!(
+                "backup has migrations {:?} unknown to this server, it was taken by a newer version",
+                unknown
+            ));
+        }
+        let pending = known.difference(&applied).count();
+        embedded_migrations::run(&conn).map_err(|e| format!("{}", e))?;
+        Ok(pending)
+    };
+    let pending = match check() {
+        Ok(pending) => pending,
+        Err(err) => {
+            let _ = fs::remove_file(&staging);
+            return Err(err);
+        }
+    };
+    let mut message = String::new();
+    if db_path.exists() {
+        let previous = PathBuf::from(format!(
+            "{}.pre-restore-{}",
+            db_path.to_string_lossy(),
+            Utc::now().format(TIMESTAMP_FORMAT)
+        ));
+        fs::rename(db_path, &previous).map_err(|e| format!("{}", e))?;
+        message.push_str(&format!("previous database moved to {:?}, ", previous));
+    }
+    fs::rename(&staging, db_path).map_err(|e| format!("{}", e))?;
+    message.push_str(&format!(
+        "restored {:?} to {:?}, applied {} pending migrations",
+        backup_path, db_path, pending
+    ));
+    Ok(message)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    pub dir: PathBuf,
+    /// time between scheduled backups, 0 disables the schedule
+    pub interval_hours: u64,
+    /// number of backups kept by rotation
+    pub keep: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> BackupConfig {
+        BackupConfig {
+            dir: "./backups".into(),
+            interval_hours: 24,
+            keep: 7,
+        }
+    }
+}
+
+impl BackupConfig {
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        if self.dir.as_os_str().is_empty() {
+            errors.push("backup.dir: can't be empty".to_owned());
+        }
+        if self.keep == 0 {
+            errors.push("backup.keep: must be above 0".to_owned());
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BackupInfo {
+    pub name: String,
+    pub size: u64,
+    /// unix seconds
+    pub created: u64,
+}
+
+/// Creates backups in the backup directory on request or on a schedule,
+/// removing the oldest beyond `keep`. Should be started in its own arbiter as
+/// backups block the thread.
+pub struct Backups {
+    /// live database file
+    db_path: PathBuf,
+    config: BackupConfig,
+}
+
+impl Actor for Backups {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        if self.config.interval_hours > 0 {
+            let interval = Duration::from_secs(self.config.interval_hours * 60 * 60);
+            ctx.run_interval(interval, |act, _| {
+                if let Err(err) = act.create() {
+                    tracing::error!(error = %err, "scheduled backup failed");
+                }
+            });
+        }
+    }
+}
+
+impl Backups {
+    pub fn new(db_path: PathBuf, config: BackupConfig) -> Backups {
+        Backups { db_path, config }
+    }
+
+    fn prefix(&self) -> String {
+        let stem = self
+            .db_path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "server".to_owned());
+        format!("{}-", stem)
+    }
+
+    fn create(&mut self) -> Result<BackupInfo, String> {
+        fs::create_dir_all(&self.config.dir).map_err(|e| format!("{}", e))?;
+        // a name already taken in the same microsecond is retried with the next
+        let (name, dest) = loop {
+            let name = format!(
+                "{}{}.db",
+                self.prefix(),
+                Utc::now().format(TIMESTAMP_FORMAT)
+            );
+            let dest = self.config.dir.join(&name);
+            if !dest.exists() {
+                break (name, dest);
+            }
+        };
+        let tmp = dest.with_extension("tmp");
+        backup(&self.db_path, &tmp)
+            .and_then(|_| fs::rename(&tmp, &dest).map_err(|e| format!("{}", e)))
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&tmp);
+            })?;
+        tracing::info!(?dest, "backup created");
+        self.rotate();
+        self.list()
+            .into_iter()
+            .find(|b| b.name == name)
+            .ok_or_else(|| format!("{} was removed by rotation", name))
+    }
+
+    /// backups newest first
+    fn list(&self) -> Vec<BackupInfo> {
+        let prefix = self.prefix();
+        let mut backups = fs::read_dir(&self.config.dir)
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .filter_map(|entry| {
+                        let name = entry.file_name().to_string_lossy().into_owned();
+                        if !name.starts_with(&prefix) || !name.ends_with(".db") {
+                            return None;
+                        }
+                        let meta = entry.metadata().ok()?;
+                        let created = meta
+                            .modified()
+                            .ok()
+                            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                            .map(|d| d.as_secs())
+                            .unwrap_or(0);
+                        Some(BackupInfo {
+                            name,
+                            size: meta.len(),
+                            created,
+                        })
+                    })
+                    .collect::<Vec<BackupInfo>>()
+            })
+            .unwrap_or_default();
+        // names contain the timestamp, so they sort chronologically
+        backups.sort_by(|a, b| b.name.cmp(&a.name));
+        backups
+    }
+
+    fn rotate(&self) {
+        for old in self.list().iter().skip(self.config.keep) {
+            let path = self.config.dir.join(&old.name);
+            match fs::remove_file(&path) {
+                Ok(_) => tracing::info!(?path, "backup rotated"),
+                Err(err) => tracing::error!(?path, error = %err, "couldn't remove backup"),
+            }
+        }
+    }
+}
+
+/// Create a backup now
+pub struct CreateBackup;
+
+impl Message for CreateBackup {
+    type Result = Result<BackupInfo, String>;
+}
+
+impl Handler<CreateBackup> for Backups {
+    type Result = Result<BackupInfo, String>;
+
+    fn handle(&mut self, _: CreateBackup, _: &mut Context<Self>) -> Self::Result {
+        self.create()
+    }
+}
+
+/// List backups in the backup directory, newest first
+pub struct ListBackups;
+
+impl Message for ListBackups {
+    type Result = Vec<BackupInfo>;
+}
+
+impl Handler<ListBackups> for Backups {
+    type Result = MessageResult<ListBackups>;
+
+    fn handle(&mut self, _: ListBackups, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.list())
+    }
+}
+
+/// Get the path of a backup by name, if it exists
+pub struct BackupPath(pub String);
+
+impl Message for BackupPath {
+    type Result = Option<PathBuf>;
+}
+
+impl Handler<BackupPath> for Backups {
+    type Result = Option<PathBuf>;
+
+    fn handle(&mut self, msg: BackupPath, _: &mut Context<Self>) -> Self::Result {
+        // only names from the listing, so the path can't escape the backup dir
+        self.list()
+            .into_iter()
+            .find(|b| b.name == msg.0)
+            .map(|b| self.config.dir.join(b.name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::connection::SimpleConnection;
+    use diesel::dsl::sql;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("air_meter-backup-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// a migrated database at `path` holding `marker`
+    fn database(path: &Path, marker: &str) -> SqliteConnection {
+        let conn = SqliteConnection::establish(&path.to_string_lossy()).unwrap();
+        embedded_migrations::run(&conn).unwrap();
+        conn.batch_execute(&format!(
+            "CREATE TABLE marker (name TEXT NOT NULL); INSERT INTO marker VALUES ('{}');",
+            marker
+        ))
+        .unwrap();
+        conn
+    }
+
+    fn marker(path: &Path) -> String {
+        let conn = SqliteConnection::establish(&path.to_string_lossy()).unwrap();
+        diesel::select(sql::<Text>("(SELECT name FROM marker)"))
+            .get_result(&conn)
+            .unwrap()
+    }
+
+    #[test]
+    fn restore_rejects_unknown_migrations() {
+        let dir = temp_dir("newer");
+        let (db, snapshot) = (dir.join("server.db"), dir.join("snapshot.db"));
+        database(&db, "live");
+        database(&snapshot, "snapshot")
+            .batch_execute(
+                "INSERT INTO __diesel_schema_migrations (version) VALUES ('29990101000000');",
+            )
+            .unwrap();
+
+        let err = restore(&db, &snapshot).unwrap_err();
+        assert!(err.contains("29990101000000"), "{}", err);
+        assert_eq!(marker(&db), "live");
+        assert!(!db.with_extension("restore").exists());
+    }
+
+    #[test]
+    fn restore_rejects_a_failed_integrity_check() {
+        let dir = temp_dir("corrupt");
+        let (db, snapshot) = (dir.join("server.db"), dir.join("snapshot.db"));
+        database(&db, "live");
+        // an index whose definition no longer matches its entries
+        database(&snapshot, "snapshot")
+            .batch_execute(
+                "CREATE TABLE pairs (a INTEGER, b INTEGER);
+                 INSERT INTO pairs VALUES (1, 10), (2, 20);
+                 CREATE INDEX pairs_a ON pairs (a);
+                 PRAGMA writable_schema = ON;
+                 UPDATE sqlite_master SET sql = 'CREATE INDEX pairs_a ON pairs (b)'
+                     WHERE name = 'pairs_a';",
+            )
+            .unwrap();
+
+        let err = restore(&db, &snapshot).unwrap_err();
+        assert_eq!(err, "backup failed integrity check");
+        assert_eq!(marker(&db), "live");
+    }
+
+    #[test]
+    fn restore_swaps_in_the_snapshot_and_keeps_the_previous_database() {
+        let dir = temp_dir("restore");
+        let (db, snapshot) = (dir.join("server.db"), dir.join("snapshot.db"));
+        database(&db, "live");
+        database(&snapshot, "snapshot");
+
+        restore(&db, &snapshot).unwrap();
+        assert_eq!(marker(&db), "snapshot");
+        let previous = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.to_string_lossy().contains("server.db.pre-restore-"))
+            .collect::<Vec<_>>();
+        assert_eq!(previous.len(), 1);
+        assert_eq!(marker(&previous[0]), "live");
+        assert_eq!(marker(&snapshot), "snapshot");
+    }
+
+    #[test]
+    fn rotation_keeps_the_newest_backups() {
+        let dir = temp_dir("rotate");
+        let db = dir.join("server.db");
+        database(&db, "live");
+        let mut backups = Backups::new(
+            db,
+            BackupConfig {
+                dir: dir.join("backups"),
+                interval_hours: 0,
+                keep: 3,
+            },
+        );
+
+        // taken within the same second, their names still differ
+        let created = (0..5)
+            .map(|_| backups.create().unwrap().name)
+            .collect::<Vec<_>>();
+        let kept = backups
+            .list()
+            .into_iter()
+            .map(|b| b.name)
+            .collect::<Vec<_>>();
+        assert_eq!(kept, created[2..].iter().rev().cloned().collect::<Vec<_>>());
+        assert!(kept.iter().all(|name| name.starts_with("server-")));
+    }
+}
@@ -1,3 +1,6 @@
 pub mod actions;
+pub mod backup;
 pub mod model;
 pub use actions::Actions;
+
+embed_migrations!("../migrations");
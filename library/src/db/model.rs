@@ -1,5 +1,144 @@
-use crate::schema::readings;
-use serde::Serialize;
+use crate::schema::{
+    alert_events, alert_rules, reading_metrics, readings, webhook_deliveries, webhooks,
+};
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::{BigInt, Double, Integer, Nullable, SmallInt};
+use diesel::sqlite::Sqlite;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use utoipa::ToSchema;
+
+/// `quality_flags` bits
+/// reading was bulk imported rather than reported by a publisher
+pub const QUALITY_IMPORTED: i32 = 1;
+/// source had no TVOC value, `evtoc` is 0
+pub const QUALITY_NO_EVTOC: i32 = 1 << 1;
+/// sensor reported identical values for too long, likely stuck
+pub const QUALITY_FLATLINE: i32 = 1 << 2;
+/// changed faster than air plausibly can
+pub const QUALITY_SPIKE: i32 = 1 << 3;
+/// outside the sensor's range
+pub const QUALITY_OUT_OF_RANGE: i32 = 1 << 4;
+/// any of the bits marking a reading as suspect
+pub const QUALITY_SUSPECT: i32 = QUALITY_FLATLINE | QUALITY_SPIKE | QUALITY_OUT_OF_RANGE;
+
+/// why a reading may be inaccurate, or where it came from
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Flag {
+    /// bulk imported rather than reported by a publisher
+    Imported,
+    /// the sensor reported no TVOC value
+    NoEvtoc,
+    /// identical values for too long, likely stuck
+    Flatline,
+    /// changed faster than air plausibly can
+    Spike,
+    /// outside the sensor's range
+    OutOfRange,
+}
+
+impl Flag {
+    const BITS: [(i32, Flag); 5] = [
+        (QUALITY_IMPORTED, Flag::Imported),
+        (QUALITY_NO_EVTOC, Flag::NoEvtoc),
+        (QUALITY_FLATLINE, Flag::Flatline),
+        (QUALITY_SPIKE, Flag::Spike),
+        (QUALITY_OUT_OF_RANGE, Flag::OutOfRange),
+    ];
+
+    pub fn from_bits(bits: i32) -> Vec<Flag> {
+        Flag::BITS
+            .iter()
+            .filter(|(bit, _)| bits & bit != 0)
+            .map(|(_, flag)| *flag)
+            .collect()
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Flag::Imported => "imported",
+            Flag::NoEvtoc => "no_evtoc",
+            Flag::Flatline => "flatline",
+            Flag::Spike => "spike",
+            Flag::OutOfRange => "out_of_range",
+        }
+    }
+}
+
+/// Sensor measurement mode, stored as a `measurement_modes` id
+/// serialized with the same names the sensor client reports as `increment`
+#[derive(
+    AsExpression, FromSqlRow, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema,
+)]
+#[sql_type = "SmallInt"]
+pub enum Mode {
+    Unknown = 0,
+    Idle = 1,
+    ConstantPower1s = 2,
+    PulseHeating10s = 3,
+    LowPowerPulseHeating60s = 4,
+    ConstantPower250ms = 5,
+}
+
+impl Mode {
+    /// modes a sensor can be set to
+    pub const SETTABLE: [Mode; 5] = [
+        Mode::Idle,
+        Mode::ConstantPower250ms,
+        Mode::ConstantPower1s,
+        Mode::PulseHeating10s,
+        Mode::LowPowerPulseHeating60s,
+    ];
+
+    /// parse the `increment` string sent by publishers, unrecognised modes map to `Unknown`
+    pub fn from_name(name: &str) -> Mode {
+        match name {
+            "Idle" => Mode::Idle,
+            "ConstantPower1s" => Mode::ConstantPower1s,
+            "PulseHeating10s" => Mode::PulseHeating10s,
+            "LowPowerPulseHeating60s" => Mode::LowPowerPulseHeating60s,
+            "ConstantPower250ms" => Mode::ConstantPower250ms,
+            _ => Mode::Unknown,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Unknown => "Unknown",
+            Mode::Idle => "Idle",
+            Mode::ConstantPower1s => "ConstantPower1s",
+            Mode::PulseHeating10s => "PulseHeating10s",
+            Mode::LowPowerPulseHeating60s => "LowPowerPulseHeating60s",
+            Mode::ConstantPower250ms => "ConstantPower250ms",
+        }
+    }
+
+    fn from_id(id: i16) -> Mode {
+        match id {
+            1 => Mode::Idle,
+            2 => Mode::ConstantPower1s,
+            3 => Mode::PulseHeating10s,
+            4 => Mode::LowPowerPulseHeating60s,
+            5 => Mode::ConstantPower250ms,
+            _ => Mode::Unknown,
+        }
+    }
+}
+
+impl ToSql<SmallInt, Sqlite> for Mode {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Sqlite>) -> serialize::Result {
+        ToSql::<SmallInt, Sqlite>::to_sql(&(*self as i16), out)
+    }
+}
+
+impl FromSql<SmallInt, Sqlite> for Mode {
+    fn from_sql(bytes: Option<&<Sqlite as Backend>::RawValue>) -> deserialize::Result<Self> {
+        <i16 as FromSql<SmallInt, Sqlite>>::from_sql(bytes).map(Mode::from_id)
+    }
+}
 
 #[derive(Insertable, Debug)]
 #[table_name = "readings"]
@@ -9,7 +148,13 @@ pub struct NewReading {
     pub evtoc: i32,
     pub read_time: i64,
     pub start_time: i64,
-    pub increment: String,
+    pub mode: Mode,
+    pub sensor_model: Option<String>,
+    pub temperature: Option<f64>,
+    pub humidity: Option<f64>,
+    pub quality_flags: i32,
+    pub raw_current: Option<i32>,
+    pub raw_voltage: Option<i32>,
 }
 
 #[derive(Queryable, Debug, Clone, Serialize)]
@@ -21,5 +166,155 @@ pub struct DbReading {
     pub evtoc: i32,
     pub read_time: i64,
     pub start_time: i64,
-    pub increment: String,
+    #[serde(rename = "increment")]
+    pub mode: Mode,
+    pub sensor_model: Option<String>,
+    pub temperature: Option<f64>,
+    pub humidity: Option<f64>,
+    pub quality_flags: i32,
+    pub raw_current: Option<i32>,
+    pub raw_voltage: Option<i32>,
+}
+
+/// readings of a publisher aggregated over a time bucket, evtoc excludes
+/// readings without a TVOC value and is none when the bucket has none
+#[derive(QueryableByName, Debug, Clone, Serialize)]
+pub struct ReadingBucket {
+    /// read_time the bucket starts at
+    #[sql_type = "BigInt"]
+    pub start: i64,
+    #[sql_type = "BigInt"]
+    pub readings: i64,
+    /// readings with any `QUALITY_SUSPECT` bit
+    #[sql_type = "BigInt"]
+    pub suspect: i64,
+    #[sql_type = "Double"]
+    pub eco2_mean: f64,
+    #[sql_type = "Integer"]
+    pub eco2_min: i32,
+    #[sql_type = "Integer"]
+    pub eco2_max: i32,
+    #[sql_type = "Nullable<Double>"]
+    pub evtoc_mean: Option<f64>,
+    #[sql_type = "Nullable<Integer>"]
+    pub evtoc_min: Option<i32>,
+    #[sql_type = "Nullable<Integer>"]
+    pub evtoc_max: Option<i32>,
+}
+
+/// value of a metric without a dedicated `readings` column
+#[derive(Insertable, Queryable, Debug, Clone, Serialize)]
+#[table_name = "reading_metrics"]
+pub struct ReadingMetric {
+    pub reading_id: i32,
+    pub metric_name: String,
+    pub value: f64,
+}
+
+/// threshold alert with hysteresis, fires when `metric` stays above
+/// `trigger_above` for `trigger_secs` and clears once it stays below
+/// `clear_below` for `clear_secs`
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct AlertRule {
+    pub id: i32,
+    pub name: String,
+    /// rule applies to every publisher when none
+    pub publisher_id: Option<i64>,
+    pub metric: String,
+    pub trigger_above: f64,
+    pub trigger_secs: i64,
+    pub clear_below: f64,
+    pub clear_secs: i64,
+    pub enabled: bool,
+}
+
+#[derive(Insertable, Debug, Clone, Deserialize)]
+#[table_name = "alert_rules"]
+pub struct NewAlertRule {
+    pub name: String,
+    pub publisher_id: Option<i64>,
+    pub metric: String,
+    pub trigger_above: f64,
+    pub trigger_secs: i64,
+    pub clear_below: f64,
+    pub clear_secs: i64,
+}
+
+/// alert state transition
+#[derive(Queryable, QueryableByName, Debug, Clone, Serialize)]
+#[table_name = "alert_events"]
+pub struct AlertEvent {
+    pub id: i32,
+    pub rule_id: i32,
+    pub publisher_id: i64,
+    /// `firing` or `cleared`
+    pub state: String,
+    pub value: f64,
+    pub event_time: i64,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "alert_events"]
+pub struct NewAlertEvent {
+    pub rule_id: i32,
+    pub publisher_id: i64,
+    pub state: String,
+    pub value: f64,
+    pub event_time: i64,
+}
+
+/// url notified of alerts and device events
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    #[serde(skip_serializing)]
+    pub secret: Option<String>,
+    /// comma separated event names, or `*` for every event
+    pub events: String,
+    pub enabled: bool,
+}
+
+impl Webhook {
+    pub fn wants(&self, event: &str) -> bool {
+        self.events
+            .split(',')
+            .map(str::trim)
+            .any(|e| e == "*" || e == event)
+    }
+}
+
+#[derive(Insertable, Debug, Clone, Deserialize)]
+#[table_name = "webhooks"]
+pub struct NewWebhook {
+    pub url: String,
+    pub secret: Option<String>,
+    pub events: String,
+}
+
+/// a single attempt to deliver an event to a webhook
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub webhook_id: i32,
+    pub event: String,
+    pub payload: String,
+    pub attempt: i32,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub delivered: bool,
+    pub created_at: i64,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "webhook_deliveries"]
+pub struct NewWebhookDelivery {
+    pub webhook_id: i32,
+    pub event: String,
+    pub payload: String,
+    pub attempt: i32,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub delivered: bool,
+    pub created_at: i64,
 }
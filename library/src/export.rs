@@ -0,0 +1,510 @@
+//! Encodes pages of `ExportedReading` into csv, jsonl or parquet chunks.
+//! Each page is encoded as soon as it's loaded from the db so exports can be
+//! streamed to the client without holding every reading in memory
+use crate::db::model::{DbReading, Flag, QUALITY_NO_EVTOC};
+use bytes::Bytes;
+use parquet::{
+    basic::Compression,
+    data_type::{ByteArray, ByteArrayType, DataType, DoubleType, Int32Type, Int64Type},
+    file::{
+        properties::WriterProperties,
+        writer::{SerializedFileWriter, SerializedRowGroupWriter},
+    },
+    schema::parser::parse_message_type,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+/// column order shared by the csv header and the parquet schema
+const COLUMNS: [&str; 13] = [
+    "id",
+    "pub_id",
+    "eco2",
+    "evtoc",
+    "read_time",
+    "start_time",
+    "mode",
+    "sensor_model",
+    "temperature",
+    "humidity",
+    "flags",
+    "raw_current",
+    "raw_voltage",
+];
+
+const PARQUET_SCHEMA: &str = "
+message reading {
+    REQUIRED INT32 id;
+    REQUIRED INT64 pub_id;
+    REQUIRED INT32 eco2;
+    OPTIONAL INT32 evtoc;
+    REQUIRED INT64 read_time;
+    REQUIRED INT64 start_time;
+    REQUIRED BYTE_ARRAY mode (UTF8);
+    OPTIONAL BYTE_ARRAY sensor_model (UTF8);
+    OPTIONAL DOUBLE temperature;
+    OPTIONAL DOUBLE humidity;
+    REQUIRED BYTE_ARRAY flags (UTF8);
+    OPTIONAL INT32 raw_current;
+    OPTIONAL INT32 raw_voltage;
+}
+";
+
+/// a row of an export, flat so every format has the same columns
+#[derive(Debug, Serialize)]
+pub struct ExportedReading {
+    pub id: i32,
+    pub pub_id: u64,
+    pub eco2: u32,
+    /// empty when the sensor reported no TVOC value
+    pub evtoc: Option<u32>,
+    pub read_time: u64,
+    pub start_time: u64,
+    pub mode: &'static str,
+    pub sensor_model: Option<String>,
+    pub temperature: Option<f64>,
+    pub humidity: Option<f64>,
+    /// flag names separated by `|`
+    pub flags: String,
+    pub raw_current: Option<u32>,
+    pub raw_voltage: Option<u32>,
+}
+
+impl From<&DbReading> for ExportedReading {
+    fn from(reading: &DbReading) -> ExportedReading {
+        ExportedReading {
+            id: reading.id,
+            pub_id: reading.publisher_id as u64,
+            eco2: reading.eco2 as u32,
+            evtoc: Some(reading.evtoc as u32)
+                .filter(|_| reading.quality_flags & QUALITY_NO_EVTOC == 0),
+            read_time: reading.read_time as u64,
+            start_time: reading.start_time as u64,
+            mode: reading.mode.name(),
+            sensor_model: reading.sensor_model.clone(),
+            temperature: reading.temperature,
+            humidity: reading.humidity,
+            flags: Flag::from_bits(reading.quality_flags)
+                .iter()
+                .map(|flag| flag.as_str())
+                .collect::<Vec<_>>()
+                .join("|"),
+            raw_current: reading.raw_current.map(|c| c as u32),
+            raw_voltage: reading.raw_voltage.map(|v| v as u32),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Jsonl,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Jsonl => "application/x-ndjson",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+pub enum Encoder {
+    Csv {
+        header_written: bool,
+    },
+    Jsonl,
+    /// each page is written as a row group, the footer is written by `finish`
+    Parquet(Box<SerializedFileWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    pub fn new(format: ExportFormat) -> Result<Encoder, String> {
+        Ok(match format {
+            ExportFormat::Csv => Encoder::Csv {
+                header_written: false,
+            },
+            ExportFormat::Jsonl => Encoder::Jsonl,
+            ExportFormat::Parquet => {
+                let schema = parse_message_type(PARQUET_SCHEMA).map_err(|e| format!("{:?}", e))?;
+                let props = WriterProperties::builder()
+                    .set_compression(Compression::UNCOMPRESSED)
+                    .build();
+                let writer =
+                    SerializedFileWriter::new(Vec::new(), Arc::new(schema), Arc::new(props))
+                        .map_err(|e| format!("{:?}", e))?;
+                Encoder::Parquet(Box::new(writer))
+            }
+        })
+    }
+
+    /// encode a page of readings, returns the bytes ready to be sent
+    pub fn encode(&mut self, page: &[ExportedReading]) -> Result<Bytes, String> {
+        match self {
+            Encoder::Csv { header_written } => {
+                let mut wtr = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(Vec::new());
+                if !*header_written {
+                    wtr.write_record(COLUMNS).map_err(|e| format!("{:?}", e))?;
+                    *header_written = true;
+                }
+                for reading in page {
+                    wtr.serialize(reading).map_err(|e| format!("{:?}", e))?;
+                }
+                wtr.into_inner()
+                    .map(Bytes::from)
+                    .map_err(|e| format!("{:?}", e))
+            }
+            Encoder::Jsonl => {
+                let mut buf = Vec::new();
+                for reading in page {
+                    serde_json::to_writer(&mut buf, reading).map_err(|e| format!("{:?}", e))?;
+                    buf.push(b'\n');
+                }
+                Ok(Bytes::from(buf))
+            }
+            Encoder::Parquet(writer) => {
+                write_row_group(writer, page).map_err(|e| format!("{:?}", e))?;
+                Ok(drain(writer))
+            }
+        }
+    }
+
+    /// write any trailing bytes once the last page has been encoded
+    pub fn finish(mut self) -> Result<Bytes, String> {
+        match &mut self {
+            // csv header is still expected when there were no readings
+            Encoder::Csv { header_written } if !*header_written => self.encode(&[]),
+            Encoder::Csv { .. } | Encoder::Jsonl => Ok(Bytes::new()),
+            Encoder::Parquet(writer) => {
+                writer.finish().map_err(|e| format!("{:?}", e))?;
+                Ok(drain(writer))
+            }
+        }
+    }
+}
+
+/// take the bytes flushed so far, the writer tracks its own offsets
+fn drain(writer: &mut SerializedFileWriter<Vec<u8>>) -> Bytes {
+    Bytes::from(std::mem::take(writer.inner_mut()))
+}
+
+fn write_row_group(
+    writer: &mut SerializedFileWriter<Vec<u8>>,
+    page: &[ExportedReading],
+) -> parquet::errors::Result<()> {
+    if page.is_empty() {
+        return Ok(());
+    }
+    let mut rg = writer.next_row_group()?;
+    let col = |f: fn(&ExportedReading) -> i32| page.iter().map(|r| Some(f(r))).collect::<Vec<_>>();
+    let col64 =
+        |f: fn(&ExportedReading) -> i64| page.iter().map(|r| Some(f(r))).collect::<Vec<_>>();
+    let opt = |f: fn(&ExportedReading) -> Option<u32>| {
+        page.iter()
+            .map(|r| f(r).map(|v| v as i32))
+            .collect::<Vec<_>>()
+    };
+
+    write_column::<Int32Type>(&mut rg, col(|r| r.id), false)?;
+    write_column::<Int64Type>(&mut rg, col64(|r| r.pub_id as i64), false)?;
+    write_column::<Int32Type>(&mut rg, col(|r| r.eco2 as i32), false)?;
+    write_column::<Int32Type>(&mut rg, opt(|r| r.evtoc), true)?;
+    write_column::<Int64Type>(&mut rg, col64(|r| r.read_time as i64), false)?;
+    write_column::<Int64Type>(&mut rg, col64(|r| r.start_time as i64), false)?;
+    write_column::<ByteArrayType>(
+        &mut rg,
+        page.iter().map(|r| Some(ByteArray::from(r.mode))).collect(),
+        false,
+    )?;
+    write_column::<ByteArrayType>(
+        &mut rg,
+        page.iter()
+            .map(|r| r.sensor_model.as_deref().map(ByteArray::from))
+            .collect(),
+        true,
+    )?;
+    write_column::<DoubleType>(&mut rg, page.iter().map(|r| r.temperature).collect(), true)?;
+    write_column::<DoubleType>(&mut rg, page.iter().map(|r| r.humidity).collect(), true)?;
+    write_column::<ByteArrayType>(
+        &mut rg,
+        page.iter()
+            .map(|r| Some(ByteArray::from(r.flags.as_str())))
+            .collect(),
+        false,
+    )?;
+    write_column::<Int32Type>(&mut rg, opt(|r| r.raw_current), true)?;
+    write_column::<Int32Type>(&mut rg, opt(|r| r.raw_voltage), true)?;
+    rg.close()?;
+    Ok(())
+}
+
+fn write_column<T: DataType>(
+    rg: &mut SerializedRowGroupWriter<Vec<u8>>,
+    values: Vec<Option<T::T>>,
+    optional: bool,
+) -> parquet::errors::Result<()> {
+    let mut col = rg
+        .next_column()?
+        .expect("parquet schema has a column for every field");
+    let def_levels = values
+        .iter()
+        .map(|v| v.is_some() as i16)
+        .collect::<Vec<i16>>();
+    let present = values.into_iter().flatten().collect::<Vec<T::T>>();
+    col.typed::<T>().write_batch(
+        &present,
+        if optional { Some(&def_levels) } else { None },
+        None,
+    )?;
+    col.close()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::Field;
+    use serde_json::json;
+
+    fn readings() -> Vec<ExportedReading> {
+        vec![
+            ExportedReading {
+                id: 1,
+                pub_id: 811,
+                eco2: 640,
+                evtoc: Some(20),
+                read_time: 1_600_000_000,
+                start_time: 1_599_999_400,
+                mode: "ConstantPower1s",
+                sensor_model: Some("CCS811".to_owned()),
+                temperature: Some(21.5),
+                humidity: Some(40.25),
+                flags: "warming_up|clamped".to_owned(),
+                raw_current: Some(10),
+                raw_voltage: Some(512),
+            },
+            ExportedReading {
+                id: 2,
+                pub_id: 811,
+                eco2: 700,
+                evtoc: None,
+                read_time: 1_600_000_060,
+                start_time: 1_599_999_400,
+                mode: "PulseHeating10s",
+                sensor_model: None,
+                temperature: None,
+                humidity: None,
+                flags: String::new(),
+                raw_current: None,
+                raw_voltage: None,
+            },
+        ]
+    }
+
+    /// encodes the readings a page at a time, as an export streams them
+    fn export(format: ExportFormat, readings: &[ExportedReading]) -> Vec<u8> {
+        let mut encoder = Encoder::new(format).unwrap();
+        let mut out = Vec::new();
+        for page in readings.chunks(1) {
+            out.extend_from_slice(&encoder.encode(page).unwrap());
+        }
+        out.extend_from_slice(&encoder.finish().unwrap());
+        out
+    }
+
+    /// parquet reads from its own `Bytes` or a file, so go through a file
+    fn parquet_reader(name: &str, out: Vec<u8>) -> SerializedFileReader<std::fs::File> {
+        let path = std::env::temp_dir().join(format!(
+            "air_meter-export-{}-{}.parquet",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, out).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        SerializedFileReader::new(file).unwrap()
+    }
+
+    #[test]
+    fn csv_round_trips() {
+        let out = export(ExportFormat::Csv, &readings());
+        let mut rdr = csv::Reader::from_reader(out.as_slice());
+        assert_eq!(rdr.headers().unwrap(), COLUMNS.as_ref());
+        let rows = rdr
+            .records()
+            .map(|r| r.unwrap().iter().map(str::to_owned).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            [
+                vec![
+                    "1",
+                    "811",
+                    "640",
+                    "20",
+                    "1600000000",
+                    "1599999400",
+                    "ConstantPower1s",
+                    "CCS811",
+                    "21.5",
+                    "40.25",
+                    "warming_up|clamped",
+                    "10",
+                    "512",
+                ],
+                vec![
+                    "2",
+                    "811",
+                    "700",
+                    "",
+                    "1600000060",
+                    "1599999400",
+                    "PulseHeating10s",
+                    "",
+                    "",
+                    "",
+                    "",
+                    "",
+                    "",
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn jsonl_round_trips() {
+        let out = export(ExportFormat::Jsonl, &readings());
+        let lines = std::str::from_utf8(&out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                json!({
+                    "id": 1,
+                    "pub_id": 811,
+                    "eco2": 640,
+                    "evtoc": 20,
+                    "read_time": 1_600_000_000u64,
+                    "start_time": 1_599_999_400u64,
+                    "mode": "ConstantPower1s",
+                    "sensor_model": "CCS811",
+                    "temperature": 21.5,
+                    "humidity": 40.25,
+                    "flags": "warming_up|clamped",
+                    "raw_current": 10,
+                    "raw_voltage": 512,
+                }),
+                json!({
+                    "id": 2,
+                    "pub_id": 811,
+                    "eco2": 700,
+                    "evtoc": null,
+                    "read_time": 1_600_000_060u64,
+                    "start_time": 1_599_999_400u64,
+                    "mode": "PulseHeating10s",
+                    "sensor_model": null,
+                    "temperature": null,
+                    "humidity": null,
+                    "flags": "",
+                    "raw_current": null,
+                    "raw_voltage": null,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn parquet_round_trips() {
+        let out = export(ExportFormat::Parquet, &readings());
+        let reader = parquet_reader("round_trip", out);
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+        let rows = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        for row in &rows {
+            let names = row.get_column_iter().map(|(name, _)| name.as_str());
+            assert_eq!(names.collect::<Vec<_>>(), COLUMNS);
+        }
+        let fields = |row: usize| {
+            rows[row]
+                .get_column_iter()
+                .map(|(_, field)| field.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            fields(0),
+            [
+                Field::Int(1),
+                Field::Long(811),
+                Field::Int(640),
+                Field::Int(20),
+                Field::Long(1_600_000_000),
+                Field::Long(1_599_999_400),
+                Field::Str("ConstantPower1s".to_owned()),
+                Field::Str("CCS811".to_owned()),
+                Field::Double(21.5),
+                Field::Double(40.25),
+                Field::Str("warming_up|clamped".to_owned()),
+                Field::Int(10),
+                Field::Int(512),
+            ]
+        );
+        assert_eq!(
+            fields(1),
+            [
+                Field::Int(2),
+                Field::Long(811),
+                Field::Int(700),
+                Field::Null,
+                Field::Long(1_600_000_060),
+                Field::Long(1_599_999_400),
+                Field::Str("PulseHeating10s".to_owned()),
+                Field::Null,
+                Field::Null,
+                Field::Null,
+                Field::Str(String::new()),
+                Field::Null,
+                Field::Null,
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_ranges_still_export_a_valid_file() {
+        let csv = export(ExportFormat::Csv, &[]);
+        assert_eq!(csv, format!("{}\n", COLUMNS.join(",")).into_bytes());
+        assert!(export(ExportFormat::Jsonl, &[]).is_empty());
+
+        let parquet = export(ExportFormat::Parquet, &[]);
+        let reader = parquet_reader("empty", parquet);
+        assert_eq!(reader.metadata().num_row_groups(), 0);
+        let schema = reader.metadata().file_metadata().schema_descr();
+        let names = schema
+            .columns()
+            .iter()
+            .map(|c| c.name().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(names, COLUMNS);
+        assert_eq!(reader.get_row_iter(None).unwrap().count(), 0);
+    }
+}
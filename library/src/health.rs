@@ -0,0 +1,278 @@
+//! Liveness and readiness checks for process supervisors, e.g. systemd or a
+//! Docker `HEALTHCHECK`.
+//!
+//! `/healthz` pings the `RelayServer` and `Actions` actors, failing if either
+//! doesn't answer in time. `/readyz` also checks the db can be queried and the
+//! local sensor, when it's enabled, responds. Both answer 200 or 503 with each
+//! component's status and the server's uptime.
+use crate::common::{DbStatus, Ping};
+use crate::db::Actions;
+use crate::relay_server::server::RelayServer;
+use crate::sensor_client::{Sensor, SensorStatus};
+use actix::prelude::*;
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use std::future::Future;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// longest a component has to answer before it's reported down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// the actors checked and when the server started, shared as app data
+#[derive(Clone)]
+pub struct Health {
+    pub relay: Addr<RelayServer>,
+    pub actions: Addr<Actions>,
+    /// the local sensor, if it's enabled
+    pub sensor: Option<Addr<Sensor>>,
+    started: Instant,
+    started_at: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DbHealth {
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub max_connections: u32,
+    /// latest applied migration version
+    pub migration: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SensorHealth {
+    pub pub_id: u64,
+    pub mode: String,
+    /// not on a Pi, readings are made up
+    pub test_mode: bool,
+    pub running: bool,
+    /// connected to the server's websocket
+    pub connected: bool,
+    pub uptime_secs: u64,
+    /// readings aren't accurate for the first 20 minutes
+    pub warming_up: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Component<T: Serialize> {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub details: Option<T>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Components {
+    pub relay_server: Component<()>,
+    pub actions: Component<()>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub db: Option<Component<DbHealth>>,
+    /// none when the local sensor is disabled or not checked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sensor: Option<Component<SensorHealth>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub ok: bool,
+    /// unix seconds
+    pub started_at: u64,
+    pub uptime_secs: u64,
+    pub components: Components,
+}
+
+impl<T: Serialize> Component<T> {
+    fn from_result(result: Result<T, String>) -> Component<T> {
+        match result {
+            Ok(details) => Component {
+                ok: true,
+                error: None,
+                details: Some(details),
+            },
+            Err(error) => Component {
+                ok: false,
+                error: Some(error),
+                details: None,
+            },
+        }
+    }
+}
+
+/// a message's answer, or why there wasn't one in time
+async fn answer<T, F>(name: &str, request: F) -> Result<T, String>
+where
+    F: Future<Output = Result<T, MailboxError>>,
+{
+    match actix_rt::time::timeout(CHECK_TIMEOUT, request).await {
+        Ok(Ok(answer)) => Ok(answer),
+        Ok(Err(err)) => Err(format!("{}: {}", name, err)),
+        Err(_) => Err(format!("{}: no answer in {:?}", name, CHECK_TIMEOUT)),
+    }
+}
+
+impl Health {
+    pub fn new(
+        relay: Addr<RelayServer>,
+        actions: Addr<Actions>,
+        sensor: Option<Addr<Sensor>>,
+    ) -> Health {
+        Health {
+            relay,
+            actions,
+            sensor,
+            started: Instant::now(),
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// ping the actors, and with `ready` check the db and sensor too
+    pub async fn check(&self, ready: bool) -> HealthReport {
+        let relay = answer("relay server", self.relay.send(Ping));
+        let actions = answer("actions", self.actions.send(Ping));
+        let (relay, actions) = futures::join!(relay, actions);
+        let mut components = Components {
+            relay_server: Component::from_result(relay),
+            actions: Component::from_result(actions),
+            db: None,
+            sensor: None,
+        };
+        if ready {
+            let db = answer("actions", self.actions.send(DbStatus))
+                .await
+                .and_then(|status| status);
+            components.db = Some(Component::from_result(db));
+            if let Some(sensor) = &self.sensor {
+                let status = answer("sensor", sensor.send(SensorStatus)).await;
+                let status = status.and_then(|s| match s.running {
+                    true => Ok(s),
+                    false => Err("sensor not running".to_owned()),
+                });
+                components.sensor = Some(Component::from_result(status));
+            }
+        }
+        HealthReport {
+            ok: components.relay_server.ok
+                && components.actions.ok
+                && components.db.as_ref().is_none_or(|c| c.ok)
+                && components.sensor.as_ref().is_none_or(|c| c.ok),
+            started_at: self.started_at,
+            uptime_secs: self.uptime().as_secs(),
+            components,
+        }
+    }
+}
+
+fn respond(report: HealthReport) -> HttpResponse {
+    match report.ok {
+        true => HttpResponse::Ok().json(report),
+        false => HttpResponse::ServiceUnavailable().json(report),
+    }
+}
+
+/// the server's actors are processing messages
+pub async fn healthz(health: web::Data<Health>) -> HttpResponse {
+    respond(health.check(false).await)
+}
+
+/// the server can store and relay readings
+pub async fn readyz(health: web::Data<Health>) -> HttpResponse {
+    respond(health.check(true).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anomaly::AnomalyConfig;
+    use crate::webhooks::Webhooks;
+    use actix_web::{http::StatusCode, test, App};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    fn actions(name: &str) -> Actions {
+        let path = std::env::temp_dir().join(format!(
+            "air_meter-health-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        Actions::new(&path.to_string_lossy())
+    }
+
+    fn health(actions: Addr<Actions>) -> Health {
+        let relay = RelayServer::new(
+            Arc::new(AtomicUsize::new(0)),
+            actions.clone(),
+            Webhooks::new(actions.clone()).start(),
+            AnomalyConfig::default(),
+        )
+        .start();
+        Health::new(relay, actions, None)
+    }
+
+    async fn get(health: Health, uri: &str) -> (StatusCode, serde_json::Value) {
+        let mut app = test::init_service(
+            App::new()
+                .data(health)
+                .route("/healthz", web::get().to(healthz))
+                .route("/readyz", web::get().to(readyz)),
+        )
+        .await;
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let res = test::call_service(&mut app, req).await;
+        let status = res.status();
+        (status, test::read_body_json(res).await)
+    }
+
+    #[actix_rt::test]
+    async fn healthy_when_the_actors_answer() {
+        let health = health(actions("healthz").start());
+        let (status, report) = get(health, "/healthz").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["ok"], true);
+        assert_eq!(report["components"]["relay_server"]["ok"], true);
+        assert_eq!(report["components"]["actions"]["ok"], true);
+        // liveness doesn't check the db or sensor
+        assert!(report["components"].get("db").is_none());
+        assert!(report["components"].get("sensor").is_none());
+        assert!(report["started_at"].as_u64().unwrap() > 0);
+    }
+
+    #[actix_rt::test]
+    async fn ready_once_the_db_can_be_queried() {
+        let health = health(actions("readyz").start());
+        let (status, report) = get(health, "/readyz").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["ok"], true);
+        let db = &report["components"]["db"];
+        assert_eq!(db["ok"], true);
+        assert!(db["max_connections"].as_u64().unwrap() > 0);
+        assert!(db["migration"].is_string());
+        assert!(report["components"].get("sensor").is_none());
+    }
+
+    #[actix_rt::test]
+    async fn unavailable_when_an_actor_has_stopped() {
+        let actions = actions("stopped");
+        let stopped = Actions::create(|ctx| {
+            ctx.stop();
+            actions
+        });
+        let health = health(stopped);
+        for uri in ["/healthz", "/readyz"] {
+            let (status, report) = get(health.clone(), uri).await;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", uri);
+            assert_eq!(report["ok"], false);
+            assert_eq!(report["components"]["relay_server"]["ok"], true);
+            let actions = &report["components"]["actions"];
+            assert_eq!(actions["ok"], false);
+            assert!(actions["error"].as_str().unwrap().starts_with("actions: "));
+        }
+    }
+}
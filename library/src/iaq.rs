@@ -0,0 +1,326 @@
+//! Indoor air quality index. Readings are classified into a CO2 category by
+//! common ventilation guidance bands and a TVOC category by the German Federal
+//! Environment Agency (UBA) levels, then given an overall 0-100 score set by
+//! the worse of the two.
+//!
+//! Band thresholds are the `[iaq]` config section's `co2_bands` and
+//! `tvoc_bands`, each a baseline followed by the upper bounds of the excellent,
+//! good, fair and poor categories, e.g. `IAQ_CO2_BANDS=400,600,800,1000,1500`.
+use crate::config;
+use crate::db::model::{DbReading, ReadingBucket, QUALITY_NO_EVTOC};
+use crate::relay_server::Reading;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::openapi::{schema::AllOfBuilder, ObjectBuilder, Ref, RefOr, Schema, SchemaType};
+use utoipa::ToSchema;
+
+/// eco2 ppm: outdoor baseline then 600, 800, 1000 and 1500 ppm
+pub const DEFAULT_CO2_BANDS: Bands = Bands([400.0, 600.0, 800.0, 1000.0, 1500.0]);
+/// etvoc ppb: UBA levels 1-4 (0.3, 1, 3 and 10 mg/m³) converted at the
+/// 4.5 µg/m³ per ppb of a typical indoor TVOC mixture
+pub const DEFAULT_TVOC_BANDS: Bands = Bands([0.0, 65.0, 220.0, 660.0, 2200.0]);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    Excellent,
+    Good,
+    Fair,
+    Poor,
+    Bad,
+}
+
+impl Category {
+    const ALL: [Category; 5] = [
+        Category::Excellent,
+        Category::Good,
+        Category::Fair,
+        Category::Poor,
+        Category::Bad,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Category::Excellent => "excellent",
+            Category::Good => "good",
+            Category::Fair => "fair",
+            Category::Poor => "poor",
+            Category::Bad => "bad",
+        }
+    }
+}
+
+/// baseline value scoring 100, then the upper bounds of the excellent, good,
+/// fair and poor categories. anything above the last bound is bad
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Bands(pub [f64; 5]);
+
+impl Bands {
+    /// parse five comma separated, increasing values
+    pub fn parse(bands: &str) -> Result<Bands, String> {
+        let values = bands
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|e| format!("{:?}: {}", bands, e))?;
+        if values.len() != 5 {
+            return Err(format!(
+                "{:?}: expected a baseline and 4 upper bounds",
+                bands
+            ));
+        }
+        let mut parsed = [0.0; 5];
+        parsed.copy_from_slice(&values);
+        let parsed = Bands(parsed);
+        parsed.check().map_err(|e| format!("{:?}: {}", bands, e))?;
+        Ok(parsed)
+    }
+
+    /// bands have to be finite and increasing
+    pub fn check(&self) -> Result<(), String> {
+        if self.0.iter().any(|v| !v.is_finite()) {
+            return Err("bands must be finite".to_owned());
+        }
+        if self.0.windows(2).any(|w| w[0] >= w[1]) {
+            return Err("bands must be increasing".to_owned());
+        }
+        Ok(())
+    }
+
+    pub fn category(&self, value: f64) -> Category {
+        let band = self.0[1..]
+            .iter()
+            .take_while(|&&bound| value > bound)
+            .count();
+        Category::ALL[band]
+    }
+
+    /// falls 20 points across each band from 100 at the baseline, the bad band
+    /// is as wide as the poor band and scores 0 beyond it
+    pub fn score(&self, value: f64) -> f64 {
+        let b = &self.0;
+        let edges = [b[0], b[1], b[2], b[3], b[4], b[4] + (b[4] - b[3])];
+        if value <= edges[0] {
+            return 100.0;
+        }
+        for (band, edge) in edges.windows(2).enumerate() {
+            if value <= edge[1] {
+                let fraction = (value - edge[0]) / (edge[1] - edge[0]);
+                return 100.0 - 20.0 * (band as f64 + fraction);
+            }
+        }
+        0.0
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IaqConfig {
+    /// eco2 ppm
+    pub co2_bands: Bands,
+    /// etvoc ppb
+    pub tvoc_bands: Bands,
+}
+
+impl Default for IaqConfig {
+    fn default() -> IaqConfig {
+        IaqConfig {
+            co2_bands: DEFAULT_CO2_BANDS,
+            tvoc_bands: DEFAULT_TVOC_BANDS,
+        }
+    }
+}
+
+impl IaqConfig {
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        if let Err(err) = self.co2_bands.check() {
+            errors.push(format!("iaq.co2_bands: {}", err));
+        }
+        if let Err(err) = self.tvoc_bands.check() {
+            errors.push(format!("iaq.tvoc_bands: {}", err));
+        }
+    }
+}
+
+/// index of a reading or an aggregate of readings
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct Iaq {
+    /// 0 (bad) to 100 (excellent), the lower of the co2 and tvoc scores
+    pub score: u8,
+    /// the worse of the co2 and tvoc categories
+    pub category: Category,
+    pub co2: Category,
+    /// none when the source had no TVOC value
+    pub tvoc: Option<Category>,
+}
+
+impl Iaq {
+    pub fn new(eco2: f64, evtoc: Option<f64>) -> Iaq {
+        let bands = config::iaq();
+        let co2 = bands.co2_bands.category(eco2);
+        let tvoc = evtoc.map(|v| bands.tvoc_bands.category(v));
+        let score = evtoc
+            .map(|v| bands.tvoc_bands.score(v))
+            .unwrap_or(100.0)
+            .min(bands.co2_bands.score(eco2));
+        Iaq {
+            score: score.round() as u8,
+            category: tvoc.map_or(co2, |tvoc| tvoc.max(co2)),
+            co2,
+            tvoc,
+        }
+    }
+
+    pub fn of_reading(reading: &Reading) -> Iaq {
+        let evtoc =
+            Some(reading.evtoc as f64).filter(|_| reading.quality_flags & QUALITY_NO_EVTOC == 0);
+        Iaq::new(reading.eco2 as f64, evtoc)
+    }
+
+    pub fn of_db(reading: &DbReading) -> Iaq {
+        let evtoc =
+            Some(reading.evtoc as f64).filter(|_| reading.quality_flags & QUALITY_NO_EVTOC == 0);
+        Iaq::new(reading.eco2 as f64, evtoc)
+    }
+}
+
+/// a stored reading or bucket with its index, serialized as its own fields plus `iaq`
+#[derive(Serialize)]
+pub struct Indexed<'a, T> {
+    #[serde(flatten)]
+    pub inner: &'a T,
+    pub iaq: Iaq,
+}
+
+impl<'a> From<&'a DbReading> for Indexed<'a, DbReading> {
+    fn from(reading: &'a DbReading) -> Self {
+        Indexed {
+            inner: reading,
+            iaq: Iaq::of_db(reading),
+        }
+    }
+}
+
+/// buckets are indexed by their mean values
+impl<'a> From<&'a ReadingBucket> for Indexed<'a, ReadingBucket> {
+    fn from(bucket: &'a ReadingBucket) -> Self {
+        Indexed {
+            inner: bucket,
+            iaq: Iaq::new(bucket.eco2_mean, bucket.evtoc_mean),
+        }
+    }
+}
+
+/// a reading as relayed to subscribers, its publisher's json plus `iaq`
+pub type RelayedReading<'a> = Indexed<'a, Reading>;
+
+/// the inner type's schema plus `iaq` and any `fields`, as `flatten` serializes it
+fn indexed_schema(inner: &str, fields: ObjectBuilder) -> RefOr<Schema> {
+    AllOfBuilder::new()
+        .item(Ref::from_schema_name(inner))
+        .item(
+            fields
+                .property("iaq", Ref::from_schema_name("Iaq"))
+                .required("iaq"),
+        )
+        .into()
+}
+
+impl<'a> ToSchema<'a> for RelayedReading<'a> {
+    fn schema() -> (&'a str, RefOr<Schema>) {
+        // relayed readings always carry their publisher's id
+        let pub_id = ObjectBuilder::new().schema_type(SchemaType::Integer);
+        let fields = ObjectBuilder::new()
+            .property("pub_id", pub_id)
+            .required("pub_id");
+        ("RelayedReading", indexed_schema("Reading", fields))
+    }
+}
+
+/// add `iaq` to a relayed reading's json, left unchanged if it isn't an object
+pub fn annotate(json: &str, iaq: &Iaq) -> String {
+    match serde_json::from_str::<Value>(json) {
+        Ok(Value::Object(mut reading)) => {
+            reading.insert("iaq".to_owned(), serde_json::to_value(iaq).unwrap());
+            Value::Object(reading).to_string()
+        }
+        _ => json.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_increasing_bands() {
+        assert_eq!(
+            Bands::parse("400, 600,800 ,1000,1500"),
+            Ok(DEFAULT_CO2_BANDS)
+        );
+        assert!(Bands::parse("400,600,800,1000").is_err());
+        assert!(Bands::parse("400,600,800,1000,1500,2000").is_err());
+        assert!(Bands::parse("400,600,600,1000,1500").is_err());
+        assert!(Bands::parse("400,600,800,1000,lots").is_err());
+        assert!(Bands::parse("400,600,800,1000,inf").is_err());
+        assert!(Bands([0.0, 1.0, 2.0, 3.0, f64::NAN]).check().is_err());
+    }
+
+    #[test]
+    fn bounds_belong_to_the_better_category() {
+        let bands = DEFAULT_CO2_BANDS;
+        let categories = [300.0, 600.0, 600.1, 800.0, 1000.0, 1500.0, 1500.1]
+            .iter()
+            .map(|&v| bands.category(v))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            categories,
+            vec![
+                Category::Excellent,
+                Category::Excellent,
+                Category::Good,
+                Category::Good,
+                Category::Fair,
+                Category::Poor,
+                Category::Bad,
+            ]
+        );
+    }
+
+    #[test]
+    fn scores_fall_20_points_a_band() {
+        let bands = DEFAULT_CO2_BANDS;
+        assert_eq!(bands.score(350.0), 100.0);
+        assert_eq!(bands.score(400.0), 100.0);
+        assert_eq!(bands.score(500.0), 90.0);
+        assert_eq!(bands.score(600.0), 80.0);
+        assert_eq!(bands.score(1250.0), 30.0);
+        assert_eq!(bands.score(1500.0), 20.0);
+        // the bad band is as wide as the poor band
+        assert_eq!(bands.score(1750.0), 10.0);
+        assert_eq!(bands.score(2000.0), 0.0);
+        assert_eq!(bands.score(9000.0), 0.0);
+    }
+
+    #[test]
+    fn index_is_set_by_the_worse_of_co2_and_tvoc() {
+        let co2_only = Iaq::new(700.0, None);
+        assert_eq!((co2_only.score, co2_only.category), (70, Category::Good));
+        assert_eq!(co2_only.tvoc, None);
+
+        let both = Iaq::new(700.0, Some(1000.0));
+        assert_eq!(both.co2, Category::Good);
+        assert_eq!(both.tvoc, Some(Category::Poor));
+        assert_eq!(both.category, Category::Poor);
+        assert!(both.score < co2_only.score);
+    }
+
+    #[test]
+    fn annotates_json_objects_only() {
+        let iaq = Iaq::new(450.0, None);
+        let json: Value = serde_json::from_str(&annotate(r#"{"eco2":450}"#, &iaq)).unwrap();
+        assert_eq!(json["iaq"]["category"], "excellent");
+        assert_eq!(json["eco2"], 450);
+        assert_eq!(annotate("[1,2]", &iaq), "[1,2]");
+    }
+}
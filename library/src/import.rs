@@ -0,0 +1,419 @@
+//! Maps rows of csv or jsonl files, from this server's exports or other
+//! meters (Aranet4, SCD30 loggers), onto `NewReading`s for bulk import
+use crate::db::model::{Mode, NewReading, QUALITY_IMPORTED, QUALITY_NO_EVTOC};
+use chrono::{DateTime, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use utoipa::{IntoParams, ToSchema};
+
+/// rejected row reasons kept in the report, the rest are only counted
+const MAX_REPORTED_ERRORS: usize = 20;
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    Csv,
+    Jsonl,
+}
+
+/// known file layouts, individual columns can still be overridden
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    /// csv or jsonl produced by `/api/v1/readings/export`
+    #[default]
+    Native,
+    Aranet,
+    Scd30,
+}
+
+/// import parameters, the file itself is the request body
+#[derive(Clone, Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImportQuery {
+    pub format: ImportFormat,
+    /// publisher the readings are stored under
+    pub pub_id: u64,
+    #[serde(default)]
+    pub preset: Preset,
+    pub time_col: Option<String>,
+    pub eco2_col: Option<String>,
+    pub evtoc_col: Option<String>,
+    pub temperature_col: Option<String>,
+    pub humidity_col: Option<String>,
+    /// `unix`, `unix_ms`, `rfc3339` or a strftime pattern such as `%d/%m/%Y %H:%M:%S`
+    pub time_format: Option<String>,
+    /// seconds east of UTC for strftime times without a zone
+    #[serde(default)]
+    pub utc_offset: i64,
+    pub sensor_model: Option<String>,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// source column names for each reading field
+#[derive(Clone, Debug)]
+pub struct ColumnMap {
+    pub time: String,
+    pub time_format: String,
+    pub utc_offset: i64,
+    pub eco2: String,
+    pub evtoc: Option<String>,
+    pub start_time: Option<String>,
+    pub increment: Option<String>,
+    pub sensor_model: Option<String>,
+    pub temperature: Option<String>,
+    pub humidity: Option<String>,
+    /// (metric name, column) pairs stored in `reading_metrics`
+    pub metrics: Vec<(String, String)>,
+    /// model recorded when the file has no sensor model column
+    pub default_model: Option<String>,
+}
+
+impl ColumnMap {
+    pub fn preset(preset: Preset) -> ColumnMap {
+        let s = |v: &str| v.to_owned();
+        match preset {
+            Preset::Native => ColumnMap {
+                time: s("read_time"),
+                time_format: s("unix"),
+                utc_offset: 0,
+                eco2: s("eco2"),
+                evtoc: Some(s("evtoc")),
+                start_time: Some(s("start_time")),
+                increment: Some(s("mode")),
+                sensor_model: Some(s("sensor_model")),
+                temperature: Some(s("temperature")),
+                humidity: Some(s("humidity")),
+                metrics: vec![],
+                default_model: None,
+            },
+            Preset::Aranet => ColumnMap {
+                time: s("Time(dd/mm/yyyy)"),
+                time_format: s("%d/%m/%Y %H:%M:%S"),
+                utc_offset: 0,
+                eco2: s("Carbon dioxide(ppm)"),
+                evtoc: None,
+                start_time: None,
+                increment: None,
+                sensor_model: None,
+                temperature: Some(s("Temperature(°C)")),
+                humidity: Some(s("Relative humidity(%)")),
+                metrics: vec![(s("pressure_hpa"), s("Atmospheric pressure(hPa)"))],
+                default_model: Some(s("Aranet4")),
+            },
+            Preset::Scd30 => ColumnMap {
+                time: s("timestamp"),
+                time_format: s("unix"),
+                utc_offset: 0,
+                eco2: s("co2"),
+                evtoc: None,
+                start_time: None,
+                increment: None,
+                sensor_model: None,
+                temperature: Some(s("temperature")),
+                humidity: Some(s("humidity")),
+                metrics: vec![],
+                default_model: Some(s("SCD30")),
+            },
+        }
+    }
+
+    /// preset columns with any overrides from the query applied
+    pub fn from_query(query: &ImportQuery) -> ColumnMap {
+        let mut map = ColumnMap::preset(query.preset);
+        if let Some(col) = &query.time_col {
+            map.time = col.clone();
+        }
+        if let Some(col) = &query.eco2_col {
+            map.eco2 = col.clone();
+        }
+        if let Some(col) = &query.evtoc_col {
+            map.evtoc = Some(col.clone());
+        }
+        if let Some(col) = &query.temperature_col {
+            map.temperature = Some(col.clone());
+        }
+        if let Some(col) = &query.humidity_col {
+            map.humidity = Some(col.clone());
+        }
+        if let Some(format) = &query.time_format {
+            map.time_format = format.clone();
+        }
+        if let Some(model) = &query.sensor_model {
+            map.default_model = Some(model.clone());
+        }
+        map.utc_offset = query.utc_offset;
+        map
+    }
+}
+
+/// reading mapped from a source row, ready for `Actions`
+#[derive(Debug)]
+pub struct ImportedReading {
+    pub reading: NewReading,
+    pub metrics: HashMap<String, f64>,
+}
+
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    /// rows inserted, or that would be inserted on a dry run
+    pub inserted: usize,
+    /// rows with a (publisher_id, read_time) already stored or repeated in the file
+    pub skipped: usize,
+    /// rows that couldn't be mapped onto a reading
+    pub rejected: usize,
+    pub errors: Vec<String>,
+}
+
+impl ImportReport {
+    pub fn reject(&mut self, line: usize, reason: String) {
+        self.rejected += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(format!("line {}: {}", line, reason));
+        }
+    }
+}
+
+type Row = HashMap<String, String>;
+/// rows with the line they're on, or why they couldn't be read
+type Rows = Vec<(usize, Result<Row, String>)>;
+
+/// parse the file into readings, rows that can't be mapped are added to the report
+pub fn parse(
+    body: &[u8],
+    query: &ImportQuery,
+    report: &mut ImportReport,
+) -> Result<Vec<ImportedReading>, String> {
+    let map = ColumnMap::from_query(query);
+    let rows = match query.format {
+        ImportFormat::Csv => csv_rows(body)?,
+        ImportFormat::Jsonl => jsonl_rows(body),
+    };
+    let mut readings = Vec::new();
+    for (line, row) in rows {
+        match row.and_then(|row| map_row(&row, &map, query.pub_id)) {
+            Ok(reading) => readings.push(reading),
+            Err(reason) => report.reject(line, reason),
+        }
+    }
+    // without a start_time column, the import is treated as one sensor session
+    if map.start_time.is_none() {
+        if let Some(start) = readings.iter().map(|r| r.reading.read_time).min() {
+            for r in &mut readings {
+                r.reading.start_time = start;
+            }
+        }
+    }
+    Ok(readings)
+}
+
+fn csv_rows(body: &[u8]) -> Result<Rows, String> {
+    let mut rdr = csv::ReaderBuilder::new().flexible(true).from_reader(body);
+    let headers = rdr
+        .headers()
+        .map_err(|e| format!("couldn't read csv header: {}", e))?
+        .iter()
+        .map(|h| h.trim_start_matches('\u{feff}').trim().to_owned())
+        .collect::<Vec<String>>();
+    Ok(rdr
+        .records()
+        .enumerate()
+        .map(|(i, record)| {
+            // header is line 1
+            let line = record
+                .as_ref()
+                .ok()
+                .and_then(|r| r.position())
+                .map(|p| p.line() as usize)
+                .unwrap_or(i + 2);
+            let row = record.map_err(|e| format!("{}", e)).map(|r| {
+                headers
+                    .iter()
+                    .cloned()
+                    .zip(r.iter().map(str::to_owned))
+                    .collect()
+            });
+            (line, row)
+        })
+        .collect())
+}
+
+fn jsonl_rows(body: &[u8]) -> Rows {
+    String::from_utf8_lossy(body)
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(i, l)| {
+            let row = serde_json::from_str::<HashMap<String, serde_json::Value>>(l)
+                .map_err(|e| format!("{}", e))
+                .map(|obj| {
+                    obj.into_iter()
+                        .filter_map(|(k, v)| match v {
+                            serde_json::Value::Null => None,
+                            serde_json::Value::String(s) => Some((k, s)),
+                            v => Some((k, v.to_string())),
+                        })
+                        .collect()
+                });
+            (i + 1, row)
+        })
+        .collect()
+}
+
+fn field<'a>(row: &'a Row, col: &Option<String>) -> Option<&'a str> {
+    col.as_ref()
+        .and_then(|c| row.get(c))
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+}
+
+fn number(row: &Row, col: &Option<String>) -> Result<Option<f64>, String> {
+    match field(row, col) {
+        Some(v) => v
+            .parse::<f64>()
+            .map(Some)
+            .map_err(|_| format!("{} is not a number: {:?}", col.as_deref().unwrap_or(""), v)),
+        None => Ok(None),
+    }
+}
+
+fn parse_time(value: &str, format: &str, utc_offset: i64) -> Result<i64, String> {
+    let parsed = match format {
+        "unix" => value.parse::<f64>().map(|t| t as i64).ok(),
+        "unix_ms" => value.parse::<f64>().map(|t| (t / 1000.0) as i64).ok(),
+        "rfc3339" => DateTime::parse_from_rfc3339(value)
+            .map(|t| t.timestamp())
+            .ok(),
+        pattern => DateTime::parse_from_str(value, pattern)
+            .map(|t| t.timestamp())
+            .or_else(|_| {
+                NaiveDateTime::parse_from_str(value, pattern)
+                    .map(|t| t.and_utc().timestamp() - utc_offset)
+            })
+            .ok(),
+    };
+    parsed.ok_or_else(|| format!("couldn't parse time {:?} as {}", value, format))
+}
+
+fn map_row(row: &Row, map: &ColumnMap, pub_id: u64) -> Result<ImportedReading, String> {
+    let time = field(row, &Some(map.time.clone())).ok_or(format!("missing {}", map.time))?;
+    let read_time = parse_time(time, &map.time_format, map.utc_offset)?;
+    let eco2 = number(row, &Some(map.eco2.clone()))?.ok_or(format!("missing {}", map.eco2))?;
+    let evtoc = number(row, &map.evtoc)?;
+    let start_time = match field(row, &map.start_time) {
+        Some(t) => parse_time(t, &map.time_format, map.utc_offset)?,
+        None => read_time,
+    };
+    let mut quality_flags = QUALITY_IMPORTED;
+    if evtoc.is_none() {
+        quality_flags |= QUALITY_NO_EVTOC;
+    }
+    let mut metrics = HashMap::new();
+    for (name, col) in &map.metrics {
+        if let Some(value) = number(row, &Some(col.clone()))? {
+            metrics.insert(name.clone(), value);
+        }
+    }
+    Ok(ImportedReading {
+        reading: NewReading {
+            publisher_id: pub_id as i64,
+            eco2: eco2 as i32,
+            evtoc: evtoc.unwrap_or(0.0) as i32,
+            read_time,
+            start_time,
+            mode: field(row, &map.increment)
+                .map(Mode::from_name)
+                .unwrap_or(Mode::Unknown),
+            sensor_model: field(row, &map.sensor_model)
+                .map(str::to_owned)
+                .or_else(|| map.default_model.clone()),
+            temperature: number(row, &map.temperature)?,
+            humidity: number(row, &map.humidity)?,
+            quality_flags,
+            raw_current: None,
+            raw_voltage: None,
+        },
+        metrics,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2021-08-01T00:00:00Z
+    const AUG_1: i64 = 1_627_776_000;
+
+    #[test]
+    fn parse_time_formats() {
+        assert_eq!(parse_time("1627776000", "unix", 0), Ok(AUG_1));
+        assert_eq!(parse_time("1627776000.9", "unix", 0), Ok(AUG_1));
+        assert_eq!(parse_time("1627776000500", "unix_ms", 0), Ok(AUG_1));
+        assert_eq!(
+            parse_time("2021-08-01T02:00:00+02:00", "rfc3339", 0),
+            Ok(AUG_1)
+        );
+        assert_eq!(
+            parse_time("01/08/2021 00:00:00", "%d/%m/%Y %H:%M:%S", 0),
+            Ok(AUG_1)
+        );
+    }
+
+    #[test]
+    fn parse_time_offsets_only_zoneless_times() {
+        let pattern = "%d/%m/%Y %H:%M:%S";
+        assert_eq!(parse_time("01/08/2021 01:00:00", pattern, 3600), Ok(AUG_1));
+        assert_eq!(
+            parse_time("31/07/2021 19:00:00", pattern, -5 * 3600),
+            Ok(AUG_1)
+        );
+        // a zone in the time wins over utc_offset
+        assert_eq!(
+            parse_time("2021-08-01 03:00:00 +0300", "%Y-%m-%d %H:%M:%S %z", 3600),
+            Ok(AUG_1)
+        );
+    }
+
+    #[test]
+    fn parse_time_rejects_mismatches() {
+        assert!(parse_time("yesterday", "unix", 0).is_err());
+        assert!(parse_time("2021-08-01", "rfc3339", 0).is_err());
+        assert!(parse_time("2021-08-01 00:00:00", "%d/%m/%Y %H:%M:%S", 0).is_err());
+    }
+
+    #[test]
+    fn aranet_rows_share_a_session() {
+        let query: ImportQuery = serde_json::from_value(serde_json::json!({
+            "format": "csv",
+            "pub_id": 900,
+            "preset": "aranet",
+        }))
+        .unwrap();
+        let body = "Time(dd/mm/yyyy),Carbon dioxide(ppm),Temperature(°C),Relative humidity(%),Atmospheric pressure(hPa)\n\
+            01/08/2021 00:05:00,612,21.5,40,1013\n\
+            01/08/2021 00:00:00,600,21.4,41,1013\n\
+            01/08/2021 00:10:00,lots,21.6,40,1013\n";
+        let mut report = ImportReport::default();
+        let readings = parse(body.as_bytes(), &query, &mut report).unwrap();
+        assert_eq!(readings.len(), 2);
+        assert_eq!(report.rejected, 1);
+        assert!(
+            report.errors[0].starts_with("line 4:"),
+            "{:?}",
+            report.errors
+        );
+        let first = &readings[0];
+        assert_eq!(first.reading.publisher_id, 900);
+        assert_eq!(first.reading.eco2, 612);
+        assert_eq!(first.reading.read_time, AUG_1 + 300);
+        assert_eq!(first.reading.start_time, AUG_1);
+        assert_eq!(first.reading.sensor_model.as_deref(), Some("Aranet4"));
+        assert_eq!(
+            first.reading.quality_flags,
+            QUALITY_IMPORTED | QUALITY_NO_EVTOC
+        );
+        assert_eq!(first.metrics.get("pressure_hpa"), Some(&1013.0));
+    }
+}
@@ -0,0 +1,608 @@
+//! InfluxDB line protocol, both ways. `InfluxSink` batches relayed readings
+//! and writes them to an InfluxDB v2 `/api/v2/write` endpoint, retrying with
+//! backoff while it's unavailable. `parse_line` and `to_reading` map lines
+//! pushed to `/api/v1/write` by third party devices onto publisher readings.
+use crate::config;
+use crate::db::model::{Mode, QUALITY_NO_EVTOC};
+use crate::relay_server::{AddReadingSink, PublisherMessage, Reading};
+use crate::RelayServer;
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+/// lines kept while InfluxDB is unreachable, the oldest are dropped beyond this
+const MAX_BUFFERED: usize = 10_000;
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+    Float(f64),
+    Int(i64),
+    UInt(u64),
+    Bool(bool),
+    Str(String),
+}
+
+impl FieldValue {
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            FieldValue::Float(v) => Some(*v),
+            FieldValue::Int(v) => Some(*v as f64),
+            FieldValue::UInt(v) => Some(*v as f64),
+            FieldValue::Bool(_) | FieldValue::Str(_) => None,
+        }
+    }
+
+    fn parse(raw: &str) -> Result<FieldValue, String> {
+        if let Some(s) = raw.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+            return Ok(FieldValue::Str(unescape(s)));
+        }
+        let invalid = || format!("invalid field value {}", raw);
+        if let Some(i) = raw.strip_suffix('i') {
+            return i.parse().map(FieldValue::Int).map_err(|_| invalid());
+        }
+        if let Some(u) = raw.strip_suffix('u') {
+            return u.parse().map(FieldValue::UInt).map_err(|_| invalid());
+        }
+        match raw {
+            "t" | "T" | "true" | "True" | "TRUE" => Ok(FieldValue::Bool(true)),
+            "f" | "F" | "false" | "False" | "FALSE" => Ok(FieldValue::Bool(false)),
+            // influxdb doesn't accept NaN or infinity either
+            _ => match raw.parse::<f64>() {
+                Ok(v) if v.is_finite() => Ok(FieldValue::Float(v)),
+                _ => Err(invalid()),
+            },
+        }
+    }
+}
+
+/// a single line of line protocol
+#[derive(Clone, Debug)]
+pub struct Point {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, FieldValue)>,
+    pub timestamp: Option<i64>,
+}
+
+/// timestamp precision of written lines
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    /// line protocol timestamps are nanoseconds unless told otherwise
+    #[default]
+    Ns,
+    Us,
+    Ms,
+    S,
+}
+
+impl Precision {
+    fn to_secs(self, timestamp: i64) -> i64 {
+        match self {
+            Precision::Ns => timestamp / 1_000_000_000,
+            Precision::Us => timestamp / 1_000_000,
+            Precision::Ms => timestamp / 1_000,
+            Precision::S => timestamp,
+        }
+    }
+}
+
+/// split on `sep` where it isn't escaped with a backslash or inside a quoted string
+fn split_unescaped(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let (mut start, mut escaped, mut quoted) = (0, false, false);
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == sep && !quoted => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.extend(chars.next()),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn escape(s: &str, special: &[char]) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '\\' || special.contains(&c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn key_value(pair: &str) -> Result<(String, &str), String> {
+    let kv = split_unescaped(pair, '=');
+    match kv.as_slice() {
+        [key, value] if !key.is_empty() && !value.is_empty() => Ok((unescape(key), value)),
+        _ => Err(format!("invalid key=value pair {:?}", pair)),
+    }
+}
+
+/// parse one line of line protocol, comment and blank lines should be skipped first
+pub fn parse_line(line: &str) -> Result<Point, String> {
+    let sections = split_unescaped(line.trim(), ' ')
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<&str>>();
+    let (series, fields, timestamp) = match sections.as_slice() {
+        [series, fields] => (*series, *fields, None),
+        [series, fields, timestamp] => (*series, *fields, Some(*timestamp)),
+        _ => return Err("expected measurement, fields and an optional timestamp".to_owned()),
+    };
+    let mut series = split_unescaped(series, ',').into_iter();
+    let measurement = unescape(series.next().unwrap_or(""));
+    if measurement.is_empty() {
+        return Err("missing measurement".to_owned());
+    }
+    let tags = series
+        .map(|tag| key_value(tag).map(|(k, v)| (k, unescape(v))))
+        .collect::<Result<Vec<(String, String)>, String>>()?;
+    let fields = split_unescaped(fields, ',')
+        .into_iter()
+        .map(|field| key_value(field).and_then(|(k, v)| Ok((k, FieldValue::parse(v)?))))
+        .collect::<Result<Vec<(String, FieldValue)>, String>>()?;
+    let timestamp = match timestamp {
+        Some(t) => Some(
+            t.parse::<i64>()
+                .map_err(|_| format!("invalid timestamp {}", t))?,
+        ),
+        None => None,
+    };
+    Ok(Point {
+        measurement,
+        tags,
+        fields,
+        timestamp,
+    })
+}
+
+/// map a written point onto a reading. the publisher comes from a `pub_id` tag,
+/// falling back to `default_pub_id`. `eco2`/`co2` is required, `evtoc`/`tvoc`
+/// is 0 when missing, other numeric fields are kept as extra metrics
+pub fn to_reading(
+    point: &Point,
+    default_pub_id: Option<u64>,
+    precision: Precision,
+    now: u64,
+) -> Result<Reading, String> {
+    let tag = |names: &[&str]| {
+        point
+            .tags
+            .iter()
+            .find(|(k, _)| names.contains(&k.as_str()))
+            .map(|(_, v)| v.clone())
+    };
+    let pub_id = match tag(&["pub_id"]) {
+        Some(id) => id
+            .parse::<u64>()
+            .map_err(|_| format!("invalid pub_id {}", id))?,
+        None => default_pub_id.ok_or("missing pub_id tag")?,
+    };
+    let read_time = match point.timestamp {
+        Some(t) if precision.to_secs(t) >= 0 => precision.to_secs(t) as u64,
+        Some(t) => return Err(format!("invalid timestamp {}", t)),
+        None => now,
+    };
+    let mut reading = Reading {
+        pub_id,
+        eco2: 0,
+        evtoc: 0,
+        read_time,
+        start_time: read_time,
+        increment: tag(&["increment", "mode"]).unwrap_or_else(|| Mode::Unknown.name().to_owned()),
+        sensor_model: tag(&["sensor_model", "model"]),
+        temperature: None,
+        humidity: None,
+        raw_current: None,
+        raw_voltage: None,
+        metrics: BTreeMap::new(),
+        quality_flags: 0,
+        corr_id: None,
+    };
+    let mut eco2 = None;
+    let mut evtoc = None;
+    let u16_field = |name: &str, v: f64| {
+        if v >= 0.0 && v <= u16::MAX as f64 {
+            Ok(v as u16)
+        } else {
+            Err(format!("{} out of range: {}", name, v))
+        }
+    };
+    for (name, value) in &point.fields {
+        let v = match value.as_f64() {
+            Some(v) => v,
+            None => continue,
+        };
+        match name.as_str() {
+            "eco2" | "co2" => eco2 = Some(u16_field(name, v)?),
+            "evtoc" | "tvoc" | "etvoc" => evtoc = Some(u16_field(name, v)?),
+            "temperature" | "temp" => reading.temperature = Some(v),
+            "humidity" | "rh" => reading.humidity = Some(v),
+            "raw_current" => reading.raw_current = Some(u16_field(name, v)?),
+            "raw_voltage" => reading.raw_voltage = Some(u16_field(name, v)?),
+            "start_time" if v >= 0.0 => reading.start_time = v as u64,
+            other => {
+                reading.metrics.insert(other.to_owned(), v);
+            }
+        }
+    }
+    reading.eco2 = eco2.ok_or("missing eco2 or co2 field")?;
+    match evtoc {
+        Some(evtoc) => reading.evtoc = evtoc,
+        None => reading.quality_flags |= QUALITY_NO_EVTOC,
+    }
+    Ok(reading)
+}
+
+/// a reading as a line of line protocol with second precision
+pub fn reading_line(measurement: &str, pub_id: u64, reading: &Reading) -> String {
+    let tag = |s: &str| escape(s, &[',', '=', ' ']);
+    let mut line = escape(measurement, &[',', ' ']);
+    line.push_str(&format!(",pub_id={}", pub_id));
+    if let Some(model) = &reading.sensor_model {
+        line.push_str(&format!(",sensor_model={}", tag(model)));
+    }
+    if !reading.increment.is_empty() {
+        line.push_str(&format!(",increment={}", tag(&reading.increment)));
+    }
+    let mut fields = vec![format!("eco2={}i", reading.eco2)];
+    // the placeholder 0 of sources without TVOC isn't written
+    if reading.quality_flags & QUALITY_NO_EVTOC == 0 {
+        fields.push(format!("evtoc={}i", reading.evtoc));
+    }
+    if let Some(t) = reading.temperature.filter(|t| t.is_finite()) {
+        fields.push(format!("temperature={}", t));
+    }
+    if let Some(h) = reading.humidity.filter(|h| h.is_finite()) {
+        fields.push(format!("humidity={}", h));
+    }
+    if let Some(c) = reading.raw_current {
+        fields.push(format!("raw_current={}i", c));
+    }
+    if let Some(v) = reading.raw_voltage {
+        fields.push(format!("raw_voltage={}i", v));
+    }
+    for (name, value) in &reading.metrics {
+        if value.is_finite() {
+            fields.push(format!("{}={}", tag(name), value));
+        }
+    }
+    format!("{} {} {}", line, fields.join(","), reading.read_time)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InfluxConfig {
+    /// e.g. `http://localhost:8086`
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    #[serde(
+        serialize_with = "config::redact",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub token: Option<String>,
+    pub measurement: String,
+    /// lines per write request
+    pub batch_size: usize,
+    /// most time a line waits in the batch before being written
+    pub flush_secs: u64,
+}
+
+impl Default for InfluxConfig {
+    fn default() -> InfluxConfig {
+        InfluxConfig {
+            url: String::new(),
+            org: String::new(),
+            bucket: "air_meter".to_owned(),
+            token: None,
+            measurement: "air_meter".to_owned(),
+            batch_size: 100,
+            flush_secs: 5,
+        }
+    }
+}
+
+impl InfluxConfig {
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            errors.push(format!(
+                "influx.url {:?}: expected http(s)://host[:port], or set INFLUX_URL",
+                self.url
+            ));
+        }
+        if self.bucket.is_empty() {
+            errors.push("influx.bucket: can't be empty".to_owned());
+        }
+        if self.measurement.is_empty() {
+            errors.push("influx.measurement: can't be empty".to_owned());
+        }
+        if self.batch_size == 0 {
+            errors.push("influx.batch_size: must be above 0".to_owned());
+        }
+        if self.flush_secs == 0 {
+            errors.push("influx.flush_secs: must be above 0".to_owned());
+        }
+    }
+}
+
+/// Writes relayed readings to InfluxDB in batches, one write in flight at a time
+pub struct InfluxSink {
+    config: InfluxConfig,
+    buffer: VecDeque<String>,
+    in_flight: bool,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+}
+
+impl Actor for InfluxSink {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        let interval = Duration::from_secs(self.config.flush_secs);
+        ctx.run_interval(interval, |act, ctx| act.flush(ctx));
+    }
+}
+
+impl InfluxSink {
+    /// start the sink and register it as a `RelayServer` reading sink
+    pub fn start(config: InfluxConfig, relay: Addr<RelayServer>) -> Addr<InfluxSink> {
+        tracing::info!(url = %config.url, "writing readings to influxdb");
+        let sink = InfluxSink {
+            config,
+            buffer: VecDeque::new(),
+            in_flight: false,
+            backoff: Duration::from_secs(1),
+            retry_at: None,
+        }
+        .start();
+        relay.do_send(AddReadingSink(sink.clone().recipient()));
+        sink
+    }
+
+    fn flush(&mut self, ctx: &mut Context<Self>) {
+        if self.in_flight || self.buffer.is_empty() {
+            return;
+        }
+        if self.retry_at.is_some_and(|at| Instant::now() < at) {
+            return;
+        }
+        let count = self.buffer.len().min(self.config.batch_size);
+        let batch = self.buffer.drain(..count).collect::<Vec<String>>();
+        self.in_flight = true;
+        let url = format!("{}/api/v2/write", self.config.url.trim_end_matches('/'));
+        let query = [
+            ("org", self.config.org.as_str()),
+            ("bucket", self.config.bucket.as_str()),
+            ("precision", "s"),
+        ];
+        let mut req = awc::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .finish()
+            .post(url)
+            .content_type("text/plain; charset=utf-8")
+            .query(&query)
+            .expect("string pairs always encode");
+        if let Some(token) = &self.config.token {
+            req = req.header("Authorization", format!("Token {}", token));
+        }
+        let body = batch.join("\n");
+        async move {
+            match req.send_body(body).await {
+                Ok(res) if res.status().is_success() => Ok(()),
+                Ok(res) => {
+                    let status = res.status();
+                    // other client errors won't succeed on retry
+                    let retry = status.is_server_error() || status.as_u16() == 429;
+                    Err((format!("HTTP {}", status), retry))
+                }
+                Err(err) => Err((format!("{}", err), true)),
+            }
+        }
+        .into_actor(self)
+        .then(move |res, act, ctx| {
+            act.in_flight = false;
+            match res {
+                Ok(()) => {
+                    act.backoff = Duration::from_secs(1);
+                    act.retry_at = None;
+                    if act.buffer.len() >= act.config.batch_size {
+                        act.flush(ctx);
+                    }
+                }
+                Err((err, true)) => {
+                    tracing::warn!(retry_in = ?act.backoff, error = %err, "influxdb write failed");
+                    for line in batch.into_iter().rev() {
+                        act.buffer.push_front(line);
+                    }
+                    act.trim();
+                    act.retry_at = Some(Instant::now() + act.backoff);
+                    act.backoff = (act.backoff * 2).min(MAX_BACKOFF);
+                }
+                Err((err, false)) => {
+                    tracing::error!(lines = batch.len(), error = %err, "influxdb rejected lines, dropping them");
+                }
+            }
+            fut::ready(())
+        })
+        .spawn(ctx);
+    }
+
+    /// drop the oldest lines beyond `MAX_BUFFERED`
+    fn trim(&mut self) {
+        let excess = self.buffer.len().saturating_sub(MAX_BUFFERED);
+        if excess > 0 {
+            tracing::warn!(lines = excess, "influxdb buffer full, dropping lines");
+            self.buffer.drain(..excess);
+        }
+    }
+}
+
+impl Handler<PublisherMessage<Reading>> for InfluxSink {
+    type Result = ();
+
+    fn handle(&mut self, msg: PublisherMessage<Reading>, ctx: &mut Context<Self>) {
+        self.buffer
+            .push_back(reading_line(&self.config.measurement, msg.pub_id, &msg.msg));
+        self.trim();
+        if self.buffer.len() >= self.config.batch_size {
+            self.flush(ctx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tags_fields_and_timestamp() {
+        let point =
+            parse_line(r#"air,pub_id=812,model=SCD30 co2=612i,temperature=21.5,n=3u,ok=t,note="hi" 1630000000"#)
+                .unwrap();
+        assert_eq!(point.measurement, "air");
+        assert_eq!(
+            point.tags,
+            vec![
+                ("pub_id".to_owned(), "812".to_owned()),
+                ("model".to_owned(), "SCD30".to_owned())
+            ]
+        );
+        assert_eq!(
+            point.fields,
+            vec![
+                ("co2".to_owned(), FieldValue::Int(612)),
+                ("temperature".to_owned(), FieldValue::Float(21.5)),
+                ("n".to_owned(), FieldValue::UInt(3)),
+                ("ok".to_owned(), FieldValue::Bool(true)),
+                ("note".to_owned(), FieldValue::Str("hi".to_owned())),
+            ]
+        );
+        assert_eq!(point.timestamp, Some(1_630_000_000));
+        assert_eq!(parse_line("air co2=600").unwrap().timestamp, None);
+    }
+
+    #[test]
+    fn parses_escapes_and_quoted_strings() {
+        let point =
+            parse_line(r#"my\ air\,meter,room=living\ room,a\=b=c note="x, y=z \"q\"",co2=1 5"#)
+                .unwrap();
+        assert_eq!(point.measurement, "my air,meter");
+        assert_eq!(
+            point.tags,
+            vec![
+                ("room".to_owned(), "living room".to_owned()),
+                ("a=b".to_owned(), "c".to_owned())
+            ]
+        );
+        assert_eq!(
+            point.fields[0],
+            (
+                "note".to_owned(),
+                FieldValue::Str(r#"x, y=z "q""#.to_owned())
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for line in &[
+            "air",
+            ",pub_id=1 co2=1",
+            "air co2=1 1 2",
+            "air co2= 1",
+            "air,pub_id co2=1",
+            "air co2=NaN",
+            "air co2=inf",
+            "air co2=1x",
+            "air co2=1 soon",
+        ] {
+            assert!(parse_line(line).is_err(), "{:?} parsed", line);
+        }
+    }
+
+    #[test]
+    fn maps_points_onto_readings() {
+        let point =
+            parse_line("air,model=SCD30 co2=612,rh=40,pressure=1013 1630000000000").unwrap();
+        let reading = to_reading(&point, Some(812), Precision::Ms, 0).unwrap();
+        assert_eq!(reading.pub_id, 812);
+        assert_eq!(reading.eco2, 612);
+        assert_eq!(
+            (reading.read_time, reading.start_time),
+            (1_630_000_000, 1_630_000_000)
+        );
+        assert_eq!(reading.sensor_model.as_deref(), Some("SCD30"));
+        assert_eq!(reading.humidity, Some(40.0));
+        assert_eq!(reading.metrics.get("pressure"), Some(&1013.0));
+        assert_eq!(reading.quality_flags, QUALITY_NO_EVTOC);
+
+        let point = parse_line("air,pub_id=813 eco2=700,tvoc=50").unwrap();
+        let reading = to_reading(&point, Some(812), Precision::Ns, 42).unwrap();
+        assert_eq!(
+            (reading.pub_id, reading.evtoc, reading.read_time),
+            (813, 50, 42)
+        );
+        assert_eq!(reading.quality_flags, 0);
+
+        let missing =
+            |line: &str, pub_id| to_reading(&parse_line(line).unwrap(), pub_id, Precision::S, 0);
+        assert!(missing("air temperature=21", Some(812)).is_err());
+        assert!(missing("air co2=600", None).is_err());
+        assert!(missing("air co2=70000", Some(812)).is_err());
+        assert!(missing("air co2=600 -5", Some(812)).is_err());
+    }
+
+    #[test]
+    fn reading_lines_round_trip() {
+        let point = parse_line(
+            "air,pub_id=811,model=CCS811\\ rev\\,2,increment=ConstantPower1s \
+             co2=612,tvoc=40,temperature=21.5,raw_current=12i,weird\\=name=1.5 1630000000",
+        )
+        .unwrap();
+        let mut reading = to_reading(&point, None, Precision::S, 0).unwrap();
+        let line = reading_line("air meter", 811, &reading);
+        assert_eq!(
+            line,
+            "air\\ meter,pub_id=811,sensor_model=CCS811\\ rev\\,2,increment=ConstantPower1s \
+             eco2=612i,evtoc=40i,temperature=21.5,raw_current=12i,weird\\=name=1.5 1630000000"
+        );
+        let parsed = to_reading(&parse_line(&line).unwrap(), None, Precision::S, 0).unwrap();
+        assert_eq!(
+            serde_json::to_value(&parsed).unwrap(),
+            serde_json::to_value(&reading).unwrap()
+        );
+
+        // values influxdb would reject, and the placeholder TVOC, aren't written
+        reading.humidity = Some(f64::NAN);
+        reading.metrics.insert("broken".to_owned(), f64::INFINITY);
+        reading.quality_flags |= QUALITY_NO_EVTOC;
+        let line = reading_line("air", 811, &reading);
+        assert!(!line.contains("humidity") && !line.contains("broken") && !line.contains("evtoc"));
+        assert!(parse_line(&line).is_ok());
+    }
+}
@@ -5,11 +5,25 @@ extern crate diesel_migrations;
 
 use std::time::Duration;
 
-/// How often heartbeat pings are sent
+/// How often heartbeat pings are sent, unless configured otherwise
 pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
-/// How long before lack of client response causes a timeout
+/// How long before lack of client response causes a timeout, unless configured otherwise
 pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
 
+pub mod config;
+
+pub mod logging;
+
+pub mod tls;
+
+pub mod shutdown;
+
+pub mod rate_limit;
+
+pub mod health;
+
+pub mod systemd;
+
 pub mod sensor_client;
 pub use sensor_client::SessionClient;
 
@@ -25,3 +39,25 @@ pub mod templates;
 pub mod rest_api;
 
 pub mod common;
+
+pub mod alerts;
+
+pub mod export;
+
+pub mod metrics;
+
+pub mod import;
+
+pub mod webhooks;
+
+pub mod mqtt;
+
+pub mod influx;
+
+pub mod iaq;
+
+pub mod ventilation;
+
+pub mod anomaly;
+
+pub mod report;
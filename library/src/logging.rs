@@ -0,0 +1,51 @@
+//! Structured logging through `tracing`. Events are written as text or one
+//! JSON object per line, filtered per module by an `EnvFilter` directive like
+//! `info,library::relay_server=debug,actix_web=warn`. `log` records from
+//! dependencies, e.g. actix-web's request logger, are forwarded as events.
+//!
+//! Websocket and SSE sessions log inside a span carrying their role and id,
+//! and each relayed reading carries a correlation id, `corr_id`, from the
+//! sensor read through the relay to the db insert.
+use serde::{Deserialize, Serialize};
+use std::io::IsTerminal;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(format: &str) -> Result<LogFormat, String> {
+        match format {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("{:?}: expected text or json", format)),
+        }
+    }
+}
+
+/// check a filter directive without installing it
+pub fn parse_filter(filter: &str) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(filter).map_err(|e| format!("{:?}: {}", filter, e))
+}
+
+/// install the global subscriber, only the first call has an effect
+pub fn init(filter: &str, format: LogFormat) -> Result<(), String> {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(parse_filter(filter)?)
+        // no colour codes in redirected logs
+        .with_ansi(std::io::stdout().is_terminal());
+    let installed = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).try_init(),
+    };
+    installed.map_err(|e| format!("{}", e))
+}
+
+/// a new reading correlation id, for readings that didn't come with one
+pub fn corr_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
@@ -0,0 +1,256 @@
+//! Prometheus metrics for the server and its sensors, kept in the default
+//! registry and served in the text format at `/metrics`
+use actix_web::HttpResponse;
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge_vec,
+    Encoder, Histogram, IntCounter, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use std::sync::OnceLock;
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub struct Metrics {
+    pub eco2: IntGaugeVec,
+    pub evtoc: IntGaugeVec,
+    pub readings: IntCounterVec,
+    pub sensor_read_errors: IntCounter,
+    pub anomalies: IntCounterVec,
+    pub ws_sessions: IntGaugeVec,
+    pub mailbox: IntGaugeVec,
+    pub db_insert_seconds: Histogram,
+    pub rate_limited: IntCounterVec,
+    pub readings_rejected: IntCounterVec,
+    pub clock_skew: IntGaugeVec,
+    pub visitors: IntCounter,
+}
+
+/// the metrics, registered together on first use so unused ones are still
+/// reported
+pub fn get() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::register)
+}
+
+impl Metrics {
+    fn register() -> Metrics {
+        Metrics {
+            eco2: register_int_gauge_vec!(
+                "air_meter_eco2_ppm",
+                "Latest eCO2 reading of each publisher",
+                &["pub_id"]
+            )
+            .unwrap(),
+            evtoc: register_int_gauge_vec!(
+                "air_meter_evtoc_ppb",
+                "Latest eTVOC reading of each publisher",
+                &["pub_id"]
+            )
+            .unwrap(),
+            readings: register_int_counter_vec!(
+                "air_meter_readings_total",
+                "Readings relayed from each publisher",
+                &["pub_id"]
+            )
+            .unwrap(),
+            sensor_read_errors: register_int_counter!(
+                "air_meter_sensor_read_errors_total",
+                "Errors reading the local sensor"
+            )
+            .unwrap(),
+            anomalies: register_int_counter_vec!(
+                "air_meter_sensor_anomalies_total",
+                "Suspect readings of each publisher by anomaly",
+                &["pub_id", "anomaly"]
+            )
+            .unwrap(),
+            ws_sessions: register_int_gauge_vec!(
+                "air_meter_ws_sessions",
+                "Open websocket sessions by role",
+                &["role"]
+            )
+            .unwrap(),
+            mailbox: register_int_gauge_vec!(
+                "air_meter_mailbox_readings",
+                "Readings queued in an actor's mailbox",
+                &["actor"]
+            )
+            .unwrap(),
+            db_insert_seconds: register_histogram!(
+                "air_meter_db_insert_seconds",
+                "Time taken to insert a reading",
+                vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]
+            )
+            .unwrap(),
+            rate_limited: register_int_counter_vec!(
+                "air_meter_rate_limited_total",
+                "Messages and requests refused for exceeding a limit, by limit",
+                &["limit"]
+            )
+            .unwrap(),
+            readings_rejected: register_int_counter_vec!(
+                "air_meter_readings_rejected_total",
+                "Readings refused by validation, by reason",
+                &["reason"]
+            )
+            .unwrap(),
+            clock_skew: register_int_gauge_vec!(
+                "air_meter_clock_skew_seconds",
+                "How far each websocket publisher's latest read_time was ahead of the server's clock",
+                &["pub_id"]
+            )
+            .unwrap(),
+            visitors: register_int_counter!(
+                "air_meter_visitors_total",
+                "Websocket and SSE sessions connected since the server started"
+            )
+            .unwrap(),
+        }
+    }
+}
+
+/// actor names for the `mailbox` gauge
+pub const RELAY_SERVER: &str = "relay_server";
+pub const ACTIONS: &str = "actions";
+
+/// a reading was sent to `actor`'s mailbox
+pub fn queued(actor: &str) {
+    get().mailbox.with_label_values(&[actor]).inc();
+}
+
+/// `actor` took a reading from its mailbox
+pub fn dequeued(actor: &str) {
+    get().mailbox.with_label_values(&[actor]).dec();
+}
+
+/// `/metrics` handler
+pub async fn serve() -> HttpResponse {
+    get();
+    let mut buf = Vec::new();
+    let encoder = TextEncoder::new();
+    match encoder.encode(&prometheus::gather(), &mut buf) {
+        Ok(_) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(buf),
+        Err(err) => HttpResponse::InternalServerError().body(format!("{:?}", err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anomaly::AnomalyConfig;
+    use crate::db::Actions;
+    use crate::relay_server::{
+        self, Close, Connect, ListSubs, PublisherMessage, RegisterPublisher, Role,
+    };
+    use crate::webhooks::Webhooks;
+    use crate::RelayServer;
+    use actix::prelude::*;
+    use actix_web::{test, web, App};
+    use serde_json::json;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    /// a session that ignores what it's sent
+    struct Session;
+
+    impl Actor for Session {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<relay_server::Message> for Session {
+        type Result = ();
+
+        fn handle(&mut self, _: relay_server::Message, _: &mut Context<Self>) {}
+    }
+
+    impl Handler<Close> for Session {
+        type Result = ();
+
+        fn handle(&mut self, _: Close, _: &mut Context<Self>) {}
+    }
+
+    fn relay(name: &str) -> Addr<RelayServer> {
+        let path = std::env::temp_dir().join(format!(
+            "air_meter-metrics-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let actions = Actions::new(&path.to_string_lossy()).start();
+        RelayServer::new(
+            Arc::new(AtomicUsize::new(0)),
+            actions.clone(),
+            Webhooks::new(actions).start(),
+            AnomalyConfig::default(),
+        )
+        .start()
+    }
+
+    async fn exposition() -> String {
+        let mut app = test::init_service(App::new().route("/metrics", web::get().to(serve))).await;
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = test::read_body(test::call_service(&mut app, req).await).await;
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn readings_update_the_publishers_metrics() {
+        let relay = relay("reading");
+        relay.send(RegisterPublisher { pub_id: 821 }).await.unwrap();
+        let readings = get().readings.with_label_values(&["821"]);
+        assert_eq!(readings.get(), 0);
+
+        for (eco2, evtoc) in [(640, 20), (700, 35)] {
+            let msg = serde_json::from_value(json!({
+                "eco2": eco2,
+                "evtoc": evtoc,
+                "read_time": 1_600_000_000,
+                "start_time": 1_599_999_400,
+                "increment": "ConstantPower1s",
+            }))
+            .unwrap();
+            relay.do_send(PublisherMessage {
+                msg,
+                pub_id: 821,
+                json: String::new(),
+            });
+        }
+        relay.send(ListSubs).await.unwrap();
+
+        assert_eq!(readings.get(), 2);
+        assert_eq!(get().eco2.with_label_values(&["821"]).get(), 700);
+        assert_eq!(get().evtoc.with_label_values(&["821"]).get(), 35);
+        let text = exposition().await;
+        assert!(text.contains("air_meter_readings_total{pub_id=\"821\"} 2\n"));
+        assert!(text.contains("air_meter_eco2_ppm{pub_id=\"821\"} 700\n"));
+    }
+
+    #[actix_rt::test]
+    async fn connecting_counts_a_visitor() {
+        let relay = relay("connect");
+        let before = get().visitors.get();
+        let session = Session.start();
+        relay
+            .send(Connect {
+                ses_role: Role::Subscriber(0),
+                addr: session.clone().recipient(),
+                close: session.recipient(),
+            })
+            .await
+            .unwrap();
+        // other tests connect to their own relays at the same time
+        assert!(get().visitors.get() > before);
+    }
+
+    #[actix_rt::test]
+    async fn unused_metrics_are_still_served() {
+        let text = exposition().await;
+        for name in [
+            "air_meter_sensor_read_errors_total",
+            "air_meter_visitors_total",
+            "air_meter_db_insert_seconds",
+        ] {
+            assert!(text.contains(&format!("# TYPE {} ", name)), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,511 @@
+//! Optional MQTT bridge for home automation. `MqttBridge` publishes each relayed
+//! reading to `air_meter/<pub_id>/state`, announcing the publisher with Home
+//! Assistant discovery configs on its first reading, and relays measurement
+//! mode commands from `air_meter/<pub_id>/set` to the publisher.
+//!
+//! rumqttc runs its own event loop, so the connection is polled on a separate
+//! thread which reconnects to the broker whenever the connection drops.
+use crate::config;
+use crate::db::model::Mode;
+use crate::relay_server::{AddReadingSink, PublisherMessage, Reading, SetMode};
+use crate::RelayServer;
+use actix::prelude::*;
+use rumqttc::{Client, Connection, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// topic prefix for everything the bridge publishes and subscribes to
+pub const TOPIC_PREFIX: &str = "air_meter";
+/// requests buffered for the event loop before publishes are dropped
+const REQUEST_CAPACITY: usize = 64;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+fn bridge_status_topic() -> String {
+    format!("{}/bridge/status", TOPIC_PREFIX)
+}
+
+fn state_topic(pub_id: u64) -> String {
+    format!("{}/{}/state", TOPIC_PREFIX, pub_id)
+}
+
+fn set_topic(pub_id: u64) -> String {
+    format!("{}/{}/set", TOPIC_PREFIX, pub_id)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    #[serde(
+        serialize_with = "config::redact",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub password: Option<String>,
+    /// Home Assistant discovery topic prefix
+    pub discovery_prefix: String,
+}
+
+impl Default for MqttConfig {
+    fn default() -> MqttConfig {
+        MqttConfig {
+            host: String::new(),
+            port: 1883,
+            client_id: "air_meter_server".to_owned(),
+            username: None,
+            password: None,
+            discovery_prefix: "homeassistant".to_owned(),
+        }
+    }
+}
+
+impl MqttConfig {
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        if self.host.is_empty() {
+            errors.push("mqtt.host: required, or set MQTT_HOST".to_owned());
+        }
+        if self.port == 0 {
+            errors.push("mqtt.port: must be above 0".to_owned());
+        }
+        if self.client_id.is_empty() {
+            errors.push("mqtt.client_id: can't be empty".to_owned());
+        }
+        if self.username.is_some() != self.password.is_some() {
+            errors.push("mqtt.username: needs a password, and a password a username".to_owned());
+        }
+        if self.discovery_prefix.is_empty() {
+            errors.push("mqtt.discovery_prefix: can't be empty".to_owned());
+        }
+    }
+}
+
+pub struct MqttBridge {
+    client: Client,
+    discovery_prefix: String,
+    /// publishers whose discovery configs have been published
+    announced: HashSet<u64>,
+}
+
+impl Actor for MqttBridge {
+    type Context = Context<Self>;
+}
+
+impl MqttBridge {
+    /// connect to the broker and register the bridge as a `RelayServer` reading sink
+    pub fn start(config: MqttConfig, relay: Addr<RelayServer>) -> Addr<MqttBridge> {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(KEEP_ALIVE);
+        options.set_last_will(LastWill::new(
+            bridge_status_topic(),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            options.set_credentials(username, password);
+        }
+        let (client, connection) = Client::new(options, REQUEST_CAPACITY);
+        tracing::info!(host = %config.host, port = config.port, "mqtt bridge connecting");
+        let event_client = client.clone();
+        let event_relay = relay.clone();
+        std::thread::spawn(move || poll(connection, event_client, event_relay));
+        let bridge = MqttBridge {
+            client,
+            discovery_prefix: config.discovery_prefix,
+            announced: HashSet::new(),
+        }
+        .start();
+        relay.do_send(AddReadingSink(bridge.clone().recipient()));
+        bridge
+    }
+
+    fn publish(&self, topic: String, retain: bool, payload: String) {
+        if let Err(err) = self
+            .client
+            .try_publish(topic.as_str(), QoS::AtMostOnce, retain, payload)
+        {
+            tracing::warn!(%topic, error = %err, "couldn't publish");
+        }
+    }
+
+    /// retained Home Assistant discovery configs for a publisher's entities
+    fn announce(&self, reading: &Reading, pub_id: u64) {
+        for (topic, config) in discovery(&self.discovery_prefix, reading, pub_id) {
+            self.publish(topic, true, config.to_string());
+        }
+    }
+}
+
+/// discovery topics and configs of a publisher's entities, a sensor for each
+/// value its reading has and a select for the measurement mode
+fn discovery(prefix: &str, reading: &Reading, pub_id: u64) -> Vec<(String, serde_json::Value)> {
+    let device = json!({
+        "identifiers": [format!("air_meter_{}", pub_id)],
+        "name": format!("Air Meter {}", pub_id),
+        "model": reading.sensor_model.as_deref().unwrap_or("unknown"),
+        "manufacturer": "air_meter",
+    });
+    let mut sensors = vec![
+        ("eco2", "eCO2", "ppm", "carbon_dioxide"),
+        ("evtoc", "eTVOC", "ppb", "volatile_organic_compounds_parts"),
+    ];
+    if reading.temperature.is_some() {
+        sensors.push(("temperature", "Temperature", "°C", "temperature"));
+    }
+    if reading.humidity.is_some() {
+        sensors.push(("humidity", "Humidity", "%", "humidity"));
+    }
+    let mut configs = sensors
+        .into_iter()
+        .map(|(field, name, unit, class)| {
+            let config = json!({
+                "name": name,
+                "unique_id": format!("air_meter_{}_{}", pub_id, field),
+                "state_topic": state_topic(pub_id),
+                "value_template": format!("{{{{ value_json.{} }}}}", field),
+                "unit_of_measurement": unit,
+                "device_class": class,
+                "state_class": "measurement",
+                "availability_topic": bridge_status_topic(),
+                "device": device,
+            });
+            let topic = format!("{}/sensor/air_meter_{}/{}/config", prefix, pub_id, field);
+            (topic, config)
+        })
+        .collect::<Vec<_>>();
+    let modes = Mode::SETTABLE
+        .iter()
+        .map(|m| m.name())
+        .collect::<Vec<&str>>();
+    let config = json!({
+        "name": "Measurement mode",
+        "unique_id": format!("air_meter_{}_mode", pub_id),
+        "state_topic": state_topic(pub_id),
+        "value_template": "{{ value_json.increment }}",
+        "command_topic": set_topic(pub_id),
+        "options": modes,
+        "availability_topic": bridge_status_topic(),
+        "device": device,
+    });
+    let topic = format!("{}/select/air_meter_{}/mode/config", prefix, pub_id);
+    configs.push((topic, config));
+    configs
+}
+
+impl Handler<PublisherMessage<Reading>> for MqttBridge {
+    type Result = ();
+
+    fn handle(&mut self, msg: PublisherMessage<Reading>, _: &mut Context<Self>) {
+        if self.announced.insert(msg.pub_id) {
+            self.announce(&msg.msg, msg.pub_id);
+        }
+        self.publish(state_topic(msg.pub_id), false, msg.json);
+    }
+}
+
+/// `/set` payloads are a mode name or `{"mode": "<name>"}`
+#[derive(Deserialize)]
+struct SetCommand {
+    mode: String,
+}
+
+fn parse_set(topic: &str, payload: &[u8]) -> Result<SetMode, String> {
+    let pub_id = topic
+        .strip_prefix(TOPIC_PREFIX)
+        .and_then(|t| t.strip_prefix('/'))
+        .and_then(|t| t.strip_suffix("/set"))
+        .and_then(|id| id.parse::<u64>().ok())
+        .ok_or_else(|| format!("unexpected topic {}", topic))?;
+    let payload = String::from_utf8_lossy(payload);
+    let name = match serde_json::from_str::<SetCommand>(&payload) {
+        Ok(cmd) => cmd.mode,
+        Err(_) => payload.trim().trim_matches('"').to_owned(),
+    };
+    match Mode::from_name(&name) {
+        Mode::Unknown => Err(format!("unknown measurement mode {:?}", name)),
+        mode => Ok(SetMode { pub_id, mode }),
+    }
+}
+
+/// drive the mqtt event loop, subscribing on every (re)connect and relaying
+/// mode commands to `RelayServer`
+fn poll(mut connection: Connection, client: Client, relay: Addr<RelayServer>) {
+    for event in connection.iter() {
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                tracing::info!("mqtt bridge connected");
+                let subscribed = client
+                    .try_subscribe(format!("{}/+/set", TOPIC_PREFIX), QoS::AtLeastOnce)
+                    .and_then(|_| {
+                        client.try_publish(bridge_status_topic(), QoS::AtLeastOnce, true, "online")
+                    });
+                if let Err(err) = subscribed {
+                    tracing::error!(error = %err, "couldn't subscribe");
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let result = parse_set(&publish.topic, &publish.payload).and_then(|cmd| {
+                    tracing::info!(?cmd, "mqtt command");
+                    futures::executor::block_on(relay.send(cmd)).map_err(|e| format!("{}", e))?
+                });
+                if let Err(err) = result {
+                    tracing::warn!(topic = %publish.topic, error = %err, "mqtt command rejected");
+                }
+            }
+            Ok(_) => {}
+            Err(err) => {
+                tracing::warn!(error = %err, "mqtt connection error");
+                std::thread::sleep(RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anomaly::AnomalyConfig;
+    use crate::db::Actions;
+    use crate::relay_server::{self, Close, Connect, Role};
+    use crate::webhooks::Webhooks;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::{mpsc, Arc, Mutex};
+
+    fn reading(temperature: Option<f64>) -> Reading {
+        serde_json::from_value(json!({
+            "eco2": 612,
+            "evtoc": 31,
+            "read_time": 1_600_000_000,
+            "start_time": 1_599_990_000,
+            "increment": "ConstantPower1s",
+            "sensor_model": "CCS811",
+            "temperature": temperature,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_mode_names_and_json_commands() {
+        for payload in [
+            "ConstantPower250ms",
+            " \"ConstantPower250ms\"\n",
+            r#"{"mode": "ConstantPower250ms"}"#,
+        ] {
+            let cmd = parse_set("air_meter/811/set", payload.as_bytes()).unwrap();
+            assert_eq!((cmd.pub_id, cmd.mode), (811, Mode::ConstantPower250ms));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_modes_and_topics() {
+        let err = parse_set("air_meter/811/set", b"Turbo").unwrap_err();
+        assert_eq!(err, "unknown measurement mode \"Turbo\"");
+        assert!(parse_set("air_meter/811/set", br#"{"mode": "Unknown"}"#).is_err());
+        for topic in ["air_meter/abc/set", "other/811/set", "air_meter/811/state"] {
+            let err = parse_set(topic, b"Idle").unwrap_err();
+            assert_eq!(err, format!("unexpected topic {}", topic));
+        }
+    }
+
+    #[test]
+    fn announces_a_sensor_per_value_and_a_mode_select() {
+        let configs = discovery("homeassistant", &reading(Some(21.5)), 811);
+        let topics = configs.iter().map(|(t, _)| t.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/sensor/air_meter_811/eco2/config",
+                "homeassistant/sensor/air_meter_811/evtoc/config",
+                "homeassistant/sensor/air_meter_811/temperature/config",
+                "homeassistant/select/air_meter_811/mode/config",
+            ]
+        );
+        let eco2 = &configs[0].1;
+        assert_eq!(eco2["state_topic"], "air_meter/811/state");
+        assert_eq!(eco2["value_template"], "{{ value_json.eco2 }}");
+        assert_eq!(eco2["unique_id"], "air_meter_811_eco2");
+        assert_eq!(eco2["device"]["model"], "CCS811");
+        let mode = &configs[3].1;
+        assert_eq!(mode["command_topic"], "air_meter/811/set");
+        assert_eq!(mode["options"][1], "ConstantPower250ms");
+        assert_eq!(mode["availability_topic"], "air_meter/bridge/status");
+
+        // no humidity or temperature, no entities for them
+        assert_eq!(discovery("ha", &reading(None), 811).len(), 3);
+    }
+
+    /// a packet's first byte and the rest after its remaining length
+    fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte).ok()?;
+        let header = byte[0];
+        let (mut len, mut shift) = (0usize, 0);
+        loop {
+            stream.read_exact(&mut byte).ok()?;
+            len |= ((byte[0] & 0x7f) as usize) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).ok()?;
+        Some((header, body))
+    }
+
+    /// a QoS 0 publish, small enough for a one byte remaining length
+    fn publish_packet(topic: &str, payload: &str) -> Vec<u8> {
+        let len = 2 + topic.len() + payload.len();
+        assert!(len < 128);
+        let mut packet = vec![0x30, len as u8, 0, topic.len() as u8];
+        packet.extend(topic.bytes().chain(payload.bytes()));
+        packet
+    }
+
+    /// `(topic, payload, retain)` of a publish the broker received
+    type Published = (String, String, bool);
+
+    /// stands in for a broker with a single client, acknowledging its
+    /// connect, subscribes, QoS 1 publishes and pings. Returns the port, the
+    /// publishes it receives and the client's connection
+    fn broker() -> (u16, mpsc::Receiver<Published>, mpsc::Receiver<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (published_tx, published) = mpsc::channel();
+        let (client_tx, client) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            client_tx.send(stream.try_clone().unwrap()).unwrap();
+            while let Some((header, body)) = read_packet(&mut stream) {
+                let reply = match header >> 4 {
+                    // connect, accepted
+                    1 => vec![0x20, 2, 0, 0],
+                    // subscribe, granted QoS 1
+                    8 => vec![0x90, 3, body[0], body[1], 1],
+                    // publish, acknowledged if QoS 1
+                    3 => {
+                        let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let topic = String::from_utf8_lossy(&body[2..2 + len]).into_owned();
+                        let (ack, payload) = match (header >> 1) & 3 {
+                            0 => (vec![], &body[2 + len..]),
+                            _ => (
+                                vec![0x40, 2, body[2 + len], body[3 + len]],
+                                &body[4 + len..],
+                            ),
+                        };
+                        let payload = String::from_utf8_lossy(payload).into_owned();
+                        let _ = published_tx.send((topic, payload, header & 1 == 1));
+                        ack
+                    }
+                    // ping
+                    12 => vec![0xd0, 0],
+                    _ => vec![],
+                };
+                stream.write_all(&reply).unwrap();
+            }
+        });
+        (port, published, client)
+    }
+
+    /// stands in for a publisher's session, recording what it's sent
+    struct Publisher(Arc<Mutex<Vec<String>>>);
+
+    impl Actor for Publisher {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<relay_server::Message> for Publisher {
+        type Result = ();
+
+        fn handle(&mut self, msg: relay_server::Message, _: &mut Context<Self>) {
+            self.0.lock().unwrap().push(msg.0);
+        }
+    }
+
+    impl Handler<Close> for Publisher {
+        type Result = ();
+
+        fn handle(&mut self, _: Close, _: &mut Context<Self>) {}
+    }
+
+    fn next_publish(published: &mpsc::Receiver<Published>) -> Published {
+        published.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn publishes_state_and_discovery_and_relays_commands() {
+        let (port, published, client) = broker();
+        let path = std::env::temp_dir().join(format!("air_meter-mqtt-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let actions = Actions::new(&path.to_string_lossy()).start();
+        let relay = RelayServer::new(
+            Arc::new(AtomicUsize::new(0)),
+            actions.clone(),
+            Webhooks::new(actions).start(),
+            AnomalyConfig::default(),
+        )
+        .start();
+        let received = Arc::new(Mutex::new(vec![]));
+        let publisher = Publisher(received.clone()).start();
+        relay
+            .send(Connect {
+                ses_role: Role::Publisher(811),
+                addr: publisher.clone().recipient(),
+                close: publisher.recipient(),
+            })
+            .await
+            .unwrap();
+
+        let config = MqttConfig {
+            host: "127.0.0.1".to_owned(),
+            port,
+            ..MqttConfig::default()
+        };
+        let bridge = MqttBridge::start(config, relay);
+        // published once subscribed
+        let online = next_publish(&published);
+        assert_eq!(
+            online,
+            ("air_meter/bridge/status".into(), "online".into(), true)
+        );
+
+        let msg = reading(Some(21.5));
+        let json = serde_json::to_string(&msg).unwrap();
+        for _ in 0..2 {
+            bridge
+                .send(PublisherMessage {
+                    msg: msg.clone(),
+                    pub_id: 811,
+                    json: json.clone(),
+                })
+                .await
+                .unwrap();
+        }
+        // discovery configs on the first reading only
+        let announced = (0..4).map(|_| next_publish(&published)).collect::<Vec<_>>();
+        assert!(announced.iter().all(|(topic, _, retain)| {
+            topic.starts_with("homeassistant/") && topic.ends_with("/config") && *retain
+        }));
+        let state = ("air_meter/811/state".to_owned(), json, false);
+        assert_eq!(next_publish(&published), state);
+        assert_eq!(next_publish(&published), state);
+
+        let mut client = client.recv_timeout(Duration::from_secs(5)).unwrap();
+        let command = publish_packet("air_meter/811/set", r#"{"mode": "PulseHeating10s"}"#);
+        client.write_all(&command).unwrap();
+        for _ in 0..100 {
+            if !received.lock().unwrap().is_empty() {
+                break;
+            }
+            actix_rt::time::delay_for(Duration::from_millis(20)).await;
+        }
+        assert_eq!(*received.lock().unwrap(), ["/set_mode PulseHeating10s"]);
+    }
+}
@@ -0,0 +1,303 @@
+//! Token bucket rate limits for websocket messages and REST requests. Each
+//! websocket session has its own bucket, and sessions and requests from the
+//! same ip share one more, so a client can't get around the limits by opening
+//! more connections.
+use crate::{metrics, rest_api::error::ApiError};
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::{header, HeaderValue},
+    web, Error,
+};
+use futures::future::{ok, Either, Ready};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// ip buckets are dropped once refilled when there are more than this many
+const PRUNE_ABOVE: usize = 4096;
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// largest websocket frame accepted, in bytes
+    pub ws_max_frame_bytes: usize,
+    /// messages a websocket session may send per second, 0 disables the limit
+    pub ws_messages_per_sec: f64,
+    /// messages a websocket session may send at once after being idle
+    pub ws_message_burst: u32,
+    /// messages all websocket sessions from one ip may send per second
+    pub ws_ip_messages_per_sec: f64,
+    pub ws_ip_message_burst: u32,
+    /// publishers a subscriber session may join
+    pub ws_max_subscriptions: usize,
+    /// violations a websocket session is warned of, the last disconnects it
+    pub ws_max_violations: u32,
+    /// REST requests one ip may make per second, 0 disables the limit
+    pub rest_requests_per_sec: f64,
+    pub rest_request_burst: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            ws_max_frame_bytes: 16 * 1024,
+            ws_messages_per_sec: 10.0,
+            ws_message_burst: 30,
+            ws_ip_messages_per_sec: 50.0,
+            ws_ip_message_burst: 100,
+            ws_max_subscriptions: 32,
+            ws_max_violations: 10,
+            rest_requests_per_sec: 20.0,
+            rest_request_burst: 100,
+        }
+    }
+}
+
+impl Limits {
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        if self.ws_max_frame_bytes < 1024 {
+            errors.push("limits.ws_max_frame_bytes: must be at least 1024".to_owned());
+        }
+        let rates = [
+            ("ws_messages_per_sec", self.ws_messages_per_sec),
+            ("ws_ip_messages_per_sec", self.ws_ip_messages_per_sec),
+            ("rest_requests_per_sec", self.rest_requests_per_sec),
+        ];
+        for (key, rate) in rates.iter() {
+            if !rate.is_finite() || *rate < 0.0 {
+                errors.push(format!("limits.{}: must be 0 or above", key));
+            }
+        }
+        let bursts = [
+            ("ws_message_burst", self.ws_message_burst),
+            ("ws_ip_message_burst", self.ws_ip_message_burst),
+            ("rest_request_burst", self.rest_request_burst),
+        ];
+        for (key, burst) in bursts.iter() {
+            if *burst == 0 {
+                errors.push(format!("limits.{}: must be above 0", key));
+            }
+        }
+        if self.ws_max_subscriptions == 0 {
+            errors.push("limits.ws_max_subscriptions: must be above 0".to_owned());
+        }
+        if self.ws_max_violations == 0 {
+            errors.push("limits.ws_max_violations: must be above 0".to_owned());
+        }
+    }
+}
+
+/// `burst` tokens refilled at `rate` a second, each message takes one
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: u32) -> TokenBucket {
+        TokenBucket {
+            rate,
+            burst: burst as f64,
+            tokens: burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+
+    /// take a token, or how long until one is available
+    pub fn take(&mut self) -> Result<(), Duration> {
+        if self.rate == 0.0 {
+            return Ok(());
+        }
+        self.refill(Instant::now());
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+/// a token bucket per client ip, shared by every worker
+#[derive(Clone)]
+pub struct IpLimiter {
+    rate: f64,
+    burst: u32,
+    buckets: Arc<Mutex<HashMap<IpAddr, TokenBucket>>>,
+}
+
+impl IpLimiter {
+    pub fn new(rate: f64, burst: u32) -> IpLimiter {
+        IpLimiter {
+            rate,
+            burst,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// take a token from `ip`'s bucket, or how long until one is available
+    pub fn take(&self, ip: IpAddr) -> Result<(), Duration> {
+        if self.rate == 0.0 {
+            return Ok(());
+        }
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() > PRUNE_ABOVE {
+            let now = Instant::now();
+            buckets.retain(|_, bucket| {
+                bucket.refill(now);
+                bucket.tokens < bucket.burst
+            });
+        }
+        let (rate, burst) = (self.rate, self.burst);
+        buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(rate, burst))
+            .take()
+    }
+}
+
+/// the per ip limiters, registered as app data
+#[derive(Clone)]
+pub struct RateLimits {
+    pub ws: IpLimiter,
+    pub rest: IpLimiter,
+}
+
+impl RateLimits {
+    pub fn new(limits: &Limits) -> RateLimits {
+        RateLimits {
+            ws: IpLimiter::new(limits.ws_ip_messages_per_sec, limits.ws_ip_message_burst),
+            rest: IpLimiter::new(limits.rest_requests_per_sec, limits.rest_request_burst),
+        }
+    }
+}
+
+/// `wrap_fn` middleware answering requests over their ip's limit with a 429
+/// and a `Retry-After` header
+pub fn limit_requests<S>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> Either<S::Future, Ready<Result<ServiceResponse, Error>>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+{
+    let limited = match (req.app_data::<web::Data<RateLimits>>(), req.peer_addr()) {
+        (Some(limits), Some(addr)) => limits.rest.take(addr.ip()).err(),
+        _ => None,
+    };
+    match limited {
+        None => Either::Left(srv.call(req)),
+        Some(wait) => {
+            metrics::get()
+                .rate_limited
+                .with_label_values(&["rest"])
+                .inc();
+            tracing::debug!(path = req.path(), "rate limited");
+            let mut res =
+                req.error_response(ApiError::too_many_requests("too many requests, slow down"));
+            let secs = wait.as_secs() + (wait.subsec_nanos() > 0) as u64;
+            res.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+            Either::Right(ok(res))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, App, HttpResponse};
+
+    /// as if `secs` passed since the bucket was last refilled
+    fn wait(bucket: &mut TokenBucket, secs: f64) {
+        bucket.updated -= Duration::from_secs_f64(secs);
+    }
+
+    #[test]
+    fn bursts_then_waits_for_refills() {
+        let mut bucket = TokenBucket::new(2.0, 3);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(), Ok(()));
+        }
+        let retry = bucket.take().unwrap_err();
+        assert!(retry > Duration::from_millis(490) && retry <= Duration::from_millis(500));
+
+        wait(&mut bucket, 0.5);
+        assert_eq!(bucket.take(), Ok(()));
+        assert!(bucket.take().is_err());
+
+        // refills stop at the burst
+        wait(&mut bucket, 60.0);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(), Ok(()));
+        }
+        assert!(bucket.take().is_err());
+    }
+
+    #[test]
+    fn zero_rate_is_unlimited() {
+        let mut bucket = TokenBucket::new(0.0, 0);
+        let limiter = IpLimiter::new(0.0, 0);
+        for _ in 0..1000 {
+            assert_eq!(bucket.take(), Ok(()));
+            assert_eq!(limiter.take([10, 0, 0, 1].into()), Ok(()));
+        }
+    }
+
+    #[test]
+    fn each_ip_has_its_own_bucket() {
+        let limiter = IpLimiter::new(1.0, 2);
+        let (a, b): (IpAddr, IpAddr) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        assert_eq!(limiter.take(a), Ok(()));
+        assert_eq!(limiter.take(a), Ok(()));
+        assert!(limiter.take(a).is_err());
+        assert_eq!(limiter.take(b), Ok(()));
+        // clones share the buckets, as workers do
+        assert!(limiter.clone().take(a).is_err());
+        assert_eq!(limiter.clone().take(b), Ok(()));
+    }
+
+    #[actix_rt::test]
+    async fn answers_over_limit_requests_with_retry_after() {
+        let limits = Limits {
+            rest_requests_per_sec: 0.25,
+            rest_request_burst: 2,
+            ..Limits::default()
+        };
+        let mut app = test::init_service(
+            App::new()
+                .data(RateLimits::new(&limits))
+                .wrap_fn(limit_requests)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |ip: &str| {
+            test::TestRequest::get()
+                .peer_addr(format!("{}:40000", ip).parse().unwrap())
+                .to_request()
+        };
+        for _ in 0..2 {
+            let res = test::call_service(&mut app, request("10.0.0.1")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = test::call_service(&mut app, request("10.0.0.1")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "4");
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["error"], "too_many_requests");
+
+        let res = test::call_service(&mut app, request("10.0.0.2")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -1,10 +1,16 @@
+use crate::db::model::Mode;
 use actix::prelude::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
 
+mod sse_session;
 mod ws_session;
 
 pub mod server;
+pub mod validate;
 
+pub use sse_session::{EventSender, SseSession, STREAM_CAPACITY};
 pub use ws_session::ws_route;
 
 #[derive(Copy, Clone, Debug)]
@@ -23,6 +29,13 @@ impl Into<u64> for Role {
     }
 }
 impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Publisher(_) => "publisher",
+            Role::Subscriber(_) => "subscriber",
+        }
+    }
+
     // replace the id value property of the enum while persisting enum value
     pub fn replace(self, id: u64) -> Role {
         match self {
@@ -39,12 +52,27 @@ impl Role {
 #[rtype(result = "()")]
 pub struct Message(pub String);
 
+/// server tells a session to close, giving the client the reason
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct Close(pub String);
+
 /// New client session with relay server is created
 #[derive(Message, Clone, Debug)]
 #[rtype(u64)]
 pub struct Connect {
     pub ses_role: Role,
     pub addr: Recipient<Message>,
+    pub close: Recipient<Close>,
+}
+
+/// Close every session and any that connect after, returning how many were
+/// open. Readings queued before it have been passed on to the db by the time
+/// it returns
+#[derive(Message, Debug)]
+#[rtype(usize)]
+pub struct Shutdown {
+    pub reason: String,
 }
 
 /// Session is disconnected
@@ -52,6 +80,8 @@ pub struct Connect {
 #[rtype(result = "()")]
 pub struct Disconnect {
     pub ses_id: u64,
+    /// the session's address, a publisher's id is reused by its next session
+    pub addr: Recipient<Message>,
 }
 
 /// Send message to publishers subscribers
@@ -65,19 +95,51 @@ where
     pub msg: T,
     /// publisher id
     pub pub_id: u64,
-    // msg as original json string
+    // msg as json, serialized by the server rather than as the publisher sent it
     pub json: String,
 }
 
 /// Publisher reading
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
 pub struct Reading {
+    /// optional for http publishers, whose publisher id is in the url
+    #[serde(default)]
     pub pub_id: u64,
     pub eco2: u16,
     pub evtoc: u16,
     pub read_time: u64,
     pub start_time: u64,
     pub increment: String,
+    /// sensor hardware, e.g. `CCS811`
+    #[serde(default)]
+    pub sensor_model: Option<String>,
+    /// degrees celsius
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    /// relative humidity percentage
+    #[serde(default)]
+    pub humidity: Option<f64>,
+    #[serde(default)]
+    pub raw_current: Option<u16>,
+    #[serde(default)]
+    pub raw_voltage: Option<u16>,
+    /// any other metrics the sensor reports, stored in `reading_metrics`
+    #[serde(default)]
+    pub metrics: BTreeMap<String, f64>,
+    /// `quality_flags` stored with the reading, set by the server not publishers
+    #[serde(skip)]
+    pub quality_flags: i32,
+    /// logged with the reading from the sensor read to the db insert, the
+    /// relay assigns one if the publisher didn't
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub corr_id: Option<String>,
+}
+
+impl Reading {
+    /// the reading's correlation id, assigning a new one if it has none
+    pub fn corr_id(&mut self) -> &str {
+        self.corr_id.get_or_insert_with(crate::logging::corr_id)
+    }
 }
 
 /// List of available subscriptions
@@ -88,6 +150,42 @@ impl actix::Message for ListSubs {
     type Result = Vec<u64>;
 }
 
+/// List active alerts, optionally only for one publisher
+pub struct ListAlerts {
+    pub pub_id: Option<u64>,
+}
+
+impl actix::Message for ListAlerts {
+    type Result = Vec<crate::alerts::Alert>;
+}
+
+/// Reload alert rules from the db after they've changed
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct ReloadAlertRules;
+
+/// Also send publisher readings to another actor, e.g. the mqtt bridge
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AddReadingSink(pub Recipient<PublisherMessage<Reading>>);
+
+/// Create a subscription for a publisher that sends readings over http rather
+/// than a websocket session, so subscribers can join it
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct RegisterPublisher {
+    pub pub_id: u64,
+}
+
+/// Ask a connected publisher to change its sensor's measurement mode,
+/// relayed to the publisher as `/set_mode <mode name>`
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), String>")]
+pub struct SetMode {
+    pub pub_id: u64,
+    pub mode: Mode,
+}
+
 /// Join subscription, if non-existant throw error
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
@@ -3,12 +3,23 @@
 //! Publishing clients send messages to subscribed users through `RelayServer`.
 //! Each publisher has its own subscription, multiple users can connect to a single
 //! publisher's subscription
-use crate::db::Actions;
+use crate::alerts::AlertEngine;
+use crate::anomaly::{Anomaly, AnomalyConfig, AnomalyDetector};
+use crate::common::{GetAlertRules, GetLatestAlertEvents, MarkReadings, Ping, SaveAlertEvent};
+use crate::db::{
+    model::{Mode, QUALITY_FLATLINE},
+    Actions,
+};
+use crate::iaq::{self, Iaq};
+use crate::metrics;
 use crate::relay_server::{
-    Connect, Disconnect, Join, ListSubs, Message, PublisherMessage, Reading, Role,
+    AddReadingSink, Close, Connect, Disconnect, Join, ListAlerts, ListSubs, Message,
+    PublisherMessage, Reading, RegisterPublisher, ReloadAlertRules, Role, SetMode, Shutdown,
 };
+use crate::webhooks::{self, Notify, Webhooks};
 use actix::prelude::*;
 use rand::{rngs::ThreadRng, Rng};
+use serde_json::json;
 use std::collections::{HashMap, HashSet};
 use std::sync::{
     atomic::{AtomicUsize, Ordering},
@@ -22,15 +33,24 @@ use std::sync::{
 /// users are appended to subscriptions HashSet on joining
 pub struct RelayServer {
     sessions: HashMap<u64, Recipient<Message>>,
+    /// sessions' close recipients, by the same ids
+    closers: HashMap<u64, Recipient<Close>>,
+    /// reason sessions are closed with once shutting down
+    shutdown: Option<String>,
     subs: HashMap<u64, HashSet<u64>>,
     rng: ThreadRng,
     visitor_count: Arc<AtomicUsize>,
     actions: Addr<Actions>,
+    alerts: AlertEngine,
+    anomalies: AnomalyDetector,
+    webhooks: Addr<Webhooks>,
+    /// other actors sent every relayed reading
+    sinks: Vec<Recipient<PublisherMessage<Reading>>>,
 }
 
 fn do_send_log(addr: &actix::Recipient<Message>, message: &str) {
     if let Err(err) = addr.do_send(Message(message.to_owned())) {
-        println!("[srv/m] do_send error: {:?}", err)
+        tracing::warn!(error = ?err, "couldn't send to session")
     }
 }
 
@@ -38,25 +58,81 @@ fn do_send_log(addr: &actix::Recipient<Message>, message: &str) {
 impl Actor for RelayServer {
     // Simple context
     type Context = Context<Self>;
+
+    // load alert rules and restore active alerts before relaying readings
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        self.load_alert_rules(ctx, true);
+    }
 }
 
 impl RelayServer {
-    pub fn new(visitor_count: Arc<AtomicUsize>, actions: Addr<Actions>) -> RelayServer {
+    pub fn new(
+        visitor_count: Arc<AtomicUsize>,
+        actions: Addr<Actions>,
+        webhooks: Addr<Webhooks>,
+        anomaly_config: AnomalyConfig,
+    ) -> RelayServer {
         // default subscription?
         RelayServer {
             sessions: HashMap::new(),
+            closers: HashMap::new(),
+            shutdown: None,
             subs: HashMap::new(),
             rng: rand::thread_rng(),
             visitor_count,
             actions,
+            alerts: AlertEngine::new(),
+            anomalies: AnomalyDetector::new(anomaly_config),
+            webhooks,
+            sinks: vec![],
+        }
+    }
+
+    fn load_alert_rules(&mut self, ctx: &mut Context<Self>, restore: bool) {
+        let actions = self.actions.clone();
+        async move {
+            let rules = actions.send(GetAlertRules { enabled_only: true }).await?;
+            let events = match restore {
+                true => Some(actions.send(GetLatestAlertEvents).await?),
+                false => None,
+            };
+            Ok((rules, events))
         }
+        .into_actor(self)
+        .then(|res: Result<_, MailboxError>, act, _| {
+            match res {
+                Ok((rules, events)) => {
+                    tracing::info!(rules = rules.len(), "alert rules loaded");
+                    act.alerts.set_rules(rules);
+                    if let Some(events) = events {
+                        act.alerts.restore(events);
+                    }
+                }
+                Err(err) => tracing::error!(error = ?err, "couldn't load alert rules"),
+            }
+            fut::ready(())
+        })
+        .wait(ctx);
     }
 
     fn message_session(&self, session_id: &u64, message: &str) {
         if let Some(addr) = self.sessions.get(session_id) {
             do_send_log(addr, message);
         } else {
-            println!("[srv/m] error: session {} doesnt exist", session_id);
+            tracing::warn!(ses_id = session_id, "session doesn't exist");
+        }
+    }
+
+    /// send `/presence` to a publisher's subscribers when it connects or disconnects
+    fn broadcast_presence(&self, pub_id: u64, online: bool) {
+        if let Some(sessions) = self.subs.get(&pub_id) {
+            let msg = format!(
+                "/presence {}",
+                json!({ "pub_id": pub_id, "online": online })
+            );
+            for user_id in sessions {
+                self.message_session(user_id, &msg);
+            }
         }
     }
 
@@ -73,9 +149,15 @@ impl RelayServer {
         // create subscription entry if none
         if self.subs.get(&ses_role.into()).is_none() {
             self.subs.insert(ses_role.into(), HashSet::new());
-            println!("[srv/m] {:?} SUBSCRIPTION SET INIT'ED", ses_role);
+            tracing::debug!(?ses_role, "subscription created");
         };
-        println!("[srv/m] {:?} PUBLISHER CONNECTED", ses_role);
+        let pub_id: u64 = ses_role.into();
+        tracing::info!(pub_id, "publisher connected");
+        self.broadcast_presence(pub_id, true);
+        self.webhooks.do_send(Notify {
+            event: webhooks::PUBLISHER_ONLINE,
+            data: json!({ "pub_id": pub_id }),
+        });
         ses_role.into()
     }
 }
@@ -86,9 +168,8 @@ impl Handler<Connect> for RelayServer {
     type Result = u64;
 
     fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
-        println!("[srv/m] {:?}", msg);
-
         self.visitor_count.fetch_add(1, Ordering::SeqCst);
+        metrics::get().visitors.inc();
 
         // if publisher, id is specified by publisher, else gen new id
         let id: u64 = match msg.ses_role {
@@ -98,23 +179,54 @@ impl Handler<Connect> for RelayServer {
             }
             _ => self.rng.gen::<u64>(),
         };
+        tracing::debug!(role = msg.ses_role.name(), id, "session connected");
         self.sessions.insert(id, msg.addr);
+        match &self.shutdown {
+            Some(reason) => {
+                let _ = msg.close.do_send(Close(reason.clone()));
+            }
+            None => {
+                self.closers.insert(id, msg.close);
+            }
+        }
         id
     }
 }
 
+impl Handler<Shutdown> for RelayServer {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Shutdown, _: &mut Context<Self>) -> Self::Result {
+        let closed = self.closers.len();
+        tracing::info!(sessions = closed, reason = %msg.reason, "closing sessions");
+        for (_, close) in self.closers.drain() {
+            let _ = close.do_send(Close(msg.reason.clone()));
+        }
+        self.shutdown = Some(msg.reason);
+        closed
+    }
+}
+
 impl Handler<Disconnect> for RelayServer {
     type Result = ();
     fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
-        println!("[srv/m] {:?}", msg);
-
-        // remove address
-        if self.sessions.get(&msg.ses_id).is_some() {
-            println!("[srv/m] {:?} REMOVED", msg);
+        // a publisher's old session may stop after its new one connected
+        if self.sessions.get(&msg.ses_id) == Some(&msg.addr) {
+            tracing::debug!(id = msg.ses_id, "session removed");
+            self.sessions.remove(&msg.ses_id);
+            self.closers.remove(&msg.ses_id);
             // remove session from all subscriptions
             for sessions in &mut self.subs.values_mut() {
                 sessions.remove(&msg.ses_id);
             }
+            // publisher sessions are keyed by their publisher id
+            if self.subs.contains_key(&msg.ses_id) {
+                self.broadcast_presence(msg.ses_id, false);
+                self.webhooks.do_send(Notify {
+                    event: webhooks::PUBLISHER_OFFLINE,
+                    data: json!({ "pub_id": msg.ses_id }),
+                });
+            }
         }
     }
 }
@@ -123,16 +235,93 @@ impl Handler<Disconnect> for RelayServer {
 impl Handler<PublisherMessage<Reading>> for RelayServer {
     type Result = ();
 
-    fn handle(&mut self, msg: PublisherMessage<Reading>, _: &mut Context<Self>) {
+    fn handle(&mut self, mut msg: PublisherMessage<Reading>, _: &mut Context<Self>) {
+        metrics::dequeued(metrics::RELAY_SERVER);
+        let corr_id = msg.msg.corr_id().to_owned();
         if let Some(sessions) = self.subs.get(&msg.pub_id) {
+            let pub_id = msg.pub_id.to_string();
+            // flag suspect readings before they're stored
+            let check = self.anomalies.check(msg.pub_id, &msg.msg);
+            msg.msg.quality_flags |= check.flags;
+            for anomaly in Anomaly::ALL.iter().filter(|a| check.flags & a.flag() != 0) {
+                metrics::get()
+                    .anomalies
+                    .with_label_values(&[&pub_id, anomaly.as_str()])
+                    .inc();
+            }
+            metrics::get().readings.with_label_values(&[&pub_id]).inc();
+            metrics::get()
+                .eco2
+                .with_label_values(&[&pub_id])
+                .set(msg.msg.eco2 as i64);
+            metrics::get()
+                .evtoc
+                .with_label_values(&[&pub_id])
+                .set(msg.msg.evtoc as i64);
             // send to db
+            metrics::queued(metrics::ACTIONS);
             self.actions.do_send(msg.clone());
+            // readings stored before a flatline was recognised are part of it
+            if let Some(since) = check.flatline_since {
+                self.actions.do_send(MarkReadings {
+                    pub_id: msg.pub_id,
+                    from: since,
+                    to: msg.msg.read_time,
+                    flags: QUALITY_FLATLINE,
+                });
+            }
+            for event in check.events {
+                tracing::warn!(%corr_id, ?event, "device health");
+                let json = serde_json::to_string(&event).unwrap();
+                for user_id in sessions {
+                    self.message_session(user_id, &format!("/health {}", json));
+                }
+                self.webhooks.do_send(Notify {
+                    event: webhooks::DEVICE_HEALTH,
+                    data: serde_json::to_value(&event).unwrap(),
+                });
+            }
+            // publisher sessions are keyed by their publisher id
+            if check.reset_sensor {
+                if let Some(addr) = self.sessions.get(&msg.pub_id) {
+                    tracing::warn!(pub_id = msg.pub_id, "resetting stuck sensor");
+                    do_send_log(addr, "/reset_sensor");
+                }
+            }
+            // subscribers and sinks are sent the reading with its air quality index
+            let msg = PublisherMessage {
+                json: iaq::annotate(&msg.json, &Iaq::of_reading(&msg.msg)),
+                ..msg
+            };
+            for sink in &self.sinks {
+                let _ = sink.do_send(msg.clone());
+            }
             // send to all subscribers
             for user_id in sessions {
                 self.message_session(user_id, &format!("/reading {}", msg.json));
             }
+            tracing::debug!(
+                %corr_id,
+                pub_id = msg.pub_id,
+                subscribers = sessions.len(),
+                flags = msg.msg.quality_flags,
+                "reading broadcast"
+            );
+            // evaluate alert rules, persisting and relaying state changes
+            for alert in self.alerts.evaluate(msg.pub_id, &msg.msg) {
+                tracing::info!(%corr_id, ?alert, "alert");
+                let json = serde_json::to_string(&alert).unwrap();
+                for user_id in sessions {
+                    self.message_session(user_id, &format!("/alert {}", json));
+                }
+                self.webhooks.do_send(Notify {
+                    event: webhooks::ALERT,
+                    data: serde_json::to_value(&alert).unwrap(),
+                });
+                self.actions.do_send(SaveAlertEvent(alert));
+            }
         } else {
-            println!("[srv/m] UNKNOWN PUBLISHER {}", msg.pub_id);
+            tracing::warn!(%corr_id, pub_id = msg.pub_id, "unknown publisher, reading dropped");
         }
     }
 }
@@ -158,6 +347,11 @@ impl Handler<Join> for RelayServer {
             .map(|subs| if subs.insert(ses_id) { Some(()) } else { None })
             .map(|_| {
                 self.message_session(&ses_id, &format!("/msg joined {}", pub_id));
+                // let new subscribers know about alerts already firing
+                for alert in self.alerts.active(Some(pub_id)) {
+                    let json = serde_json::to_string(&alert).unwrap();
+                    self.message_session(&ses_id, &format!("/alert {}", json));
+                }
                 Some(())
             })
             .or_else(|| {
@@ -167,3 +361,66 @@ impl Handler<Join> for RelayServer {
             });
     }
 }
+
+/// Handler for listing active alerts
+impl Handler<ListAlerts> for RelayServer {
+    type Result = MessageResult<ListAlerts>;
+
+    fn handle(&mut self, msg: ListAlerts, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.alerts.active(msg.pub_id))
+    }
+}
+
+/// Handler for reloading alert rules, active alerts of remaining rules are kept
+impl Handler<ReloadAlertRules> for RelayServer {
+    type Result = ();
+
+    fn handle(&mut self, _: ReloadAlertRules, ctx: &mut Context<Self>) {
+        self.load_alert_rules(ctx, false);
+    }
+}
+
+/// Handler for registering a reading sink
+impl Handler<AddReadingSink> for RelayServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: AddReadingSink, _: &mut Context<Self>) {
+        self.sinks.push(msg.0);
+    }
+}
+
+/// Handler for relaying a measurement mode change to a publisher
+impl Handler<SetMode> for RelayServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: SetMode, _: &mut Context<Self>) -> Self::Result {
+        if msg.mode == Mode::Unknown {
+            return Err("unknown measurement mode".to_owned());
+        }
+        // publisher sessions are keyed by their publisher id
+        match (self.subs.get(&msg.pub_id), self.sessions.get(&msg.pub_id)) {
+            (Some(_), Some(addr)) => addr
+                .do_send(Message(format!("/set_mode {}", msg.mode.name())))
+                .map_err(|_| format!("publisher {} is disconnected", msg.pub_id)),
+            _ => Err(format!("unknown publisher {}", msg.pub_id)),
+        }
+    }
+}
+
+impl Handler<Ping> for RelayServer {
+    type Result = ();
+
+    fn handle(&mut self, _: Ping, _: &mut Context<Self>) {}
+}
+
+/// Handler for registering a publisher without a session
+impl Handler<RegisterPublisher> for RelayServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RegisterPublisher, _: &mut Context<Self>) {
+        if let std::collections::hash_map::Entry::Vacant(entry) = self.subs.entry(msg.pub_id) {
+            entry.insert(HashSet::new());
+            tracing::info!(pub_id = msg.pub_id, "http publisher registered");
+        }
+    }
+}
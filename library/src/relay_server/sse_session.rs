@@ -0,0 +1,296 @@
+//! `SseSession` subscribes to a publisher on behalf of a Server-Sent Events
+//! client, for consumers that can't speak the websocket `/join` protocol.
+//! Relay messages are written to the response stream as events:
+//! `reading` (with the reading's row id as its id), `alert`, `presence`,
+//! `health`, `error`, `truncated` and `close`, sent before the server ends the
+//! stream. Readings are serialized by the function the session is given, so
+//! they match the api serving the stream. Reading events are read back from
+//! the db once stored, so a client reconnecting with `Last-Event-ID` is sent
+//! exactly the readings it missed, up to `BACKLOG_LIMIT` of them. `truncated` tells it older ones were skipped. A publisher that
+//! isn't connected has no live tail, its client is sent the readings it
+//! missed then `close`, and reconnects to resume later.
+use actix::prelude::*;
+use actix_web::Error;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use serde_json::json;
+
+use crate::common::ReadingsAfter;
+use crate::config;
+use crate::db::{model::DbReading, Actions};
+use crate::relay_server::{self, server::RelayServer, Join, Role};
+
+/// events buffered for a client before it's considered too slow and dropped
+pub const STREAM_CAPACITY: usize = 256;
+/// most stored readings sent at once, older ones are skipped
+const BACKLOG_LIMIT: u16 = 1000;
+
+pub type EventSender = mpsc::Sender<Result<Bytes, Error>>;
+
+pub struct SseSession {
+    ses_id: u64,
+    pub_id: u64,
+    server_addr: Addr<RelayServer>,
+    actions: Addr<Actions>,
+    tx: EventSender,
+    /// a stored reading's event data
+    reading_json: fn(&DbReading) -> String,
+    /// row id of the last reading the client saw before reconnecting
+    last_event_id: Option<i32>,
+    /// the publisher is connected, so there's a live tail to join
+    live: bool,
+    /// row id of the last reading sent, 0 before any
+    cursor: i32,
+    /// readings are being read from the db
+    fetching: bool,
+    /// more readings were relayed while fetching
+    stale: bool,
+    /// carries the session's id and publisher, entered while handling its events
+    span: tracing::Span,
+}
+
+fn event(id: Option<u64>, name: &str, data: &str) -> String {
+    let mut event = String::new();
+    if let Some(id) = id {
+        event.push_str(&format!("id: {}\n", id));
+    }
+    event.push_str(&format!("event: {}\n", name));
+    // multi-line data needs a data field per line
+    for line in data.lines() {
+        event.push_str(&format!("data: {}\n", line));
+    }
+    event.push('\n');
+    event
+}
+
+impl SseSession {
+    pub fn new(
+        pub_id: u64,
+        last_event_id: Option<i32>,
+        live: bool,
+        reading_json: fn(&DbReading) -> String,
+        server_addr: Addr<RelayServer>,
+        actions: Addr<Actions>,
+        tx: EventSender,
+    ) -> SseSession {
+        SseSession {
+            ses_id: 0,
+            pub_id,
+            server_addr,
+            actions,
+            tx,
+            reading_json,
+            last_event_id,
+            live,
+            cursor: last_event_id.unwrap_or(0),
+            fetching: false,
+            stale: false,
+            span: tracing::info_span!(
+                "sse_session",
+                role = Role::Subscriber(0).name(),
+                id = tracing::field::Empty,
+                pub_id
+            ),
+        }
+    }
+
+    /// write to the response stream, stopping if the client has gone or fallen behind
+    fn send(&mut self, chunk: String, ctx: &mut Context<Self>) {
+        if let Err(err) = self.tx.try_send(Ok(Bytes::from(chunk))) {
+            if err.is_full() {
+                let _entered = self.span.enter();
+                tracing::info!("too slow, disconnecting");
+            }
+            ctx.stop();
+        }
+    }
+
+    /// turn a relay message into an event, relayed readings are sent once
+    /// they're read back from the db
+    fn relay(&mut self, msg: &str, ctx: &mut Context<Self>) {
+        let mut parts = msg.splitn(2, ' ');
+        let (cmd, data) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        match cmd {
+            "/reading" => self.fetch(ctx),
+            "/alert" => self.send(event(None, "alert", data), ctx),
+            "/presence" => self.send(event(None, "presence", data), ctx),
+            "/health" => self.send(event(None, "health", data), ctx),
+            "/err" => {
+                self.send(event(None, "error", data), ctx);
+                ctx.stop();
+            }
+            _ => {}
+        }
+    }
+
+    /// send the readings stored after the last one sent. The relay server
+    /// queues a reading to be stored before relaying it, so it's stored by the
+    /// time this reads
+    fn fetch(&mut self, ctx: &mut Context<Self>) {
+        if self.fetching {
+            self.stale = true;
+            return;
+        }
+        self.fetching = true;
+        self.stale = false;
+        self.actions
+            .send(ReadingsAfter {
+                pub_id: self.pub_id,
+                after: Some(self.cursor),
+                // one more tells if any were skipped
+                limit: BACKLOG_LIMIT + 1,
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(mut readings) => {
+                        if readings.len() > BACKLOG_LIMIT as usize {
+                            readings.remove(0);
+                            let skipped = json!({ "after": act.cursor, "until": readings[0].id });
+                            act.send(event(None, "truncated", &skipped.to_string()), ctx);
+                        }
+                        for reading in &readings {
+                            let id = reading.id as u64;
+                            act.send(
+                                event(Some(id), "reading", &(act.reading_json)(reading)),
+                                ctx,
+                            );
+                            act.cursor = reading.id;
+                        }
+                    }
+                    Err(err) => {
+                        let _entered = act.span.enter();
+                        tracing::error!(error = ?err, "couldn't load readings");
+                    }
+                }
+                act.fetching = false;
+                if act.stale {
+                    act.fetch(ctx);
+                } else if !act.live {
+                    act.offline(ctx);
+                }
+                fut::ready(())
+            })
+            .spawn(ctx);
+    }
+
+    /// end the stream of a publisher that isn't connected
+    fn offline(&mut self, ctx: &mut Context<Self>) {
+        let reason = format!("publisher {} offline", self.pub_id);
+        self.send(event(None, "close", &reason), ctx);
+        ctx.stop();
+    }
+
+    /// join the publisher, resuming after the client's last event or else
+    /// from its latest stored reading
+    fn join(&mut self, ctx: &mut Context<Self>) {
+        if !self.live {
+            match self.last_event_id {
+                Some(_) => self.fetch(ctx),
+                None => self.offline(ctx),
+            }
+            return;
+        }
+        if self.last_event_id.is_some() {
+            self.server_addr.do_send(Join {
+                ses_id: self.ses_id,
+                pub_id: self.pub_id,
+            });
+            self.fetch(ctx);
+            return;
+        }
+        self.actions
+            .send(ReadingsAfter {
+                pub_id: self.pub_id,
+                after: None,
+                limit: 1,
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(latest) => {
+                        act.cursor = latest.last().map_or(0, |reading| reading.id);
+                        act.server_addr.do_send(Join {
+                            ses_id: act.ses_id,
+                            pub_id: act.pub_id,
+                        });
+                    }
+                    Err(err) => {
+                        let _entered = act.span.enter();
+                        tracing::error!(error = ?err, "couldn't load latest reading");
+                        ctx.stop();
+                    }
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+}
+
+impl Actor for SseSession {
+    type Context = Context<Self>;
+
+    // register with the relay server as a subscriber then join the publisher
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        self.send("retry: 5000\n\n".to_owned(), ctx);
+        ctx.run_interval(config::timeouts().heartbeat, |act, ctx| {
+            act.send(": keep-alive\n\n".to_owned(), ctx);
+        });
+        self.server_addr
+            .send(relay_server::Connect {
+                ses_role: Role::Subscriber(0),
+                addr: ctx.address().recipient(),
+                close: ctx.address().recipient(),
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(ses_id) => {
+                        act.ses_id = ses_id;
+                        act.span.record("id", &ses_id);
+                        act.span.in_scope(|| {
+                            tracing::info!(last_event_id = ?act.last_event_id, "sse session started")
+                        });
+                        act.join(ctx);
+                    }
+                    Err(err) => {
+                        let _entered = act.span.enter();
+                        tracing::error!(error = ?err, "sse connect failed");
+                        ctx.stop();
+                    }
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn stopping(&mut self, ctx: &mut Context<Self>) -> Running {
+        let _entered = self.span.enter();
+        tracing::info!("sse session stopping");
+        self.server_addr.do_send(relay_server::Disconnect {
+            ses_id: self.ses_id,
+            addr: ctx.address().recipient(),
+        });
+        Running::Stop
+    }
+}
+
+/// Handle messages from relay server, written to the event stream
+impl Handler<relay_server::Message> for SseSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: relay_server::Message, ctx: &mut Context<Self>) {
+        self.relay(&msg.0, ctx);
+    }
+}
+
+/// Handle the relay server closing the session, the client is sent a `close`
+/// event with the reason before the stream ends
+impl Handler<relay_server::Close> for SseSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: relay_server::Close, ctx: &mut Context<Self>) {
+        self.send(event(None, "close", &msg.0), ctx);
+        ctx.stop();
+    }
+}
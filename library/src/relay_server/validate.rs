@@ -0,0 +1,260 @@
+//! Checks publishers' readings before they're relayed: the reading must be from
+//! the publisher sending it, within its sensor's range, and read at about the
+//! server's time. A reading that passes is relayed as the server serializes
+//! it, not as the publisher sent it.
+use crate::{anomaly, metrics, relay_server::Reading};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReadingsConfig {
+    /// reject readings outside their sensor's range rather than storing them
+    /// flagged `out_of_range`
+    pub reject_out_of_range: bool,
+    /// furthest a reading's `read_time` may be from the server's clock, 0
+    /// disables the check
+    pub max_clock_skew_secs: u64,
+    /// move skewed websocket readings to the time they were received instead
+    /// of rejecting them
+    pub correct_clock_skew: bool,
+}
+
+impl Default for ReadingsConfig {
+    fn default() -> ReadingsConfig {
+        ReadingsConfig {
+            reject_out_of_range: true,
+            max_clock_skew_secs: 5 * 60,
+            correct_clock_skew: false,
+        }
+    }
+}
+
+/// why a reading wasn't relayed
+#[derive(Debug)]
+pub enum Rejection {
+    PubId { reading: u64, publisher: u64 },
+    OutOfRange { eco2: u16, evtoc: u16 },
+    ClockSkew { secs: i64 },
+}
+
+impl Rejection {
+    /// label of `air_meter_readings_rejected_total`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::PubId { .. } => "pub_id",
+            Rejection::OutOfRange { .. } => "out_of_range",
+            Rejection::ClockSkew { .. } => "clock_skew",
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::PubId { reading, publisher } => write!(
+                f,
+                "reading pub_id {} doesn't match publisher {}",
+                reading, publisher
+            ),
+            Rejection::OutOfRange { eco2, evtoc } => write!(
+                f,
+                "eco2 {} or evtoc {} outside the sensor's range",
+                eco2, evtoc
+            ),
+            Rejection::ClockSkew { secs } => write!(
+                f,
+                "read_time is {}s {} the server's clock",
+                secs.abs(),
+                if *secs > 0 { "ahead of" } else { "behind" }
+            ),
+        }
+    }
+}
+
+/// check `reading` from `pub_id`, received at `now`, filling in a missing
+/// pub_id. `live` readings are sent as they're read, so may be neither ahead
+/// of nor behind the server's clock, others may be sent long after and are
+/// only checked for being ahead of it
+pub fn normalize(
+    reading: &mut Reading,
+    pub_id: u64,
+    now: u64,
+    live: bool,
+    config: &ReadingsConfig,
+) -> Result<(), Rejection> {
+    let result = check(reading, pub_id, now, live, config);
+    if let Err(rejection) = &result {
+        metrics::get()
+            .readings_rejected
+            .with_label_values(&[rejection.as_str()])
+            .inc();
+    }
+    result
+}
+
+fn check(
+    reading: &mut Reading,
+    pub_id: u64,
+    now: u64,
+    live: bool,
+    config: &ReadingsConfig,
+) -> Result<(), Rejection> {
+    match reading.pub_id {
+        0 => reading.pub_id = pub_id,
+        id if id != pub_id => {
+            return Err(Rejection::PubId {
+                reading: id,
+                publisher: pub_id,
+            })
+        }
+        _ => {}
+    }
+    if config.reject_out_of_range && !anomaly::in_range(reading) {
+        return Err(Rejection::OutOfRange {
+            eco2: reading.eco2,
+            evtoc: reading.evtoc,
+        });
+    }
+    let skew = reading.read_time as i64 - now as i64;
+    if live {
+        metrics::get()
+            .clock_skew
+            .with_label_values(&[&pub_id.to_string()])
+            .set(skew);
+    }
+    let max = config.max_clock_skew_secs as i64;
+    if max == 0 || skew <= max && (!live || skew >= -max) {
+        return Ok(());
+    }
+    if !(live && config.correct_clock_skew) {
+        return Err(Rejection::ClockSkew { secs: skew });
+    }
+    // keep how long the sensor has been running
+    tracing::debug!(pub_id, skew, "correcting clock skew");
+    reading.start_time = (reading.start_time as i64 - skew).max(0) as u64;
+    reading.read_time = now;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_630_000_000;
+
+    fn reading(pub_id: u64, eco2: u16, read_time: u64) -> Reading {
+        serde_json::from_value(serde_json::json!({
+            "pub_id": pub_id,
+            "eco2": eco2,
+            "evtoc": 10,
+            "read_time": read_time,
+            "start_time": read_time - 100,
+            "increment": "ConstantPower1s",
+        }))
+        .unwrap()
+    }
+
+    fn checked(
+        mut reading: Reading,
+        live: bool,
+        config: &ReadingsConfig,
+    ) -> Result<Reading, String> {
+        check(&mut reading, 811, NOW, live, config)
+            .map(|_| reading)
+            .map_err(|rejection| rejection.as_str().to_owned())
+    }
+
+    #[test]
+    fn readings_are_for_their_publisher() {
+        let config = ReadingsConfig::default();
+        assert_eq!(
+            checked(reading(0, 600, NOW), true, &config).unwrap().pub_id,
+            811
+        );
+        assert_eq!(
+            checked(reading(811, 600, NOW), true, &config)
+                .unwrap()
+                .pub_id,
+            811
+        );
+        assert_eq!(
+            checked(reading(812, 600, NOW), true, &config).unwrap_err(),
+            "pub_id"
+        );
+    }
+
+    #[test]
+    fn out_of_range_readings_are_rejected_unless_configured() {
+        let mut config = ReadingsConfig::default();
+        assert_eq!(
+            checked(reading(811, 9000, NOW), true, &config).unwrap_err(),
+            "out_of_range"
+        );
+        config.reject_out_of_range = false;
+        assert!(checked(reading(811, 9000, NOW), true, &config).is_ok());
+    }
+
+    #[test]
+    fn live_readings_are_near_the_servers_clock() {
+        let mut config = ReadingsConfig::default();
+        assert!(checked(reading(811, 600, NOW + 300), true, &config).is_ok());
+        assert!(checked(reading(811, 600, NOW - 300), true, &config).is_ok());
+        assert_eq!(
+            checked(reading(811, 600, NOW + 301), true, &config).unwrap_err(),
+            "clock_skew"
+        );
+        assert_eq!(
+            checked(reading(811, 600, NOW - 301), true, &config).unwrap_err(),
+            "clock_skew"
+        );
+        // others may be old, but not from the future
+        assert!(checked(reading(811, 600, NOW - 86400), false, &config).is_ok());
+        assert_eq!(
+            checked(reading(811, 600, NOW + 301), false, &config).unwrap_err(),
+            "clock_skew"
+        );
+        config.max_clock_skew_secs = 0;
+        assert!(checked(reading(811, 600, NOW + 86400), true, &config).is_ok());
+    }
+
+    #[test]
+    fn skewed_live_readings_can_be_corrected() {
+        let config = ReadingsConfig {
+            correct_clock_skew: true,
+            ..ReadingsConfig::default()
+        };
+        let corrected = checked(reading(811, 600, NOW - 3600), true, &config).unwrap();
+        assert_eq!(
+            (corrected.read_time, corrected.start_time),
+            (NOW, NOW - 100)
+        );
+        let corrected = checked(reading(811, 600, NOW + 3600), true, &config).unwrap();
+        assert_eq!(
+            (corrected.read_time, corrected.start_time),
+            (NOW, NOW - 100)
+        );
+        // only live readings are moved
+        assert_eq!(
+            checked(reading(811, 600, NOW + 3600), false, &config).unwrap_err(),
+            "clock_skew"
+        );
+    }
+
+    #[test]
+    fn rejections_explain_themselves() {
+        let skew = Rejection::ClockSkew { secs: -400 };
+        assert_eq!(
+            skew.to_string(),
+            "read_time is 400s behind the server's clock"
+        );
+        let pub_id = Rejection::PubId {
+            reading: 812,
+            publisher: 811,
+        };
+        assert_eq!(
+            pub_id.to_string(),
+            "reading pub_id 812 doesn't match publisher 811"
+        );
+    }
+}
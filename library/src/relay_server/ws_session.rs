@@ -1,19 +1,24 @@
-use std::time::Instant;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::{Instant, SystemTime};
 
 use actix::prelude::*;
 
 use actix_web::{error, web, Error, HttpRequest, HttpResponse};
 
+use actix_http::ws::Codec;
 use actix_web_actors::ws;
 
 use crate::{
+    metrics,
+    rate_limit::{IpLimiter, Limits, RateLimits, TokenBucket},
     relay_server,
     relay_server::{
-        server::RelayServer, Join, ListSubs, PublisherMessage as PubMsg, Reading, Role,
+        server::RelayServer, validate, Join, ListSubs, PublisherMessage as PubMsg, Reading, Role,
     },
 };
 
-use crate::{CLIENT_TIMEOUT, HEARTBEAT_INTERVAL};
+use crate::config;
 use serde::{Deserialize, Serialize};
 use serde_json::from_slice;
 
@@ -33,6 +38,17 @@ pub struct WsSession {
     /// relay server
     server_addr: Addr<RelayServer>,
     ses_role: Role,
+    /// carries the session's role and id, entered while handling its events
+    span: tracing::Span,
+    limits: Limits,
+    /// the session's own message rate limit
+    bucket: TokenBucket,
+    /// the rate limit shared with other sessions from the client's ip
+    ip_bucket: Option<(IpLimiter, IpAddr)>,
+    /// publishers joined, at most `ws_max_subscriptions`
+    joined: HashSet<u64>,
+    /// limits exceeded so far, the session is closed at `ws_max_violations`
+    violations: u32,
 }
 
 fn from_json<'a, T>(des: &'a str) -> Result<T, String>
@@ -46,11 +62,13 @@ impl WsSession {
     // helper method that sends intermittent ping to client
     // also checks ws client heartbeat and terminates session on timeout
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+        let timeouts = config::timeouts();
+        ctx.run_interval(timeouts.heartbeat, move |act, ctx| {
             // check client hearbeats
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+            if Instant::now().duration_since(act.hb) > timeouts.client {
                 // heartbeat timed out
-                println!("[srv/s] {:?} TIMED OUT, DISCONNECTING", act.ses_role);
+                let _entered = act.span.enter();
+                tracing::info!("heartbeat timed out, disconnecting");
 
                 // stop actor
                 ctx.stop();
@@ -62,6 +80,39 @@ impl WsSession {
         });
     }
 
+    // takes a token from the session's and its ip's buckets, returning the
+    // limit exceeded if either is empty
+    fn rate_limited(&mut self) -> Option<&'static str> {
+        if self.bucket.take().is_err() {
+            return Some("ws_session");
+        }
+        match &self.ip_bucket {
+            Some((limiter, ip)) if limiter.take(*ip).is_err() => Some("ws_ip"),
+            _ => None,
+        }
+    }
+
+    // tells the client it exceeded `limit`, closing the session once it has
+    // done so `ws_max_violations` times
+    fn violation(&mut self, limit: &str, message: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        metrics::get()
+            .rate_limited
+            .with_label_values(&[limit])
+            .inc();
+        self.violations += 1;
+        let max = self.limits.ws_max_violations;
+        tracing::debug!(limit, violations = self.violations, "limit exceeded");
+        ctx.text(format!("/err {} ({}/{})", message, self.violations, max));
+        if self.violations >= max {
+            tracing::warn!(limit, "too many limit violations, disconnecting");
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some("too many limit violations".to_owned()),
+            }));
+            ctx.stop();
+        }
+    }
+
     //helper method that gets list of subs for client in json format
     fn list_subs(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
         self.server_addr
@@ -84,7 +135,7 @@ impl WsSession {
         text: &str,
         ctx: &mut ws::WebsocketContext<Self>,
     ) -> Result<(), String> {
-        println!("{:?}: {}", self.ses_role, text);
+        tracing::trace!(text, "received");
         let m = text.trim();
         // parse command
         let v: Vec<&str> = m.splitn(2, ' ').collect();
@@ -93,11 +144,28 @@ impl WsSession {
         match self.ses_role {
             Role::Publisher(pub_id) => match cmd {
                 "/reading" => {
-                    let msg_des = from_json::<Reading>(&msg)?;
+                    let mut reading = from_json::<Reading>(&msg)?;
+                    let corr_id = reading.corr_id().to_owned();
+                    tracing::debug!(%corr_id, "reading received");
+                    let now = SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0);
+                    let checks = config::readings();
+                    if let Err(rejection) =
+                        validate::normalize(&mut reading, pub_id, now, true, &checks)
+                    {
+                        tracing::warn!(%corr_id, %rejection, "reading rejected");
+                        ctx.text(format!("/err reading rejected, {}", rejection));
+                        return Ok(());
+                    }
+                    // relayed as the server serializes it, not as it was sent
+                    let json = serde_json::to_string(&reading).map_err(|e| e.to_string())?;
+                    metrics::queued(metrics::RELAY_SERVER);
                     self.server_addr.do_send(PubMsg::<Reading> {
-                        msg: msg_des,
+                        msg: reading,
                         pub_id,
-                        json: msg,
+                        json,
                     });
                     Ok(())
                 }
@@ -107,6 +175,13 @@ impl WsSession {
                 "/join" => {
                     let payload = from_json::<WsJoin>(&msg)?;
                     let WsJoin { pub_id } = payload;
+                    let max = self.limits.ws_max_subscriptions;
+                    if !self.joined.contains(&pub_id) && self.joined.len() >= max {
+                        let message = format!("at most {} subscriptions per session", max);
+                        self.violation("ws_subscriptions", &message, ctx);
+                        return Ok(());
+                    }
+                    self.joined.insert(pub_id);
                     self.server_addr.do_send(Join { ses_id, pub_id });
                     Ok(())
                 }
@@ -129,12 +204,32 @@ impl Handler<relay_server::Message> for WsSession {
     }
 }
 
+/// Handle the relay server closing the session, e.g. on shutdown
+impl Handler<relay_server::Close> for WsSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: relay_server::Close, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Away,
+            description: Some(msg.0),
+        }));
+        ctx.stop();
+    }
+}
+
 impl Actor for WsSession {
     type Context = ws::WebsocketContext<Self>;
 
     // Method is called on actor start
     // register ws session with RelayServer
     fn started(&mut self, ctx: &mut Self::Context) {
+        let span = self.span.clone();
+        let _entered = span.enter();
+        tracing::info!("ws session started");
+        metrics::get()
+            .ws_sessions
+            .with_label_values(&[self.ses_role.name()])
+            .inc();
         // start heartbeat with ws client
         self.hb(ctx);
 
@@ -148,15 +243,20 @@ impl Actor for WsSession {
         self.server_addr
             .send(relay_server::Connect {
                 ses_role: self.ses_role,
-                addr: addr.recipient(),
+                addr: addr.clone().recipient(),
+                close: addr.recipient(),
             })
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(res) => act.ses_role = act.ses_role.replace(res),
+                    Ok(res) => {
+                        act.ses_role = act.ses_role.replace(res);
+                        act.span.record("id", &res);
+                    }
                     // something wrong
                     Err(err) => {
-                        println!("[srv/s] WS CONNECT ERROR: {:?}", err);
+                        let _entered = act.span.enter();
+                        tracing::error!(error = ?err, "ws connect failed");
                         ctx.stop();
                     }
                 }
@@ -165,11 +265,17 @@ impl Actor for WsSession {
             .wait(ctx);
     }
 
-    fn stopping(&mut self, _: &mut Self::Context) -> Running {
-        println!("[srv/s] {:?} WS SESSION STOPPING", self.ses_role);
+    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
+        let _entered = self.span.enter();
+        tracing::info!("ws session stopping");
+        metrics::get()
+            .ws_sessions
+            .with_label_values(&[self.ses_role.name()])
+            .dec();
         // notify relay server
         self.server_addr.do_send(relay_server::Disconnect {
             ses_id: self.ses_role.into(),
+            addr: ctx.address().recipient(),
         });
         Running::Stop
     }
@@ -178,15 +284,40 @@ impl Actor for WsSession {
 // Handles messages from Websocket client, forwarding text to helper method
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let span = self.span.clone();
+        let _entered = span.enter();
         let msg = match msg {
+            Err(ws::ProtocolError::Overflow) => {
+                metrics::get()
+                    .rate_limited
+                    .with_label_values(&["ws_frame"])
+                    .inc();
+                let max = self.limits.ws_max_frame_bytes;
+                tracing::warn!(max, "frame too large, disconnecting");
+                ctx.text(format!("/err frame too large, at most {} bytes", max));
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Size,
+                    description: Some("frame too large".to_owned()),
+                }));
+                ctx.stop();
+                return;
+            }
             Err(err) => {
-                println!("[srv/s] RECEIVED ERROR FROM WS CLIENT {:?}", err);
+                tracing::warn!(error = ?err, "ws protocol error");
                 ctx.stop();
                 return;
             }
             Ok(msg) => msg,
         };
 
+        // commands are dropped while the client is over its rate limit
+        if let ws::Message::Text(_) | ws::Message::Binary(_) = msg {
+            if let Some(limit) = self.rate_limited() {
+                self.violation(limit, "rate limited, message dropped", ctx);
+                return;
+            }
+        }
+
         match msg {
             ws::Message::Ping(msg) => {
                 self.hb = Instant::now();
@@ -195,10 +326,10 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
             ws::Message::Pong(_) => self.hb = Instant::now(),
             ws::Message::Text(text) => {
                 self.parse_message(&text, ctx).unwrap_or_else(|err| {
-                    ctx.text(&format!("COMMAND ERROR: {:?}", err));
+                    ctx.text(format!("COMMAND ERROR: {:?}", err));
                 });
             }
-            ws::Message::Binary(_) => println!("[srv/s] Unexpected binary"),
+            ws::Message::Binary(_) => tracing::warn!("unexpected binary message"),
             ws::Message::Close(reason) => {
                 ctx.close(reason);
                 ctx.stop();
@@ -213,33 +344,40 @@ pub async fn ws_route(
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<Addr<RelayServer>>,
+    rate_limits: web::Data<RateLimits>,
 ) -> Result<HttpResponse, Error> {
     let role: Result<Role, String> = match req.headers().get("authorization") {
         Some(auth) => match auth.to_str() {
             Ok(ses_id_str) => match ses_id_str.parse::<u64>() {
                 Ok(ses_id) => Ok(Role::Publisher(ses_id)),
                 Err(err) => {
-                    println!("[srv/s] {:?}", err);
+                    tracing::warn!(error = ?err, "invalid publisher id");
                     Err(format!("couldn't parse {}", ses_id_str))
                 }
             },
             Err(err) => {
-                println!("[srv/s] {:?}", err);
+                tracing::warn!(error = ?err, "invalid authorization header");
                 Err("couldn't convert auth header to string".to_owned())
             }
         },
         None => Ok(Role::Subscriber(0)),
     };
-    match role {
-        Ok(role) => ws::start(
-            WsSession {
-                hb: Instant::now(),
-                ses_role: role,
-                server_addr: srv.get_ref().clone(),
-            },
-            &req,
-            stream,
-        ),
-        Err(msg) => Err(error::ErrorBadRequest(msg)),
-    }
+    let role = role.map_err(error::ErrorBadRequest)?;
+    let limits = config::limits();
+    let session = WsSession {
+        hb: Instant::now(),
+        ses_role: role,
+        server_addr: srv.get_ref().clone(),
+        span: tracing::info_span!("ws_session", role = role.name(), id = tracing::field::Empty),
+        limits,
+        bucket: TokenBucket::new(limits.ws_messages_per_sec, limits.ws_message_burst),
+        ip_bucket: req
+            .peer_addr()
+            .map(|addr| (rate_limits.ws.clone(), addr.ip())),
+        joined: HashSet::new(),
+        violations: 0,
+    };
+    // frames over `ws_max_frame_bytes` end the stream with an overflow error
+    let codec = Codec::new().max_size(limits.ws_max_frame_bytes);
+    Ok(ws::handshake(&req)?.streaming(ws::WebsocketContext::with_codec(session, stream, codec)))
 }
@@ -0,0 +1,674 @@
+//! Daily and weekly per-publisher reports summarising stored readings: time
+//! spent above the eCO2 and TVOC thresholds, each UTC day's min/max/mean, the
+//! worst hours, alerts fired and how much of the period the sensor reported.
+//!
+//! `Reports` writes the previous day's reports as json and html after each UTC
+//! midnight, and weekly reports on Mondays, posting each one to webhooks
+//! subscribed to `report`.
+use crate::common::{AggregateReadings, GetAlertHistory, GetAlertRules, GetPublishers};
+use crate::db::{model::ReadingBucket, Actions};
+use crate::iaq::Iaq;
+use crate::templates;
+use crate::webhooks::{self, Notify, Webhooks};
+use actix::prelude::*;
+use chrono::{Datelike, Duration as ChronoDuration, NaiveDate, TimeZone, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use utoipa::{IntoParams, ToSchema};
+
+/// width of the buckets reports are built from, in seconds
+const BUCKET_SECS: i64 = 60;
+/// hours listed as the worst of a period
+const WORST_HOURS: usize = 5;
+/// time after midnight UTC the scheduled reports are generated, so late
+/// readings are included
+const SCHEDULE_DELAY: ChronoDuration = ChronoDuration::minutes(5);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    #[default]
+    Daily,
+    Weekly,
+}
+
+impl Period {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Period::Daily => "daily",
+            Period::Weekly => "weekly",
+        }
+    }
+
+    /// unix seconds from the start of the period to the end of `last_day`
+    pub fn range(self, last_day: NaiveDate) -> (i64, i64) {
+        let days = match self {
+            Period::Daily => 1,
+            Period::Weekly => 7,
+        };
+        let midnight = |day: NaiveDate| {
+            Utc.from_utc_datetime(&day.and_hms_opt(0, 0, 0).unwrap())
+                .timestamp()
+        };
+        let to = midnight(last_day + ChronoDuration::days(1));
+        (to - days * 24 * 60 * 60, to)
+    }
+}
+
+/// `?period=daily|weekly&date=YYYY-MM-DD` of a report request
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReportQuery {
+    pub period: Option<Period>,
+    /// last day of the period, defaults to today
+    pub date: Option<String>,
+}
+
+impl ReportQuery {
+    pub fn resolve(&self) -> Result<(Period, NaiveDate), String> {
+        let date = match &self.date {
+            Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .map_err(|e| format!("date {:?}: {}", date, e))?,
+            None => Utc::now().date_naive(),
+        };
+        Ok((self.period.unwrap_or_default(), date))
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Thresholds {
+    /// ppm
+    pub eco2: f64,
+    /// ppb
+    pub evtoc: f64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportConfig {
+    /// scheduled reports are written here, none disables the schedule
+    pub dir: Option<PathBuf>,
+    /// eCO2 ppm time above is reported for
+    pub eco2_threshold: f64,
+    /// TVOC ppb time above is reported for
+    pub evtoc_threshold: f64,
+}
+
+impl Default for ReportConfig {
+    fn default() -> ReportConfig {
+        ReportConfig {
+            dir: None,
+            eco2_threshold: 1000.0,
+            evtoc_threshold: 660.0,
+        }
+    }
+}
+
+impl ReportConfig {
+    pub fn thresholds(&self) -> Thresholds {
+        Thresholds {
+            eco2: self.eco2_threshold,
+            evtoc: self.evtoc_threshold,
+        }
+    }
+
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        let thresholds = [
+            ("eco2_threshold", self.eco2_threshold),
+            ("evtoc_threshold", self.evtoc_threshold),
+        ];
+        for (key, threshold) in thresholds.iter() {
+            if !threshold.is_finite() || *threshold <= 0.0 {
+                errors.push(format!("report.{}: must be above 0", key));
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TimeAbove {
+    pub threshold: f64,
+    pub secs: i64,
+    /// of the time the sensor reported
+    pub percent: f64,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct Stats {
+    pub min: i32,
+    pub max: i32,
+    pub mean: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DaySummary {
+    /// `YYYY-MM-DD`, UTC
+    pub date: String,
+    pub readings: i64,
+    pub eco2: Stats,
+    /// none when no reading of the day had a TVOC value
+    pub evtoc: Option<Stats>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HourSummary {
+    pub start: i64,
+    pub eco2_mean: f64,
+    pub evtoc_mean: Option<f64>,
+    pub iaq: Iaq,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AlertCount {
+    pub rule_id: i32,
+    pub name: String,
+    pub fired: u32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Uptime {
+    /// time with at least one reading, to the minute
+    pub reporting_secs: i64,
+    /// time elapsed in the period
+    pub period_secs: i64,
+    pub percent: f64,
+    pub longest_gap_secs: i64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Report {
+    pub pub_id: u64,
+    pub period: Period,
+    /// unix seconds, `to` is exclusive
+    pub from: i64,
+    pub to: i64,
+    pub generated: i64,
+    pub readings: i64,
+    pub suspect_readings: i64,
+    pub eco2_above: TimeAbove,
+    pub evtoc_above: TimeAbove,
+    pub days: Vec<DaySummary>,
+    /// highest mean eCO2 hours, worst first
+    pub worst_hours: Vec<HourSummary>,
+    pub alerts_fired: u32,
+    pub alerts: Vec<AlertCount>,
+    pub uptime: Uptime,
+}
+
+/// min/max/weighted mean over buckets, none if no bucket has a value
+fn stats<'a>(
+    buckets: impl Iterator<Item = &'a ReadingBucket>,
+    value: impl Fn(&ReadingBucket) -> Option<(i32, i32, f64)>,
+) -> Option<Stats> {
+    let (mut min, mut max, mut sum, mut count) = (i32::MAX, i32::MIN, 0.0, 0);
+    for bucket in buckets {
+        if let Some((b_min, b_max, b_mean)) = value(bucket) {
+            min = min.min(b_min);
+            max = max.max(b_max);
+            sum += b_mean * bucket.readings as f64;
+            count += bucket.readings;
+        }
+    }
+    match count {
+        0 => None,
+        _ => Some(Stats {
+            min,
+            max,
+            mean: sum / count as f64,
+        }),
+    }
+}
+
+fn eco2_of(bucket: &ReadingBucket) -> Option<(i32, i32, f64)> {
+    Some((bucket.eco2_min, bucket.eco2_max, bucket.eco2_mean))
+}
+
+fn evtoc_of(bucket: &ReadingBucket) -> Option<(i32, i32, f64)> {
+    Some((bucket.evtoc_min?, bucket.evtoc_max?, bucket.evtoc_mean?))
+}
+
+fn percent(part: i64, whole: i64) -> f64 {
+    match whole {
+        0 => 0.0,
+        _ => 100.0 * part as f64 / whole as f64,
+    }
+}
+
+/// build a report from per-minute buckets in ascending order, the (rule_id,
+/// event_time) of firing alert events and every alert rule's name
+fn summarize(
+    pub_id: u64,
+    period: Period,
+    (from, to): (i64, i64),
+    thresholds: &Thresholds,
+    buckets: &[ReadingBucket],
+    fired: &[(i32, i64)],
+    rule_names: &HashMap<i32, String>,
+) -> Report {
+    let reporting_secs = buckets.len() as i64 * BUCKET_SECS;
+    let above = |threshold: f64, value: fn(&ReadingBucket) -> Option<f64>| {
+        let secs = buckets
+            .iter()
+            .filter(|b| value(b).is_some_and(|v| v > threshold))
+            .count() as i64
+            * BUCKET_SECS;
+        TimeAbove {
+            threshold,
+            secs,
+            percent: percent(secs, reporting_secs),
+        }
+    };
+
+    let mut days: BTreeMap<NaiveDate, Vec<&ReadingBucket>> = BTreeMap::new();
+    let mut hours: BTreeMap<i64, Vec<&ReadingBucket>> = BTreeMap::new();
+    for bucket in buckets {
+        if let Some(time) = Utc.timestamp_opt(bucket.start, 0).single() {
+            days.entry(time.date_naive()).or_default().push(bucket);
+        }
+        hours
+            .entry(bucket.start - bucket.start.rem_euclid(60 * 60))
+            .or_default()
+            .push(bucket);
+    }
+    let days = days
+        .into_iter()
+        .filter_map(|(date, day)| {
+            Some(DaySummary {
+                date: date.format("%Y-%m-%d").to_string(),
+                readings: day.iter().map(|b| b.readings).sum(),
+                eco2: stats(day.iter().copied(), eco2_of)?,
+                evtoc: stats(day.iter().copied(), evtoc_of),
+            })
+        })
+        .collect();
+    let mut worst_hours = hours
+        .into_iter()
+        .filter_map(|(start, hour)| {
+            let eco2_mean = stats(hour.iter().copied(), eco2_of)?.mean;
+            let evtoc_mean = stats(hour.iter().copied(), evtoc_of).map(|s| s.mean);
+            Some(HourSummary {
+                start,
+                eco2_mean,
+                evtoc_mean,
+                iaq: Iaq::new(eco2_mean, evtoc_mean),
+            })
+        })
+        .collect::<Vec<HourSummary>>();
+    worst_hours.sort_by(|a, b| b.eco2_mean.total_cmp(&a.eco2_mean));
+    worst_hours.truncate(WORST_HOURS);
+
+    let mut counts: BTreeMap<i32, u32> = BTreeMap::new();
+    for (rule_id, _) in fired.iter().filter(|(_, t)| (from..to).contains(t)) {
+        *counts.entry(*rule_id).or_default() += 1;
+    }
+    let alerts = counts
+        .into_iter()
+        .map(|(rule_id, fired)| AlertCount {
+            rule_id,
+            name: rule_names.get(&rule_id).cloned().unwrap_or_default(),
+            fired,
+        })
+        .collect::<Vec<AlertCount>>();
+
+    // gaps between reporting minutes, and before the first and after the last
+    let now = Utc::now().timestamp();
+    let end = to.min(now).max(from);
+    let mut longest_gap_secs = 0;
+    let mut last_end = from;
+    for bucket in buckets {
+        longest_gap_secs = longest_gap_secs.max(bucket.start - last_end);
+        last_end = bucket.start + BUCKET_SECS;
+    }
+    longest_gap_secs = longest_gap_secs.max(end - last_end);
+
+    Report {
+        pub_id,
+        period,
+        from,
+        to,
+        generated: now,
+        readings: buckets.iter().map(|b| b.readings).sum(),
+        suspect_readings: buckets.iter().map(|b| b.suspect).sum(),
+        eco2_above: above(thresholds.eco2, |b| Some(b.eco2_mean)),
+        evtoc_above: above(thresholds.evtoc, |b| b.evtoc_mean),
+        days,
+        worst_hours,
+        alerts_fired: alerts.iter().map(|a| a.fired).sum(),
+        alerts,
+        uptime: Uptime {
+            reporting_secs,
+            period_secs: end - from,
+            percent: percent(reporting_secs, end - from),
+            longest_gap_secs,
+        },
+    }
+}
+
+/// report on a publisher for the period ending with `last_day`
+pub async fn generate(
+    actions: &Addr<Actions>,
+    pub_id: u64,
+    period: Period,
+    last_day: NaiveDate,
+    thresholds: &Thresholds,
+) -> Result<Report, String> {
+    let (from, to) = period.range(last_day);
+    let buckets = actions
+        .send(AggregateReadings {
+            pub_id,
+            from: Some(from as u64),
+            to: Some(to as u64),
+            bucket: BUCKET_SECS as u32,
+            limit: ((to - from) / BUCKET_SECS) as u16,
+        })
+        .await
+        .map_err(|e| format!("{}", e))??;
+    // newest first, the period's events are those before its end
+    let history = actions
+        .send(GetAlertHistory {
+            pub_id: Some(pub_id),
+            before: Some(to as u64),
+            limit: u16::MAX,
+        })
+        .await
+        .map_err(|e| format!("{}", e))?;
+    let fired = history
+        .iter()
+        .filter(|e| e.state == "firing")
+        .map(|e| (e.rule_id, e.event_time))
+        .collect::<Vec<(i32, i64)>>();
+    let rules = actions
+        .send(GetAlertRules {
+            enabled_only: false,
+        })
+        .await
+        .map_err(|e| format!("{}", e))?;
+    let rule_names = rules.into_iter().map(|r| (r.id, r.name)).collect();
+    Ok(summarize(
+        pub_id,
+        period,
+        (from, to),
+        thresholds,
+        &buckets,
+        &fired,
+        &rule_names,
+    ))
+}
+
+/// Writes reports for every publisher with readings once each day, and week,
+/// is over
+pub struct Reports {
+    actions: Addr<Actions>,
+    webhooks: Addr<Webhooks>,
+    dir: PathBuf,
+    thresholds: Thresholds,
+}
+
+impl Actor for Reports {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        tracing::info!(dir = ?self.dir, "reports will be written");
+        self.schedule(ctx);
+    }
+}
+
+impl Reports {
+    /// none if `config` has no report directory
+    pub fn new(
+        config: ReportConfig,
+        actions: Addr<Actions>,
+        webhooks: Addr<Webhooks>,
+    ) -> Option<Reports> {
+        Some(Reports {
+            actions,
+            webhooks,
+            thresholds: config.thresholds(),
+            dir: config.dir?,
+        })
+    }
+
+    /// run after the next midnight UTC, reporting on the day that just ended
+    fn schedule(&self, ctx: &mut Context<Self>) {
+        let now = Utc::now();
+        let today = now.date_naive();
+        let next = Utc.from_utc_datetime(
+            &(today + ChronoDuration::days(1))
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+        ) + SCHEDULE_DELAY;
+        let wait = (next - now).to_std().unwrap_or_default();
+        ctx.run_later(wait, move |act, ctx| {
+            act.write_reports(ctx, today);
+            act.schedule(ctx);
+        });
+    }
+
+    fn write_reports(&self, ctx: &mut Context<Self>, day: NaiveDate) {
+        let mut periods = vec![Period::Daily];
+        if day.weekday() == Weekday::Sun {
+            periods.push(Period::Weekly);
+        }
+        let (actions, webhooks) = (self.actions.clone(), self.webhooks.clone());
+        let (dir, thresholds) = (self.dir.clone(), self.thresholds.clone());
+        ctx.spawn(
+            async move {
+                for period in periods {
+                    let (from, to) = period.range(day);
+                    let publishers = match actions
+                        .send(GetPublishers {
+                            from: from as u64,
+                            to: to as u64,
+                        })
+                        .await
+                        .map_err(|e| format!("{}", e))
+                        .and_then(|p| p)
+                    {
+                        Ok(publishers) => publishers,
+                        Err(err) => {
+                            tracing::error!(error = %err, "couldn't list publishers");
+                            return;
+                        }
+                    };
+                    for pub_id in publishers {
+                        match generate(&actions, pub_id, period, day, &thresholds).await {
+                            Ok(report) => {
+                                if let Err(err) = write(&dir, &report, day) {
+                                    tracing::error!(error = %err, "couldn't write report");
+                                }
+                                webhooks.do_send(Notify {
+                                    event: webhooks::REPORT,
+                                    data: serde_json::to_value(&report).unwrap(),
+                                });
+                            }
+                            Err(err) => tracing::error!(
+                                pub_id,
+                                period = period.as_str(),
+                                error = %err,
+                                "report failed"
+                            ),
+                        }
+                    }
+                }
+            }
+            .into_actor(self),
+        );
+    }
+}
+
+/// `<dir>/<pub_id>/<period>-<last day>.json` and `.html`
+fn write(dir: &std::path::Path, report: &Report, day: NaiveDate) -> Result<(), String> {
+    let dir = dir.join(report.pub_id.to_string());
+    fs::create_dir_all(&dir).map_err(|e| format!("{}", e))?;
+    let stem = format!("{}-{}", report.period.as_str(), day.format("%Y-%m-%d"));
+    let json = serde_json::to_string_pretty(report).map_err(|e| format!("{}", e))?;
+    fs::write(dir.join(format!("{}.json", stem)), json).map_err(|e| format!("{}", e))?;
+    let html = templates::render_report(report).map_err(|e| format!("{}", e))?;
+    fs::write(dir.join(format!("{}.html", stem)), html).map_err(|e| format!("{}", e))?;
+    tracing::info!(path = ?dir.join(&stem), "report written");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2021-08-01, midnight to midnight UTC
+    const DAY: (i64, i64) = (1_627_776_000, 1_627_862_400);
+
+    /// a minute of `readings` readings at `minute` past the start of the day
+    fn bucket(
+        minute: i64,
+        readings: i64,
+        eco2: (i32, i32, f64),
+        evtoc: Option<f64>,
+    ) -> ReadingBucket {
+        ReadingBucket {
+            start: DAY.0 + minute * BUCKET_SECS,
+            readings,
+            suspect: 0,
+            eco2_mean: eco2.2,
+            eco2_min: eco2.0,
+            eco2_max: eco2.1,
+            evtoc_mean: evtoc,
+            evtoc_min: evtoc.map(|v| v as i32),
+            evtoc_max: evtoc.map(|v| v as i32),
+        }
+    }
+
+    fn report(buckets: &[ReadingBucket]) -> Report {
+        let thresholds = Thresholds {
+            eco2: 1000.0,
+            evtoc: 660.0,
+        };
+        summarize(
+            811,
+            Period::Daily,
+            DAY,
+            &thresholds,
+            buckets,
+            &[],
+            &HashMap::new(),
+        )
+    }
+
+    #[test]
+    fn range_covers_the_period_up_to_the_end_of_its_last_day() {
+        let day = NaiveDate::from_ymd_opt(2021, 8, 1).unwrap();
+        assert_eq!(Period::Daily.range(day), DAY);
+        assert_eq!(Period::Weekly.range(day), (DAY.1 - 7 * 86_400, DAY.1));
+    }
+
+    #[test]
+    fn stats_weigh_bucket_means_by_their_readings() {
+        let buckets = [
+            bucket(0, 1, (400, 400, 400.0), Some(100.0)),
+            bucket(1, 3, (600, 1000, 800.0), None),
+        ];
+        let eco2 = stats(buckets.iter(), eco2_of).unwrap();
+        assert_eq!((eco2.min, eco2.max), (400, 1000));
+        assert_eq!(eco2.mean, 700.0);
+        // buckets without a TVOC value don't count towards its mean
+        let evtoc = stats(buckets.iter(), evtoc_of).unwrap();
+        assert_eq!((evtoc.min, evtoc.max, evtoc.mean), (100, 100, 100.0));
+        assert!(stats(buckets[1..].iter(), evtoc_of).is_none());
+        assert!(stats([].iter(), eco2_of).is_none());
+    }
+
+    #[test]
+    fn time_above_counts_minutes_over_the_threshold() {
+        let report = report(&[
+            bucket(0, 2, (500, 700, 600.0), Some(700.0)),
+            bucket(1, 2, (900, 1300, 1100.0), Some(200.0)),
+            bucket(2, 2, (1000, 1000, 1000.0), None),
+            bucket(3, 2, (1200, 1600, 1400.0), None),
+        ]);
+        assert_eq!(report.eco2_above.threshold, 1000.0);
+        assert_eq!(report.eco2_above.secs, 120);
+        assert_eq!(report.eco2_above.percent, 50.0);
+        assert_eq!(report.evtoc_above.secs, 60);
+        assert_eq!(report.evtoc_above.percent, 25.0);
+    }
+
+    #[test]
+    fn summarizes_days_and_worst_hours() {
+        let report = report(&[
+            bucket(0, 2, (400, 600, 500.0), Some(100.0)),
+            bucket(60, 1, (1400, 1400, 1400.0), None),
+            bucket(61, 1, (800, 800, 800.0), Some(300.0)),
+        ]);
+        assert_eq!(report.readings, 4);
+        assert_eq!(report.days.len(), 1);
+        let day = &report.days[0];
+        assert_eq!(day.date, "2021-08-01");
+        assert_eq!(day.readings, 4);
+        assert_eq!(
+            (day.eco2.min, day.eco2.max, day.eco2.mean),
+            (400, 1400, 800.0)
+        );
+        let evtoc = day.evtoc.as_ref().unwrap();
+        assert_eq!((evtoc.min, evtoc.max, evtoc.mean), (100, 300, 500.0 / 3.0));
+        let hours = report
+            .worst_hours
+            .iter()
+            .map(|h| (h.start, h.eco2_mean, h.evtoc_mean))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            hours,
+            [
+                (DAY.0 + 3600, 1100.0, Some(300.0)),
+                (DAY.0, 500.0, Some(100.0))
+            ]
+        );
+        assert_eq!(report.uptime.reporting_secs, 180);
+        assert_eq!(report.uptime.period_secs, 86_400);
+        // from the last reporting minute to the end of the day
+        assert_eq!(report.uptime.longest_gap_secs, 86_400 - 62 * 60);
+    }
+
+    #[test]
+    fn an_empty_window_reports_nothing_above_and_no_uptime() {
+        let report = report(&[]);
+        assert_eq!((report.from, report.to), DAY);
+        assert_eq!(report.readings, 0);
+        assert_eq!(report.suspect_readings, 0);
+        assert_eq!(
+            (report.eco2_above.secs, report.eco2_above.percent),
+            (0, 0.0)
+        );
+        assert_eq!(
+            (report.evtoc_above.secs, report.evtoc_above.percent),
+            (0, 0.0)
+        );
+        assert!(report.days.is_empty());
+        assert!(report.worst_hours.is_empty());
+        assert_eq!(report.alerts_fired, 0);
+        assert_eq!(report.uptime.reporting_secs, 0);
+        assert_eq!(report.uptime.percent, 0.0);
+        assert_eq!(report.uptime.longest_gap_secs, 86_400);
+    }
+
+    #[test]
+    fn counts_alerts_fired_in_the_period() {
+        let names = vec![(1, "stuffy".to_owned())].into_iter().collect();
+        let fired = [(1, DAY.0 - 1), (1, DAY.0), (1, DAY.1 - 1), (2, DAY.0 + 10)];
+        let report = summarize(
+            811,
+            Period::Daily,
+            DAY,
+            &ReportConfig::default().thresholds(),
+            &[],
+            &fired,
+            &names,
+        );
+        assert_eq!(report.alerts_fired, 3);
+        let alerts = report
+            .alerts
+            .iter()
+            .map(|a| (a.rule_id, a.name.as_str(), a.fired))
+            .collect::<Vec<_>>();
+        assert_eq!(alerts, [(1, "stuffy", 2), (2, "", 1)]);
+    }
+}
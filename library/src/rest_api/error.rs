@@ -0,0 +1,98 @@
+//! Every REST error is answered with the same JSON body,
+//! `{"error": "not_found", "message": "no such backup"}`, including requests
+//! the extractors reject before reaching a handler.
+use actix_web::{
+    error::{JsonPayloadError, ResponseError},
+    http::StatusCode,
+    web, HttpResponse,
+};
+use serde::Serialize;
+use std::fmt;
+use utoipa::ToSchema;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiError {
+    #[serde(skip)]
+    pub status: StatusCode,
+    /// `bad_request`, `unauthorized`, `forbidden`, `not_found`, `too_large`,
+    /// `too_many_requests` or `internal`
+    #[schema(example = "not_found")]
+    pub error: &'static str,
+    #[schema(example = "no such backup")]
+    pub message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, error: &'static str, message: impl fmt::Display) -> ApiError {
+        ApiError {
+            status,
+            error,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn bad_request(message: impl fmt::Display) -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    pub fn unauthorized(message: impl fmt::Display) -> ApiError {
+        ApiError::new(StatusCode::UNAUTHORIZED, "unauthorized", message)
+    }
+
+    pub fn forbidden(message: impl fmt::Display) -> ApiError {
+        ApiError::new(StatusCode::FORBIDDEN, "forbidden", message)
+    }
+
+    pub fn not_found(message: impl fmt::Display) -> ApiError {
+        ApiError::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    pub fn too_large(message: impl fmt::Display) -> ApiError {
+        ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "too_large", message)
+    }
+
+    pub fn too_many_requests(message: impl fmt::Display) -> ApiError {
+        ApiError::new(StatusCode::TOO_MANY_REQUESTS, "too_many_requests", message)
+    }
+
+    pub fn internal(message: impl fmt::Display) -> ApiError {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", message)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.error, self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(self)
+    }
+}
+
+/// `Json` extractor config answering malformed bodies with an `ApiError`
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|err, _| match err {
+        JsonPayloadError::Overflow => ApiError::too_large(err).into(),
+        err => ApiError::bad_request(err).into(),
+    })
+}
+
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|err, _| ApiError::bad_request(err).into())
+}
+
+pub fn path_config() -> web::PathConfig {
+    web::PathConfig::default().error_handler(|err, _| ApiError::bad_request(err).into())
+}
+
+/// unknown routes under `/api`
+pub async fn not_found() -> Result<HttpResponse, ApiError> {
+    Err(ApiError::not_found("no such route"))
+}
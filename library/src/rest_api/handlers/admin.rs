@@ -0,0 +1,111 @@
+use crate::{
+    db::backup::{BackupPath, Backups, CreateBackup, ListBackups},
+    rest_api::{error::ApiError, handlers::tokens_match},
+};
+use actix::prelude::*;
+use actix_files::NamedFile;
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+
+/// bearer token required by admin routes, admin routes are disabled without one
+#[derive(Clone, Debug)]
+pub struct AdminToken(pub Option<String>);
+
+pub fn authorize(req: &HttpRequest, token: &AdminToken) -> Result<(), ApiError> {
+    let expected = token
+        .0
+        .as_ref()
+        .ok_or_else(|| ApiError::forbidden("admin routes are disabled, set ADMIN_TOKEN"))?;
+    let given = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match given {
+        Some(given) if tokens_match(given, expected) => Ok(()),
+        _ => Err(ApiError::unauthorized("invalid admin token")),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/backups",
+    tag = "admin",
+    responses(
+        (status = 200, description = "backups, newest first", body = [BackupInfo]),
+        (status = 401, description = "missing or wrong admin token", body = ApiError),
+        (status = 403, description = "admin routes are disabled", body = ApiError),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn list_backups(
+    req: HttpRequest,
+    token: web::Data<AdminToken>,
+    backups: web::Data<Addr<Backups>>,
+) -> Result<HttpResponse, ApiError> {
+    authorize(&req, &token)?;
+    let list = backups
+        .get_ref()
+        .send(ListBackups)
+        .await
+        .map_err(ApiError::internal)?;
+    Ok(HttpResponse::Ok().json(list))
+}
+
+/// snapshot the live database into the backup directory
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/backups",
+    tag = "admin",
+    responses(
+        (status = 201, description = "the new backup", body = BackupInfo),
+        (status = 401, description = "missing or wrong admin token", body = ApiError),
+        (status = 403, description = "admin routes are disabled", body = ApiError),
+        (status = 500, description = "the backup failed", body = ApiError),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn create_backup(
+    req: HttpRequest,
+    token: web::Data<AdminToken>,
+    backups: web::Data<Addr<Backups>>,
+) -> Result<HttpResponse, ApiError> {
+    authorize(&req, &token)?;
+    let info = backups
+        .get_ref()
+        .send(CreateBackup)
+        .await
+        .map_err(ApiError::internal)?
+        .map_err(ApiError::internal)?;
+    Ok(HttpResponse::Created().json(info))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/backups/{name}",
+    tag = "admin",
+    params(("name" = String, Path, description = "backup file name")),
+    responses(
+        (status = 200, description = "the sqlite database", content_type = "application/vnd.sqlite3"),
+        (status = 401, description = "missing or wrong admin token", body = ApiError),
+        (status = 403, description = "admin routes are disabled", body = ApiError),
+        (status = 404, description = "no such backup", body = ApiError),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn download_backup(
+    req: HttpRequest,
+    name: web::Path<String>,
+    token: web::Data<AdminToken>,
+    backups: web::Data<Addr<Backups>>,
+) -> Result<NamedFile, ApiError> {
+    authorize(&req, &token)?;
+    let path = backups
+        .get_ref()
+        .send(BackupPath(name.into_inner()))
+        .await
+        .map_err(ApiError::internal)?
+        .ok_or_else(|| ApiError::not_found("no such backup"))?;
+    Ok(NamedFile::open(path)
+        .map_err(ApiError::internal)?
+        .set_content_type("application/vnd.sqlite3".parse().unwrap()))
+}
@@ -0,0 +1,170 @@
+use crate::{
+    alerts::METRICS,
+    common::{AddAlertRule, DisableAlertRule, GetMetricNames},
+    db::{
+        actions::Actions,
+        model::{AlertRule, NewAlertRule},
+    },
+    relay_server::ReloadAlertRules,
+    rest_api::{
+        error::ApiError,
+        handlers::admin::{authorize, AdminToken},
+    },
+    RelayServer,
+};
+use actix::prelude::*;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+/// validate and store a rule, reloading the relay's rules
+pub(crate) async fn create_rule(
+    rule: NewAlertRule,
+    actions: &Addr<Actions>,
+    srv: &Addr<RelayServer>,
+) -> Result<AlertRule, ApiError> {
+    if rule.clear_below > rule.trigger_above {
+        return Err(ApiError::bad_request(
+            "clear_below must not be above trigger_above",
+        ));
+    }
+    if rule.trigger_secs < 0 || rule.clear_secs < 0 {
+        return Err(ApiError::bad_request(
+            "trigger_secs and clear_secs must not be negative",
+        ));
+    }
+    if !METRICS.contains(&rule.metric.as_str()) {
+        let reported = actions
+            .send(GetMetricNames)
+            .await
+            .map_err(ApiError::internal)?
+            .map_err(ApiError::internal)?;
+        if !reported.contains(&rule.metric) {
+            return Err(ApiError::bad_request(format!(
+                "unknown metric {:?}, expected one of {} or a metric a sensor has reported",
+                rule.metric,
+                METRICS.join(", ")
+            )));
+        }
+    }
+    let rule = actions
+        .send(AddAlertRule(rule))
+        .await
+        .map_err(ApiError::internal)?
+        .map_err(ApiError::internal)?;
+    srv.do_send(ReloadAlertRules);
+    Ok(rule)
+}
+
+/// disable a rule, keeping its alert history
+#[utoipa::path(
+    delete,
+    path = "/api/v1/alerts/rules/{id}",
+    tag = "alerts",
+    params(("id" = i32, Path, description = "rule id")),
+    responses(
+        (status = 204, description = "rule disabled"),
+        (status = 401, description = "missing or wrong admin token", body = ApiError),
+        (status = 403, description = "admin routes are disabled", body = ApiError),
+        (status = 404, description = "no such rule", body = ApiError),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn disable_rule(
+    req: HttpRequest,
+    id: web::Path<i32>,
+    token: web::Data<AdminToken>,
+    actions: web::Data<Addr<Actions>>,
+    srv: web::Data<Addr<RelayServer>>,
+) -> Result<HttpResponse, ApiError> {
+    authorize(&req, &token)?;
+    actions
+        .get_ref()
+        .send(DisableAlertRule { id: *id })
+        .await
+        .map_err(ApiError::internal)?
+        .map_err(ApiError::not_found)?;
+    srv.get_ref().do_send(ReloadAlertRules);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anomaly::AnomalyConfig;
+    use crate::common::Flush;
+    use crate::relay_server::PublisherMessage;
+    use crate::webhooks::Webhooks;
+    use actix_web::http::StatusCode;
+    use serde_json::json;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    fn actions(name: &str) -> Addr<Actions> {
+        let path = std::env::temp_dir().join(format!(
+            "air_meter-alerts-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        Actions::new(&path.to_string_lossy()).start()
+    }
+
+    fn relay(actions: &Addr<Actions>) -> Addr<RelayServer> {
+        RelayServer::new(
+            Arc::new(AtomicUsize::new(0)),
+            actions.clone(),
+            Webhooks::new(actions.clone()).start(),
+            AnomalyConfig::default(),
+        )
+        .start()
+    }
+
+    fn rule(metric: &str) -> NewAlertRule {
+        NewAlertRule {
+            name: format!("{} too high", metric),
+            publisher_id: Some(811),
+            metric: metric.to_owned(),
+            trigger_above: 1000.0,
+            trigger_secs: 300,
+            clear_below: 800.0,
+            clear_secs: 120,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn rules_need_a_known_metric() {
+        let actions = actions("metric");
+        let srv = relay(&actions);
+
+        let err = create_rule(rule("co2"), &actions, &srv).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            err.message,
+            "unknown metric \"co2\", expected one of eco2, evtoc, temperature, humidity, iaq \
+             or a metric a sensor has reported"
+        );
+        for metric in METRICS.iter() {
+            let stored = create_rule(rule(metric), &actions, &srv).await.unwrap();
+            assert_eq!(stored.metric, *metric);
+        }
+
+        // extra metrics are known once a sensor has reported them
+        assert!(create_rule(rule("pm25"), &actions, &srv).await.is_err());
+        let msg = serde_json::from_value(json!({
+            "eco2": 640,
+            "evtoc": 20,
+            "read_time": 1_600_000_000,
+            "start_time": 1_599_999_400,
+            "increment": "ConstantPower1s",
+            "metrics": { "pm25": 12.5 },
+        }))
+        .unwrap();
+        actions.do_send(PublisherMessage {
+            msg,
+            pub_id: 811,
+            json: String::new(),
+        });
+        actions.send(Flush).await.unwrap();
+        let stored = create_rule(rule("pm25"), &actions, &srv).await.unwrap();
+        assert_eq!(stored.metric, "pm25");
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{
+    config,
+    influx::{self, Precision},
+    metrics,
+    relay_server::{validate, PublisherMessage, Reading, RegisterPublisher},
+    rest_api::{error::ApiError, handlers::tokens_match},
+    RelayServer,
+};
+use actix::prelude::*;
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::time::SystemTime;
+use utoipa::{IntoParams, ToSchema};
+
+/// rejected line reasons kept in the response, the rest are only counted
+const MAX_REPORTED_ERRORS: usize = 20;
+
+/// each http publisher's token, a device can only push readings as the
+/// publisher its token is for. http ingest is disabled without any
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(try_from = "BTreeMap<String, String>")]
+pub struct IngestTokens(pub Vec<(u64, String)>);
+
+impl IngestTokens {
+    /// `<pub_id>=<token>` pairs separated by commas, e.g. `812=s3cret,813=0ther`
+    pub fn parse(tokens: &str) -> Result<IngestTokens, String> {
+        tokens
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once('=') {
+                Some((pub_id, token)) => IngestTokens::pair(pub_id, token),
+                None => Err(format!("expected <pub_id>=<token>, got {:?}", pair)),
+            })
+            .collect::<Result<_, _>>()
+            .map(IngestTokens)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn pair(pub_id: &str, token: &str) -> Result<(u64, String), String> {
+        let pub_id = pub_id
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid publisher id {:?}", pub_id))?;
+        match token {
+            "" => Err(format!("publisher {}'s token is empty", pub_id)),
+            _ => Ok((pub_id, token.to_owned())),
+        }
+    }
+}
+
+/// an `[ingest_tokens]` table of publisher ids to tokens
+impl TryFrom<BTreeMap<String, String>> for IngestTokens {
+    type Error = String;
+
+    fn try_from(tokens: BTreeMap<String, String>) -> Result<IngestTokens, String> {
+        tokens
+            .iter()
+            .map(|(pub_id, token)| IngestTokens::pair(pub_id, token))
+            .collect::<Result<_, _>>()
+            .map(IngestTokens)
+    }
+}
+
+/// publisher ids with their tokens redacted, for the printed config
+impl Serialize for IngestTokens {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(
+            self.0
+                .iter()
+                .map(|(pub_id, _)| (pub_id.to_string(), config::REDACTED)),
+        )
+    }
+}
+
+/// the publisher whose token was sent as `Authorization: Token <token>`, as
+/// sent by influx clients, or `Bearer <token>`
+pub fn authorize_device(req: &HttpRequest, tokens: &IngestTokens) -> Result<u64, ApiError> {
+    if tokens.0.is_empty() {
+        return Err(ApiError::forbidden(
+            "http ingest is disabled, set ingest_tokens or INGEST_TOKENS",
+        ));
+    }
+    let given = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            v.strip_prefix("Token ")
+                .or_else(|| v.strip_prefix("Bearer "))
+        });
+    let given = given.ok_or_else(|| ApiError::unauthorized("missing ingest token"))?;
+    // every token is compared so the time taken doesn't tell which matched
+    tokens
+        .0
+        .iter()
+        .filter(|(_, token)| tokens_match(given, token))
+        .fold(None, |found, (pub_id, _)| found.or(Some(*pub_id)))
+        .ok_or_else(|| ApiError::unauthorized("invalid ingest token"))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct WriteQuery {
+    /// the token's publisher, the only one lines may be for
+    pub pub_id: Option<u64>,
+    #[serde(default)]
+    pub precision: Precision,
+}
+
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct WriteReport {
+    pub code: &'static str,
+    pub message: String,
+    pub accepted: usize,
+    pub rejected: usize,
+    pub errors: Vec<String>,
+}
+
+/// relay readings from http publishers through the same `RelayServer` broadcast
+/// and storage path as websocket publishers
+pub async fn relay_readings(
+    srv: &Addr<RelayServer>,
+    readings: Vec<Reading>,
+) -> Result<(), ApiError> {
+    let publishers = readings.iter().map(|r| r.pub_id).collect::<HashSet<u64>>();
+    for pub_id in publishers {
+        srv.send(RegisterPublisher { pub_id })
+            .await
+            .map_err(ApiError::internal)?;
+    }
+    for reading in readings {
+        metrics::queued(metrics::RELAY_SERVER);
+        srv.do_send(PublisherMessage {
+            pub_id: reading.pub_id,
+            json: serde_json::to_string(&reading).map_err(ApiError::internal)?,
+            msg: reading,
+        });
+    }
+    Ok(())
+}
+
+/// relay readings written as influx line protocol, as if sent by a publisher.
+/// valid lines are accepted even when others are rejected
+#[utoipa::path(
+    post,
+    path = "/api/v1/write",
+    tag = "sensors",
+    params(WriteQuery),
+    request_body(content = String, content_type = "text/plain", description = "influx line protocol"),
+    responses(
+        (status = 204, description = "every line accepted"),
+        (status = 400, description = "some lines rejected, valid ones were accepted", body = WriteReport),
+        (status = 401, description = "missing or wrong ingest token", body = ApiError),
+        (status = 403, description = "http ingest is disabled, or `pub_id` isn't the token's", body = ApiError),
+    ),
+    security(("ingest_token" = []))
+)]
+pub async fn write(
+    req: HttpRequest,
+    web::Query(query): web::Query<WriteQuery>,
+    body: String,
+    tokens: web::Data<IngestTokens>,
+    srv: web::Data<Addr<RelayServer>>,
+) -> Result<HttpResponse, ApiError> {
+    let pub_id = authorize_device(&req, &tokens)?;
+    if query.pub_id.is_some_and(|id| id != pub_id) {
+        return Err(ApiError::forbidden(format!(
+            "the token is for publisher {}",
+            pub_id
+        )));
+    }
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let checks = config::readings();
+    let mut report = WriteReport::default();
+    let mut readings = vec![];
+    for (i, line) in body.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let reading = influx::parse_line(line)
+            .and_then(|point| influx::to_reading(&point, Some(pub_id), query.precision, now))
+            .and_then(|mut reading| {
+                validate::normalize(&mut reading, pub_id, now, false, &checks)
+                    .map(|_| reading)
+                    .map_err(|rejection| rejection.to_string())
+            });
+        match reading {
+            Ok(reading) => readings.push(reading),
+            Err(err) => {
+                report.rejected += 1;
+                if report.errors.len() < MAX_REPORTED_ERRORS {
+                    report.errors.push(format!("line {}: {}", i + 1, err));
+                }
+            }
+        }
+    }
+    report.accepted = readings.len();
+    relay_readings(&srv, readings).await?;
+    if report.rejected == 0 {
+        return Ok(HttpResponse::NoContent().finish());
+    }
+    report.code = "invalid";
+    report.message = report.errors[0].clone();
+    Ok(HttpResponse::BadRequest().json(report))
+}
@@ -1 +1,17 @@
+pub mod admin;
+pub mod alerts;
+pub mod ingest;
 pub mod sensors;
+pub mod webhooks;
+
+/// compare a token given by a client with an expected one, taking the same time
+/// wherever they first differ
+pub(crate) fn tokens_match(given: &str, expected: &str) -> bool {
+    let (given, expected) = (given.as_bytes(), expected.as_bytes());
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
@@ -1,15 +1,551 @@
 use crate::{
-    common::GetReadings,
-    db::{actions::Actions, model::DbReading},
+    common::{AggregateReadings, GetReadings, ImportReadings, ReadingsAfter},
+    config,
+    db::{actions::Actions, model::ReadingBucket},
+    iaq::Indexed,
+    import::{self, ImportQuery, ImportReport},
+    relay_server::{validate, ListSubs, Reading, SseSession, STREAM_CAPACITY},
+    rest_api::{
+        error::ApiError,
+        handlers::{
+            admin::{authorize, AdminToken},
+            ingest::{authorize_device, relay_readings, IngestTokens},
+        },
+        v1::dto::StoredReading,
+    },
+    RelayServer,
 };
 use actix::prelude::*;
-use actix_web::{web, Error, HttpResponse};
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use futures::channel::mpsc;
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+use utoipa::{IntoParams, ToSchema};
 
+/// the unversioned api's readings, as db rows
 pub async fn get_readings(
     web::Query(query): web::Query<GetReadings>,
     actions: web::Data<Addr<Actions>>,
-) -> Result<HttpResponse, Error> {
-    let readings = actions.get_ref().send(query).await.unwrap();
-    let res = readings.iter().rev().collect::<Vec<&DbReading>>();
+) -> Result<HttpResponse, ApiError> {
+    let readings = actions
+        .get_ref()
+        .send(query)
+        .await
+        .map_err(ApiError::internal)?;
+    let res = readings.iter().rev().map(Indexed::from).collect::<Vec<_>>();
     Ok(HttpResponse::Ok().json(res))
 }
+
+/// default and limits of the summary bucket width, in seconds
+const DEFAULT_BUCKET: u32 = 60 * 60;
+const MIN_BUCKET: u32 = 60;
+const MAX_BUCKET: u32 = 7 * 24 * 60 * 60;
+/// most buckets returned by a summary
+const SUMMARY_LIMIT: u16 = 1000;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SummaryQuery {
+    /// bucket width in seconds, an hour by default
+    pub bucket: Option<u32>,
+    /// unix seconds
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+/// the buckets of a summary, oldest first
+pub(crate) async fn aggregate(
+    pub_id: u64,
+    query: &SummaryQuery,
+    actions: &Addr<Actions>,
+) -> Result<Vec<ReadingBucket>, ApiError> {
+    let bucket = query.bucket.unwrap_or(DEFAULT_BUCKET);
+    if !(MIN_BUCKET..=MAX_BUCKET).contains(&bucket) {
+        return Err(ApiError::bad_request(format!(
+            "bucket must be between {} and {} seconds",
+            MIN_BUCKET, MAX_BUCKET
+        )));
+    }
+    actions
+        .send(AggregateReadings {
+            pub_id,
+            from: query.from,
+            to: query.to,
+            bucket,
+            limit: SUMMARY_LIMIT,
+        })
+        .await
+        .map_err(ApiError::internal)?
+        .map_err(ApiError::internal)
+}
+
+/// maps an uploaded csv or jsonl file onto readings and stores them,
+/// responds with how many rows were inserted, skipped or rejected
+#[utoipa::path(
+    post,
+    path = "/api/v1/readings/import",
+    tag = "sensors",
+    params(ImportQuery),
+    request_body(content = String, content_type = "text/csv", description = "csv or jsonl file"),
+    responses(
+        (status = 200, description = "rows inserted, skipped and rejected", body = ImportReport),
+        (status = 400, description = "unreadable file or mapping", body = ApiError),
+        (status = 401, description = "missing or wrong admin token", body = ApiError),
+        (status = 403, description = "admin routes are disabled", body = ApiError),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn import_readings(
+    req: HttpRequest,
+    web::Query(query): web::Query<ImportQuery>,
+    body: web::Bytes,
+    token: web::Data<AdminToken>,
+    actions: web::Data<Addr<Actions>>,
+) -> Result<HttpResponse, ApiError> {
+    authorize(&req, &token)?;
+    let mut report = ImportReport::default();
+    let readings = import::parse(&body, &query, &mut report).map_err(ApiError::bad_request)?;
+    let stored = actions
+        .get_ref()
+        .send(ImportReadings {
+            pub_id: query.pub_id,
+            readings,
+            dry_run: query.dry_run,
+        })
+        .await
+        .map_err(ApiError::internal)?
+        .map_err(ApiError::internal)?;
+    report.dry_run = stored.dry_run;
+    report.inserted = stored.inserted;
+    report.skipped = stored.skipped;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// live readings, alerts and presence of a publisher as Server-Sent Events,
+/// resuming after the reading in `Last-Event-ID` if given. A publisher that
+/// isn't connected is only sent the stored readings then a `close` event
+#[utoipa::path(
+    get,
+    path = "/api/v1/sensors/{pub_id}/stream",
+    tag = "sensors",
+    params(("pub_id" = u64, Path, description = "publisher id"), ("Last-Event-ID" = Option<i32>, Header, description = "id of the last reading received, to resume after")),
+    responses(
+        (status = 200, description = "`reading`, `alert`, `presence`, `truncated` and `close` events", content_type = "text/event-stream"),
+        (status = 404, description = "publisher neither connected nor with stored readings", body = ApiError),
+    )
+)]
+pub async fn stream_readings(
+    req: HttpRequest,
+    pub_id: web::Path<u64>,
+    srv: web::Data<Addr<RelayServer>>,
+    actions: web::Data<Addr<Actions>>,
+) -> Result<HttpResponse, ApiError> {
+    let pub_id = pub_id.into_inner();
+    let publishers = srv
+        .get_ref()
+        .send(ListSubs)
+        .await
+        .map_err(ApiError::internal)?;
+    // a publisher that isn't connected, e.g. since a restart, can still be
+    // resumed from its stored readings
+    let live = publishers.contains(&pub_id);
+    if !live {
+        let stored = actions
+            .get_ref()
+            .send(ReadingsAfter {
+                pub_id,
+                after: None,
+                limit: 1,
+            })
+            .await
+            .map_err(ApiError::internal)?;
+        if stored.is_empty() {
+            return Err(ApiError::not_found(format!("unknown publisher {}", pub_id)));
+        }
+    }
+    let last_event_id = match req.headers().get("last-event-id") {
+        Some(id) => Some(
+            id.to_str()
+                .ok()
+                .and_then(|id| id.parse::<i32>().ok())
+                .ok_or_else(|| ApiError::bad_request("Last-Event-ID must be a reading id"))?,
+        ),
+        None => None,
+    };
+    let (tx, rx) = mpsc::channel(STREAM_CAPACITY);
+    SseSession::new(
+        pub_id,
+        last_event_id,
+        live,
+        StoredReading::json,
+        srv.get_ref().clone(),
+        actions.get_ref().clone(),
+        tx,
+    )
+    .start();
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .streaming(rx))
+}
+
+/// how many posted readings were relayed
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Accepted {
+    pub accepted: usize,
+}
+
+/// a single reading or an array of them
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum ReadingsBody {
+    One(Reading),
+    Many(Vec<Reading>),
+}
+
+/// readings sent by publishers that can't hold a websocket session, in the same
+/// json as `/reading`. they're relayed to subscribers and stored as if sent
+/// over the websocket
+#[utoipa::path(
+    post,
+    path = "/api/v1/sensors/{pub_id}/readings",
+    tag = "sensors",
+    params(("pub_id" = u64, Path, description = "publisher id")),
+    request_body(content = ReadingsBody),
+    responses(
+        (status = 202, description = "readings relayed", body = Accepted),
+        (status = 400, description = "no readings or a mismatched pub_id", body = ApiError),
+        (status = 401, description = "missing or wrong ingest token", body = ApiError),
+        (status = 403, description = "http ingest is disabled, or the token isn't the publisher's", body = ApiError),
+        (status = 413, description = "body too large", body = ApiError),
+    ),
+    security(("ingest_token" = []))
+)]
+pub async fn post_readings(
+    req: HttpRequest,
+    pub_id: web::Path<u64>,
+    web::Json(body): web::Json<ReadingsBody>,
+    tokens: web::Data<IngestTokens>,
+    srv: web::Data<Addr<RelayServer>>,
+) -> Result<HttpResponse, ApiError> {
+    let pub_id = pub_id.into_inner();
+    if authorize_device(&req, &tokens)? != pub_id {
+        return Err(ApiError::forbidden(format!(
+            "the token isn't publisher {}'s",
+            pub_id
+        )));
+    }
+    let mut readings = match body {
+        ReadingsBody::One(reading) => vec![reading],
+        ReadingsBody::Many(readings) => readings,
+    };
+    if readings.is_empty() {
+        return Err(ApiError::bad_request("no readings"));
+    }
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let checks = config::readings();
+    for (i, reading) in readings.iter_mut().enumerate() {
+        validate::normalize(reading, pub_id, now, false, &checks)
+            .map_err(|rejection| ApiError::bad_request(format!("reading {}: {}", i, rejection)))?;
+    }
+    let accepted = readings.len();
+    relay_readings(&srv, readings).await?;
+    Ok(HttpResponse::Accepted().json(Accepted { accepted }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anomaly::AnomalyConfig;
+    use crate::common::Flush;
+    use crate::relay_server::{
+        self, Close, Connect, Join, PublisherMessage, RegisterPublisher, Role,
+    };
+    use crate::webhooks::Webhooks;
+    use actix_web::{http::StatusCode, test, App};
+    use futures::StreamExt;
+    use serde_json::json;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::{Arc, Mutex};
+    use std::time::UNIX_EPOCH;
+
+    fn actions(name: &str) -> Addr<Actions> {
+        let path = std::env::temp_dir().join(format!(
+            "air_meter-sensors-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        Actions::new(&path.to_string_lossy()).start()
+    }
+
+    fn relay(actions: &Addr<Actions>) -> Addr<RelayServer> {
+        RelayServer::new(
+            Arc::new(AtomicUsize::new(0)),
+            actions.clone(),
+            Webhooks::new(actions.clone()).start(),
+            AnomalyConfig::default(),
+        )
+        .start()
+    }
+
+    /// stores readings of publisher 811 read at `times`, returning their row ids
+    async fn store(actions: &Addr<Actions>, times: &[u64]) -> Vec<i32> {
+        for &read_time in times {
+            let msg: Reading = serde_json::from_value(serde_json::json!({
+                "eco2": 600,
+                "evtoc": 0,
+                "read_time": read_time,
+                "start_time": 0,
+                "increment": "ConstantPower1s",
+            }))
+            .unwrap();
+            actions
+                .send(PublisherMessage {
+                    msg,
+                    pub_id: 811,
+                    json: String::new(),
+                })
+                .await
+                .unwrap();
+        }
+        let stored = actions
+            .send(ReadingsAfter {
+                pub_id: 811,
+                after: None,
+                limit: 100,
+            })
+            .await
+            .unwrap();
+        stored.iter().map(|reading| reading.id).collect()
+    }
+
+    async fn stream(
+        actions: &Addr<Actions>,
+        relay: &Addr<RelayServer>,
+        last_event_id: Option<i32>,
+    ) -> actix_web::dev::ServiceResponse {
+        let mut app = test::init_service(
+            App::new()
+                .data(actions.clone())
+                .data(relay.clone())
+                .route("/stream/{pub_id}", web::get().to(stream_readings)),
+        )
+        .await;
+        let mut req = test::TestRequest::get().uri("/stream/811");
+        if let Some(id) = last_event_id {
+            req = req.header("last-event-id", id.to_string());
+        }
+        test::call_service(&mut app, req.to_request()).await
+    }
+
+    fn event_ids(body: &str) -> Vec<i32> {
+        body.lines()
+            .filter_map(|line| line.strip_prefix("id: "))
+            .map(|id| id.parse().unwrap())
+            .collect()
+    }
+
+    #[actix_rt::test]
+    async fn resumes_an_offline_publisher_from_stored_readings() {
+        let actions = actions("offline");
+        let relay = relay(&actions);
+        let ids = store(&actions, &[100, 200, 300]).await;
+
+        let resp = stream(&actions, &relay, Some(ids[0])).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+        let body = String::from_utf8_lossy(&body);
+        assert_eq!(event_ids(&body), ids[1..]);
+        assert!(body.ends_with("event: close\ndata: publisher 811 offline\n\n"));
+    }
+
+    #[actix_rt::test]
+    async fn resumes_a_live_publisher_after_the_last_event() {
+        let actions = actions("live");
+        let relay = relay(&actions);
+        relay.send(RegisterPublisher { pub_id: 811 }).await.unwrap();
+        let ids = store(&actions, &[100, 200, 300]).await;
+
+        let mut resp = stream(&actions, &relay, Some(ids[1])).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let mut body = resp.take_body();
+        let mut received = String::new();
+        while !received.contains("event: reading") {
+            let chunk = body.next().await.unwrap().unwrap();
+            received.push_str(&String::from_utf8_lossy(&chunk));
+        }
+        assert_eq!(event_ids(&received), ids[2..]);
+    }
+
+    #[actix_rt::test]
+    async fn unknown_publishers_are_not_found() {
+        let actions = actions("unknown");
+        let relay = relay(&actions);
+
+        let resp = stream(&actions, &relay, Some(1)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// stands in for a subscriber's session, recording what it's sent
+    struct Subscriber(Arc<Mutex<Vec<String>>>);
+
+    impl Actor for Subscriber {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<relay_server::Message> for Subscriber {
+        type Result = ();
+
+        fn handle(&mut self, msg: relay_server::Message, _: &mut Context<Self>) {
+            self.0.lock().unwrap().push(msg.0);
+        }
+    }
+
+    impl Handler<Close> for Subscriber {
+        type Result = ();
+
+        fn handle(&mut self, _: Close, _: &mut Context<Self>) {}
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    async fn post(
+        relay: &Addr<RelayServer>,
+        token: Option<&str>,
+        body: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let tokens = IngestTokens::parse("812=s3cret,813=0ther").unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(tokens)
+                .data(relay.clone())
+                .route("/sensors/{pub_id}/readings", web::post().to(post_readings)),
+        )
+        .await;
+        let mut req = test::TestRequest::post()
+            .uri("/sensors/812/readings")
+            .set_json(&body);
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let res = test::call_service(&mut app, req.to_request()).await;
+        let status = res.status();
+        (status, test::read_body_json(res).await)
+    }
+
+    fn posted(pub_id: Option<u64>, read_time: u64) -> serde_json::Value {
+        let mut reading = json!({
+            "eco2": 640,
+            "evtoc": 20,
+            "read_time": read_time,
+            "start_time": read_time - 600,
+            "increment": "ConstantPower1s",
+        });
+        if let Some(pub_id) = pub_id {
+            reading["pub_id"] = json!(pub_id);
+        }
+        reading
+    }
+
+    #[actix_rt::test]
+    async fn ingest_needs_the_publishers_token() {
+        let actions = actions("ingest_token");
+        let relay = relay(&actions);
+        let body = posted(None, now());
+
+        let (status, res) = post(&relay, None, body.clone()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(res["message"], "missing ingest token");
+        let (status, res) = post(&relay, Some("guess"), body.clone()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(res["message"], "invalid ingest token");
+        let (status, res) = post(&relay, Some("0ther"), body).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(res["message"], "the token isn't publisher 812's");
+    }
+
+    #[actix_rt::test]
+    async fn ingest_rejects_another_publishers_readings() {
+        let actions = actions("ingest_pub_id");
+        let relay = relay(&actions);
+        let body = json!([posted(Some(812), now()), posted(Some(813), now())]);
+
+        let (status, res) = post(&relay, Some("s3cret"), body).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(res["error"], "bad_request");
+        assert!(res["message"].as_str().unwrap().starts_with("reading 1: "));
+        let stored = actions
+            .send(ReadingsAfter {
+                pub_id: 812,
+                after: None,
+                limit: 10,
+            })
+            .await
+            .unwrap();
+        assert!(stored.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn ingested_readings_are_relayed_and_stored() {
+        let actions = actions("ingest");
+        let relay = relay(&actions);
+        // a subscriber already following the publisher
+        relay.send(RegisterPublisher { pub_id: 812 }).await.unwrap();
+        let received = Arc::new(Mutex::new(vec![]));
+        let subscriber = Subscriber(received.clone()).start();
+        let ses_id = relay
+            .send(Connect {
+                ses_role: Role::Subscriber(0),
+                addr: subscriber.clone().recipient(),
+                close: subscriber.recipient(),
+            })
+            .await
+            .unwrap();
+        relay
+            .send(Join {
+                ses_id,
+                pub_id: 812,
+            })
+            .await
+            .unwrap();
+
+        let now = now();
+        let body = json!([posted(None, now - 1), posted(Some(812), now)]);
+        let (status, res) = post(&relay, Some("s3cret"), body).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(res, json!({ "accepted": 2 }));
+
+        // the relay queues readings to be stored before it answers this
+        relay.send(ListSubs).await.unwrap();
+        actions.send(Flush).await.unwrap();
+        let stored = actions
+            .send(ReadingsAfter {
+                pub_id: 812,
+                after: None,
+                limit: 10,
+            })
+            .await
+            .unwrap();
+        let times = stored
+            .iter()
+            .map(|r| r.read_time as u64)
+            .collect::<Vec<_>>();
+        assert_eq!(times, [now - 1, now]);
+        let relayed = received
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|msg| msg.starts_with("/reading "))
+            .count();
+        assert_eq!(relayed, 2);
+    }
+}
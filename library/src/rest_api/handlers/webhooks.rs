@@ -0,0 +1,103 @@
+use crate::{
+    common::{AddWebhook, DisableWebhook, GetWebhooks},
+    db::{
+        actions::Actions,
+        model::{NewWebhook, Webhook},
+    },
+    rest_api::{
+        error::ApiError,
+        handlers::admin::{authorize, AdminToken},
+    },
+    webhooks::{PingWebhook, ReloadWebhooks, Webhooks},
+};
+use actix::prelude::*;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+/// validate and store a webhook, reloading the webhooks notified
+pub(crate) async fn create_webhook(
+    hook: NewWebhook,
+    actions: &Addr<Actions>,
+    webhooks: &Addr<Webhooks>,
+) -> Result<Webhook, ApiError> {
+    if !hook.url.starts_with("http://") && !hook.url.starts_with("https://") {
+        return Err(ApiError::bad_request("url must be http or https"));
+    }
+    if hook.events.trim().is_empty() {
+        return Err(ApiError::bad_request("events must not be empty"));
+    }
+    let hook = actions
+        .send(AddWebhook(hook))
+        .await
+        .map_err(ApiError::internal)?
+        .map_err(ApiError::internal)?;
+    webhooks.do_send(ReloadWebhooks);
+    Ok(hook)
+}
+
+/// disable a webhook, keeping its delivery log
+#[utoipa::path(
+    delete,
+    path = "/api/v1/webhooks/{id}",
+    tag = "webhooks",
+    params(("id" = i32, Path, description = "webhook id")),
+    responses(
+        (status = 204, description = "webhook disabled"),
+        (status = 401, description = "missing or wrong admin token", body = ApiError),
+        (status = 403, description = "admin routes are disabled", body = ApiError),
+        (status = 404, description = "no such webhook", body = ApiError),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn disable_webhook(
+    req: HttpRequest,
+    id: web::Path<i32>,
+    token: web::Data<AdminToken>,
+    actions: web::Data<Addr<Actions>>,
+    webhooks: web::Data<Addr<Webhooks>>,
+) -> Result<HttpResponse, ApiError> {
+    authorize(&req, &token)?;
+    actions
+        .get_ref()
+        .send(DisableWebhook { id: *id })
+        .await
+        .map_err(ApiError::internal)?
+        .map_err(ApiError::not_found)?;
+    webhooks.get_ref().do_send(ReloadWebhooks);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// send a `ping` event to a webhook, the result shows up in its delivery log
+#[utoipa::path(
+    post,
+    path = "/api/v1/webhooks/{id}/test",
+    tag = "webhooks",
+    params(("id" = i32, Path, description = "webhook id")),
+    responses(
+        (status = 202, description = "ping queued"),
+        (status = 401, description = "missing or wrong admin token", body = ApiError),
+        (status = 403, description = "admin routes are disabled", body = ApiError),
+        (status = 404, description = "no such webhook", body = ApiError),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn test_webhook(
+    req: HttpRequest,
+    id: web::Path<i32>,
+    token: web::Data<AdminToken>,
+    actions: web::Data<Addr<Actions>>,
+    webhooks: web::Data<Addr<Webhooks>>,
+) -> Result<HttpResponse, ApiError> {
+    authorize(&req, &token)?;
+    let hook = actions
+        .get_ref()
+        .send(GetWebhooks {
+            enabled_only: false,
+        })
+        .await
+        .map_err(ApiError::internal)?
+        .into_iter()
+        .find(|h| h.id == *id)
+        .ok_or_else(|| ApiError::not_found(format!("no webhook {}", id)))?;
+    webhooks.get_ref().do_send(PingWebhook(hook));
+    Ok(HttpResponse::Accepted().finish())
+}
@@ -1,15 +1,104 @@
-use actix_web::web;
+use crate::rate_limit::limit_requests;
+use actix_web::{middleware::DefaultHeaders, web, Scope};
 
+pub mod error;
 pub mod handlers;
-use handlers::sensors;
+pub mod openapi;
+pub mod v1;
+use handlers::{admin, alerts, ingest, sensors, webhooks};
+
+/// largest file accepted by the import endpoint
+const IMPORT_SIZE_LIMIT: usize = 64 * 1024 * 1024;
+/// largest batch of readings accepted from an http publisher
+const READINGS_SIZE_LIMIT: usize = 1024 * 1024;
+
+/// a scope whose malformed requests and unknown routes get the same json
+/// errors as its handlers
+fn api_scope(path: &str) -> Scope {
+    web::scope(path)
+        .app_data(error::json_config())
+        .app_data(error::query_config())
+        .app_data(error::path_config())
+        .default_service(web::route().to(error::not_found))
+}
 
 pub fn rest_config(cfg: &mut web::ServiceConfig) {
     cfg.service(
-        web::scope("/api").service(
-            web::scope("/sensors").service(
-                web::scope("/readings")
-                    .service(web::resource("").route(web::get().to(sensors::get_readings))),
+        api_scope("/api/v1")
+            .configure(v1::config)
+            .wrap_fn(limit_requests),
+    )
+    .service(web::resource("/api/openapi.json").route(web::get().to(openapi::openapi_json)))
+    .service(web::resource("/api/docs").route(web::get().to(openapi::docs)));
+    // superseded by /api/v1, kept for existing scripts
+    cfg.service(
+        api_scope("/api")
+            .wrap(
+                DefaultHeaders::new()
+                    .header("Deprecation", "true")
+                    .header("Link", "</api/docs>; rel=\"deprecation\""),
+            )
+            .wrap_fn(limit_requests)
+            .configure(deprecated_config),
+    );
+}
+
+/// the unversioned routes, answered by the v1 handlers except
+/// `/sensors/readings` which still returns db rows
+fn deprecated_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin/backups")
+            .service(
+                web::resource("")
+                    .route(web::get().to(admin::list_backups))
+                    .route(web::post().to(admin::create_backup)),
+            )
+            .service(web::resource("/{name}").route(web::get().to(admin::download_backup))),
+    )
+    .service(
+        web::scope("/alerts")
+            .service(web::resource("").route(web::get().to(v1::handlers::active_alerts)))
+            .service(web::resource("/history").route(web::get().to(v1::handlers::alert_history)))
+            .service(
+                web::resource("/rules")
+                    .route(web::get().to(v1::handlers::list_rules))
+                    .route(web::post().to(v1::handlers::add_rule)),
+            )
+            .service(web::resource("/rules/{id}").route(web::delete().to(alerts::disable_rule))),
+    )
+    // registered before `/sensors/{pub_id}`, which would match `readings`
+    .service(
+        web::scope("/sensors/readings")
+            .service(web::resource("").route(web::get().to(sensors::get_readings)))
+            .service(web::resource("/export").route(web::get().to(v1::handlers::export_readings)))
+            .service(
+                web::resource("/import")
+                    .data(web::PayloadConfig::new(IMPORT_SIZE_LIMIT))
+                    .route(web::post().to(sensors::import_readings)),
             ),
-        ),
+    )
+    .service(
+        web::scope("/sensors/{pub_id}")
+            .service(web::resource("/analytics").route(web::get().to(v1::handlers::analytics)))
+            .service(
+                web::resource("/readings")
+                    .app_data(error::json_config().limit(READINGS_SIZE_LIMIT))
+                    .route(web::post().to(sensors::post_readings)),
+            )
+            .service(web::resource("/report").route(web::get().to(v1::handlers::report)))
+            .service(web::resource("/summary").route(web::get().to(v1::handlers::summary)))
+            .service(web::resource("/stream").route(web::get().to(sensors::stream_readings))),
+    )
+    .service(web::resource("/write").route(web::post().to(ingest::write)))
+    .service(
+        web::scope("/webhooks")
+            .service(
+                web::resource("")
+                    .route(web::get().to(v1::handlers::list_webhooks))
+                    .route(web::post().to(v1::handlers::add_webhook)),
+            )
+            .service(web::resource("/deliveries").route(web::get().to(v1::handlers::deliveries)))
+            .service(web::resource("/{id}").route(web::delete().to(webhooks::disable_webhook)))
+            .service(web::resource("/{id}/test").route(web::post().to(webhooks::test_webhook))),
     );
 }
@@ -0,0 +1,401 @@
+//! OpenAPI 3 document of the `/api/v1` REST api, generated from the handlers'
+//! request and response types. It's served at `/api/openapi.json`, browsable at `/api/docs`,
+//! and `server openapi typescript` turns its schemas into the web app's
+//! `lib/api.ts` so the two can't drift.
+use crate::{
+    alerts::AlertState,
+    db::{
+        backup::BackupInfo,
+        model::{Flag, Mode},
+    },
+    export::ExportFormat,
+    iaq::{Category, Iaq, RelayedReading},
+    import::{ImportFormat, ImportReport, Preset},
+    influx::Precision,
+    relay_server::Reading,
+    report::{Period, Stats},
+    rest_api::{
+        error::ApiError,
+        handlers::{
+            admin, alerts,
+            ingest::{self, WriteReport},
+            sensors::{self, Accepted, ReadingsBody},
+            webhooks,
+        },
+        v1::{
+            dto::{
+                ActiveAlert, AlertCount, AlertEvent, AlertRule, Analytics, Bucket, DaySummary,
+                Decay, HourSummary, NewAlertRule, NewWebhook, Occupancy, Report, StoredReading,
+                TimeAbove, Uptime, Ventilation, Webhook, WebhookDelivery,
+            },
+            handlers as v1,
+        },
+    },
+};
+use actix_web::HttpResponse;
+use std::fmt::Write;
+use std::sync::OnceLock;
+use utoipa::openapi::{
+    schema::AdditionalProperties,
+    security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    OpenApi as Document, RefOr, Schema, SchemaType,
+};
+use utoipa::{Modify, OpenApi};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "air meter",
+        description = "Readings, alerts, reports and webhooks of the air meter relay server. Requests over a client's rate limit are answered 429 with a `Retry-After` header. The unversioned `/api/sensors/readings` is deprecated"
+    ),
+    paths(
+        admin::list_backups,
+        admin::create_backup,
+        admin::download_backup,
+        v1::active_alerts,
+        v1::alert_history,
+        v1::list_rules,
+        v1::add_rule,
+        alerts::disable_rule,
+        v1::export_readings,
+        sensors::import_readings,
+        v1::analytics,
+        v1::readings,
+        sensors::post_readings,
+        v1::report,
+        v1::summary,
+        sensors::stream_readings,
+        ingest::write,
+        v1::list_webhooks,
+        v1::add_webhook,
+        v1::deliveries,
+        webhooks::disable_webhook,
+        webhooks::test_webhook,
+    ),
+    components(schemas(
+        ApiError,
+        Accepted,
+        ActiveAlert,
+        AlertCount,
+        AlertEvent,
+        AlertRule,
+        AlertState,
+        Analytics,
+        BackupInfo,
+        Bucket,
+        Category,
+        DaySummary,
+        Decay,
+        ExportFormat,
+        Flag,
+        HourSummary,
+        Iaq,
+        ImportFormat,
+        ImportReport,
+        Mode,
+        NewAlertRule,
+        NewWebhook,
+        Occupancy,
+        Period,
+        Precision,
+        Preset,
+        Reading,
+        ReadingsBody,
+        RelayedReading,
+        Report,
+        Stats,
+        StoredReading,
+        TimeAbove,
+        Uptime,
+        Ventilation,
+        Webhook,
+        WebhookDelivery,
+        WriteReport,
+    )),
+    modifiers(&Tokens, &Unlicensed),
+    tags(
+        (name = "sensors", description = "readings of publishers"),
+        (name = "alerts", description = "threshold alerts and their rules"),
+        (name = "webhooks", description = "alert and report notifications"),
+        (name = "admin", description = "database backups"),
+    )
+)]
+pub struct ApiDoc;
+
+/// the admin and ingest tokens, see `authorize` and `authorize_device`
+struct Tokens;
+
+impl Modify for Tokens {
+    fn modify(&self, doc: &mut Document) {
+        let components = doc.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "admin_token",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+        components.add_security_scheme(
+            "ingest_token",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "Authorization",
+                "`Token <token>` or `Bearer <token>`, the publisher's token from INGEST_TOKENS",
+            ))),
+        );
+    }
+}
+
+/// the crate has no license, left out rather than given an empty one
+struct Unlicensed;
+
+impl Modify for Unlicensed {
+    fn modify(&self, doc: &mut Document) {
+        doc.info.license = None;
+    }
+}
+
+static DOCUMENT: OnceLock<String> = OnceLock::new();
+
+/// the OpenAPI document
+pub async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok().content_type("application/json").body(
+        DOCUMENT
+            .get_or_init(|| ApiDoc::openapi().to_json().expect("openapi document"))
+            .as_str(),
+    )
+}
+
+/// Swagger UI for the document, loaded from a CDN
+pub async fn docs() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(DOCS_PAGE)
+}
+
+const DOCS_PAGE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+    <meta charset="utf-8">
+    <title>air meter api</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+    <div id="swagger-ui"></div>
+    <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+    <script>
+        SwaggerUIBundle({url: "/api/openapi.json", dom_id: "#swagger-ui"});
+    </script>
+</body>
+</html>
+"##;
+
+/// TypeScript declarations of the document's schemas, one per schema
+pub fn typescript(doc: &Document) -> String {
+    let mut out = String::from(
+        "// generated from the server's OpenAPI document by `server openapi typescript`, don't edit\n",
+    );
+    let schemas = doc.components.iter().flat_map(|c| c.schemas.iter());
+    for (name, schema) in schemas {
+        out.push('\n');
+        match schema {
+            RefOr::T(Schema::Object(object))
+                if object.schema_type == SchemaType::Object && !object.properties.is_empty() =>
+            {
+                comment(&mut out, "", object.description.as_deref());
+                let _ = writeln!(out, "export interface {} {}", name, ts_object(object, ""));
+            }
+            _ => {
+                comment(&mut out, "", description(schema));
+                let _ = writeln!(out, "export type {} = {};", name, ts_type(schema, ""));
+            }
+        }
+    }
+    out
+}
+
+fn description(schema: &RefOr<Schema>) -> Option<&str> {
+    match schema {
+        RefOr::T(Schema::Object(object)) => object.description.as_deref(),
+        RefOr::T(Schema::Array(array)) => array.description.as_deref(),
+        RefOr::T(Schema::AllOf(all)) => all.description.as_deref(),
+        RefOr::T(Schema::OneOf(one)) => one.description.as_deref(),
+        _ => None,
+    }
+}
+
+fn comment(out: &mut String, indent: &str, description: Option<&str>) {
+    if let Some(description) = description.filter(|d| !d.is_empty()) {
+        let _ = writeln!(out, "{}/** {} */", indent, description.replace('\n', " "));
+    }
+}
+
+/// `{ ... }` with a property per line, `indent` being the closing brace's
+fn ts_object(object: &utoipa::openapi::Object, indent: &str) -> String {
+    let inner = format!("{}    ", indent);
+    let mut out = String::from("{\n");
+    for (name, property) in &object.properties {
+        comment(&mut out, &inner, description(property));
+        let optional = match object.required.contains(name) {
+            true => "",
+            false => "?",
+        };
+        let _ = writeln!(
+            out,
+            "{}{}{}: {};",
+            inner,
+            name,
+            optional,
+            ts_type(property, &inner)
+        );
+    }
+    out.push_str(indent);
+    out.push('}');
+    out
+}
+
+fn nullable(ty: String, nullable: bool) -> String {
+    match nullable {
+        true => format!("{} | null", ty),
+        false => ty,
+    }
+}
+
+/// parenthesised if it's a union or intersection
+fn operand(ty: String) -> String {
+    match ty.contains(" | ") || ty.contains(" & ") {
+        true => format!("({})", ty),
+        false => ty,
+    }
+}
+
+fn ts_type(schema: &RefOr<Schema>, indent: &str) -> String {
+    let schema = match schema {
+        RefOr::Ref(reference) => {
+            let location = &reference.ref_location;
+            return location.rsplit('/').next().unwrap_or(location).to_owned();
+        }
+        RefOr::T(schema) => schema,
+    };
+    match schema {
+        Schema::Object(object) => {
+            let ty = match (&object.enum_values, &object.schema_type) {
+                (Some(values), _) => values
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" | "),
+                (None, SchemaType::String) => "string".to_owned(),
+                (None, SchemaType::Integer | SchemaType::Number) => "number".to_owned(),
+                (None, SchemaType::Boolean) => "boolean".to_owned(),
+                (None, SchemaType::Object) if !object.properties.is_empty() => {
+                    ts_object(object, indent)
+                }
+                (None, SchemaType::Object) => match object.additional_properties.as_deref() {
+                    Some(AdditionalProperties::RefOr(values)) => {
+                        format!("Record<string, {}>", ts_type(values, indent))
+                    }
+                    _ => "Record<string, unknown>".to_owned(),
+                },
+                _ => "unknown".to_owned(),
+            };
+            nullable(ty, object.nullable)
+        }
+        Schema::Array(array) => nullable(
+            format!("{}[]", operand(ts_type(&array.items, indent))),
+            array.nullable,
+        ),
+        Schema::AllOf(all) => {
+            let items = all.items.iter().map(|s| operand(ts_type(s, indent)));
+            nullable(items.collect::<Vec<_>>().join(" & "), all.nullable)
+        }
+        Schema::OneOf(one) => {
+            let items = one.items.iter().map(|s| operand(ts_type(s, indent)));
+            items.collect::<Vec<_>>().join(" | ")
+        }
+        _ => "unknown".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::model::{DbReading, QUALITY_NO_EVTOC};
+    use serde_json::{json, Value};
+
+    fn document() -> Value {
+        serde_json::to_value(ApiDoc::openapi()).unwrap()
+    }
+
+    fn keys(object: &Value) -> Vec<&str> {
+        let mut keys = object
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys
+    }
+
+    #[test]
+    fn only_documents_the_versioned_api() {
+        let doc = document();
+        let paths = keys(&doc["paths"]);
+        assert!(paths.contains(&"/api/v1/sensors/{pub_id}/readings"));
+        assert!(
+            paths.iter().all(|path| path.starts_with("/api/v1/")),
+            "{:?}",
+            paths
+        );
+        assert!(doc["info"].get("license").is_none());
+        let schemes = keys(&doc["components"]["securitySchemes"]);
+        assert_eq!(schemes, ["admin_token", "ingest_token"]);
+    }
+
+    #[test]
+    fn stored_reading_schema_matches_its_serialization() {
+        let doc = document();
+        let schema = &doc["components"]["schemas"]["StoredReading"];
+        let reading = DbReading {
+            id: 7,
+            publisher_id: 811,
+            eco2: 640,
+            evtoc: 0,
+            read_time: 1_600_000_000,
+            start_time: 1_599_999_400,
+            mode: Mode::ConstantPower1s,
+            sensor_model: None,
+            temperature: None,
+            humidity: None,
+            quality_flags: QUALITY_NO_EVTOC,
+            raw_current: None,
+            raw_voltage: None,
+        };
+        let stored = serde_json::to_value(StoredReading::from(&reading)).unwrap();
+        assert_eq!(keys(&schema["properties"]), keys(&stored));
+        // fields left out of `required` are the ones that can be null
+        for (name, value) in stored.as_object().unwrap() {
+            let required = schema["required"]
+                .as_array()
+                .unwrap()
+                .contains(&json!(name));
+            assert_eq!(required, !value.is_null(), "{}", name);
+        }
+        assert_eq!(
+            schema["properties"]["flags"]["items"]["$ref"],
+            "#/components/schemas/Flag"
+        );
+        assert_eq!(
+            doc["components"]["schemas"]["Flag"]["enum"],
+            json!(["imported", "no_evtoc", "flatline", "spike", "out_of_range"])
+        );
+    }
+
+    #[test]
+    fn typescript_has_an_interface_per_object_schema() {
+        let ts = typescript(&ApiDoc::openapi());
+        assert!(ts.contains(
+            "export type Flag = \"imported\" | \"no_evtoc\" | \"flatline\" | \"spike\" | \"out_of_range\";"
+        ));
+        assert!(ts.contains("export interface StoredReading {\n    id: number;\n"));
+        assert!(ts.contains("    evtoc?: number | null;\n"));
+        assert!(ts.contains("    flags: Flag[];\n"));
+    }
+}
@@ -0,0 +1,822 @@
+//! Request and response bodies of `/api/v1`. Actor messages and db models are
+//! converted to and from these at the edge, so either can change without
+//! changing the api.
+use crate::{
+    alerts::{self, AlertState},
+    common::{GetAlertHistory, GetPublisherReadings, GetWebhookDeliveries},
+    db::model::{self, Flag, Mode, QUALITY_NO_EVTOC},
+    export::ExportFormat,
+    iaq::Iaq,
+    report::{self, Period, Stats},
+    rest_api::error::ApiError,
+    ventilation,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+/// items listed when a request gives no `limit`
+pub const DEFAULT_LIMIT: u16 = 100;
+/// most items a request can list at once
+pub const MAX_LIMIT: u16 = 1000;
+
+fn limit(limit: Option<u16>) -> Result<u16, ApiError> {
+    match limit.unwrap_or(DEFAULT_LIMIT) {
+        limit @ 1..=MAX_LIMIT => Ok(limit),
+        _ => Err(ApiError::bad_request(format!(
+            "limit must be between 1 and {}",
+            MAX_LIMIT
+        ))),
+    }
+}
+
+/// a page of readings, the latest before `before`
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReadingsQuery {
+    /// unix seconds, the latest readings when none
+    pub before: Option<u64>,
+    /// 100 by default, at most 1000
+    pub limit: Option<u16>,
+}
+
+impl ReadingsQuery {
+    pub fn to_message(&self, pub_id: u64) -> Result<GetPublisherReadings, ApiError> {
+        Ok(GetPublisherReadings {
+            pub_id,
+            before: self.before,
+            limit: limit(self.limit)?,
+        })
+    }
+}
+
+/// a stored reading with its air quality index
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StoredReading {
+    pub id: i32,
+    pub pub_id: u64,
+    /// ppm
+    pub eco2: u32,
+    /// ppb, none when the sensor reported no TVOC value
+    pub evtoc: Option<u32>,
+    /// unix seconds
+    pub read_time: u64,
+    /// when the sensor started its current run, unix seconds
+    pub start_time: u64,
+    pub mode: Mode,
+    /// sensor hardware, e.g. `CCS811`
+    pub sensor_model: Option<String>,
+    /// degrees celsius
+    pub temperature: Option<f64>,
+    /// relative humidity percentage
+    pub humidity: Option<f64>,
+    pub flags: Vec<Flag>,
+    pub iaq: Iaq,
+}
+
+impl From<&model::DbReading> for StoredReading {
+    fn from(reading: &model::DbReading) -> StoredReading {
+        StoredReading {
+            id: reading.id,
+            pub_id: reading.publisher_id as u64,
+            eco2: reading.eco2 as u32,
+            evtoc: Some(reading.evtoc as u32)
+                .filter(|_| reading.quality_flags & QUALITY_NO_EVTOC == 0),
+            read_time: reading.read_time as u64,
+            start_time: reading.start_time as u64,
+            mode: reading.mode,
+            sensor_model: reading.sensor_model.clone(),
+            temperature: reading.temperature,
+            humidity: reading.humidity,
+            flags: Flag::from_bits(reading.quality_flags),
+            iaq: Iaq::of_db(reading),
+        }
+    }
+}
+
+impl StoredReading {
+    /// a db row as `/api/v1` serializes it, for streams outside the rest api
+    pub fn json(reading: &model::DbReading) -> String {
+        serde_json::to_string(&StoredReading::from(reading)).unwrap_or_default()
+    }
+}
+
+/// readings to export, oldest first
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+    pub format: ExportFormat,
+    /// every publisher's readings when none
+    pub pub_id: Option<u64>,
+    /// unix seconds, inclusive
+    pub from: Option<u64>,
+    /// unix seconds, exclusive
+    pub to: Option<u64>,
+}
+
+/// a publisher's readings aggregated over a time bucket, indexed by their means
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Bucket {
+    /// unix seconds the bucket starts at
+    pub start: u64,
+    pub readings: u64,
+    /// readings flagged `flatline`, `spike` or `out_of_range`
+    pub suspect: u64,
+    pub eco2: Stats,
+    /// none when no reading in the bucket had a TVOC value
+    pub evtoc: Option<Stats>,
+    pub iaq: Iaq,
+}
+
+impl From<&model::ReadingBucket> for Bucket {
+    fn from(bucket: &model::ReadingBucket) -> Bucket {
+        let evtoc = match (bucket.evtoc_min, bucket.evtoc_max, bucket.evtoc_mean) {
+            (Some(min), Some(max), Some(mean)) => Some(Stats { min, max, mean }),
+            _ => None,
+        };
+        Bucket {
+            start: bucket.start as u64,
+            readings: bucket.readings as u64,
+            suspect: bucket.suspect as u64,
+            eco2: Stats {
+                min: bucket.eco2_min,
+                max: bucket.eco2_max,
+                mean: bucket.eco2_mean,
+            },
+            evtoc,
+            iaq: Iaq::new(bucket.eco2_mean, bucket.evtoc_mean),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AnalyticsQuery {
+    /// unix seconds, defaults to a day before `to`
+    pub from: Option<u64>,
+    /// unix seconds, defaults to now
+    pub to: Option<u64>,
+    /// outdoor CO2 in ppm, 400 by default
+    pub outdoor: Option<f64>,
+    /// room volume in m³, needed for occupant counts
+    pub volume: Option<f64>,
+}
+
+/// ventilation and occupancy estimated from a publisher's CO2 readings
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Analytics {
+    pub pub_id: u64,
+    /// unix seconds of the range analysed
+    pub from: u64,
+    pub to: u64,
+    pub outdoor_co2: f64,
+    /// room volume in m³
+    pub volume: Option<f64>,
+    pub ventilation: Ventilation,
+    pub occupancy: Vec<Occupancy>,
+}
+
+impl Analytics {
+    pub fn new(pub_id: u64, from: u64, to: u64, estimates: ventilation::Estimates) -> Analytics {
+        Analytics {
+            pub_id,
+            from,
+            to,
+            outdoor_co2: estimates.outdoor_co2,
+            volume: estimates.volume,
+            ventilation: Ventilation {
+                ach: estimates.ventilation.ach,
+                confidence: estimates.ventilation.confidence,
+                decays: estimates
+                    .ventilation
+                    .decays
+                    .into_iter()
+                    .map(Decay::from)
+                    .collect(),
+            },
+            occupancy: estimates
+                .occupancy
+                .into_iter()
+                .map(Occupancy::from)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Ventilation {
+    /// air changes per hour, none without a decay to fit
+    pub ach: Option<f64>,
+    /// 0-1
+    pub confidence: f64,
+    pub decays: Vec<Decay>,
+}
+
+/// CO2 falling after a room emptied, fitted to find the air changes per hour
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Decay {
+    /// unix seconds
+    pub start: u64,
+    pub end: u64,
+    /// ppm
+    pub start_co2: f64,
+    pub end_co2: f64,
+    pub ach: f64,
+    /// coefficient of determination of the fit
+    pub r2: f64,
+}
+
+impl From<ventilation::Decay> for Decay {
+    fn from(decay: ventilation::Decay) -> Decay {
+        Decay {
+            start: decay.start as u64,
+            end: decay.end as u64,
+            start_co2: decay.start_co2,
+            end_co2: decay.end_co2,
+            ach: decay.ach,
+            r2: decay.r2,
+        }
+    }
+}
+
+/// CO2 steady while a room was occupied
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Occupancy {
+    /// unix seconds
+    pub start: u64,
+    pub end: u64,
+    /// mean ppm
+    pub co2: f64,
+    /// none without a ventilation rate or room volume
+    pub occupants: Option<f64>,
+    /// 0-1
+    pub confidence: f64,
+}
+
+impl From<ventilation::Occupancy> for Occupancy {
+    fn from(occupancy: ventilation::Occupancy) -> Occupancy {
+        Occupancy {
+            start: occupancy.start as u64,
+            end: occupancy.end as u64,
+            co2: occupancy.co2,
+            occupants: occupancy.occupants,
+            confidence: occupancy.confidence,
+        }
+    }
+}
+
+/// a publisher's air quality over a day or week
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Report {
+    pub pub_id: u64,
+    pub period: Period,
+    /// unix seconds, `to` is exclusive
+    pub from: u64,
+    pub to: u64,
+    pub generated: u64,
+    pub readings: u64,
+    /// readings flagged `flatline`, `spike` or `out_of_range`
+    pub suspect_readings: u64,
+    pub eco2_above: TimeAbove,
+    pub evtoc_above: TimeAbove,
+    pub days: Vec<DaySummary>,
+    /// highest mean eCO2 hours, worst first
+    pub worst_hours: Vec<HourSummary>,
+    pub alerts_fired: u32,
+    pub alerts: Vec<AlertCount>,
+    pub uptime: Uptime,
+}
+
+impl From<report::Report> for Report {
+    fn from(report: report::Report) -> Report {
+        Report {
+            pub_id: report.pub_id,
+            period: report.period,
+            from: report.from as u64,
+            to: report.to as u64,
+            generated: report.generated as u64,
+            readings: report.readings as u64,
+            suspect_readings: report.suspect_readings as u64,
+            eco2_above: report.eco2_above.into(),
+            evtoc_above: report.evtoc_above.into(),
+            days: report.days.into_iter().map(DaySummary::from).collect(),
+            worst_hours: report
+                .worst_hours
+                .into_iter()
+                .map(HourSummary::from)
+                .collect(),
+            alerts_fired: report.alerts_fired,
+            alerts: report.alerts.into_iter().map(AlertCount::from).collect(),
+            uptime: report.uptime.into(),
+        }
+    }
+}
+
+/// time spent above a threshold
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TimeAbove {
+    pub threshold: f64,
+    pub secs: u64,
+    /// of the time the sensor reported
+    pub percent: f64,
+}
+
+impl From<report::TimeAbove> for TimeAbove {
+    fn from(above: report::TimeAbove) -> TimeAbove {
+        TimeAbove {
+            threshold: above.threshold,
+            secs: above.secs as u64,
+            percent: above.percent,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DaySummary {
+    /// `YYYY-MM-DD`, UTC
+    pub date: String,
+    pub readings: u64,
+    pub eco2: Stats,
+    /// none when no reading of the day had a TVOC value
+    pub evtoc: Option<Stats>,
+}
+
+impl From<report::DaySummary> for DaySummary {
+    fn from(day: report::DaySummary) -> DaySummary {
+        DaySummary {
+            date: day.date,
+            readings: day.readings as u64,
+            eco2: day.eco2,
+            evtoc: day.evtoc,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HourSummary {
+    /// unix seconds
+    pub start: u64,
+    pub eco2_mean: f64,
+    pub evtoc_mean: Option<f64>,
+    pub iaq: Iaq,
+}
+
+impl From<report::HourSummary> for HourSummary {
+    fn from(hour: report::HourSummary) -> HourSummary {
+        HourSummary {
+            start: hour.start as u64,
+            eco2_mean: hour.eco2_mean,
+            evtoc_mean: hour.evtoc_mean,
+            iaq: hour.iaq,
+        }
+    }
+}
+
+/// times a rule fired in the period
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AlertCount {
+    pub rule_id: i32,
+    pub name: String,
+    pub fired: u32,
+}
+
+impl From<report::AlertCount> for AlertCount {
+    fn from(count: report::AlertCount) -> AlertCount {
+        AlertCount {
+            rule_id: count.rule_id,
+            name: count.name,
+            fired: count.fired,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Uptime {
+    /// time with at least one reading, to the minute
+    pub reporting_secs: u64,
+    /// time elapsed in the period
+    pub period_secs: u64,
+    pub percent: f64,
+    pub longest_gap_secs: u64,
+}
+
+impl From<report::Uptime> for Uptime {
+    fn from(uptime: report::Uptime) -> Uptime {
+        Uptime {
+            reporting_secs: uptime.reporting_secs as u64,
+            period_secs: uptime.period_secs as u64,
+            percent: uptime.percent,
+            longest_gap_secs: uptime.longest_gap_secs as u64,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ActiveAlertsQuery {
+    /// every publisher's alerts when none
+    pub pub_id: Option<u64>,
+}
+
+/// a rule currently firing for a publisher
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ActiveAlert {
+    pub rule_id: i32,
+    pub name: String,
+    pub pub_id: u64,
+    pub metric: String,
+    /// metric value of the reading that fired the alert
+    pub value: f64,
+    /// unix seconds it fired at
+    pub since: u64,
+}
+
+impl From<alerts::Alert> for ActiveAlert {
+    fn from(alert: alerts::Alert) -> ActiveAlert {
+        ActiveAlert {
+            rule_id: alert.rule_id,
+            name: alert.name,
+            pub_id: alert.pub_id,
+            metric: alert.metric,
+            value: alert.value,
+            since: alert.time,
+        }
+    }
+}
+
+/// threshold alert with hysteresis, fires when `metric` stays above
+/// `trigger_above` for `trigger_secs` and clears once it stays below
+/// `clear_below` for `clear_secs`
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AlertRule {
+    pub id: i32,
+    pub name: String,
+    /// the rule applies to every publisher when none
+    pub pub_id: Option<u64>,
+    /// `eco2`, `evtoc`, `temperature`, `humidity`, `iaq` or a metric a sensor reports
+    pub metric: String,
+    pub trigger_above: f64,
+    pub trigger_secs: u32,
+    pub clear_below: f64,
+    pub clear_secs: u32,
+    pub enabled: bool,
+}
+
+impl From<model::AlertRule> for AlertRule {
+    fn from(rule: model::AlertRule) -> AlertRule {
+        AlertRule {
+            id: rule.id,
+            name: rule.name,
+            pub_id: rule.publisher_id.map(|id| id as u64),
+            metric: rule.metric,
+            trigger_above: rule.trigger_above,
+            trigger_secs: rule.trigger_secs as u32,
+            clear_below: rule.clear_below,
+            clear_secs: rule.clear_secs as u32,
+            enabled: rule.enabled,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewAlertRule {
+    pub name: String,
+    /// every publisher when none
+    pub pub_id: Option<u64>,
+    /// `eco2`, `evtoc`, `temperature`, `humidity`, `iaq` or a metric a sensor has reported
+    pub metric: String,
+    pub trigger_above: f64,
+    pub trigger_secs: u32,
+    /// must not be above `trigger_above`
+    pub clear_below: f64,
+    pub clear_secs: u32,
+}
+
+impl From<NewAlertRule> for model::NewAlertRule {
+    fn from(rule: NewAlertRule) -> model::NewAlertRule {
+        model::NewAlertRule {
+            name: rule.name,
+            publisher_id: rule.pub_id.map(|id| id as i64),
+            metric: rule.metric,
+            trigger_above: rule.trigger_above,
+            trigger_secs: rule.trigger_secs as i64,
+            clear_below: rule.clear_below,
+            clear_secs: rule.clear_secs as i64,
+        }
+    }
+}
+
+/// alert transitions, newest first
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AlertHistoryQuery {
+    /// every publisher's alerts when none
+    pub pub_id: Option<u64>,
+    /// unix seconds, the latest transitions when none
+    pub before: Option<u64>,
+    /// 100 by default, at most 1000
+    pub limit: Option<u16>,
+}
+
+impl AlertHistoryQuery {
+    pub fn to_message(&self) -> Result<GetAlertHistory, ApiError> {
+        Ok(GetAlertHistory {
+            pub_id: self.pub_id,
+            before: self.before,
+            limit: limit(self.limit)?,
+        })
+    }
+}
+
+/// an alert firing or clearing
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AlertEvent {
+    pub id: i32,
+    pub rule_id: i32,
+    pub pub_id: u64,
+    pub state: AlertState,
+    /// metric value of the reading that caused the transition
+    pub value: f64,
+    /// unix seconds
+    pub time: u64,
+}
+
+impl From<model::AlertEvent> for AlertEvent {
+    fn from(event: model::AlertEvent) -> AlertEvent {
+        AlertEvent {
+            id: event.id,
+            rule_id: event.rule_id,
+            pub_id: event.publisher_id as u64,
+            state: match event.state.as_str() {
+                "firing" => AlertState::Firing,
+                _ => AlertState::Cleared,
+            },
+            value: event.value,
+            time: event.event_time as u64,
+        }
+    }
+}
+
+/// url notified of alerts and device events
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    /// event names, or `*` for every event
+    pub events: Vec<String>,
+    /// deliveries carry a signature made with the webhook's secret
+    pub signed: bool,
+    pub enabled: bool,
+}
+
+impl From<model::Webhook> for Webhook {
+    fn from(hook: model::Webhook) -> Webhook {
+        Webhook {
+            id: hook.id,
+            url: hook.url,
+            events: hook
+                .events
+                .split(',')
+                .map(|e| e.trim().to_owned())
+                .filter(|e| !e.is_empty())
+                .collect(),
+            signed: hook.secret.is_some(),
+            enabled: hook.enabled,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewWebhook {
+    /// http or https
+    pub url: String,
+    /// signs deliveries, never returned
+    pub secret: Option<String>,
+    /// event names, or `*` for every event
+    pub events: Vec<String>,
+}
+
+impl From<NewWebhook> for model::NewWebhook {
+    fn from(hook: NewWebhook) -> model::NewWebhook {
+        model::NewWebhook {
+            url: hook.url,
+            secret: hook.secret,
+            events: hook.events.join(","),
+        }
+    }
+}
+
+/// delivery attempts, newest first
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeliveriesQuery {
+    /// every webhook's deliveries when none
+    pub webhook_id: Option<i32>,
+    /// a delivery id, the latest deliveries when none
+    pub before: Option<i32>,
+    /// 100 by default, at most 1000
+    pub limit: Option<u16>,
+}
+
+impl DeliveriesQuery {
+    pub fn to_message(&self) -> Result<GetWebhookDeliveries, ApiError> {
+        Ok(GetWebhookDeliveries {
+            webhook_id: self.webhook_id,
+            before: self.before,
+            limit: limit(self.limit)?,
+        })
+    }
+}
+
+/// a single attempt to deliver an event to a webhook
+#[derive(Debug, Serialize, ToSchema)]
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub webhook_id: i32,
+    pub event: String,
+    /// the json body sent
+    pub payload: String,
+    pub attempt: u32,
+    /// none when no response was received
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub delivered: bool,
+    /// unix seconds
+    pub time: u64,
+}
+
+impl From<model::WebhookDelivery> for WebhookDelivery {
+    fn from(delivery: model::WebhookDelivery) -> WebhookDelivery {
+        WebhookDelivery {
+            id: delivery.id,
+            webhook_id: delivery.webhook_id,
+            event: delivery.event,
+            payload: delivery.payload,
+            attempt: delivery.attempt as u32,
+            status_code: delivery.status_code.map(|code| code as u16),
+            error: delivery.error,
+            delivered: delivery.delivered,
+            time: delivery.created_at as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::model::{DbReading, ReadingBucket, QUALITY_SPIKE};
+    use serde_json::json;
+
+    fn reading(quality_flags: i32) -> DbReading {
+        DbReading {
+            id: 7,
+            publisher_id: 811,
+            eco2: 640,
+            evtoc: 20,
+            read_time: 1_600_000_000,
+            start_time: 1_599_999_400,
+            mode: Mode::ConstantPower1s,
+            sensor_model: Some("CCS811".to_owned()),
+            temperature: Some(21.5),
+            humidity: None,
+            quality_flags,
+            raw_current: Some(10),
+            raw_voltage: Some(512),
+        }
+    }
+
+    #[test]
+    fn stored_readings_serialize_flags_and_iaq() {
+        let stored = serde_json::to_value(StoredReading::from(&reading(0))).unwrap();
+        assert_eq!(
+            stored,
+            json!({
+                "id": 7,
+                "pub_id": 811,
+                "eco2": 640,
+                "evtoc": 20,
+                "read_time": 1_600_000_000u64,
+                "start_time": 1_599_999_400u64,
+                "mode": "ConstantPower1s",
+                "sensor_model": "CCS811",
+                "temperature": 21.5,
+                "humidity": null,
+                "flags": [],
+                "iaq": serde_json::to_value(Iaq::new(640.0, Some(20.0))).unwrap(),
+            })
+        );
+    }
+
+    #[test]
+    fn stored_readings_without_tvoc_have_no_evtoc() {
+        let reading = reading(QUALITY_NO_EVTOC | QUALITY_SPIKE);
+        let stored = serde_json::to_value(StoredReading::from(&reading)).unwrap();
+        assert_eq!(stored["evtoc"], json!(null));
+        assert_eq!(stored["flags"], json!(["no_evtoc", "spike"]));
+        assert_eq!(stored["iaq"]["tvoc"], json!(null));
+        let json: serde_json::Value = serde_json::from_str(&StoredReading::json(&reading)).unwrap();
+        assert_eq!(json, stored);
+    }
+
+    #[test]
+    fn buckets_nest_their_stats() {
+        let bucket = ReadingBucket {
+            start: 1_600_000_000,
+            readings: 4,
+            suspect: 1,
+            eco2_mean: 700.0,
+            eco2_min: 400,
+            eco2_max: 1000,
+            evtoc_mean: None,
+            evtoc_min: None,
+            evtoc_max: None,
+        };
+        let json = serde_json::to_value(Bucket::from(&bucket)).unwrap();
+        assert_eq!(json["start"], 1_600_000_000u64);
+        assert_eq!(json["suspect"], 1);
+        assert_eq!(
+            json["eco2"],
+            json!({ "min": 400, "max": 1000, "mean": 700.0 })
+        );
+        assert_eq!(json["evtoc"], json!(null));
+        assert_eq!(
+            json["iaq"],
+            serde_json::to_value(Iaq::new(700.0, None)).unwrap()
+        );
+    }
+
+    #[test]
+    fn webhooks_never_serialize_their_secret() {
+        let hook = model::Webhook {
+            id: 3,
+            url: "https://example.com/hook".to_owned(),
+            secret: Some("s3cret".to_owned()),
+            events: "alert, report,".to_owned(),
+            enabled: true,
+        };
+        assert_eq!(
+            serde_json::to_value(Webhook::from(hook)).unwrap(),
+            json!({
+                "id": 3,
+                "url": "https://example.com/hook",
+                "events": ["alert", "report"],
+                "signed": true,
+                "enabled": true,
+            })
+        );
+    }
+
+    #[test]
+    fn alert_events_serialize_their_state() {
+        let event = model::AlertEvent {
+            id: 1,
+            rule_id: 2,
+            publisher_id: 811,
+            state: "firing".to_owned(),
+            value: 1200.0,
+            event_time: 1_600_000_000,
+        };
+        assert_eq!(
+            serde_json::to_value(AlertEvent::from(event)).unwrap(),
+            json!({
+                "id": 1,
+                "rule_id": 2,
+                "pub_id": 811,
+                "state": "firing",
+                "value": 1200.0,
+                "time": 1_600_000_000u64,
+            })
+        );
+    }
+
+    #[test]
+    fn new_alert_rules_deserialize_to_the_model() {
+        let rule: NewAlertRule = serde_json::from_value(json!({
+            "name": "stuffy",
+            "pub_id": null,
+            "metric": "eco2",
+            "trigger_above": 1000.0,
+            "trigger_secs": 300,
+            "clear_below": 800.0,
+            "clear_secs": 600,
+        }))
+        .unwrap();
+        let rule = model::NewAlertRule::from(rule);
+        assert_eq!(rule.publisher_id, None);
+        assert_eq!((rule.trigger_secs, rule.clear_secs), (300, 600));
+    }
+
+    #[test]
+    fn limits_default_and_are_bounded() {
+        let query = |limit| ReadingsQuery {
+            before: None,
+            limit,
+        };
+        assert_eq!(query(None).to_message(811).unwrap().limit, DEFAULT_LIMIT);
+        assert_eq!(
+            query(Some(MAX_LIMIT)).to_message(811).unwrap().limit,
+            MAX_LIMIT
+        );
+        assert!(query(Some(0)).to_message(811).is_err());
+        assert!(query(Some(MAX_LIMIT + 1)).to_message(811).is_err());
+    }
+}
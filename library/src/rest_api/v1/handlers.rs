@@ -0,0 +1,413 @@
+//! `/api/v1` handlers answering with `dto` types, those whose bodies aren't
+//! db models or actor messages are in `rest_api::handlers`
+use super::dto::{
+    ActiveAlert, ActiveAlertsQuery, AlertEvent, AlertHistoryQuery, AlertRule, Analytics,
+    AnalyticsQuery, Bucket, DeliveriesQuery, ExportQuery, NewAlertRule, NewWebhook, ReadingsQuery,
+    Report, StoredReading, Webhook, WebhookDelivery,
+};
+use crate::{
+    common::{AggregateReadings, ExportReadings, GetAlertRules, GetWebhooks},
+    db::actions::Actions,
+    export::{Encoder, ExportedReading},
+    relay_server::ListAlerts,
+    report::{self, ReportQuery, Thresholds},
+    rest_api::{
+        error::ApiError,
+        handlers::{
+            admin::{authorize, AdminToken},
+            alerts::create_rule,
+            sensors::{aggregate, SummaryQuery},
+            webhooks::create_webhook,
+        },
+    },
+    ventilation,
+    webhooks::Webhooks,
+    RelayServer,
+};
+use actix::prelude::*;
+use actix_web::{web, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use futures::stream;
+use std::time::SystemTime;
+
+/// readings loaded from the db per exported chunk
+const EXPORT_PAGE_SIZE: u16 = 5000;
+/// longest range analysed at once, in seconds
+const MAX_ANALYTICS_RANGE: u64 = 7 * 24 * 60 * 60;
+
+/// a publisher's latest readings before `before`, oldest first
+#[utoipa::path(
+    get,
+    path = "/api/v1/sensors/{pub_id}/readings",
+    tag = "sensors",
+    params(("pub_id" = u64, Path, description = "publisher id"), ReadingsQuery),
+    responses(
+        (status = 200, description = "readings, oldest first", body = [StoredReading]),
+        (status = 400, description = "invalid query", body = ApiError),
+    )
+)]
+pub async fn readings(
+    pub_id: web::Path<u64>,
+    web::Query(query): web::Query<ReadingsQuery>,
+    actions: web::Data<Addr<Actions>>,
+) -> Result<HttpResponse, ApiError> {
+    let readings = actions
+        .get_ref()
+        .send(query.to_message(pub_id.into_inner())?)
+        .await
+        .map_err(ApiError::internal)?;
+    let res = readings
+        .iter()
+        .rev()
+        .map(StoredReading::from)
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(res))
+}
+
+/// a publisher's readings aggregated into time buckets
+#[utoipa::path(
+    get,
+    path = "/api/v1/sensors/{pub_id}/summary",
+    tag = "sensors",
+    params(("pub_id" = u64, Path, description = "publisher id"), SummaryQuery),
+    responses(
+        (status = 200, description = "buckets, oldest first", body = [Bucket]),
+        (status = 400, description = "bucket out of range", body = ApiError),
+    )
+)]
+pub async fn summary(
+    pub_id: web::Path<u64>,
+    web::Query(query): web::Query<SummaryQuery>,
+    actions: web::Data<Addr<Actions>>,
+) -> Result<HttpResponse, ApiError> {
+    let buckets = aggregate(pub_id.into_inner(), &query, &actions).await?;
+    let res = buckets.iter().map(Bucket::from).collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(res))
+}
+
+/// daily or weekly report on a publisher, `?period=weekly&date=YYYY-MM-DD`
+#[utoipa::path(
+    get,
+    path = "/api/v1/sensors/{pub_id}/report",
+    tag = "sensors",
+    params(("pub_id" = u64, Path, description = "publisher id"), ReportQuery),
+    responses(
+        (status = 200, description = "the report", body = Report),
+        (status = 400, description = "invalid period or date", body = ApiError),
+    )
+)]
+pub async fn report(
+    pub_id: web::Path<u64>,
+    web::Query(query): web::Query<ReportQuery>,
+    actions: web::Data<Addr<Actions>>,
+    thresholds: web::Data<Thresholds>,
+) -> Result<HttpResponse, ApiError> {
+    let (period, date) = query.resolve().map_err(ApiError::bad_request)?;
+    let report = report::generate(&actions, pub_id.into_inner(), period, date, &thresholds)
+        .await
+        .map_err(ApiError::internal)?;
+    Ok(HttpResponse::Ok().json(Report::from(report)))
+}
+
+/// ventilation rate and occupancy estimated from a publisher's CO2 readings
+#[utoipa::path(
+    get,
+    path = "/api/v1/sensors/{pub_id}/analytics",
+    tag = "sensors",
+    params(("pub_id" = u64, Path, description = "publisher id"), AnalyticsQuery),
+    responses(
+        (status = 200, description = "ventilation and occupancy estimates", body = Analytics),
+        (status = 400, description = "invalid range or volume", body = ApiError),
+    )
+)]
+pub async fn analytics(
+    pub_id: web::Path<u64>,
+    web::Query(query): web::Query<AnalyticsQuery>,
+    actions: web::Data<Addr<Actions>>,
+) -> Result<HttpResponse, ApiError> {
+    let pub_id = pub_id.into_inner();
+    let to = query.to.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    });
+    let from = query
+        .from
+        .unwrap_or_else(|| to.saturating_sub(24 * 60 * 60));
+    if from >= to || to - from > MAX_ANALYTICS_RANGE {
+        return Err(ApiError::bad_request(format!(
+            "from must be before to and at most {} seconds earlier",
+            MAX_ANALYTICS_RANGE
+        )));
+    }
+    if query.volume.is_some_and(|v| v <= 0.0) {
+        return Err(ApiError::bad_request("volume must be positive"));
+    }
+    let buckets = actions
+        .get_ref()
+        .send(AggregateReadings {
+            pub_id,
+            from: Some(from),
+            to: Some(to),
+            bucket: ventilation::BUCKET_SECS,
+            limit: (MAX_ANALYTICS_RANGE / ventilation::BUCKET_SECS as u64 + 1) as u16,
+        })
+        .await
+        .map_err(ApiError::internal)?
+        .map_err(ApiError::internal)?;
+    let estimates = ventilation::estimate(
+        &buckets,
+        query.outdoor.unwrap_or(ventilation::DEFAULT_OUTDOOR_CO2),
+        query.volume,
+    );
+    Ok(HttpResponse::Ok().json(Analytics::new(pub_id, from, to, estimates)))
+}
+
+struct ExportState {
+    actions: Addr<Actions>,
+    page: ExportReadings,
+    encoder: Encoder,
+}
+
+/// streams readings page by page in the requested format
+#[utoipa::path(
+    get,
+    path = "/api/v1/readings/export",
+    tag = "sensors",
+    params(ExportQuery),
+    responses(
+        (status = 200, description = "csv, jsonl or parquet file of the readings"),
+        (status = 400, description = "invalid query", body = ApiError),
+    )
+)]
+pub async fn export_readings(
+    web::Query(query): web::Query<ExportQuery>,
+    actions: web::Data<Addr<Actions>>,
+) -> Result<HttpResponse, ApiError> {
+    let encoder = Encoder::new(query.format).map_err(ApiError::internal)?;
+    let state = ExportState {
+        actions: actions.get_ref().clone(),
+        page: ExportReadings {
+            pub_id: query.pub_id,
+            from: query.from,
+            to: query.to,
+            after: None,
+            limit: EXPORT_PAGE_SIZE,
+        },
+        encoder,
+    };
+    let body = Box::pin(stream::unfold(Some(state), |state| async move {
+        let mut state = state?;
+        let page = match state.actions.send(state.page.clone()).await {
+            Ok(page) => page,
+            Err(err) => return Some((Err(ApiError::internal(err)), None)),
+        };
+        let chunk: Result<Bytes, String>;
+        let next = match page.last() {
+            Some(last) => {
+                state.page.after = Some((last.read_time, last.id));
+                let rows = page.iter().map(ExportedReading::from).collect::<Vec<_>>();
+                chunk = state.encoder.encode(&rows);
+                chunk.as_ref().ok().map(|_| state)
+            }
+            None => {
+                chunk = state.encoder.finish();
+                None
+            }
+        };
+        Some((chunk.map_err(ApiError::internal), next))
+    }));
+    Ok(HttpResponse::Ok()
+        .content_type(query.format.content_type())
+        .header(
+            "content-disposition",
+            format!(
+                "attachment; filename=\"readings.{}\"",
+                query.format.extension()
+            ),
+        )
+        .streaming(body))
+}
+
+/// currently firing alerts
+#[utoipa::path(
+    get,
+    path = "/api/v1/alerts",
+    tag = "alerts",
+    params(ActiveAlertsQuery),
+    responses(
+        (status = 200, description = "firing alerts", body = [ActiveAlert]),
+    )
+)]
+pub async fn active_alerts(
+    web::Query(query): web::Query<ActiveAlertsQuery>,
+    srv: web::Data<Addr<RelayServer>>,
+) -> Result<HttpResponse, ApiError> {
+    let alerts = srv
+        .get_ref()
+        .send(ListAlerts {
+            pub_id: query.pub_id,
+        })
+        .await
+        .map_err(ApiError::internal)?;
+    let res = alerts
+        .into_iter()
+        .map(ActiveAlert::from)
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(res))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/alerts/history",
+    tag = "alerts",
+    params(AlertHistoryQuery),
+    responses(
+        (status = 200, description = "alert events, newest first", body = [AlertEvent]),
+        (status = 400, description = "invalid query", body = ApiError),
+    )
+)]
+pub async fn alert_history(
+    web::Query(query): web::Query<AlertHistoryQuery>,
+    actions: web::Data<Addr<Actions>>,
+) -> Result<HttpResponse, ApiError> {
+    let events = actions
+        .get_ref()
+        .send(query.to_message()?)
+        .await
+        .map_err(ApiError::internal)?;
+    let res = events.into_iter().map(AlertEvent::from).collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(res))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/alerts/rules",
+    tag = "alerts",
+    responses(
+        (status = 200, description = "rules including disabled ones", body = [AlertRule]),
+    )
+)]
+pub async fn list_rules(actions: web::Data<Addr<Actions>>) -> Result<HttpResponse, ApiError> {
+    let rules = actions
+        .get_ref()
+        .send(GetAlertRules {
+            enabled_only: false,
+        })
+        .await
+        .map_err(ApiError::internal)?;
+    let res = rules.into_iter().map(AlertRule::from).collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(res))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/alerts/rules",
+    tag = "alerts",
+    request_body(content = NewAlertRule),
+    responses(
+        (status = 201, description = "the new rule", body = AlertRule),
+        (status = 400, description = "unknown metric or inconsistent thresholds", body = ApiError),
+        (status = 401, description = "missing or wrong admin token", body = ApiError),
+        (status = 403, description = "admin routes are disabled", body = ApiError),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn add_rule(
+    req: HttpRequest,
+    web::Json(rule): web::Json<NewAlertRule>,
+    token: web::Data<AdminToken>,
+    actions: web::Data<Addr<Actions>>,
+    srv: web::Data<Addr<RelayServer>>,
+) -> Result<HttpResponse, ApiError> {
+    authorize(&req, &token)?;
+    let rule = create_rule(rule.into(), &actions, &srv).await?;
+    Ok(HttpResponse::Created().json(AlertRule::from(rule)))
+}
+
+/// webhooks including disabled ones, secrets aren't returned
+#[utoipa::path(
+    get,
+    path = "/api/v1/webhooks",
+    tag = "webhooks",
+    responses(
+        (status = 200, description = "webhooks including disabled ones", body = [Webhook]),
+        (status = 401, description = "missing or wrong admin token", body = ApiError),
+        (status = 403, description = "admin routes are disabled", body = ApiError),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn list_webhooks(
+    req: HttpRequest,
+    token: web::Data<AdminToken>,
+    actions: web::Data<Addr<Actions>>,
+) -> Result<HttpResponse, ApiError> {
+    authorize(&req, &token)?;
+    let hooks = actions
+        .get_ref()
+        .send(GetWebhooks {
+            enabled_only: false,
+        })
+        .await
+        .map_err(ApiError::internal)?;
+    let res = hooks.into_iter().map(Webhook::from).collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(res))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/webhooks",
+    tag = "webhooks",
+    request_body(content = NewWebhook),
+    responses(
+        (status = 201, description = "the new webhook", body = Webhook),
+        (status = 400, description = "invalid url or events", body = ApiError),
+        (status = 401, description = "missing or wrong admin token", body = ApiError),
+        (status = 403, description = "admin routes are disabled", body = ApiError),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn add_webhook(
+    req: HttpRequest,
+    web::Json(hook): web::Json<NewWebhook>,
+    token: web::Data<AdminToken>,
+    actions: web::Data<Addr<Actions>>,
+    webhooks: web::Data<Addr<Webhooks>>,
+) -> Result<HttpResponse, ApiError> {
+    authorize(&req, &token)?;
+    let hook = create_webhook(hook.into(), &actions, &webhooks).await?;
+    Ok(HttpResponse::Created().json(Webhook::from(hook)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/webhooks/deliveries",
+    tag = "webhooks",
+    params(DeliveriesQuery),
+    responses(
+        (status = 200, description = "delivery attempts, newest first", body = [WebhookDelivery]),
+        (status = 400, description = "invalid query", body = ApiError),
+        (status = 401, description = "missing or wrong admin token", body = ApiError),
+        (status = 403, description = "admin routes are disabled", body = ApiError),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn deliveries(
+    req: HttpRequest,
+    web::Query(query): web::Query<DeliveriesQuery>,
+    token: web::Data<AdminToken>,
+    actions: web::Data<Addr<Actions>>,
+) -> Result<HttpResponse, ApiError> {
+    authorize(&req, &token)?;
+    let deliveries = actions
+        .get_ref()
+        .send(query.to_message()?)
+        .await
+        .map_err(ApiError::internal)?;
+    let res = deliveries
+        .into_iter()
+        .map(WebhookDelivery::from)
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(res))
+}
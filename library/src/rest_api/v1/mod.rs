@@ -0,0 +1,76 @@
+//! The stable, versioned REST api. Its bodies and queries are the types in
+//! `dto` rather than actor messages or db models, so those can change without
+//! breaking clients. The unversioned `/api` routes it replaces answer with a
+//! `Deprecation` header.
+use super::{handlers as shared, IMPORT_SIZE_LIMIT, READINGS_SIZE_LIMIT};
+use crate::rest_api::error;
+use actix_web::web;
+
+pub mod dto;
+pub mod handlers;
+
+/// routes under `/api/v1`
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin/backups")
+            .service(
+                web::resource("")
+                    .route(web::get().to(shared::admin::list_backups))
+                    .route(web::post().to(shared::admin::create_backup)),
+            )
+            .service(web::resource("/{name}").route(web::get().to(shared::admin::download_backup))),
+    )
+    .service(
+        web::scope("/alerts")
+            .service(web::resource("").route(web::get().to(handlers::active_alerts)))
+            .service(web::resource("/history").route(web::get().to(handlers::alert_history)))
+            .service(
+                web::resource("/rules")
+                    .route(web::get().to(handlers::list_rules))
+                    .route(web::post().to(handlers::add_rule)),
+            )
+            .service(
+                web::resource("/rules/{id}").route(web::delete().to(shared::alerts::disable_rule)),
+            ),
+    )
+    .service(
+        web::scope("/readings")
+            .service(web::resource("/export").route(web::get().to(handlers::export_readings)))
+            .service(
+                web::resource("/import")
+                    .data(web::PayloadConfig::new(IMPORT_SIZE_LIMIT))
+                    .route(web::post().to(shared::sensors::import_readings)),
+            ),
+    )
+    .service(
+        web::scope("/sensors/{pub_id}")
+            .service(web::resource("/analytics").route(web::get().to(handlers::analytics)))
+            .service(
+                web::resource("/readings")
+                    .app_data(error::json_config().limit(READINGS_SIZE_LIMIT))
+                    .route(web::get().to(handlers::readings))
+                    .route(web::post().to(shared::sensors::post_readings)),
+            )
+            .service(web::resource("/report").route(web::get().to(handlers::report)))
+            .service(web::resource("/summary").route(web::get().to(handlers::summary)))
+            .service(
+                web::resource("/stream").route(web::get().to(shared::sensors::stream_readings)),
+            ),
+    )
+    .service(web::resource("/write").route(web::post().to(shared::ingest::write)))
+    .service(
+        web::scope("/webhooks")
+            .service(
+                web::resource("")
+                    .route(web::get().to(handlers::list_webhooks))
+                    .route(web::post().to(handlers::add_webhook)),
+            )
+            .service(web::resource("/deliveries").route(web::get().to(handlers::deliveries)))
+            .service(
+                web::resource("/{id}").route(web::delete().to(shared::webhooks::disable_webhook)),
+            )
+            .service(
+                web::resource("/{id}/test").route(web::post().to(shared::webhooks::test_webhook)),
+            ),
+    );
+}
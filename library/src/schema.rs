@@ -1,3 +1,43 @@
+table! {
+    alert_events (id) {
+        id -> Integer,
+        rule_id -> Integer,
+        publisher_id -> BigInt,
+        state -> Text,
+        value -> Double,
+        event_time -> BigInt,
+    }
+}
+
+table! {
+    alert_rules (id) {
+        id -> Integer,
+        name -> Text,
+        publisher_id -> Nullable<BigInt>,
+        metric -> Text,
+        trigger_above -> Double,
+        trigger_secs -> BigInt,
+        clear_below -> Double,
+        clear_secs -> BigInt,
+        enabled -> Bool,
+    }
+}
+
+table! {
+    measurement_modes (id) {
+        id -> SmallInt,
+        name -> Text,
+    }
+}
+
+table! {
+    reading_metrics (reading_id, metric_name) {
+        reading_id -> Integer,
+        metric_name -> Text,
+        value -> Double,
+    }
+}
+
 table! {
     readings (id) {
         id -> Integer,
@@ -6,6 +46,51 @@ table! {
         evtoc -> Integer,
         read_time -> BigInt,
         start_time -> BigInt,
-        increment -> Text,
+        mode -> SmallInt,
+        sensor_model -> Nullable<Text>,
+        temperature -> Nullable<Double>,
+        humidity -> Nullable<Double>,
+        quality_flags -> Integer,
+        raw_current -> Nullable<Integer>,
+        raw_voltage -> Nullable<Integer>,
+    }
+}
+
+table! {
+    webhook_deliveries (id) {
+        id -> Integer,
+        webhook_id -> Integer,
+        event -> Text,
+        payload -> Text,
+        attempt -> Integer,
+        status_code -> Nullable<Integer>,
+        error -> Nullable<Text>,
+        delivered -> Bool,
+        created_at -> BigInt,
+    }
+}
+
+table! {
+    webhooks (id) {
+        id -> Integer,
+        url -> Text,
+        secret -> Nullable<Text>,
+        events -> Text,
+        enabled -> Bool,
     }
 }
+
+joinable!(alert_events -> alert_rules (rule_id));
+joinable!(reading_metrics -> readings (reading_id));
+joinable!(webhook_deliveries -> webhooks (webhook_id));
+joinable!(readings -> measurement_modes (mode));
+
+allow_tables_to_appear_in_same_query!(
+    alert_events,
+    alert_rules,
+    measurement_modes,
+    reading_metrics,
+    readings,
+    webhook_deliveries,
+    webhooks,
+);
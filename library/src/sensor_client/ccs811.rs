@@ -2,11 +2,15 @@ use actix::*;
 use embedded_ccs811::{prelude::*, Ccs811Awake, MeasurementMode, ModeChangeError, SlaveAddr};
 use linux_embedded_hal::I2cdev;
 use nb::block;
-use std::time::SystemTime;
+use serde_json::json;
+use std::time::{Duration, SystemTime};
 
+use crate::health::SensorHealth;
 use crate::sensor_client::{
-    ConnectSession, CurrentMode, Reading as ReadingMsg, Sensor, TakeReading,
+    ChangeMode, ConnectSession, CurrentMode, Reading as ReadingMsg, ResetSensor, SaveBaseline,
+    Sensor, SensorStatus, TakeReading,
 };
+use crate::{logging, metrics};
 
 pub struct Reading {
     pub eco2: u16,
@@ -14,8 +18,16 @@ pub struct Reading {
     pub read_time: u64,
     pub start_time: u64,
     pub increment: String,
+    pub raw_current: Option<u8>,
+    pub raw_voltage: Option<u16>,
 }
 
+/// time the sensor needs after a software reset before accepting commands
+const RESET_DELAY: Duration = Duration::from_millis(20);
+/// running time before the sensor's baseline is meaningful, a saved one is
+/// restored and the current one saved only after it, in seconds
+const BASELINE_WARM_UP: u64 = 20 * 60;
+
 fn now_secs() -> u64 {
     SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
@@ -50,6 +62,83 @@ impl Handler<TakeReading> for Sensor {
     }
 }
 
+/// handle requests to change measurement mode, the session is told the new
+/// mode so it can reschedule readings
+impl Handler<ChangeMode> for Sensor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ChangeMode, _: &mut SyncContext<Self>) {
+        if let Some(app) = &mut self.app {
+            if let Err(err) = app.set_mode(msg.inc) {
+                tracing::error!(error = ?err, "sensor mode change failed");
+                return;
+            }
+        }
+        tracing::info!(mode = ?msg.inc, "sensor mode changed");
+        self.increment = msg.inc;
+        if let Some(session) = &self.session {
+            session.do_send(CurrentMode { inc: msg.inc });
+        }
+    }
+}
+
+/// handle requests to reset the sensor, e.g. when it's stuck reporting the same
+/// values. the sensor restarts in the same mode and begins its warm up again
+impl Handler<ResetSensor> for Sensor {
+    type Result = ();
+
+    fn handle(&mut self, _: ResetSensor, _: &mut SyncContext<Self>) {
+        let app = match self.app.take() {
+            Some(app) => app,
+            None => {
+                tracing::warn!("sensor in test mode, reset ignored");
+                return;
+            }
+        };
+        tracing::info!("resetting sensor");
+        // the device in boot mode is dropped, releasing the i2c bus
+        if let Err(ModeChangeError { dev, error }) = app.software_reset() {
+            tracing::error!(error = ?error, "sensor reset failed");
+            self.app = Some(dev);
+            return;
+        }
+        std::thread::sleep(RESET_DELAY);
+        self.start_time = now_secs();
+        self.baseline_restored = false;
+        // readings are skipped until the sensor starts, see take_reading
+        if self.start_app().is_err() {
+            tracing::error!("sensor failed to start after reset");
+        }
+    }
+}
+
+/// handle requests to save the baseline, e.g. on shutdown
+impl Handler<SaveBaseline> for Sensor {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, _: SaveBaseline, _: &mut SyncContext<Self>) -> Self::Result {
+        self.save_baseline()
+    }
+}
+
+/// handle health checks, a sensor busy reading answers once it's done
+impl Handler<SensorStatus> for Sensor {
+    type Result = MessageResult<SensorStatus>;
+
+    fn handle(&mut self, _: SensorStatus, _: &mut SyncContext<Self>) -> Self::Result {
+        let uptime_secs = now_secs().saturating_sub(self.start_time);
+        MessageResult(SensorHealth {
+            pub_id: self.pub_id,
+            mode: self.mode_to_str(),
+            test_mode: cfg!(not(target_arch = "arm")),
+            running: self.app.is_some() || cfg!(not(target_arch = "arm")),
+            connected: self.session.is_some(),
+            uptime_secs,
+            warming_up: uptime_secs < BASELINE_WARM_UP,
+        })
+    }
+}
+
 impl Sensor {
     pub fn new(pub_id: u64, mode: MeasurementMode) -> Result<Sensor, ()> {
         Sensor {
@@ -58,6 +147,8 @@ impl Sensor {
             start_time: now_secs(),
             increment: mode,
             session: None,
+            baseline_file: None,
+            baseline_restored: false,
         }
         .load_sensor()
     }
@@ -68,31 +159,86 @@ impl Sensor {
 
     #[cfg(target_arch = "arm")]
     pub fn load_sensor(mut self) -> Result<Sensor, ()> {
-        let dev = I2cdev::new("/dev/i2c-1").unwrap();
+        self.start_app().map(|_| self)
+    }
+
+    #[cfg(not(target_arch = "arm"))]
+    pub fn load_sensor(self) -> Result<Sensor, ()> {
+        tracing::warn!("sensor in test mode, readings aren't real");
+        Ok(self)
+    }
+
+    /// open the sensor and start it in the current measurement mode
+    #[cfg(target_arch = "arm")]
+    fn start_app(&mut self) -> Result<(), ()> {
+        let dev = I2cdev::new("/dev/i2c-1").map_err(|err| {
+            tracing::error!(error = ?err, "couldn't open i2c device");
+        })?;
         let address = SlaveAddr::default();
         let sensor = Ccs811Awake::new(dev, address);
         match sensor.start_application() {
             Err(ModeChangeError { dev: _, error }) => {
-                println!("Error during application start: {:?}", error);
+                tracing::error!(error = ?error, "sensor application start failed");
                 Err(())
             }
             Ok(mut sensor) => match sensor.set_mode(self.increment) {
                 Err(err) => {
-                    println!("{:?}", err);
+                    tracing::error!(error = ?err, "sensor mode set failed");
                     Err(())
                 }
                 Ok(_) => {
                     self.app = Some(sensor);
-                    Ok(self)
+                    Ok(())
                 }
             },
         }
     }
 
     #[cfg(not(target_arch = "arm"))]
-    pub fn load_sensor(self) -> Result<Sensor, ()> {
-        println!("<<SENSOR IN TEST MODE - NOT REAL READINGS>>");
-        Ok(self)
+    fn start_app(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    /// write the sensor's current baseline to the baseline file as hex
+    #[cfg(target_arch = "arm")]
+    fn save_baseline(&mut self) -> Result<(), String> {
+        let path = match &self.baseline_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if now_secs().saturating_sub(self.start_time) < BASELINE_WARM_UP {
+            return Err("sensor still warming up, baseline not saved".to_owned());
+        }
+        let app = self.app.as_mut().ok_or("sensor not running")?;
+        let baseline = app.baseline().map_err(|err| format!("{:?}", err))?;
+        std::fs::write(path, hex::encode(baseline))
+            .map_err(|err| format!("{:?}: {}", path, err))?;
+        tracing::info!(baseline = %hex::encode(baseline), ?path, "sensor baseline saved");
+        Ok(())
+    }
+
+    #[cfg(not(target_arch = "arm"))]
+    fn save_baseline(&mut self) -> Result<(), String> {
+        tracing::warn!("sensor in test mode, baseline not saved");
+        Ok(())
+    }
+
+    /// write a saved baseline to the sensor, none saved is fine
+    #[cfg(target_arch = "arm")]
+    fn restore_baseline(&mut self) -> Result<(), String> {
+        let path = match &self.baseline_file {
+            Some(path) if path.exists() => path,
+            _ => return Ok(()),
+        };
+        let saved = std::fs::read_to_string(path).map_err(|err| format!("{:?}: {}", path, err))?;
+        let mut baseline = [0u8; 2];
+        hex::decode_to_slice(saved.trim(), &mut baseline)
+            .map_err(|err| format!("{:?}: {}", path, err))?;
+        let app = self.app.as_mut().ok_or("sensor not running")?;
+        app.set_baseline(baseline)
+            .map_err(|err| format!("{:?}", err))?;
+        tracing::info!(baseline = saved.trim(), ?path, "sensor baseline restored");
+        Ok(())
     }
 
     fn mode_to_str(&self) -> String {
@@ -108,22 +254,49 @@ impl Sensor {
     }
 
     pub fn take_reading(&mut self) {
+        // a sensor that failed to restart after a reset is retried before reading
+        #[cfg(target_arch = "arm")]
+        if self.app.is_none() && self.start_app().is_err() {
+            metrics::get().sensor_read_errors.inc();
+            return;
+        }
+        // a saved baseline is only valid once the sensor has warmed up
+        #[cfg(target_arch = "arm")]
+        if !self.baseline_restored && now_secs().saturating_sub(self.start_time) >= BASELINE_WARM_UP
+        {
+            self.baseline_restored = true;
+            if let Err(err) = self.restore_baseline() {
+                tracing::error!(error = %err, "sensor baseline restore failed");
+            }
+        }
         // read() blocks the thread
         match &mut self.session.clone() {
             Some(session) => match self.read() {
                 Ok(read) => {
-                    let cmd = format!(
-                                "{{ \"pub_id\": {}, \"eco2\": {}, \"evtoc\":{}, \"increment\":\"{}\", \"read_time\":{}, \"start_time\":{} }}",
-                                self.pub_id, read.eco2, read.evtoc, read.increment, read.read_time, read.start_time
-                            );
+                    let corr_id = logging::corr_id();
+                    tracing::debug!(%corr_id, eco2 = read.eco2, evtoc = read.evtoc, "sensor read");
+                    let cmd = json!({
+                        "pub_id": self.pub_id,
+                        "eco2": read.eco2,
+                        "evtoc": read.evtoc,
+                        "increment": read.increment,
+                        "read_time": read.read_time,
+                        "start_time": read.start_time,
+                        "sensor_model": "CCS811",
+                        "raw_current": read.raw_current,
+                        "raw_voltage": read.raw_voltage,
+                        "corr_id": corr_id,
+                    })
+                    .to_string();
                     session.do_send(ReadingMsg(cmd));
                 }
                 Err(err) => {
-                    println!("SENSOR READ ERROR: {:?}", err);
+                    metrics::get().sensor_read_errors.inc();
+                    tracing::error!(error = ?err, "sensor read failed");
                 }
             },
             None => {
-                println!("Sensor waiting for session");
+                tracing::debug!("sensor waiting for session");
             }
         };
     }
@@ -143,14 +316,19 @@ impl Sensor {
                     increment: self.mode_to_str(),
                     read_time: now_secs(),
                     start_time: self.start_time,
+                    raw_current: Some(data.raw_current),
+                    raw_voltage: Some(data.raw_voltage),
                 })
             }
+            // made up values within the CCS811's range
             None => Ok(Reading {
-                eco2: now_secs() as u16,
-                evtoc: now_secs() as u16 / 2,
+                eco2: 400 + (now_secs() % 1600) as u16,
+                evtoc: (now_secs() % 600) as u16,
                 increment: self.mode_to_str(),
                 read_time: now_secs(),
                 start_time: self.start_time,
+                raw_current: None,
+                raw_voltage: None,
             }),
         }
     }
@@ -11,6 +11,7 @@ use awc::{
 use futures::stream::SplitSink;
 
 use actix::prelude::{Addr, Message as ActixMessage};
+use std::path::PathBuf;
 
 mod ccs811;
 mod session_client;
@@ -33,6 +34,30 @@ pub struct CurrentMode {
     inc: MeasurementMode,
 }
 
+/// SessionClient tells the Sensor to change MeasurementMode, as asked by the server
+#[derive(ActixMessage, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct ChangeMode {
+    inc: MeasurementMode,
+}
+
+/// SessionClient tells the Sensor to reset itself, as asked by the server when
+/// the sensor looks stuck
+#[derive(ActixMessage, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct ResetSensor;
+
+/// tells the Sensor to save its baseline to the baseline file, e.g. before the
+/// server shuts down, so it's restored on the next start
+#[derive(ActixMessage, Clone, Debug)]
+#[rtype(result = "Result<(), String>")]
+pub struct SaveBaseline;
+
+/// the Sensor's state, for health checks
+#[derive(ActixMessage, Clone, Debug)]
+#[rtype(result = "crate::health::SensorHealth")]
+pub struct SensorStatus;
+
 /// tells the SessionClient to tell the Sensor to take a reading at intervals
 #[derive(ActixMessage, Debug, Clone, Copy)]
 #[rtype(result = "()")]
@@ -45,6 +70,9 @@ pub struct SessionClient {
     sensor: Addr<Sensor>,
     mode: Option<MeasurementMode>,
     version: u64,
+    /// the server closed the session because it's shutting down, which it
+    /// finishes itself
+    server_shutdown: bool,
 }
 
 pub struct Sensor {
@@ -53,4 +81,8 @@ pub struct Sensor {
     start_time: u64,
     increment: MeasurementMode,
     session: Option<Addr<SessionClient>>,
+    /// where the baseline is saved and restored from, none disables both
+    baseline_file: Option<PathBuf>,
+    /// the saved baseline has been written to the sensor since it started
+    baseline_restored: bool,
 }
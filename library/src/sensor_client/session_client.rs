@@ -3,18 +3,20 @@ use actix::*;
 use awc::{
     error::WsProtocolError,
     ws::{Frame, Message},
-    Client,
 };
 use embedded_ccs811::MeasurementMode;
 use futures::stream::StreamExt;
+use std::path::PathBuf;
 use std::time::Duration;
 
 use bytes::Bytes;
 
-use crate::HEARTBEAT_INTERVAL;
+use crate::{config, shutdown, tls};
 
 use crate::sensor_client;
-use crate::sensor_client::{ConnectSession, CurrentMode, Sensor, SessionClient, TakeReading};
+use crate::sensor_client::{
+    ChangeMode, ConnectSession, CurrentMode, ResetSensor, Sensor, SessionClient, TakeReading,
+};
 
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
@@ -32,14 +34,16 @@ impl Actor for SessionClient {
                 addr: ctx.address(),
             })
             .unwrap();
-        println!("SESSION CLIENT STARTED");
+        tracing::info!("session client started");
     }
 
     fn stopped(&mut self, _: &mut Context<Self>) {
-        println!("Disconnected");
-
+        if self.server_shutdown {
+            tracing::info!("session client closed by server shutdown");
+            return;
+        }
         // Stop application on disconnect
-        println!("STOPPING SENSOR CLIENT");
+        tracing::info!("session client disconnected, stopping");
         System::current().stop();
     }
 }
@@ -70,6 +74,10 @@ impl Handler<CurrentMode> for SessionClient {
 impl Handler<TakeReading> for SessionClient {
     type Result = ();
     fn handle(&mut self, msg: TakeReading, ctx: &mut Context<Self>) {
+        // an idle sensor takes no readings until its mode is changed again
+        if self.mode == Some(MeasurementMode::Idle) {
+            return;
+        }
         // check measurement mode hasn't been changed before reading
         if msg.version.eq(&self.version) {
             // sensor should have connected to session client in order for
@@ -80,7 +88,11 @@ impl Handler<TakeReading> for SessionClient {
             // queue reading for later
             ctx.notify_later(msg, Duration::from_millis(mode));
         } else {
-            println!("{:?} does not match {:?}", self.version, msg.version);
+            tracing::debug!(
+                current = self.version,
+                queued = msg.version,
+                "stale reading timer"
+            );
         }
     }
 }
@@ -88,17 +100,29 @@ impl Handler<TakeReading> for SessionClient {
 /// Handle server websocket messages
 impl StreamHandler<Result<Frame, WsProtocolError>> for SessionClient {
     fn handle(&mut self, msg: Result<Frame, WsProtocolError>, _: &mut Context<Self>) {
+        if let Ok(Frame::Close(Some(reason))) = &msg {
+            self.server_shutdown = reason.description.as_deref() == Some(shutdown::REASON);
+        }
         if let Ok(Frame::Text(txt)) = msg {
-            println!("Server: {:?}", txt)
+            tracing::debug!(text = ?txt, "from server");
+            let txt = String::from_utf8_lossy(&txt);
+            if let Some(name) = txt.strip_prefix("/set_mode ") {
+                match mode_from_name(name.trim()) {
+                    Some(inc) => self.sensor.do_send(ChangeMode { inc }),
+                    None => tracing::warn!(mode = name, "unknown measurement mode"),
+                }
+            } else if txt.trim() == "/reset_sensor" {
+                self.sensor.do_send(ResetSensor);
+            }
         }
     }
 
     fn started(&mut self, _: &mut Context<Self>) {
-        println!("Session Client Connected");
+        tracing::info!("session client connected");
     }
 
     fn finished(&mut self, ctx: &mut Context<Self>) {
-        println!("Session Client Disconnected");
+        tracing::info!("session client stream finished");
         ctx.stop()
     }
 }
@@ -111,7 +135,19 @@ impl Handler<Heartbeat> for SessionClient {
 
     fn handle(&mut self, msg: Heartbeat, ctx: &mut Context<Self>) {
         self.sink.write(Message::Ping(Bytes::from_static(b"")));
-        ctx.notify_later(msg, HEARTBEAT_INTERVAL);
+        ctx.notify_later(msg, config::timeouts().heartbeat);
+    }
+}
+
+fn mode_from_name(name: &str) -> Option<MeasurementMode> {
+    use MeasurementMode::*;
+    match name {
+        "Idle" => Some(Idle),
+        "ConstantPower250ms" => Some(ConstantPower250ms),
+        "ConstantPower1s" => Some(ConstantPower1s),
+        "PulseHeating10s" => Some(PulseHeating10s),
+        "LowPowerPulseHeating60s" => Some(LowPowerPulseHeating60s),
+        _ => None,
     }
 }
 
@@ -132,24 +168,32 @@ impl SessionClient {
         res
     }
 
-    pub fn spawn(server_url: &'static str) {
-        let server_url = &(*server_url);
+    /// connect to a `ws://` or `wss://` url, trusting only the CA certificates
+    /// in `ca` when given. the sensor's baseline is kept in `baseline_file`
+    pub fn spawn(url: String, ca: Option<PathBuf>, baseline_file: Option<PathBuf>) -> Addr<Sensor> {
+        // thread spawn a ccs811 Sensor actor using SyncArbiter with access to session addr
+        let sensor_add = SyncArbiter::start(1, move || {
+            let mut sensor = Sensor::new_1s(811).unwrap();
+            sensor.baseline_file = baseline_file.clone();
+            sensor
+        });
+        let sensor = sensor_add.clone();
         Arbiter::spawn(async move {
-            // thread spawn a ccs811 Sensor actor using SyncArbiter with access to session addr
-            let sensor_add = SyncArbiter::start(1, || Sensor::new_1s(811).unwrap());
-            let mut url = "http://".to_owned();
-            url.push_str(server_url);
-            url.push_str("/ws/");
-            let (response, framed) = Client::new()
+            let client = tls::ws_client(ca.as_deref())
+                .map_err(|e| {
+                    tracing::error!(error = %e, "couldn't build ws client");
+                })
+                .unwrap();
+            let (response, framed) = client
                 .ws(url)
                 .set_header("authorization", "811")
                 .connect()
                 .await
                 .map_err(|e| {
-                    println!("Error: {}", e);
+                    tracing::error!(error = %e, "couldn't connect to server");
                 })
                 .unwrap();
-            println!("ws response {:?}", response);
+            tracing::debug!(?response, "ws connected");
             let (sink, stream) = framed.split();
             SessionClient::create(|ctx| {
                 SessionClient::add_stream(stream, ctx);
@@ -158,8 +202,10 @@ impl SessionClient {
                     sensor: sensor_add.clone(), // initialize ccs811 sensor
                     mode: None,
                     version: 0,
+                    server_shutdown: false,
                 }
             });
         });
+        sensor
     }
 }
@@ -0,0 +1,205 @@
+//! Coordinated shutdown on SIGTERM or ctrl-c. New connections are refused,
+//! the local sensor saves its baseline, websocket and SSE clients are sent a
+//! close with the reason and readings already relayed are written to the db
+//! before the http server stops. Whatever is left when the deadline passes is
+//! abandoned.
+use crate::common::Flush;
+use crate::db::Actions;
+use crate::relay_server::{server::RelayServer, Shutdown as CloseSessions};
+use crate::sensor_client::SaveBaseline;
+use crate::systemd;
+use actix::prelude::*;
+use actix_rt::signal::{self, unix};
+use actix_web::dev::Server;
+use futures::future::{self, Either};
+use std::time::Duration;
+
+/// sent to clients in their close frame or event
+pub const REASON: &str = "server shutting down";
+
+pub struct Shutdown {
+    pub relay: Addr<RelayServer>,
+    pub actions: Addr<Actions>,
+    /// the local sensor, if it's enabled
+    pub sensor: Option<Recipient<SaveBaseline>>,
+    pub deadline: Duration,
+}
+
+/// resolves on the first SIGTERM or SIGINT
+async fn signalled() -> std::io::Result<&'static str> {
+    let mut terminate = unix::signal(unix::SignalKind::terminate())?;
+    let (terminated, ctrl_c) = (terminate.recv(), signal::ctrl_c());
+    futures::pin_mut!(terminated, ctrl_c);
+    let signal = match future::select(terminated, ctrl_c).await {
+        Either::Left(_) => "SIGTERM",
+        Either::Right(_) => "SIGINT",
+    };
+    Ok(signal)
+}
+
+impl Shutdown {
+    /// wait for a signal then shut `server` down, the server has to be started
+    /// with its own signal handling disabled
+    pub async fn on_signal(self, server: Server) {
+        match signalled().await {
+            Ok(signal) => tracing::info!(signal, "signal received"),
+            Err(err) => {
+                tracing::error!(error = %err, "couldn't listen for signals");
+                return;
+            }
+        }
+        self.run(server).await
+    }
+
+    pub async fn run(self, server: Server) {
+        tracing::info!(deadline = ?self.deadline, "shutting down");
+        systemd::notify_or_log("STOPPING=1\nSTATUS=shutting down");
+        let Shutdown {
+            relay,
+            actions,
+            sensor,
+            deadline,
+        } = self;
+        let steps = async {
+            server.pause().await;
+            // saved first, nothing waits for it once the local sensor's
+            // session is closed
+            if let Some(sensor) = sensor {
+                match sensor.send(SaveBaseline).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => tracing::warn!(error = %err, "sensor baseline not saved"),
+                    Err(err) => tracing::warn!(error = %err, "sensor baseline not saved"),
+                }
+            }
+            match relay
+                .send(CloseSessions {
+                    reason: REASON.to_owned(),
+                })
+                .await
+            {
+                Ok(closed) => tracing::info!(sessions = closed, "sessions closed"),
+                Err(err) => tracing::error!(error = %err, "couldn't close sessions"),
+            }
+            match actions.send(Flush).await {
+                Ok(()) => tracing::info!("pending db writes flushed"),
+                Err(err) => tracing::error!(error = %err, "db flush failed"),
+            }
+            server.stop(true).await;
+        };
+        if actix_rt::time::timeout(deadline, steps).await.is_err() {
+            tracing::warn!("shutdown deadline passed, stopping now");
+            server.stop(false).await;
+        }
+        tracing::info!("shutdown complete");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anomaly::AnomalyConfig;
+    use crate::common::GetReadings;
+    use crate::rate_limit::{Limits, RateLimits};
+    use crate::relay_server::{ListSubs, PublisherMessage, Reading};
+    use crate::webhooks::Webhooks;
+    use crate::{ws_route, SessionClient};
+    use actix_web::{web, App, HttpServer};
+    use std::path::PathBuf;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    /// stands in for the local sensor, saving a fixed baseline
+    struct Baseline(PathBuf);
+
+    impl Actor for Baseline {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<SaveBaseline> for Baseline {
+        type Result = Result<(), String>;
+
+        fn handle(&mut self, _: SaveBaseline, _: &mut Context<Self>) -> Self::Result {
+            std::fs::write(&self.0, "8f45").map_err(|err| err.to_string())
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "air_meter-shutdown-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn reading(read_time: u64) -> PublisherMessage<Reading> {
+        let msg: Reading = serde_json::from_value(serde_json::json!({
+            "eco2": 600,
+            "evtoc": 0,
+            "read_time": read_time,
+            "start_time": 0,
+            "increment": "ConstantPower1s",
+        }))
+        .unwrap();
+        PublisherMessage {
+            json: serde_json::to_string(&msg).unwrap(),
+            msg,
+            pub_id: 811,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn saves_baseline_and_flushes_with_the_local_sensor_connected() {
+        let actions = Actions::new(&temp_path("db").to_string_lossy()).start();
+        let webhooks = Webhooks::new(actions.clone()).start();
+        let relay = RelayServer::new(
+            Arc::new(AtomicUsize::new(0)),
+            actions.clone(),
+            webhooks,
+            AnomalyConfig::default(),
+        )
+        .start();
+        let rate_limits = RateLimits::new(&Limits::default());
+        let app_relay = relay.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .data(app_relay.clone())
+                .data(rate_limits.clone())
+                .service(web::resource("/ws/").to(ws_route))
+        })
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let port = server.addrs()[0].port();
+        let server = server.run();
+
+        // the local sensor's session, which stopped the system when closed
+        SessionClient::spawn(format!("ws://127.0.0.1:{}/ws/", port), None, None);
+        while !relay.send(ListSubs).await.unwrap().contains(&811) {
+            actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        }
+        relay.send(reading(1_600_000_000)).await.unwrap();
+
+        let baseline = temp_path("baseline");
+        Shutdown {
+            relay: relay.clone(),
+            actions: actions.clone(),
+            sensor: Some(Baseline(baseline.clone()).start().recipient()),
+            deadline: Duration::from_secs(5),
+        }
+        .run(server)
+        .await;
+
+        assert_eq!(std::fs::read_to_string(&baseline).unwrap(), "8f45");
+        let stored = actions
+            .send(GetReadings {
+                pub_id: 811,
+                before: None,
+                limit: 10,
+            })
+            .await
+            .unwrap();
+        assert!(stored.iter().any(|r| r.read_time == 1_600_000_000));
+    }
+}
@@ -0,0 +1,312 @@
+//! Running as a systemd service, and only once per machine.
+//!
+//! With `Type=notify` the server reports `READY=1` once it's listening and
+//! `STOPPING=1` when shutting down. With `WatchdogSec=` set, `Watchdog` sends
+//! keepalives only while the relay server and db actors answer pings, so
+//! systemd restarts a server that's hung. A socket unit's listening sockets are
+//! used instead of binding. Everything here does nothing outside systemd.
+//!
+//! `InstanceLock` holds an exclusive lock on a file for as long as the server
+//! runs, so a second copy can't share the sensor's I2C bus or the db.
+use crate::health::Health;
+use actix::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::net::TcpListener;
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// the first file descriptor passed by socket activation
+const LISTEN_FDS_START: RawFd = 3;
+
+/// the variable's value if it was set for this process, not inherited by it
+fn own_var(key: &str, pid_key: &str) -> Option<String> {
+    let value = std::env::var(key).ok()?;
+    match std::env::var(pid_key) {
+        Ok(pid) if pid != std::process::id().to_string() => None,
+        _ => Some(value),
+    }
+}
+
+/// send a state change, e.g. `READY=1`, false if not run by systemd
+pub fn notify(state: &str) -> Result<bool, String> {
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+    let socket = UnixDatagram::unbound().map_err(|e| format!("notify socket: {}", e))?;
+    let bytes = path.as_encoded_bytes();
+    // a leading @ is a socket in the abstract namespace
+    let sent = match bytes.strip_prefix(b"@") {
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+        }
+        None => socket.send_to(state.as_bytes(), &path),
+    };
+    sent.map(|_| true)
+        .map_err(|e| format!("notify {:?}: {}", path, e))
+}
+
+/// log instead of failing, the service runs without notifications
+pub fn notify_or_log(state: &str) {
+    if let Err(err) = notify(state) {
+        tracing::warn!(error = %err, "couldn't notify systemd");
+    }
+}
+
+/// listening sockets passed by a socket unit, none if not socket activated
+pub fn listeners() -> Result<Vec<TcpListener>, String> {
+    let count = match own_var("LISTEN_FDS", "LISTEN_PID") {
+        Some(count) => count
+            .parse::<RawFd>()
+            .map_err(|_| format!("LISTEN_FDS {:?}: expected a number", count))?,
+        None => return Ok(vec![]),
+    };
+    // SAFETY: systemd passes these open for this process, nothing else uses them
+    Ok(unsafe { take_listeners(LISTEN_FDS_START..LISTEN_FDS_START + count) })
+}
+
+/// # Safety
+/// the fds must be open listening sockets that nothing else owns
+unsafe fn take_listeners(fds: Range<RawFd>) -> Vec<TcpListener> {
+    fds.map(|fd| TcpListener::from_raw_fd(fd)).collect()
+}
+
+/// how often the watchdog expects a keepalive, none if it's disabled
+pub fn watchdog_interval() -> Option<Duration> {
+    own_var("WATCHDOG_USEC", "WATCHDOG_PID")?
+        .parse::<u64>()
+        .ok()
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros)
+}
+
+/// Sends `WATCHDOG=1` at half the watchdog interval while the server's actors
+/// are healthy. A failed check skips the keepalive, so systemd restarts the
+/// server if they stay unhealthy for the whole interval
+pub struct Watchdog {
+    health: Health,
+    interval: Duration,
+}
+
+impl Watchdog {
+    /// none if systemd's watchdog isn't enabled for the service
+    pub fn new(health: Health) -> Option<Watchdog> {
+        Some(Watchdog {
+            health,
+            interval: watchdog_interval()? / 2,
+        })
+    }
+}
+
+impl Actor for Watchdog {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        tracing::info!(interval = ?self.interval, "systemd watchdog enabled");
+        ctx.run_interval(self.interval, |act, ctx| {
+            let health = act.health.clone();
+            ctx.spawn(
+                async move { health.check(false).await }
+                    .into_actor(act)
+                    .map(|report, _, _| {
+                        if report.ok {
+                            notify_or_log("WATCHDOG=1");
+                        } else {
+                            tracing::error!(?report, "unhealthy, watchdog keepalive skipped");
+                        }
+                    }),
+            );
+        });
+    }
+}
+
+/// An exclusive `flock` on a file, held until dropped. The file contains the
+/// pid of the process holding it
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+    pub path: PathBuf,
+}
+
+impl InstanceLock {
+    /// lock `path`, failing straight away if another process holds it
+    pub fn acquire(path: &Path) -> Result<InstanceLock, String> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| format!("lock file {:?}: {}", path, e))?;
+        // SAFETY: the fd is open for as long as `file`, and flock only takes an fd
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::WouldBlock {
+                return Err(format!("lock file {:?}: {}", path, err));
+            }
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(format!(
+                "lock file {:?}: held by another server, pid {}",
+                path,
+                pid.trim()
+            ));
+        }
+        file.set_len(0)
+            .and_then(|_| file.rewind())
+            .and_then(|_| write!(file, "{}", std::process::id()))
+            .map_err(|e| format!("lock file {:?}: {}", path, e))?;
+        Ok(InstanceLock {
+            _file: file,
+            path: path.to_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::IntoRawFd;
+    use std::sync::Mutex;
+
+    /// tests setting systemd's variables take turns
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("air_meter-systemd-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn notifies_the_socket_systemd_gave() {
+        let _env = ENV.lock().unwrap();
+        std::env::remove_var("NOTIFY_SOCKET");
+        assert_eq!(notify("READY=1"), Ok(false));
+
+        let path = temp_path("notify.sock");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        let sent = notify("READY=1");
+        std::env::set_var(
+            "NOTIFY_SOCKET",
+            format!("@air_meter-{}", std::process::id()),
+        );
+        let unbound = notify("STOPPING=1");
+        std::env::remove_var("NOTIFY_SOCKET");
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(sent, Ok(true));
+        let mut buf = [0; 16];
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        // nothing is listening in the abstract namespace
+        assert!(unbound.unwrap_err().contains("@air_meter-"));
+    }
+
+    #[test]
+    fn notifies_abstract_sockets() {
+        use std::os::linux::net::SocketAddrExt;
+        let _env = ENV.lock().unwrap();
+        let name = format!("air_meter-abstract-{}", std::process::id());
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+        let systemd = UnixDatagram::bind_addr(&addr).unwrap();
+        std::env::set_var("NOTIFY_SOCKET", format!("@{}", name));
+        let sent = notify("WATCHDOG=1");
+        std::env::remove_var("NOTIFY_SOCKET");
+
+        assert_eq!(sent, Ok(true));
+        let mut buf = [0; 16];
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+    }
+
+    #[test]
+    fn only_uses_sockets_passed_to_this_process() {
+        let _env = ENV.lock().unwrap();
+        std::env::remove_var("LISTEN_FDS");
+        assert!(listeners().unwrap().is_empty());
+
+        std::env::set_var("LISTEN_FDS", "1");
+        std::env::set_var("LISTEN_PID", "1");
+        let other_process = listeners();
+        std::env::set_var("LISTEN_PID", std::process::id().to_string());
+        std::env::set_var("LISTEN_FDS", "one");
+        let not_a_number = listeners();
+        std::env::set_var("LISTEN_FDS", "0");
+        let none = listeners();
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_PID");
+
+        assert!(other_process.unwrap().is_empty());
+        assert_eq!(
+            not_a_number.unwrap_err(),
+            "LISTEN_FDS \"one\": expected a number"
+        );
+        assert!(none.unwrap().is_empty());
+    }
+
+    #[test]
+    fn takes_over_passed_sockets() {
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let fd = socket.into_raw_fd();
+        // SAFETY: the fd was just given up by its listener
+        let listeners = unsafe { take_listeners(fd..fd + 1) };
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].local_addr().unwrap(), addr);
+        let _client = std::net::TcpStream::connect(addr).unwrap();
+        assert!(listeners[0].accept().is_ok());
+    }
+
+    #[test]
+    fn watchdog_interval_is_only_this_processs() {
+        let _env = ENV.lock().unwrap();
+        std::env::set_var("WATCHDOG_USEC", "2000000");
+        std::env::set_var("WATCHDOG_PID", std::process::id().to_string());
+        let enabled = watchdog_interval();
+        std::env::set_var("WATCHDOG_PID", "1");
+        let other_process = watchdog_interval();
+        std::env::set_var("WATCHDOG_PID", std::process::id().to_string());
+        std::env::set_var("WATCHDOG_USEC", "0");
+        let disabled = watchdog_interval();
+        std::env::remove_var("WATCHDOG_USEC");
+        std::env::remove_var("WATCHDOG_PID");
+
+        assert_eq!(enabled, Some(Duration::from_secs(2)));
+        assert_eq!(other_process, None);
+        assert_eq!(disabled, None);
+    }
+
+    #[test]
+    fn instance_lock_is_exclusive_until_dropped() {
+        let path = temp_path("instance.lock");
+        let lock = InstanceLock::acquire(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            std::process::id().to_string()
+        );
+        // a second open file description conflicts, even in the same process
+        let err = InstanceLock::acquire(&path).unwrap_err();
+        assert_eq!(
+            err,
+            format!(
+                "lock file {:?}: held by another server, pid {}",
+                path,
+                std::process::id()
+            )
+        );
+
+        drop(lock);
+        let lock = InstanceLock::acquire(&path).unwrap();
+        drop(lock);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,12 +1,19 @@
-use crate::{common::GetReadings, db::actions::Actions};
+use crate::{
+    common::GetReadings,
+    db::actions::Actions,
+    iaq::Iaq,
+    report::{self, Report, ReportQuery, Stats, Thresholds, TimeAbove},
+};
 use actix::prelude::*;
-use actix_web::{web, HttpResponse, Result};
+use actix_web::{error, web, HttpResponse, Result};
 use askama::Template;
+use chrono::{TimeZone, Utc};
 
 #[derive(Template)]
 #[template(path = "index.html")]
 struct Index<'a> {
     template_readout: &'a str,
+    iaq_readout: &'a str,
 }
 
 pub async fn index(actions: web::Data<Addr<Actions>>) -> Result<HttpResponse> {
@@ -21,11 +28,148 @@ pub async fn index(actions: web::Data<Addr<Actions>>) -> Result<HttpResponse> {
         })
         .await
         .unwrap();
+    let iaq_readout = match readings.first().map(Iaq::of_db) {
+        Some(iaq) => match iaq.tvoc {
+            Some(tvoc) => format!(
+                "Air quality {} ({}/100): CO2 {}, TVOC {}",
+                iaq.category.as_str(),
+                iaq.score,
+                iaq.co2.as_str(),
+                tvoc.as_str()
+            ),
+            None => format!(
+                "Air quality {} ({}/100): CO2 {}",
+                iaq.category.as_str(),
+                iaq.score,
+                iaq.co2.as_str()
+            ),
+        },
+        None => "No readings yet".to_owned(),
+    };
     let s = Index {
         template_readout: &format!("{:?}", readings),
+        iaq_readout: &iaq_readout,
     }
     .render()
     .unwrap();
 
     Ok(HttpResponse::Ok().content_type("text/html").body(s))
 }
+
+#[derive(Template)]
+#[template(path = "report.html")]
+struct ReportPage {
+    title: String,
+    range: String,
+    readings: String,
+    eco2_above: String,
+    evtoc_above: String,
+    /// date, readings, eco2 and tvoc stats
+    days: Vec<(String, i64, String, String)>,
+    /// hour, eco2 mean, tvoc mean, iaq
+    worst_hours: Vec<(String, String, String, String)>,
+    alerts_fired: String,
+    alerts: Vec<String>,
+    uptime: String,
+}
+
+fn utc(secs: i64, format: &str) -> String {
+    Utc.timestamp_opt(secs, 0)
+        .single()
+        .map_or_else(|| secs.to_string(), |t| t.format(format).to_string())
+}
+
+fn hours(secs: i64) -> String {
+    format!("{:.1} h", secs as f64 / 3600.0)
+}
+
+fn stats(stats: Option<&Stats>) -> String {
+    match stats {
+        Some(s) => format!("{} / {} / {:.0}", s.min, s.max, s.mean),
+        None => "-".to_owned(),
+    }
+}
+
+/// render a report as a standalone html page
+pub fn render_report(report: &Report) -> Result<String, askama::Error> {
+    let above = |name: &str, unit: &str, above: &TimeAbove| {
+        format!(
+            "{} above {} {}: {} ({:.1}% of reporting time)",
+            name,
+            above.threshold,
+            unit,
+            hours(above.secs),
+            above.percent
+        )
+    };
+    ReportPage {
+        title: format!(
+            "Publisher {} {} report",
+            report.pub_id,
+            report.period.as_str()
+        ),
+        range: format!(
+            "{} to {} UTC",
+            utc(report.from, "%Y-%m-%d %H:%M"),
+            utc(report.to, "%Y-%m-%d %H:%M")
+        ),
+        readings: format!(
+            "{} readings, {} suspect",
+            report.readings, report.suspect_readings
+        ),
+        eco2_above: above("eCO2", "ppm", &report.eco2_above),
+        evtoc_above: above("TVOC", "ppb", &report.evtoc_above),
+        days: report
+            .days
+            .iter()
+            .map(|d| {
+                (
+                    d.date.clone(),
+                    d.readings,
+                    stats(Some(&d.eco2)),
+                    stats(d.evtoc.as_ref()),
+                )
+            })
+            .collect(),
+        worst_hours: report
+            .worst_hours
+            .iter()
+            .map(|h| {
+                (
+                    utc(h.start, "%Y-%m-%d %H:00"),
+                    format!("{:.0}", h.eco2_mean),
+                    h.evtoc_mean.map_or("-".to_owned(), |v| format!("{:.0}", v)),
+                    format!("{} ({}/100)", h.iaq.category.as_str(), h.iaq.score),
+                )
+            })
+            .collect(),
+        alerts_fired: format!("{} alerts fired", report.alerts_fired),
+        alerts: report
+            .alerts
+            .iter()
+            .map(|a| format!("{} (rule {}): {}", a.name, a.rule_id, a.fired))
+            .collect(),
+        uptime: format!(
+            "Reported for {} of {} ({:.1}%), longest gap {}",
+            hours(report.uptime.reporting_secs),
+            hours(report.uptime.period_secs),
+            report.uptime.percent,
+            hours(report.uptime.longest_gap_secs)
+        ),
+    }
+    .render()
+}
+
+pub async fn report(
+    pub_id: web::Path<u64>,
+    web::Query(query): web::Query<ReportQuery>,
+    actions: web::Data<Addr<Actions>>,
+    thresholds: web::Data<Thresholds>,
+) -> Result<HttpResponse> {
+    let (period, date) = query.resolve().map_err(error::ErrorBadRequest)?;
+    let report = report::generate(&actions, pub_id.into_inner(), period, date, &thresholds)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    let s = render_report(&report).map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
@@ -0,0 +1,259 @@
+//! rustls TLS for the http server and websocket clients.
+//!
+//! The server's certificate is served by `ReloadingCert`, which `CertReloader`
+//! swaps for the files' new contents when they change, so renewed
+//! certificates are picked up without a restart. Clients trust the bundled
+//! web PKI roots, or only the CA certificates in a pinned PEM file.
+use actix::prelude::*;
+use awc::{Client, Connector};
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{ClientConfig, ClientHello, NoClientAuth, ResolvesServerCert, ServerConfig};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first
+    pub cert: PathBuf,
+    /// PEM PKCS#8 or RSA private key
+    pub key: PathBuf,
+    /// how often the files are checked for changes, 0 disables reloading
+    pub reload_secs: u64,
+    /// host name the local sensor client connects to, has to be in the certificate
+    pub local_name: String,
+}
+
+impl Default for TlsConfig {
+    fn default() -> TlsConfig {
+        TlsConfig {
+            cert: PathBuf::new(),
+            key: PathBuf::new(),
+            reload_secs: 60,
+            local_name: "localhost".to_owned(),
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("{:?}: {}", path, e))
+}
+
+/// read a certificate chain and its private key
+pub fn load_cert(cert: &Path, key: &Path) -> Result<CertifiedKey, String> {
+    let chain = pemfile::certs(&mut open(cert)?).map_err(|_| format!("{:?}: invalid PEM", cert))?;
+    if chain.is_empty() {
+        return Err(format!("{:?}: no certificates", cert));
+    }
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(key)?)
+        .map_err(|_| format!("{:?}: invalid PEM", key))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(key)?)
+            .map_err(|_| format!("{:?}: invalid PEM", key))?;
+    }
+    let key_der = keys
+        .first()
+        .ok_or_else(|| format!("{:?}: no PKCS#8 or RSA private key", key))?;
+    let signing_key = sign::any_supported_type(key_der)
+        .map_err(|_| format!("{:?}: unsupported private key type", key))?;
+    Ok(CertifiedKey::new(chain, Arc::new(signing_key)))
+}
+
+/// serves the current certificate to every handshake
+pub struct ReloadingCert {
+    current: RwLock<CertifiedKey>,
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _: ClientHello) -> Option<CertifiedKey> {
+        self.current.read().ok().map(|cert| cert.clone())
+    }
+}
+
+/// a server config for `HttpServer::bind_rustls` and the resolver its
+/// certificate can be replaced through
+pub fn server_config(tls: &TlsConfig) -> Result<(ServerConfig, Arc<ReloadingCert>), String> {
+    let resolver = Arc::new(ReloadingCert {
+        current: RwLock::new(load_cert(&tls.cert, &tls.key)?),
+    });
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = resolver.clone();
+    Ok((config, resolver))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Polls the certificate and key files, loading them into the resolver when
+/// either changes. The previous certificate stays in use when a pair fails to
+/// load, e.g. written halfway, until the files change again
+pub struct CertReloader {
+    cert: PathBuf,
+    key: PathBuf,
+    interval: Duration,
+    resolver: Arc<ReloadingCert>,
+    loaded: (Option<SystemTime>, Option<SystemTime>),
+}
+
+impl CertReloader {
+    /// none if reloading is disabled
+    pub fn new(tls: &TlsConfig, resolver: Arc<ReloadingCert>) -> Option<CertReloader> {
+        Some(CertReloader {
+            cert: tls.cert.clone(),
+            key: tls.key.clone(),
+            interval: Some(Duration::from_secs(tls.reload_secs)).filter(|_| tls.reload_secs > 0)?,
+            resolver,
+            loaded: (modified(&tls.cert), modified(&tls.key)),
+        })
+    }
+
+    fn check(&mut self) {
+        let current = (modified(&self.cert), modified(&self.key));
+        if current == self.loaded {
+            return;
+        }
+        self.loaded = current;
+        match load_cert(&self.cert, &self.key) {
+            Ok(cert) => {
+                if let Ok(mut resolved) = self.resolver.current.write() {
+                    *resolved = cert;
+                }
+                tracing::info!(cert = ?self.cert, "certificate reloaded");
+            }
+            Err(err) => tracing::error!(error = %err, "certificate reload failed"),
+        }
+    }
+}
+
+impl Actor for CertReloader {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(self.interval, |act, _| act.check());
+    }
+}
+
+/// trust only the certificates in `ca` when given, else the web PKI roots
+pub fn client_config(ca: Option<&Path>) -> Result<Arc<ClientConfig>, String> {
+    let mut config = ClientConfig::new();
+    match ca {
+        Some(ca) => {
+            let (added, _) = config
+                .root_store
+                .add_pem_file(&mut open(ca)?)
+                .map_err(|_| format!("{:?}: invalid PEM", ca))?;
+            if added == 0 {
+                return Err(format!("{:?}: no CA certificates", ca));
+            }
+        }
+        None => config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+    }
+    // websockets need http/1.1
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// awc client for `ws://` and `wss://` urls, see `client_config`
+pub fn ws_client(ca: Option<&Path>) -> Result<Client, String> {
+    let connector = Connector::new().rustls(client_config(ca)?).finish();
+    Ok(Client::builder().connector(connector).finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+    use actix_web_actors::ws;
+
+    /// a test CA and a `localhost` leaf it signs, made as in the README with
+    /// `-days 36500`
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/tls")
+            .join(name)
+    }
+
+    fn tls_config() -> TlsConfig {
+        TlsConfig {
+            cert: testdata("fullchain.pem"),
+            key: testdata("key.pem"),
+            ..TlsConfig::default()
+        }
+    }
+
+    /// https server accepting websocket handshakes at `/ws/`
+    fn server() -> test::TestServer {
+        let (config, _) = server_config(&tls_config()).unwrap();
+        test::start_with(test::config().rustls(config), || {
+            App::new()
+                .route(
+                    "/ws/",
+                    web::get().to(|req: HttpRequest| async move {
+                        ws::handshake(&req).map(|mut res| res.finish())
+                    }),
+                )
+                .route("/", web::get().to(|| HttpResponse::Ok().body("ok")))
+        })
+    }
+
+    #[test]
+    fn loads_a_chain_and_key() {
+        let cert = load_cert(&testdata("fullchain.pem"), &testdata("key.pem"));
+        assert_eq!(cert.map(|c| c.cert.len()).ok(), Some(2));
+        let err = |cert: &str, key: &str| load_cert(&testdata(cert), &testdata(key)).err().unwrap();
+        assert!(err("missing.pem", "key.pem").contains("missing.pem"));
+        assert!(err("key.pem", "key.pem").ends_with("no certificates"));
+        assert!(err("fullchain.pem", "ca.pem").ends_with("no PKCS#8 or RSA private key"));
+    }
+
+    #[test]
+    fn pinned_ca_files_need_certificates() {
+        assert!(client_config(Some(&testdata("ca.pem"))).is_ok());
+        assert!(client_config(Some(&testdata("key.pem"))).is_err());
+        assert!(client_config(Some(&testdata("missing.pem"))).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn ws_client_trusts_the_pinned_ca() {
+        let srv = server();
+        let port = srv.addr().port();
+        let client = ws_client(Some(&testdata("ca.pem"))).unwrap();
+
+        let mut res = client
+            .get(format!("https://localhost:{}/", port))
+            .send()
+            .await
+            .unwrap();
+        assert!(res.status().is_success());
+        assert_eq!(res.body().await.unwrap(), "ok");
+
+        let (res, _) = client
+            .ws(format!("wss://localhost:{}/ws/", port))
+            .connect()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 101);
+    }
+
+    #[actix_rt::test]
+    async fn ws_client_rejects_certificates_from_unknown_cas() {
+        let srv = server();
+        // the test CA isn't a web PKI root
+        let web_pki = ws_client(None).unwrap();
+        let connected = web_pki
+            .ws(format!("wss://localhost:{}/ws/", srv.addr().port()))
+            .connect()
+            .await;
+        assert!(connected.is_err());
+    }
+}
@@ -0,0 +1,370 @@
+//! Ventilation and occupancy estimates from a publisher's CO2 time series.
+//!
+//! Once a room empties its CO2 excess over outdoor air decays exponentially,
+//! `C(t) - C_out = (C_0 - C_out) e^(-ACH t)`, so the air changes per hour are
+//! the slope of a straight line fitted to `ln(C - C_out)` over each decay.
+//! While occupied CO2 settles where ventilation removes it as fast as people
+//! breathe it out, `N = ACH * V * (C_ss - C_out) / G`, giving an occupant
+//! count for plateaus when the room volume is known.
+//!
+//! Estimates work on per-minute means and carry a 0-1 confidence from how well
+//! the data fits the model. CCS811 eCO2 is inferred from VOCs rather than
+//! measured, so treat them as approximate.
+use crate::db::model::ReadingBucket;
+use serde::Serialize;
+
+/// width of the buckets the series is analysed in, in seconds
+pub const BUCKET_SECS: u32 = 60;
+/// eCO2 reported by the CCS811 in clean air
+pub const DEFAULT_OUTDOOR_CO2: f64 = 400.0;
+/// CO2 exhaled by a seated adult doing office work, in m³/h
+pub const CO2_PER_PERSON: f64 = 0.018;
+
+/// buckets missing from a run before it's split
+const MAX_GAP_SECS: i64 = 5 * 60;
+/// rise tolerated between buckets of a decay, sensor noise
+const DECAY_NOISE_PPM: f64 = 10.0;
+/// shortest decay fitted, in seconds
+const MIN_DECAY_SECS: i64 = 20 * 60;
+/// excess over outdoor air a decay has to start from
+const MIN_DECAY_START_EXCESS: f64 = 200.0;
+/// excess below which the log fit is dominated by noise
+const MIN_FIT_EXCESS: f64 = 30.0;
+/// decays fitting worse than this aren't counted
+const MIN_DECAY_R2: f64 = 0.8;
+/// decays needed for full confidence in the ventilation rate
+const CONFIDENT_DECAYS: f64 = 3.0;
+/// shortest plateau treated as steady state, in seconds
+const MIN_PLATEAU_SECS: i64 = 30 * 60;
+/// excess a plateau needs to count as occupied
+const MIN_PLATEAU_EXCESS: f64 = 100.0;
+/// largest deviation of a plateau's excess from its mean, as a fraction of the mean
+const MAX_PLATEAU_DEVIATION: f64 = 0.1;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Decay {
+    pub start: i64,
+    pub end: i64,
+    pub start_co2: f64,
+    pub end_co2: f64,
+    pub ach: f64,
+    /// coefficient of determination of the log-linear fit
+    pub r2: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Ventilation {
+    /// air changes per hour, the fit weighted mean of the decays
+    pub ach: Option<f64>,
+    pub confidence: f64,
+    pub decays: Vec<Decay>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Occupancy {
+    pub start: i64,
+    pub end: i64,
+    /// mean CO2 of the plateau
+    pub co2: f64,
+    /// none without a ventilation rate or room volume
+    pub occupants: Option<f64>,
+    pub confidence: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Estimates {
+    pub outdoor_co2: f64,
+    /// room volume in m³, needed for occupant counts
+    pub volume: Option<f64>,
+    pub ventilation: Ventilation,
+    pub occupancy: Vec<Occupancy>,
+}
+
+/// (read_time, mean co2) points, split into runs without long gaps
+fn runs(buckets: &[ReadingBucket]) -> Vec<Vec<(i64, f64)>> {
+    let mut runs: Vec<Vec<(i64, f64)>> = vec![];
+    let mut last: Option<i64> = None;
+    for bucket in buckets {
+        if last.is_none_or(|last| bucket.start - last > MAX_GAP_SECS) {
+            runs.push(vec![]);
+        }
+        if let Some(run) = runs.last_mut() {
+            run.push((bucket.start, bucket.eco2_mean));
+        }
+        last = Some(bucket.start);
+    }
+    runs
+}
+
+/// least squares fit of y = a + bx, returning (b, r²)
+fn linear_fit(points: &[(f64, f64)]) -> Option<(f64, f64)> {
+    let n = points.len() as f64;
+    if n < 3.0 {
+        return None;
+    }
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let (mut sxx, mut sxy, mut syy) = (0.0, 0.0, 0.0);
+    for (x, y) in points {
+        sxx += (x - mean_x).powi(2);
+        sxy += (x - mean_x) * (y - mean_y);
+        syy += (y - mean_y).powi(2);
+    }
+    if sxx == 0.0 || syy == 0.0 {
+        return None;
+    }
+    Some((sxy / sxx, sxy * sxy / (sxx * syy)))
+}
+
+/// fit a falling stretch of a run, none if it's too short or shallow
+fn fit_decay(points: &[(i64, f64)], outdoor: f64) -> Option<Decay> {
+    let (start, start_co2) = *points.first()?;
+    if start_co2 - outdoor < MIN_DECAY_START_EXCESS {
+        return None;
+    }
+    let fitted = points
+        .iter()
+        .take_while(|(_, co2)| co2 - outdoor >= MIN_FIT_EXCESS)
+        .map(|&(t, co2)| ((t - start) as f64 / 3600.0, (co2 - outdoor).ln()))
+        .collect::<Vec<(f64, f64)>>();
+    let (end, end_co2) = points[fitted.len().checked_sub(1)?];
+    if end - start < MIN_DECAY_SECS {
+        return None;
+    }
+    let (slope, r2) = linear_fit(&fitted)?;
+    if slope >= 0.0 || r2 < MIN_DECAY_R2 {
+        return None;
+    }
+    Some(Decay {
+        start,
+        end,
+        start_co2,
+        end_co2,
+        ach: -slope,
+        r2,
+    })
+}
+
+/// decays are runs falling from a peak, allowing for sensor noise
+fn decays(runs: &[Vec<(i64, f64)>], outdoor: f64) -> Vec<Decay> {
+    let mut decays = vec![];
+    for run in runs {
+        let mut i = 0;
+        while i + 1 < run.len() {
+            // start from a local peak
+            if run[i + 1].1 >= run[i].1 {
+                i += 1;
+                continue;
+            }
+            let mut j = i + 1;
+            let mut low = run[j].1;
+            while j + 1 < run.len() && run[j + 1].1 <= low + DECAY_NOISE_PPM {
+                j += 1;
+                low = low.min(run[j].1);
+            }
+            if let Some(decay) = fit_decay(&run[i..=j], outdoor) {
+                decays.push(decay);
+            }
+            i = j;
+        }
+    }
+    decays
+}
+
+fn ventilation(decays: Vec<Decay>) -> Ventilation {
+    let weights = decays
+        .iter()
+        .map(|d| d.r2 * (d.end - d.start) as f64)
+        .collect::<Vec<f64>>();
+    let total = weights.iter().sum::<f64>();
+    if decays.is_empty() || total == 0.0 {
+        return Ventilation {
+            ach: None,
+            confidence: 0.0,
+            decays,
+        };
+    }
+    let ach = decays
+        .iter()
+        .zip(&weights)
+        .map(|(d, w)| d.ach * w)
+        .sum::<f64>()
+        / total;
+    let mean_r2 = decays.iter().map(|d| d.r2).sum::<f64>() / decays.len() as f64;
+    Ventilation {
+        ach: Some(ach),
+        confidence: mean_r2 * (decays.len() as f64 / CONFIDENT_DECAYS).min(1.0),
+        decays,
+    }
+}
+
+/// steady stretches of a run with an occupied level of CO2
+fn plateaus(runs: &[Vec<(i64, f64)>], outdoor: f64) -> Vec<(i64, i64, f64, f64)> {
+    let mut plateaus = vec![];
+    for run in runs {
+        let mut i = 0;
+        while i < run.len() {
+            // grow the window while each excess stays near the window's mean
+            let mut j = i;
+            let (mut sum, mut sum_sq) = (0.0, 0.0);
+            let mut stats = None;
+            while j < run.len() {
+                let excess = run[j].1 - outdoor;
+                if j > i {
+                    let mean = sum / (j - i) as f64;
+                    if (excess - mean).abs() > MAX_PLATEAU_DEVIATION * mean {
+                        break;
+                    }
+                }
+                sum += excess;
+                sum_sq += excess * excess;
+                let n = (j - i + 1) as f64;
+                let mean = sum / n;
+                if mean < MIN_PLATEAU_EXCESS {
+                    break;
+                }
+                let sd = (sum_sq / n - mean * mean).max(0.0).sqrt();
+                stats = Some((mean, sd / mean));
+                j += 1;
+            }
+            match stats {
+                Some((mean, cv)) if run[j - 1].0 - run[i].0 >= MIN_PLATEAU_SECS => {
+                    plateaus.push((run[i].0, run[j - 1].0, mean + outdoor, cv));
+                    i = j;
+                }
+                _ => i += 1,
+            }
+        }
+    }
+    plateaus
+}
+
+/// estimate ventilation and occupancy from per-minute CO2 buckets in
+/// ascending order
+pub fn estimate(buckets: &[ReadingBucket], outdoor: f64, volume: Option<f64>) -> Estimates {
+    let runs = runs(buckets);
+    let ventilation = ventilation(decays(&runs, outdoor));
+    let occupancy = plateaus(&runs, outdoor)
+        .into_iter()
+        .map(|(start, end, co2, cv)| {
+            let steadiness = (1.0 - cv / MAX_PLATEAU_DEVIATION).max(0.0);
+            let occupants = match (ventilation.ach, volume) {
+                (Some(ach), Some(volume)) => {
+                    Some(ach * volume * (co2 - outdoor) * 1e-6 / CO2_PER_PERSON)
+                }
+                _ => None,
+            };
+            Occupancy {
+                start,
+                end,
+                co2,
+                occupants,
+                confidence: match occupants {
+                    Some(_) => steadiness * ventilation.confidence,
+                    None => 0.0,
+                },
+            }
+        })
+        .collect();
+    Estimates {
+        outdoor_co2: outdoor,
+        volume,
+        ventilation,
+        occupancy,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(start: i64, eco2: f64) -> ReadingBucket {
+        ReadingBucket {
+            start,
+            readings: 60,
+            suspect: 0,
+            eco2_mean: eco2,
+            eco2_min: eco2 as i32,
+            eco2_max: eco2 as i32,
+            evtoc_mean: None,
+            evtoc_min: None,
+            evtoc_max: None,
+        }
+    }
+
+    /// an hour at `level` then `minutes` of decay at `ach`, one bucket a minute
+    fn occupied_then_empty(from: i64, level: f64, ach: f64, minutes: i64) -> Vec<ReadingBucket> {
+        let plateau = (0..60).map(|m| bucket(from + m * 60, level));
+        let decay = (1..=minutes).map(|m| {
+            let excess = (level - DEFAULT_OUTDOOR_CO2) * (-ach * m as f64 / 60.0).exp();
+            bucket(from + (59 + m) * 60, DEFAULT_OUTDOOR_CO2 + excess)
+        });
+        plateau.chain(decay).collect()
+    }
+
+    fn near(value: f64, expected: f64) -> bool {
+        (value - expected).abs() < 0.01 * expected
+    }
+
+    #[test]
+    fn fits_ach_from_a_decay() {
+        let buckets = occupied_then_empty(0, 1000.0, 2.0, 90);
+        let ventilation = estimate(&buckets, DEFAULT_OUTDOOR_CO2, None).ventilation;
+        assert_eq!(ventilation.decays.len(), 1);
+        let decay = &ventilation.decays[0];
+        assert_eq!(decay.start, 59 * 60);
+        assert!(near(decay.ach, 2.0), "{:?}", decay);
+        assert!(decay.r2 > 0.99);
+        assert!(near(ventilation.ach.unwrap(), 2.0));
+        // one decay of the three needed for full confidence
+        assert!(near(ventilation.confidence, 1.0 / 3.0));
+    }
+
+    #[test]
+    fn counts_occupants_of_plateaus_with_a_volume() {
+        let mut buckets = occupied_then_empty(0, 1000.0, 2.0, 90);
+        buckets.extend(occupied_then_empty(4 * 3600, 1000.0, 2.0, 90));
+        buckets.extend(occupied_then_empty(8 * 3600, 1000.0, 2.0, 90));
+        let estimates = estimate(&buckets, DEFAULT_OUTDOOR_CO2, Some(50.0));
+        assert_eq!(estimates.ventilation.decays.len(), 3);
+        assert!(estimates.ventilation.confidence > 0.99);
+        assert_eq!(estimates.occupancy.len(), 3);
+        // 2/h * 50 m³ * 600 ppm / 0.018 m³/h a person
+        let occupancy = &estimates.occupancy[0];
+        assert!(
+            (occupancy.occupants.unwrap() - 3.33).abs() < 0.1,
+            "{:?}",
+            occupancy
+        );
+        assert!(occupancy.confidence > 0.5);
+
+        let without_volume = estimate(&buckets, DEFAULT_OUTDOOR_CO2, None);
+        assert_eq!(without_volume.occupancy.len(), 3);
+        assert!(without_volume.occupancy[0].occupants.is_none());
+        assert_eq!(without_volume.occupancy[0].confidence, 0.0);
+    }
+
+    #[test]
+    fn gaps_split_decays() {
+        let mut buckets = occupied_then_empty(0, 1000.0, 2.0, 90);
+        // drop 10 minutes early in the decay, leaving 10 minutes before the gap
+        buckets.retain(|b| b.start < 70 * 60 || b.start >= 80 * 60);
+        assert_eq!(runs(&buckets).len(), 2);
+        let decays = estimate(&buckets, DEFAULT_OUTDOOR_CO2, None)
+            .ventilation
+            .decays;
+        assert_eq!(decays.len(), 1);
+        assert_eq!(decays[0].start, 80 * 60);
+        assert!(near(decays[0].ach, 2.0));
+    }
+
+    #[test]
+    fn outdoor_air_has_no_estimates() {
+        let buckets = (0..180)
+            .map(|m| bucket(m * 60, DEFAULT_OUTDOOR_CO2 + (m % 3) as f64))
+            .collect::<Vec<_>>();
+        let estimates = estimate(&buckets, DEFAULT_OUTDOOR_CO2, Some(50.0));
+        assert!(estimates.ventilation.decays.is_empty());
+        assert!(estimates.occupancy.is_empty());
+    }
+}
@@ -0,0 +1,355 @@
+//! `Webhooks` POSTs alerts and device events as json to the configured webhook
+//! urls. Failed deliveries are retried with exponential backoff and every
+//! attempt is recorded in the `webhook_deliveries` table.
+//!
+//! Each request body is `{"id", "event", "time", "data"}` and carries the headers
+//! `X-Air-Meter-Event`, `X-Air-Meter-Delivery` and, when the webhook has a secret,
+//! `X-Air-Meter-Signature: sha256=<hex hmac-sha256 of the body>`
+use crate::common::{GetWebhooks, LogWebhookDelivery};
+use crate::db::model::{NewWebhookDelivery, Webhook};
+use crate::db::Actions;
+use actix::clock::delay_for;
+use actix::prelude::*;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde_json::json;
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// event names webhooks can subscribe to
+pub const ALERT: &str = "alert";
+pub const PUBLISHER_ONLINE: &str = "publisher_online";
+pub const PUBLISHER_OFFLINE: &str = "publisher_offline";
+pub const DEVICE_HEALTH: &str = "device_health";
+pub const REPORT: &str = "report";
+/// sent by `/api/v1/webhooks/{id}/test`, whatever events the webhook subscribes to
+pub const PING: &str = "ping";
+
+/// attempts made before a delivery is given up on
+const MAX_ATTEMPTS: i32 = 5;
+/// delay before the first retry, doubled for each one after
+const RETRY_BASE: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// `sha256=<hex>` signature of a body
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+pub struct Webhooks {
+    actions: Addr<Actions>,
+    hooks: Vec<Webhook>,
+}
+
+impl Actor for Webhooks {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        self.load(ctx);
+    }
+}
+
+impl Webhooks {
+    pub fn new(actions: Addr<Actions>) -> Webhooks {
+        Webhooks {
+            actions,
+            hooks: vec![],
+        }
+    }
+
+    fn load(&mut self, ctx: &mut Context<Self>) {
+        self.actions
+            .send(GetWebhooks { enabled_only: true })
+            .into_actor(self)
+            .then(|res, act, _| {
+                match res {
+                    Ok(hooks) => {
+                        tracing::info!(webhooks = hooks.len(), "webhooks loaded");
+                        act.hooks = hooks;
+                    }
+                    Err(err) => tracing::error!(error = ?err, "couldn't load webhooks"),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn deliver(
+        &self,
+        ctx: &mut Context<Self>,
+        hook: Webhook,
+        event: &str,
+        data: &serde_json::Value,
+    ) {
+        let delivery_id = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        let body = json!({
+            "id": delivery_id,
+            "event": event,
+            "time": unix_now(),
+            "data": data,
+        })
+        .to_string();
+        ctx.spawn(
+            deliver(
+                self.actions.clone(),
+                hook,
+                event.to_owned(),
+                delivery_id,
+                body,
+            )
+            .into_actor(self),
+        );
+    }
+}
+
+/// post `body` to the webhook until it's accepted or attempts run out.
+/// server errors, timeouts and 429s are retried, other client errors aren't
+async fn deliver(
+    actions: Addr<Actions>,
+    hook: Webhook,
+    event: String,
+    delivery_id: String,
+    body: String,
+) {
+    let signature = hook.secret.as_ref().map(|s| sign(s, body.as_bytes()));
+    for attempt in 1..=MAX_ATTEMPTS {
+        let client = awc::Client::builder().timeout(REQUEST_TIMEOUT).finish();
+        let mut req = client
+            .post(&hook.url)
+            .content_type("application/json")
+            .header("X-Air-Meter-Event", event.as_str())
+            .header("X-Air-Meter-Delivery", delivery_id.as_str());
+        if let Some(signature) = &signature {
+            req = req.header("X-Air-Meter-Signature", signature.as_str());
+        }
+        let (status_code, error, retry) = match req.send_body(body.clone()).await {
+            Ok(res) if res.status().is_success() => (Some(res.status().as_u16()), None, false),
+            Ok(res) => {
+                let status = res.status();
+                let retry = status.is_server_error() || status.as_u16() == 429;
+                (
+                    Some(status.as_u16()),
+                    Some(format!("HTTP {}", status)),
+                    retry,
+                )
+            }
+            Err(err) => (None, Some(format!("{}", err)), true),
+        };
+        let delivered = error.is_none();
+        match &error {
+            None => tracing::info!(%event, url = %hook.url, "webhook delivered"),
+            Some(err) => tracing::warn!(
+                %event,
+                url = %hook.url,
+                attempt,
+                error = %err,
+                "webhook delivery failed"
+            ),
+        }
+        actions.do_send(LogWebhookDelivery(NewWebhookDelivery {
+            webhook_id: hook.id,
+            event: event.clone(),
+            payload: body.clone(),
+            attempt,
+            status_code: status_code.map(i32::from),
+            error,
+            delivered,
+            created_at: unix_now(),
+        }));
+        if delivered || !retry || attempt == MAX_ATTEMPTS {
+            return;
+        }
+        delay_for(RETRY_BASE * 2u32.pow(attempt as u32 - 1)).await;
+    }
+}
+
+/// Send an event to every enabled webhook subscribed to it
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct Notify {
+    pub event: &'static str,
+    pub data: serde_json::Value,
+}
+
+impl Handler<Notify> for Webhooks {
+    type Result = ();
+
+    fn handle(&mut self, msg: Notify, ctx: &mut Context<Self>) {
+        for hook in self.hooks.clone() {
+            if hook.wants(msg.event) {
+                self.deliver(ctx, hook, msg.event, &msg.data);
+            }
+        }
+    }
+}
+
+/// Send a `ping` event to a webhook, whether or not it's enabled
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PingWebhook(pub Webhook);
+
+impl Handler<PingWebhook> for Webhooks {
+    type Result = ();
+
+    fn handle(&mut self, msg: PingWebhook, ctx: &mut Context<Self>) {
+        let data = json!({ "webhook_id": msg.0.id });
+        self.deliver(ctx, msg.0, PING, &data);
+    }
+}
+
+/// Reload webhooks after they've been changed
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ReloadWebhooks;
+
+impl Handler<ReloadWebhooks> for Webhooks {
+    type Result = ();
+
+    fn handle(&mut self, _: ReloadWebhooks, ctx: &mut Context<Self>) {
+        self.load(ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{AddWebhook, GetWebhookDeliveries};
+    use crate::db::model::{NewWebhook, WebhookDelivery};
+    use actix_web::http::{HeaderMap, StatusCode};
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+    use std::sync::{Arc, Mutex};
+
+    /// headers and body of each request the test server received
+    type Received = Arc<Mutex<Vec<(HeaderMap, String)>>>;
+
+    /// answers each request with the next of `statuses`, the last one repeated
+    fn receiver(statuses: &'static [u16]) -> (test::TestServer, Received) {
+        let received = Received::default();
+        let log = received.clone();
+        let srv = test::start(move || {
+            let log = log.clone();
+            App::new().route(
+                "/hook",
+                web::post().to(move |req: HttpRequest, body: String| {
+                    let mut log = log.lock().unwrap();
+                    log.push((req.headers().clone(), body));
+                    let status = statuses[(log.len() - 1).min(statuses.len() - 1)];
+                    HttpResponse::build(StatusCode::from_u16(status).unwrap()).finish()
+                }),
+            )
+        });
+        (srv, received)
+    }
+
+    async fn hook(actions: &Addr<Actions>, url: String, secret: Option<&str>) -> Webhook {
+        actions
+            .send(AddWebhook(NewWebhook {
+                url,
+                secret: secret.map(str::to_owned),
+                events: "*".to_owned(),
+            }))
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn deliveries(actions: &Addr<Actions>, hook: &Webhook) -> Vec<WebhookDelivery> {
+        actions
+            .send(GetWebhookDeliveries {
+                webhook_id: Some(hook.id),
+                before: None,
+                limit: 10,
+            })
+            .await
+            .unwrap()
+    }
+
+    fn actions(name: &str) -> Addr<Actions> {
+        let path = std::env::temp_dir().join(format!(
+            "air_meter-webhooks-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        Actions::new(&path.to_string_lossy()).start()
+    }
+
+    fn header<'a>(headers: &'a HeaderMap, name: &str) -> &'a str {
+        headers.get(name).unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn retries_server_errors_with_a_signed_body() {
+        let (srv, received) = receiver(&[503, 200]);
+        let actions = actions("retry");
+        let hook = hook(&actions, srv.url("/hook"), Some("s3cret")).await;
+        let body = r#"{"id":"0123456789abcdef","event":"ping"}"#.to_owned();
+        deliver(
+            actions.clone(),
+            hook.clone(),
+            PING.to_owned(),
+            "0123456789abcdef".to_owned(),
+            body.clone(),
+        )
+        .await;
+
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len(), 2);
+        for (headers, received_body) in received.iter() {
+            assert_eq!(received_body, &body);
+            assert_eq!(header(headers, "X-Air-Meter-Event"), PING);
+            assert_eq!(header(headers, "X-Air-Meter-Delivery"), "0123456789abcdef");
+            assert_eq!(
+                header(headers, "X-Air-Meter-Signature"),
+                sign("s3cret", body.as_bytes())
+            );
+        }
+        let logged = deliveries(&actions, &hook).await;
+        let attempts = logged
+            .iter()
+            .map(|d| (d.attempt, d.status_code, d.delivered))
+            .collect::<Vec<_>>();
+        assert_eq!(attempts, vec![(2, Some(200), true), (1, Some(503), false)]);
+    }
+
+    #[actix_rt::test]
+    async fn gives_up_on_client_errors() {
+        let (srv, received) = receiver(&[410]);
+        let actions = actions("client_error");
+        let hook = hook(&actions, srv.url("/hook"), None).await;
+        deliver(
+            actions.clone(),
+            hook.clone(),
+            ALERT.to_owned(),
+            "fedcba9876543210".to_owned(),
+            "{}".to_owned(),
+        )
+        .await;
+
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len(), 1);
+        assert!(received[0].0.get("X-Air-Meter-Signature").is_none());
+        let logged = deliveries(&actions, &hook).await;
+        assert_eq!(logged.len(), 1);
+        assert_eq!(logged[0].error.as_deref(), Some("HTTP 410 Gone"));
+        assert!(!logged[0].delivered);
+    }
+}
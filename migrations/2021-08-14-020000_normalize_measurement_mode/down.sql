@@ -0,0 +1,17 @@
+CREATE TABLE readings_old (
+  id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  publisher_id BIGINT NOT NULL,
+  eco2  INTEGER NOT NULL,
+  evtoc  INTEGER NOT NULL,
+  read_time BIGINT NOT NULL,
+  start_time BIGINT NOT NULL,
+  increment TEXT NOT NULL
+);
+
+INSERT INTO readings_old (id, publisher_id, eco2, evtoc, read_time, start_time, increment)
+SELECT r.id, r.publisher_id, r.eco2, r.evtoc, r.read_time, r.start_time, m.name
+FROM readings r JOIN measurement_modes m ON m.id = r.mode;
+
+DROP TABLE readings;
+ALTER TABLE readings_old RENAME TO readings;
+DROP TABLE measurement_modes;
@@ -0,0 +1,29 @@
+CREATE TABLE measurement_modes (
+  id SMALLINT NOT NULL PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE
+);
+
+INSERT INTO measurement_modes (id, name) VALUES
+  (0, 'Unknown'),
+  (1, 'Idle'),
+  (2, 'ConstantPower1s'),
+  (3, 'PulseHeating10s'),
+  (4, 'LowPowerPulseHeating60s'),
+  (5, 'ConstantPower250ms');
+
+CREATE TABLE readings_new (
+  id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  publisher_id BIGINT NOT NULL,
+  eco2  INTEGER NOT NULL,
+  evtoc  INTEGER NOT NULL,
+  read_time BIGINT NOT NULL,
+  start_time BIGINT NOT NULL,
+  mode SMALLINT NOT NULL DEFAULT 0 REFERENCES measurement_modes (id)
+);
+
+INSERT INTO readings_new (id, publisher_id, eco2, evtoc, read_time, start_time, mode)
+SELECT r.id, r.publisher_id, r.eco2, r.evtoc, r.read_time, r.start_time, COALESCE(m.id, 0)
+FROM readings r LEFT JOIN measurement_modes m ON m.name = r.increment;
+
+DROP TABLE readings;
+ALTER TABLE readings_new RENAME TO readings;
@@ -0,0 +1,2 @@
+DROP INDEX readings_read_time;
+DROP INDEX readings_publisher_id_read_time;
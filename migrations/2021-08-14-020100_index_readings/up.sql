@@ -0,0 +1,2 @@
+CREATE INDEX readings_publisher_id_read_time ON readings (publisher_id, read_time);
+CREATE INDEX readings_read_time ON readings (read_time);
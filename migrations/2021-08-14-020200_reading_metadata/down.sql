@@ -0,0 +1,20 @@
+DROP TABLE reading_metrics;
+
+-- sqlite can't drop columns, rebuild the table without them
+CREATE TABLE readings_old (
+  id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  publisher_id BIGINT NOT NULL,
+  eco2  INTEGER NOT NULL,
+  evtoc  INTEGER NOT NULL,
+  read_time BIGINT NOT NULL,
+  start_time BIGINT NOT NULL,
+  mode SMALLINT NOT NULL DEFAULT 0 REFERENCES measurement_modes (id)
+);
+
+INSERT INTO readings_old (id, publisher_id, eco2, evtoc, read_time, start_time, mode)
+SELECT id, publisher_id, eco2, evtoc, read_time, start_time, mode FROM readings;
+
+DROP TABLE readings;
+ALTER TABLE readings_old RENAME TO readings;
+CREATE INDEX readings_publisher_id_read_time ON readings (publisher_id, read_time);
+CREATE INDEX readings_read_time ON readings (read_time);
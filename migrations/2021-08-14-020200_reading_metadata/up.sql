@@ -0,0 +1,15 @@
+ALTER TABLE readings ADD COLUMN sensor_model TEXT;
+ALTER TABLE readings ADD COLUMN temperature DOUBLE;
+ALTER TABLE readings ADD COLUMN humidity DOUBLE;
+ALTER TABLE readings ADD COLUMN quality_flags INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE readings ADD COLUMN raw_current INTEGER;
+ALTER TABLE readings ADD COLUMN raw_voltage INTEGER;
+
+-- metrics reported by sensor types that don't have a dedicated column
+CREATE TABLE reading_metrics (
+  reading_id INTEGER NOT NULL REFERENCES readings (id) ON DELETE CASCADE,
+  metric_name TEXT NOT NULL,
+  value DOUBLE NOT NULL,
+  PRIMARY KEY (reading_id, metric_name)
+);
+CREATE INDEX reading_metrics_metric_name ON reading_metrics (metric_name);
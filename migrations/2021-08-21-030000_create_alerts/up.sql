@@ -0,0 +1,23 @@
+CREATE TABLE alert_rules (
+  id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  name TEXT NOT NULL,
+  -- NULL applies the rule to every publisher
+  publisher_id BIGINT,
+  metric TEXT NOT NULL,
+  trigger_above DOUBLE NOT NULL,
+  trigger_secs BIGINT NOT NULL,
+  clear_below DOUBLE NOT NULL,
+  clear_secs BIGINT NOT NULL,
+  enabled BOOLEAN NOT NULL DEFAULT 1
+);
+
+CREATE TABLE alert_events (
+  id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  rule_id INTEGER NOT NULL REFERENCES alert_rules (id),
+  publisher_id BIGINT NOT NULL,
+  state TEXT NOT NULL,
+  value DOUBLE NOT NULL,
+  event_time BIGINT NOT NULL
+);
+CREATE INDEX alert_events_publisher_id_event_time ON alert_events (publisher_id, event_time);
+CREATE INDEX alert_events_rule_id_publisher_id ON alert_events (rule_id, publisher_id);
@@ -0,0 +1,22 @@
+CREATE TABLE webhooks (
+  id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  url TEXT NOT NULL,
+  -- HMAC-SHA256 key used to sign payloads, unsigned when NULL
+  secret TEXT,
+  -- comma separated event names, or * for every event
+  events TEXT NOT NULL DEFAULT '*',
+  enabled BOOLEAN NOT NULL DEFAULT 1
+);
+
+CREATE TABLE webhook_deliveries (
+  id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  webhook_id INTEGER NOT NULL REFERENCES webhooks (id),
+  event TEXT NOT NULL,
+  payload TEXT NOT NULL,
+  attempt INTEGER NOT NULL,
+  status_code INTEGER,
+  error TEXT,
+  delivered BOOLEAN NOT NULL,
+  created_at BIGINT NOT NULL
+);
+CREATE INDEX webhook_deliveries_webhook_id ON webhook_deliveries (webhook_id, id);
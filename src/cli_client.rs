@@ -1,5 +1,9 @@
 //! Simple websocket client.
 use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
 use std::{io, thread};
 
 use actix::io::SinkWrite;
@@ -8,56 +12,200 @@ use actix_codec::Framed;
 use awc::{
     error::WsProtocolError,
     ws::{Codec, Frame, Message},
-    BoxedSocket, Client,
+    BoxedSocket,
 };
 use bytes::Bytes;
 use futures::stream::{SplitSink, StreamExt};
 
-use library::HEARTBEAT_INTERVAL;
+use library::{
+    logging::{self, LogFormat},
+    tls, HEARTBEAT_INTERVAL,
+};
+
+const USAGE: &str = "usage:
+    client [ws address] [--ca FILE]
+    client export <csv|jsonl|parquet> [--pub-id ID] [--from SECS] [--to SECS] [--server URL] [--out FILE] [--ca FILE]
+    client import <csv|jsonl> <FILE> --pub-id ID [--preset native|aranet|scd30] [--dry-run] [--server URL] [--ca FILE]
+        [--token ADMIN_TOKEN]
+        [--time-col COL] [--eco2-col COL] [--evtoc-col COL] [--temperature-col COL] [--humidity-col COL]
+        [--time-format unix|unix_ms|rfc3339|STRFTIME] [--utc-offset SECS] [--sensor-model MODEL]";
 
 fn main() {
-    env::set_var("RUST_LOG", "actix_web=info");
-    env_logger::init();
+    let filter = env::var("RUST_LOG").unwrap_or_else(|_| "actix_web=info".to_owned());
+    if let Err(err) = logging::init(&filter, LogFormat::Text) {
+        eprintln!("Error: RUST_LOG {}", err);
+        return;
+    }
 
     let sys = System::new("websocket-client");
+    let args: Vec<String> = env::args().collect();
 
-    Arbiter::spawn(async {
-        let backup = "http://192.168.0.67:8080/ws/".to_owned();
-        let args: Vec<String> = env::args().collect();
-        let address = args.get(1).unwrap_or(&backup);
-        println!("Connecting to {:?}", address);
-        let (response, framed) = Client::new()
-            .ws(address)
-            .connect()
-            .await
-            .map_err(|e| {
-                println!("Error: {}", e);
-            })
-            .unwrap();
-
-        println!("{:?}", response);
-        let (sink, stream) = framed.split();
-        let addr = ChatClient::create(|ctx| {
-            ChatClient::add_stream(stream, ctx);
-            ChatClient {
-                sink: SinkWrite::new(sink, ctx),
-                cache: 0,
+    match args.get(1).map(String::as_str) {
+        Some("export") => Arbiter::spawn(async move {
+            if let Err(err) = export(&args[2..]).await {
+                eprintln!("Error: {}\n{}", err, USAGE);
             }
-        });
-
-        // start console loop
-        thread::spawn(move || loop {
-            let mut cmd = String::new();
-            if io::stdin().read_line(&mut cmd).is_err() {
-                println!("error");
-                return;
+            System::current().stop();
+        }),
+        Some("import") => Arbiter::spawn(async move {
+            if let Err(err) = import(&args[2..]).await {
+                eprintln!("Error: {}\n{}", err, USAGE);
             }
-            addr.do_send(ClientCommand(cmd));
-        });
-    });
+            System::current().stop();
+        }),
+        _ => Arbiter::spawn(async move {
+            if let Err(err) = subscribe(&args[1..]).await {
+                eprintln!("Error: {}\n{}", err, USAGE);
+                System::current().stop();
+            }
+        }),
+    }
     sys.run().unwrap();
 }
 
+/// `wss://` connections trust only the CA certificates in `--ca` when given
+async fn subscribe(args: &[String]) -> Result<(), String> {
+    let mut address = "http://192.168.0.67:8080/ws/".to_owned();
+    let mut ca: Option<String> = None;
+    let mut opts = args.iter();
+    while let Some(opt) = opts.next() {
+        match opt.as_str() {
+            "--ca" => ca = Some(opts.next().ok_or("missing value for --ca")?.to_owned()),
+            _ => address = opt.to_owned(),
+        }
+    }
+    println!("Connecting to {:?}", address);
+    let (response, framed) = tls::ws_client(ca.as_deref().map(Path::new))?
+        .ws(address)
+        .connect()
+        .await
+        .map_err(|e| format!("{}", e))?;
+
+    println!("{:?}", response);
+    let (sink, stream) = framed.split();
+    let addr = ChatClient::create(|ctx| {
+        ChatClient::add_stream(stream, ctx);
+        ChatClient {
+            sink: SinkWrite::new(sink, ctx),
+            cache: 0,
+        }
+    });
+
+    // start console loop
+    thread::spawn(move || loop {
+        let mut cmd = String::new();
+        if io::stdin().read_line(&mut cmd).is_err() {
+            println!("error");
+            return;
+        }
+        addr.do_send(ClientCommand(cmd));
+    });
+    Ok(())
+}
+
+/// download readings from the export endpoint, writing chunks as they arrive
+async fn export(args: &[String]) -> Result<(), String> {
+    let format = args.first().ok_or("missing export format")?;
+    let mut server = "http://192.168.0.67:8080".to_owned();
+    let mut out: Option<String> = None;
+    let mut ca: Option<String> = None;
+    let mut query = vec![format!("format={}", format)];
+    let mut opts = args[1..].iter();
+    while let Some(opt) = opts.next() {
+        let value = opts
+            .next()
+            .ok_or_else(|| format!("missing value for {}", opt))?;
+        match opt.as_str() {
+            "--pub-id" => query.push(format!("pub_id={}", value)),
+            "--from" => query.push(format!("from={}", value)),
+            "--to" => query.push(format!("to={}", value)),
+            "--server" => server = value.trim_end_matches('/').to_owned(),
+            "--out" => out = Some(value.to_owned()),
+            "--ca" => ca = Some(value.to_owned()),
+            _ => return Err(format!("unknown option {}", opt)),
+        }
+    }
+    let url = format!("{}/api/v1/readings/export?{}", server, query.join("&"));
+    let mut res = tls::ws_client(ca.as_deref().map(Path::new))?
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| format!("{}", e))?;
+    if !res.status().is_success() {
+        return Err(format!("server responded {}", res.status()));
+    }
+    let mut writer: Box<dyn Write> = match &out {
+        Some(path) => Box::new(File::create(path).map_err(|e| format!("{}", e))?),
+        None => Box::new(io::stdout()),
+    };
+    let mut total = 0;
+    while let Some(chunk) = res.next().await {
+        let chunk = chunk.map_err(|e| format!("{}", e))?;
+        total += chunk.len();
+        writer.write_all(&chunk).map_err(|e| format!("{}", e))?;
+    }
+    writer.flush().map_err(|e| format!("{}", e))?;
+    if let Some(path) = out {
+        println!("wrote {} bytes to {}", total, path);
+    }
+    Ok(())
+}
+
+/// upload a csv or jsonl file of readings to the import endpoint and print the
+/// report, authorized with `--token` or `ADMIN_TOKEN`
+async fn import(args: &[String]) -> Result<(), String> {
+    let format = args.first().ok_or("missing import format")?;
+    let path = args.get(1).ok_or("missing file to import")?;
+    let mut server = "http://192.168.0.67:8080".to_owned();
+    let mut ca: Option<String> = None;
+    let mut token = env::var("ADMIN_TOKEN").ok();
+    let mut query = vec![("format".to_owned(), format.to_owned())];
+    let mut opts = args[2..].iter();
+    while let Some(opt) = opts.next() {
+        if opt == "--dry-run" {
+            query.push(("dry_run".to_owned(), "true".to_owned()));
+            continue;
+        }
+        let value = opts
+            .next()
+            .ok_or_else(|| format!("missing value for {}", opt))?;
+        match opt.as_str() {
+            "--server" => server = value.trim_end_matches('/').to_owned(),
+            "--ca" => ca = Some(value.to_owned()),
+            "--token" => token = Some(value.to_owned()),
+            "--pub-id" | "--preset" | "--time-col" | "--eco2-col" | "--evtoc-col"
+            | "--temperature-col" | "--humidity-col" | "--time-format" | "--utc-offset"
+            | "--sensor-model" => query.push((
+                opt.trim_start_matches("--").replace('-', "_"),
+                value.to_owned(),
+            )),
+            _ => return Err(format!("unknown option {}", opt)),
+        }
+    }
+    let body = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let url = format!("{}/api/v1/readings/import", server);
+    let token = token.ok_or("missing --token or ADMIN_TOKEN")?;
+    let mut res = tls::ws_client(ca.as_deref().map(Path::new))?
+        .post(&url)
+        .bearer_auth(token)
+        .timeout(Duration::from_secs(300))
+        .query(&query)
+        .map_err(|e| format!("{}", e))?
+        .send_body(body)
+        .await
+        .map_err(|e| format!("{}", e))?;
+    let report = res.body().await.map_err(|e| format!("{}", e))?;
+    if !res.status().is_success() {
+        return Err(format!(
+            "server responded {}: {}",
+            res.status(),
+            String::from_utf8_lossy(&report)
+        ));
+    }
+    println!("{}", String::from_utf8_lossy(&report));
+    Ok(())
+}
+
 struct ChatClient {
     sink: SinkWrite<Message, SplitSink<Framed<BoxedSocket, Codec>, Message>>,
     cache: u64,
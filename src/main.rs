@@ -2,48 +2,192 @@ use actix::*;
 use actix_cors::Cors;
 
 use library::{
-    db::Actions, rest_api::rest_config, templates, ws_route, RelayServer, SessionClient,
+    config::{self, ServerConfig},
+    db::{
+        backup::{self, Backups},
+        Actions,
+    },
+    health::{self, Health},
+    influx::InfluxSink,
+    logging, metrics,
+    mqtt::MqttBridge,
+    rate_limit::RateLimits,
+    report::Reports,
+    rest_api::{
+        handlers::admin::AdminToken,
+        openapi::{self, ApiDoc},
+        rest_config,
+    },
+    shutdown::Shutdown,
+    systemd::{self, InstanceLock, Watchdog},
+    templates,
+    tls::{self, CertReloader},
+    webhooks::Webhooks,
+    ws_route, RelayServer, SessionClient,
 };
+use std::path::Path;
 use std::sync::{atomic::AtomicUsize, Arc};
+use std::time::Duration;
 
 use actix_files as fs;
-use actix_web::{http::header, middleware, web, App, HttpRequest, HttpServer, Responder};
-
-const ADDRESS: &str = "0.0.0.0:8080";
-async fn greet(req: HttpRequest) -> impl Responder {
-    let name = req.match_info().get("name").unwrap_or("World");
-    format!("Hello {}!", &name)
-}
+use actix_web::{http::header, middleware, web, App, HttpServer};
+use utoipa::OpenApi;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    std::env::set_var("RUST_LOG", "actix_server=info,actix_web=info");
-    env_logger::init();
     dotenv::dotenv().ok();
 
+    // `server restore <backup>` swaps a backup in for the database then exits
+    let args: Vec<String> = std::env::args().collect();
+    // `server openapi [typescript]` prints the REST api's document, or the web
+    // app's types generated from it, then exits
+    if args.get(1).map(String::as_str) == Some("openapi") {
+        let doc = ApiDoc::openapi();
+        match args.get(2).map(String::as_str) {
+            Some("typescript") => print!("{}", openapi::typescript(&doc)),
+            _ => println!("{}", doc.to_pretty_json()?),
+        }
+        return Ok(());
+    }
+    let restore = args.get(1).map(String::as_str) == Some("restore");
+
+    // defaults < config file < env vars < flags, exiting if any layer is invalid
+    let flags = if restore { &[][..] } else { &args[1..] };
+    let mut server_config = ServerConfig::load(flags).map_err(|err| {
+        eprintln!("{}\n{}", err, config::USAGE);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, err)
+    })?;
+    logging::init(&server_config.log, server_config.log_format)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    server_config.install();
+    tracing::info!(config = %server_config.to_toml(), "effective config");
+
+    // one server at a time, held until exit. restores take it too
+    let _instance_lock = match server_config.lock_file.as_os_str().is_empty() {
+        true => None,
+        false => Some(
+            InstanceLock::acquire(&server_config.lock_file)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::AddrInUse, err))?,
+        ),
+    };
+
+    // sockets from a systemd socket unit replace `bind`
+    let activated = systemd::listeners()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    if let Some(addr) = activated.first().and_then(|l| l.local_addr().ok()) {
+        tracing::info!(%addr, sockets = activated.len(), "socket activated");
+        server_config.bind = addr.to_string();
+    }
+
     // App State
     // keep count of visitors
     let app_state = Arc::new(AtomicUsize::new(0));
-    // TODO track server uptime
+
+    let connspec = server_config.database_url.clone();
+
+    if restore {
+        let backup_path = args.get(2).expect("usage: server restore <backup file>");
+        return backup::restore(Path::new(&connspec), Path::new(backup_path))
+            .map(|msg| tracing::info!("{}", msg))
+            .map_err(std::io::Error::other);
+    }
 
     // set up database connection pool
-    let connspec = std::env::var("DATABASE_URL").expect("DATABASE_URL");
     let db_actions = Actions::new(&connspec).start();
 
+    // database backups, scheduled every `backup.interval_hours` (0 disables)
+    let db_path = connspec.clone().into();
+    let backup_config = server_config.backup.clone();
+    let backups = Backups::start_in_arbiter(&Arbiter::new(), move |_| {
+        Backups::new(db_path, backup_config)
+    });
+    let admin_token = AdminToken(server_config.admin_token.clone());
+    let ingest_tokens = server_config.ingest_tokens.clone();
+
+    // webhook notifications for alerts and publisher events
+    let webhooks = Webhooks::new(db_actions.clone()).start();
+
     //start relay server actor
-    let server = RelayServer::new(app_state.clone(), db_actions.clone()).start();
+    let server = RelayServer::new(
+        app_state.clone(),
+        db_actions.clone(),
+        webhooks.clone(),
+        server_config.anomaly,
+    )
+    .start();
+
+    // daily and weekly reports, written to `report.dir` when set
+    let report_thresholds = server_config.report.thresholds();
+    let report_config = server_config.report.clone();
+    if let Some(reports) = Reports::new(report_config, db_actions.clone(), webhooks.clone()) {
+        reports.start();
+    }
+
+    // optional mqtt bridge, enabled by a `[mqtt]` section or MQTT_HOST
+    if let Some(mqtt_config) = server_config.mqtt.clone() {
+        MqttBridge::start(mqtt_config, server.clone());
+    }
+
+    // optional influxdb sink, enabled by an `[influx]` section or INFLUX_URL
+    if let Some(influx_config) = server_config.influx.clone() {
+        InfluxSink::start(influx_config, server.clone());
+    }
 
     // initialize sqlite db if not already initialized
 
-    SessionClient::spawn("127.0.0.1:8080");
+    // https and wss, with certificates reloaded as they're renewed
+    let tls = match &server_config.tls {
+        Some(tls_config) => {
+            let (rustls_config, resolver) = tls::server_config(tls_config)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+            if let Some(reloader) = CertReloader::new(tls_config, resolver) {
+                reloader.start();
+            }
+            Some(rustls_config)
+        }
+        None => None,
+    };
+
+    let sensor = if server_config.local_sensor {
+        let (url, ca) = server_config.local_url();
+        Some(SessionClient::spawn(
+            url,
+            ca,
+            Some(server_config.sensor_baseline.clone()),
+        ))
+    } else {
+        None
+    };
+    // component status and uptime for /healthz and /readyz
+    let health = Health::new(server.clone(), db_actions.clone(), sensor.clone());
+    // keepalives for systemd's watchdog while the actors are healthy
+    if let Some(watchdog) = Watchdog::new(health.clone()) {
+        watchdog.start();
+    }
+    let shutdown = Shutdown {
+        relay: server.clone(),
+        actions: db_actions.clone(),
+        sensor: sensor.map(Addr::recipient),
+        deadline: Duration::from_secs(server_config.shutdown_timeout_secs),
+    };
 
-    HttpServer::new(move || {
+    // per ip buckets, shared by every worker
+    let rate_limits = RateLimits::new(&server_config.limits);
+
+    let cors_origins = server_config.cors_origins.clone();
+    let static_dir = server_config.static_dir.clone();
+    let http_server = HttpServer::new(move || {
+        let cors =
+            cors_origins
+                .iter()
+                .fold(Cors::default(), |cors, origin| match origin.as_str() {
+                    "*" => cors.allow_any_origin(),
+                    _ => cors.allowed_origin(origin),
+                });
         App::new()
             // configure CORS
             .wrap(
-                Cors::default()
-                    .allowed_origin("http://localhost:3000")
-                    .allowed_methods(vec!["GET", "POST"])
+                cors.allowed_methods(vec!["GET", "POST", "DELETE"])
                     .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
                     .allowed_header(header::CONTENT_TYPE)
                     .supports_credentials()
@@ -51,24 +195,55 @@ async fn main() -> std::io::Result<()> {
             )
             // enable logger
             .wrap(middleware::Logger::default())
-            // test route
-            .route("/test", web::get().to(greet))
-            .route("/test/{name}", web::get().to(greet))
+            // liveness and readiness checks
+            .data(health.clone())
+            .route("/healthz", web::get().to(health::healthz))
+            .route("/readyz", web::get().to(health::readyz))
             // relay_server
             .data(server.clone())
             .data(app_state.clone())
             // db actions
             .data(db_actions.clone())
+            .data(backups.clone())
+            .data(admin_token.clone())
+            .data(ingest_tokens.clone())
+            .data(webhooks.clone())
+            .data(report_thresholds.clone())
+            .data(rate_limits.clone())
+            // prometheus metrics
+            .route("/metrics", web::get().to(metrics::serve))
             // websocket route
             .service(web::resource("/ws/").to(ws_route))
             // confiure REST api
             .configure(rest_config)
             // static files
-            .service(fs::Files::new("/static", "./static").index_file("./static/404.html"))
+            .service(
+                fs::Files::new("/static", &static_dir)
+                    .index_file(static_dir.join("404.html").to_string_lossy()),
+            )
             .service(web::resource("/").route(web::get().to(templates::index)))
+            .service(web::resource("/reports/{pub_id}").route(web::get().to(templates::report)))
     })
-    // start http server on 127.0.0.1:8080
-    .bind(ADDRESS)?
-    .run()
-    .await
+    // signals are handled by `Shutdown`
+    .disable_signals()
+    .shutdown_timeout(server_config.shutdown_timeout_secs);
+    let http_server = match (tls, activated.is_empty()) {
+        (Some(rustls_config), true) => {
+            http_server.bind_rustls(&server_config.bind, rustls_config)?
+        }
+        (None, true) => http_server.bind(&server_config.bind)?,
+        (tls, false) => activated
+            .into_iter()
+            .try_fold(http_server, |http_server, listener| match &tls {
+                Some(rustls_config) => http_server.listen_rustls(listener, rustls_config.clone()),
+                None => http_server.listen(listener),
+            })?,
+    };
+    let running = http_server.run();
+    systemd::notify_or_log(&format!(
+        "READY=1\nSTATUS=listening on {}",
+        server_config.bind
+    ));
+    actix::spawn(shutdown.on_signal(running.clone()));
+    running.await
 }
@@ -0,0 +1,337 @@
+// generated from the server's OpenAPI document by `server openapi typescript`, don't edit
+
+/** how many posted readings were relayed */
+export interface Accepted {
+    accepted: number;
+}
+
+/** a rule currently firing for a publisher */
+export interface ActiveAlert {
+    rule_id: number;
+    name: string;
+    pub_id: number;
+    metric: string;
+    /** metric value of the reading that fired the alert */
+    value: number;
+    /** unix seconds it fired at */
+    since: number;
+}
+
+/** times a rule fired in the period */
+export interface AlertCount {
+    rule_id: number;
+    name: string;
+    fired: number;
+}
+
+/** an alert firing or clearing */
+export interface AlertEvent {
+    id: number;
+    rule_id: number;
+    pub_id: number;
+    state: AlertState;
+    /** metric value of the reading that caused the transition */
+    value: number;
+    /** unix seconds */
+    time: number;
+}
+
+/** threshold alert with hysteresis, fires when `metric` stays above `trigger_above` for `trigger_secs` and clears once it stays below `clear_below` for `clear_secs` */
+export interface AlertRule {
+    id: number;
+    name: string;
+    /** the rule applies to every publisher when none */
+    pub_id?: number | null;
+    /** `eco2`, `evtoc`, `temperature`, `humidity`, `iaq` or a metric a sensor reports */
+    metric: string;
+    trigger_above: number;
+    trigger_secs: number;
+    clear_below: number;
+    clear_secs: number;
+    enabled: boolean;
+}
+
+export type AlertState = "firing" | "cleared";
+
+/** ventilation and occupancy estimated from a publisher's CO2 readings */
+export interface Analytics {
+    pub_id: number;
+    /** unix seconds of the range analysed */
+    from: number;
+    to: number;
+    outdoor_co2: number;
+    /** room volume in m³ */
+    volume?: number | null;
+    ventilation: Ventilation;
+    occupancy: Occupancy[];
+}
+
+export interface ApiError {
+    /** `bad_request`, `unauthorized`, `forbidden`, `not_found`, `too_large`, `too_many_requests` or `internal` */
+    error: string;
+    message: string;
+}
+
+export interface BackupInfo {
+    name: string;
+    size: number;
+    /** unix seconds */
+    created: number;
+}
+
+/** a publisher's readings aggregated over a time bucket, indexed by their means */
+export interface Bucket {
+    /** unix seconds the bucket starts at */
+    start: number;
+    readings: number;
+    /** readings flagged `flatline`, `spike` or `out_of_range` */
+    suspect: number;
+    eco2: Stats;
+    evtoc?: Stats | null;
+    iaq: Iaq;
+}
+
+export type Category = "excellent" | "good" | "fair" | "poor" | "bad";
+
+export interface DaySummary {
+    /** `YYYY-MM-DD`, UTC */
+    date: string;
+    readings: number;
+    eco2: Stats;
+    evtoc?: Stats | null;
+}
+
+/** CO2 falling after a room emptied, fitted to find the air changes per hour */
+export interface Decay {
+    /** unix seconds */
+    start: number;
+    end: number;
+    /** ppm */
+    start_co2: number;
+    end_co2: number;
+    ach: number;
+    /** coefficient of determination of the fit */
+    r2: number;
+}
+
+export type ExportFormat = "csv" | "jsonl" | "parquet";
+
+/** why a reading may be inaccurate, or where it came from */
+export type Flag = "imported" | "no_evtoc" | "flatline" | "spike" | "out_of_range";
+
+export interface HourSummary {
+    /** unix seconds */
+    start: number;
+    eco2_mean: number;
+    evtoc_mean?: number | null;
+    iaq: Iaq;
+}
+
+/** index of a reading or an aggregate of readings */
+export interface Iaq {
+    /** 0 (bad) to 100 (excellent), the lower of the co2 and tvoc scores */
+    score: number;
+    category: Category;
+    co2: Category;
+    tvoc?: Category | null;
+}
+
+export type ImportFormat = "csv" | "jsonl";
+
+export interface ImportReport {
+    dry_run: boolean;
+    /** rows inserted, or that would be inserted on a dry run */
+    inserted: number;
+    /** rows with a (publisher_id, read_time) already stored or repeated in the file */
+    skipped: number;
+    /** rows that couldn't be mapped onto a reading */
+    rejected: number;
+    errors: string[];
+}
+
+/** Sensor measurement mode, stored as a `measurement_modes` id serialized with the same names the sensor client reports as `increment` */
+export type Mode = "Unknown" | "Idle" | "ConstantPower1s" | "PulseHeating10s" | "LowPowerPulseHeating60s" | "ConstantPower250ms";
+
+export interface NewAlertRule {
+    name: string;
+    /** every publisher when none */
+    pub_id?: number | null;
+    /** `eco2`, `evtoc`, `temperature`, `humidity`, `iaq` or a metric a sensor has reported */
+    metric: string;
+    trigger_above: number;
+    trigger_secs: number;
+    /** must not be above `trigger_above` */
+    clear_below: number;
+    clear_secs: number;
+}
+
+export interface NewWebhook {
+    /** http or https */
+    url: string;
+    /** signs deliveries, never returned */
+    secret?: string | null;
+    /** event names, or `*` for every event */
+    events: string[];
+}
+
+/** CO2 steady while a room was occupied */
+export interface Occupancy {
+    /** unix seconds */
+    start: number;
+    end: number;
+    /** mean ppm */
+    co2: number;
+    /** none without a ventilation rate or room volume */
+    occupants?: number | null;
+    /** 0-1 */
+    confidence: number;
+}
+
+export type Period = "daily" | "weekly";
+
+/** timestamp precision of written lines */
+export type Precision = "ns" | "us" | "ms" | "s";
+
+/** known file layouts, individual columns can still be overridden */
+export type Preset = "native" | "aranet" | "scd30";
+
+/** Publisher reading */
+export interface Reading {
+    /** optional for http publishers, whose publisher id is in the url */
+    pub_id?: number;
+    eco2: number;
+    evtoc: number;
+    read_time: number;
+    start_time: number;
+    increment: string;
+    /** sensor hardware, e.g. `CCS811` */
+    sensor_model?: string | null;
+    /** degrees celsius */
+    temperature?: number | null;
+    /** relative humidity percentage */
+    humidity?: number | null;
+    raw_current?: number | null;
+    raw_voltage?: number | null;
+    /** any other metrics the sensor reports, stored in `reading_metrics` */
+    metrics?: Record<string, number>;
+    /** logged with the reading from the sensor read to the db insert, the relay assigns one if the publisher didn't */
+    corr_id?: string | null;
+}
+
+/** a single reading or an array of them */
+export type ReadingsBody = Reading | Reading[];
+
+export type RelayedReading = Reading & {
+    pub_id: number;
+    iaq: Iaq;
+};
+
+/** a publisher's air quality over a day or week */
+export interface Report {
+    pub_id: number;
+    period: Period;
+    /** unix seconds, `to` is exclusive */
+    from: number;
+    to: number;
+    generated: number;
+    readings: number;
+    /** readings flagged `flatline`, `spike` or `out_of_range` */
+    suspect_readings: number;
+    eco2_above: TimeAbove;
+    evtoc_above: TimeAbove;
+    days: DaySummary[];
+    /** highest mean eCO2 hours, worst first */
+    worst_hours: HourSummary[];
+    alerts_fired: number;
+    alerts: AlertCount[];
+    uptime: Uptime;
+}
+
+export interface Stats {
+    min: number;
+    max: number;
+    mean: number;
+}
+
+/** a stored reading with its air quality index */
+export interface StoredReading {
+    id: number;
+    pub_id: number;
+    /** ppm */
+    eco2: number;
+    /** ppb, none when the sensor reported no TVOC value */
+    evtoc?: number | null;
+    /** unix seconds */
+    read_time: number;
+    /** when the sensor started its current run, unix seconds */
+    start_time: number;
+    mode: Mode;
+    /** sensor hardware, e.g. `CCS811` */
+    sensor_model?: string | null;
+    /** degrees celsius */
+    temperature?: number | null;
+    /** relative humidity percentage */
+    humidity?: number | null;
+    flags: Flag[];
+    iaq: Iaq;
+}
+
+/** time spent above a threshold */
+export interface TimeAbove {
+    threshold: number;
+    secs: number;
+    /** of the time the sensor reported */
+    percent: number;
+}
+
+export interface Uptime {
+    /** time with at least one reading, to the minute */
+    reporting_secs: number;
+    /** time elapsed in the period */
+    period_secs: number;
+    percent: number;
+    longest_gap_secs: number;
+}
+
+export interface Ventilation {
+    /** air changes per hour, none without a decay to fit */
+    ach?: number | null;
+    /** 0-1 */
+    confidence: number;
+    decays: Decay[];
+}
+
+/** url notified of alerts and device events */
+export interface Webhook {
+    id: number;
+    url: string;
+    /** event names, or `*` for every event */
+    events: string[];
+    /** deliveries carry a signature made with the webhook's secret */
+    signed: boolean;
+    enabled: boolean;
+}
+
+/** a single attempt to deliver an event to a webhook */
+export interface WebhookDelivery {
+    id: number;
+    webhook_id: number;
+    event: string;
+    /** the json body sent */
+    payload: string;
+    attempt: number;
+    /** none when no response was received */
+    status_code?: number | null;
+    error?: string | null;
+    delivered: boolean;
+    /** unix seconds */
+    time: number;
+}
+
+export interface WriteReport {
+    code: string;
+    message: string;
+    accepted: number;
+    rejected: number;
+    errors: string[];
+}
@@ -4,9 +4,9 @@ import {
     latestReadout,
     earliestReadTime,
     publisherList,
-    Reading,
     readingRangesList,
 } from '../state/sensors';
+import type {RelayedReading} from '../api';
 import RelayWS from '../WebSocket';
 
 /// helper fn for splitting string by seperator once
@@ -34,7 +34,7 @@ export const newReadingHandler = selector<string>({
     set: ({set, get}, msg) => {
         if (msg instanceof DefaultValue) throw Error('not implemented');
         const [_, data] = splitCmd(msg);
-        const reading = JSON.parse(data) as Reading;
+        const reading = JSON.parse(data) as RelayedReading;
         const cursor = `${reading.pub_id}|live`;
         if (get(earliestReadTime(reading.pub_id)) === null) {
             set(earliestReadTime(reading.pub_id), reading.read_time);
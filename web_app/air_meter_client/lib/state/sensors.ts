@@ -6,8 +6,9 @@ import {
     useRecoilCallback,
 } from 'recoil';
 import {API_ADDRESS} from '../API_ADDRESS';
+import type {RelayedReading, StoredReading} from '../api';
 
-export const latestReadout = atomFamily<Reading | null, number>({
+export const latestReadout = atomFamily<RelayedReading | null, number>({
     key: 'latestReadout_v1',
     default: null,
 });
@@ -33,14 +34,9 @@ export const readingCursorSet = atomFamily<Set<string>, number>({
     default: (pubId: number) => new Set([`${pubId}|live`]),
 });
 
-export interface Reading {
-    pub_id: number;
-    eco2: number;
-    evtoc: number;
-    read_time: number;
-    start_time: number;
-    increment: string;
-}
+/// a live reading from the websocket or a stored one from the REST api,
+/// both generated from the server's OpenAPI document
+export type Reading = RelayedReading | StoredReading;
 
 /// contains ranges of readings for different sensors, set by getEarlierReadings
 /// indexed by `{sensor_id}|{before}|{limit}` from `readingCursorList`
@@ -66,18 +62,17 @@ export function useSensorReadingsAPI() {
                 );
                 if (existing !== null) return;
                 const query = new URLSearchParams({
-                    pub_id: String(pubId),
                     before: String(readTime),
                     limit: String(limit),
                 });
-                fetch(`${API_ADDRESS}sensors/readings?${query}`, {
+                fetch(`${API_ADDRESS}v1/sensors/${pubId}/readings?${query}`, {
                     method: 'GET',
                     headers: {
                         Accept: 'application/json',
                     },
                 }).then((res) => {
                     if (!res.ok) throw res;
-                    res.json().then((data: Reading[]) => {
+                    res.json().then((data: StoredReading[]) => {
                         if (Array.isArray(data)) {
                             if (data.length) {
                                 const fReading = data[0];